@@ -0,0 +1,115 @@
+//! The flash of the STM32F407, programmed through its registers a word at a
+//! time.
+
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+use keypad_logic::boot::FLASH_BASE;
+use stm32_metapac::FLASH;
+use stm32_metapac::flash::vals::Psize;
+
+const KEY_1: u32 = 0x4567_0123;
+const KEY_2: u32 = 0xCDEF_89AB;
+
+/// The whole flash, 1 MiB, as offsets from [`FLASH_BASE`]. `ERASE_SIZE` is
+/// that of the sectors used through it: 16 KiB for sectors 0-3, 64 KiB for
+/// sector 4 and 128 KiB for the rest.
+pub struct Flash<const ERASE_SIZE: usize>;
+
+#[derive(Debug)]
+pub struct Error;
+
+impl NorFlashError for Error {
+    fn kind(&self) -> NorFlashErrorKind {
+        NorFlashErrorKind::Other
+    }
+}
+
+impl<const ERASE_SIZE: usize> ErrorType for Flash<ERASE_SIZE> {
+    type Error = Error;
+}
+
+impl<const ERASE_SIZE: usize> ReadNorFlash for Flash<ERASE_SIZE> {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Error> {
+        let from = (FLASH_BASE + offset) as *const u8;
+        unsafe { core::ptr::copy_nonoverlapping(from, bytes.as_mut_ptr(), bytes.len()) };
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        1024 * 1024
+    }
+}
+
+impl<const ERASE_SIZE: usize> NorFlash for Flash<ERASE_SIZE> {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Error> {
+        let mut at = from;
+        while at < to {
+            let (sector, size) = sector(at);
+            unlocked(|| {
+                FLASH.cr().write(|w| {
+                    w.set_ser(true);
+                    w.set_snb(sector);
+                    w.set_psize(Psize::PSIZE32);
+                });
+                FLASH.cr().modify(|w| w.set_strt(true));
+            })?;
+            at += size;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+        for (i, word) in bytes.chunks_exact(4).enumerate() {
+            let address = (FLASH_BASE + offset) as usize + i * 4;
+            let word = u32::from_le_bytes(word.try_into().unwrap());
+            unlocked(|| {
+                FLASH.cr().write(|w| {
+                    w.set_pg(true);
+                    w.set_psize(Psize::PSIZE32);
+                });
+                unsafe { (address as *mut u32).write_volatile(word) };
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// The sector at `offset`, and its size.
+fn sector(offset: u32) -> (u8, u32) {
+    match offset {
+        0..0x1_0000 => ((offset / 0x4000) as u8, 0x4000),
+        0x1_0000..0x2_0000 => (4, 0x1_0000),
+        _ => (5 + ((offset - 0x2_0000) / 0x2_0000) as u8, 0x2_0000),
+    }
+}
+
+/// Runs an operation started by `start` with the flash unlocked, waits for
+/// it and locks the flash again.
+fn unlocked(start: impl FnOnce()) -> Result<(), Error> {
+    if FLASH.cr().read().lock() {
+        FLASH.keyr().write_value(KEY_1);
+        FLASH.keyr().write_value(KEY_2);
+    }
+    // Clears the errors of earlier operations
+    FLASH.sr().write(|w| {
+        w.set_operr(true);
+        w.set_wrperr(true);
+        w.set_pgaerr(true);
+        w.set_pgperr(true);
+        w.set_pgserr(true);
+    });
+    start();
+    while FLASH.sr().read().bsy() {}
+    let sr = FLASH.sr().read();
+    FLASH.cr().write(|w| w.set_lock(true));
+    if sr.operr() || sr.wrperr() || sr.pgaerr() || sr.pgperr() || sr.pgserr() {
+        return Err(Error);
+    }
+    Ok(())
+}
@@ -0,0 +1,44 @@
+//! The A/B bootloader of the STM32F407 Discovery board, in sector 0.
+//!
+//! Swaps in a downloaded update, or swaps the old firmware back after an
+//! update that never confirmed itself, and starts the firmware in the active
+//! partition, see `keypad_logic::boot`. Flash once with a probe:
+//!
+//! ```text
+//! cargo run --release -p keypad-bootloader
+//! ```
+
+#![no_std]
+#![no_main]
+
+mod flash;
+
+use crate::flash::Flash;
+use core::panic::PanicInfo;
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::entry;
+use keypad_logic::boot::{self, ACTIVE, DFU, FLASH_BASE, Partition, STATE};
+
+#[entry]
+fn main() -> ! {
+    let mut state = Partition::new(Flash::<0x1_0000>, STATE.offset, STATE.size);
+    let mut active = Partition::new(Flash::<0x2_0000>, ACTIVE.offset, ACTIVE.size);
+    let mut dfu = Partition::new(Flash::<0x2_0000>, DFU.offset, DFU.size);
+    // A flash that fails leaves nothing better to start than what is there
+    let _ = boot::prepare(&mut state, &mut active, &mut dfu);
+
+    let firmware = FLASH_BASE + ACTIVE.offset;
+    unsafe {
+        (*SCB::PTR).vtor.write(firmware);
+        cortex_m::asm::bootload(firmware as *const u32)
+    }
+}
+
+// Stops rather than start firmware a swap may have left half copied, until
+// the next reset tries again
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
@@ -1,35 +1,816 @@
-//! This build script copies the `memory.x` file from the crate root into
-//! a directory where the linker can always find it at build time.
-//! For many projects this is optional, as the linker always searches the
-//! project root directory -- wherever `Cargo.toml` is. However, if you
-//! are using a workspace or have a more complicated build setup, this
-//! build script becomes required. Additionally, by requesting that
-//! Cargo re-run the build script whenever `memory.x` is changed,
-//! updating `memory.x` ensures a rebuild of the application with the
-//! new memory settings.
+//! This build script puts the board's memory layout into a directory where
+//! the linker can always find it at build time, as `memory.x`:
+//! `memory-ab.x` on boards with A/B updates, whose firmware starts in the
+//! active partition after the bootloader, and `memory-in-place.x` on those
+//! updated in place by the system bootloader. Neither is called `memory.x`,
+//! as the linker would find that in the project root first. Cargo re-runs
+//! the build script whenever either changes.
+//!
+//! It also turns `keypad.toml` into `keypad_config.rs`, the pins, matrix,
+//! keymap, USB identity, timing and MIDI map that `src/keypad_config.rs`
+//! includes.
+//! Mistakes in the file fail the build with one error per mistake.
 
+use std::collections::HashSet;
 use std::env;
-use std::fs::File;
+use std::fmt::Write as _;
+use std::fs::{self, File};
 use std::io::Write;
 use std::path::PathBuf;
+use toml::{Table, Value};
+
+/// Keymap layers, as `LAYERS` in `src/keymap.rs`.
+const LAYERS: usize = 4;
+/// Scans report one bit per key in a `u16`.
+const MAX_KEYS: usize = 16;
+/// Labels of the keys that pick the mode and configuration channel at boot,
+/// the profile chord and the phone's hook, flash, redial and mute buttons.
+const REQUIRED_LABELS: [char; 6] = ['A', 'B', 'C', 'D', '*', '#'];
+/// VIA's matrix test packs a row into a byte.
+const MAX_COLUMNS: usize = 8;
+/// Longest USB string, as `STRING_LEN` in `src/usb_identity.rs`.
+const STRING_LEN: usize = 32;
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let memory: &[u8] = if env::var_os("CARGO_FEATURE_AB_UPDATES").is_some() {
+        include_bytes!("memory-ab.x")
+    } else {
+        include_bytes!("memory-in-place.x")
+    };
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(memory)
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
     // By default, Cargo will re-run a build script whenever
-    // any file in the project changes. By specifying `memory.x`
+    // any file in the project changes. By specifying the layouts
     // here, we ensure the build script is only re-run when
-    // `memory.x` is changed.
-    println!("cargo:rerun-if-changed=memory.x");
+    // one of them is changed.
+    println!("cargo:rerun-if-changed=memory-ab.x");
+    println!("cargo:rerun-if-changed=memory-in-place.x");
 
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
+
+    println!("cargo:rerun-if-changed=keypad.toml");
+    for var in USB_ENV {
+        println!("cargo:rerun-if-env-changed={var}");
+    }
+    let mut config = Config::default();
+    match fs::read_to_string("keypad.toml") {
+        Ok(text) => match text.parse::<Table>() {
+            Ok(table) => config.read(&table),
+            Err(error) => {
+                let offset = error.span().map_or(0, |span| span.start);
+                let line = text[..offset].lines().count().max(1);
+                config.error(format!("line {line}: {}", error.message()))
+            }
+        },
+        Err(error) => config.error(format!("can't be read: {error}")),
+    }
+    if !config.errors.is_empty() {
+        for error in &config.errors {
+            println!("cargo::error=keypad.toml: {error}");
+        }
+        return;
+    }
+    fs::write(out.join("keypad_config.rs"), config.generate()).unwrap();
+}
+
+const USB_ENV: [&str; 5] = [
+    "KEYPAD_USB_VID",
+    "KEYPAD_USB_PID",
+    "KEYPAD_USB_MANUFACTURER",
+    "KEYPAD_USB_PRODUCT",
+    "KEYPAD_USB_SERIAL",
+];
+
+/// The board picked by the `board-*` feature.
+struct Board {
+    name: &'static str,
+    /// Every GPIO pin the package has.
+    pins: Vec<String>,
+    /// Pins the firmware or the board already uses.
+    reserved: &'static [&'static str],
+}
+
+impl Board {
+    fn selected() -> Option<Self> {
+        let feature = |name: &str| env::var_os(format!("CARGO_FEATURE_BOARD_{name}")).is_some();
+        // USB, SWD, and PB1 for the keypad interrupt and the IR LED
+        if feature("STM32F407_DISCOVERY") {
+            Some(Self {
+                name: "stm32f407-discovery",
+                // LQFP100
+                pins: port_pins(&[('A', 0..16), ('B', 0..16), ('C', 0..16), ('D', 0..16)])
+                    .chain(port_pins(&[('E', 0..16), ('H', 0..2)]))
+                    .collect(),
+                // and the user LEDs and the clock input from the ST-LINK
+                reserved: &[
+                    "PA11", "PA12", "PA13", "PA14", "PB1", "PD12", "PD13", "PD14", "PD15", "PH0",
+                    "PH1",
+                ],
+            })
+        } else if feature("STM32F411_BLACKPILL") || feature("STM32F401_BLACKPILL") {
+            Some(Self {
+                name: if feature("STM32F411_BLACKPILL") {
+                    "stm32f411-blackpill"
+                } else {
+                    "stm32f401-blackpill"
+                },
+                // UFQFPN48
+                pins: port_pins(&[('A', 0..16), ('B', 0..11), ('B', 12..16), ('C', 13..16)])
+                    .chain(port_pins(&[('H', 0..2)]))
+                    .collect(),
+                // and the user LED, BOOT1, and both crystals
+                reserved: &[
+                    "PA11", "PA12", "PA13", "PA14", "PB1", "PC13", "PB2", "PC14", "PC15", "PH0",
+                    "PH1",
+                ],
+            })
+        } else {
+            None
+        }
+    }
+}
+
+fn port_pins(ports: &[(char, std::ops::Range<u8>)]) -> impl Iterator<Item = String> + '_ {
+    ports
+        .iter()
+        .flat_map(|(port, pins)| pins.clone().map(move |pin| format!("P{port}{pin}")))
+}
+
+struct Usb {
+    vid: u16,
+    pid: u16,
+    manufacturer: String,
+    product: String,
+    serial: Option<String>,
+}
+
+impl Default for Usb {
+    fn default() -> Self {
+        Self {
+            vid: 0xC0DE,
+            pid: 0xCAFE,
+            manufacturer: "Keypad HID".into(),
+            product: "Keypad HID".into(),
+            serial: None,
+        }
+    }
+}
+
+/// What the pads play in MIDI mode.
+struct Midi {
+    /// 0-15, one less than the channel in `keypad.toml`.
+    channel: u8,
+    velocity: u8,
+    /// `PadAction`s, as code.
+    pads: Vec<String>,
+}
+
+impl Default for Midi {
+    fn default() -> Self {
+        Self {
+            channel: 0,
+            velocity: 100,
+            pads: Vec::new(),
+        }
+    }
+}
+
+struct Config {
+    errors: Vec<String>,
+    labels: Vec<Vec<char>>,
+    row_pins: Vec<String>,
+    column_pins: Vec<String>,
+    keymap: Vec<Vec<u16>>,
+    usb: Usb,
+    debounce_ms: u32,
+    keyboard_idle_ms: u32,
+    midi: Midi,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            errors: Vec::new(),
+            labels: Vec::new(),
+            row_pins: Vec::new(),
+            column_pins: Vec::new(),
+            keymap: Vec::new(),
+            usb: Usb::default(),
+            debounce_ms: 20,
+            keyboard_idle_ms: 500,
+            midi: Midi::default(),
+        }
+    }
+}
+
+impl Config {
+    fn error(&mut self, message: String) {
+        self.errors.push(message);
+    }
+
+    fn read(&mut self, table: &Table) {
+        self.read_labels(table);
+        if self.labels.is_empty() {
+            // Everything else is checked against the matrix
+            return;
+        }
+        self.read_pins(table);
+        self.read_keymap(table);
+        self.read_usb(table);
+        self.read_timing(table);
+        self.read_midi(table);
+    }
+
+    fn columns(&self) -> usize {
+        self.labels[0].len()
+    }
+
+    fn key_count(&self) -> usize {
+        self.labels.len() * self.columns()
+    }
+
+    fn read_labels(&mut self, table: &Table) {
+        let Some(rows) = self.array(section(table, "matrix"), "matrix", "labels") else {
+            return;
+        };
+        let mut labels = Vec::new();
+        let mut seen = HashSet::new();
+        for (row_index, row) in rows.iter().enumerate() {
+            let Some(row) = row.as_array() else {
+                self.error(format!("matrix.labels row {row_index} isn't a list"));
+                return;
+            };
+            let mut row_labels = Vec::new();
+            for label in row {
+                let mut chars = label.as_str().unwrap_or_default().chars();
+                match (chars.next(), chars.next()) {
+                    (Some(label), None) if seen.insert(label.to_ascii_uppercase()) => {
+                        row_labels.push(label.to_ascii_uppercase())
+                    }
+                    (Some(label), None) => {
+                        self.error(format!("matrix.labels has '{label}' more than once"))
+                    }
+                    _ => self.error(format!(
+                        "matrix.labels: {label} isn't a single character label"
+                    )),
+                }
+            }
+            labels.push(row_labels);
+        }
+        for label in REQUIRED_LABELS {
+            if !seen.contains(&label) {
+                self.error(format!("matrix.labels has no '{label}' key"));
+            }
+        }
+        if !self.errors.is_empty() {
+            return;
+        }
+        let columns = labels.first().map_or(0, Vec::len);
+        if columns == 0 || labels.iter().any(|row| row.len() != columns) {
+            self.error("matrix.labels rows must all have the same number of keys".into());
+        } else if labels.len() * columns > MAX_KEYS {
+            self.error(format!(
+                "a {}x{columns} matrix has more than {MAX_KEYS} keys",
+                labels.len()
+            ));
+        } else if columns > MAX_COLUMNS {
+            self.error(format!(
+                "matrix.labels has {columns} columns, at most {MAX_COLUMNS} are supported"
+            ));
+        } else {
+            self.labels = labels;
+        }
+    }
+
+    fn read_pins(&mut self, table: &Table) {
+        let Some(board) = Board::selected() else {
+            self.error("no board feature is enabled".into());
+            return;
+        };
+        let section = format!("pins.{}", board.name);
+        let Some(pins) = table
+            .get("pins")
+            .and_then(|pins| pins.get(board.name))
+            .and_then(Value::as_table)
+        else {
+            self.error(format!("[{section}] is missing"));
+            return;
+        };
+
+        let free = board
+            .pins
+            .iter()
+            .filter(|pin| !board.reserved.contains(&pin.as_str()))
+            .count();
+        let needed = self.labels.len() + self.columns();
+        if needed > free {
+            self.error(format!(
+                "a {}x{} matrix needs {needed} pins, {} has only {free} free",
+                self.labels.len(),
+                self.columns(),
+                board.name
+            ));
+            return;
+        }
+
+        let mut used = HashSet::new();
+        for (key, expected) in [("rows", self.labels.len()), ("columns", self.columns())] {
+            let Some(list) = self.array(Some(pins), &section, key) else {
+                continue;
+            };
+            if list.len() != expected {
+                self.error(format!(
+                    "{section}.{key} has {} pins, the matrix has {expected} {key}",
+                    list.len()
+                ));
+            }
+            let mut names = Vec::new();
+            for pin in list {
+                let name = pin.as_str().unwrap_or_default().to_ascii_uppercase();
+                if !board.pins.contains(&name) {
+                    self.error(format!("{section}.{key}: {pin} isn't a pin of this board"));
+                } else if board.reserved.contains(&name.as_str()) {
+                    self.error(format!(
+                        "{section}.{key}: {name} is already used by the board"
+                    ));
+                } else if !used.insert(name.clone()) {
+                    self.error(format!("{section}: {name} is used more than once"));
+                }
+                names.push(name);
+            }
+            match key {
+                "rows" => self.row_pins = names,
+                _ => self.column_pins = names,
+            }
+        }
+    }
+
+    fn read_keymap(&mut self, table: &Table) {
+        let Some(layers) = self.array(section(table, "keymap"), "keymap", "layers") else {
+            return;
+        };
+        if layers.len() > LAYERS {
+            self.error(format!(
+                "keymap.layers has {} layers, at most {LAYERS} are supported",
+                layers.len()
+            ));
+            return;
+        }
+        for (layer_index, layer) in layers.iter().enumerate() {
+            let rows = layer.as_array().map(Vec::as_slice).unwrap_or_default();
+            let columns = self.columns();
+            if rows.len() != self.labels.len()
+                || rows
+                    .iter()
+                    .any(|row| row.as_array().is_none_or(|row| row.len() != columns))
+            {
+                self.error(format!(
+                    "keymap layer {layer_index} isn't laid out like the {}x{columns} matrix",
+                    self.labels.len()
+                ));
+                continue;
+            }
+            let mut keycodes = Vec::new();
+            let keys = rows.iter().flat_map(|row| row.as_array().unwrap());
+            for (key, name) in keys.enumerate() {
+                let label = self.labels[key / columns][key % columns];
+                match name.as_str().and_then(keycode) {
+                    Some(code) => keycodes.push(code),
+                    None => self.error(format!(
+                        "keymap layer {layer_index}, key '{label}': unknown key name {name}"
+                    )),
+                }
+            }
+            self.keymap.push(keycodes);
+        }
+    }
+
+    fn read_usb(&mut self, table: &Table) {
+        let usb = table.get("usb");
+        let get = |key: &str| usb.and_then(|usb| usb.get(key));
+        for (key, var) in [("vid", USB_ENV[0]), ("pid", USB_ENV[1])] {
+            let id = match env::var(var) {
+                Ok(text) => {
+                    let digits = text.trim_start_matches("0x").trim_start_matches("0X");
+                    u16::from_str_radix(digits, 16).map_err(|_| format!("{var}={text}"))
+                }
+                Err(_) => match get(key) {
+                    None => continue,
+                    Some(value) => value
+                        .as_integer()
+                        .and_then(|id| u16::try_from(id).ok())
+                        .ok_or_else(|| format!("usb.{key} = {value}")),
+                },
+            };
+            match (key, id) {
+                ("vid", Ok(id)) => self.usb.vid = id,
+                (_, Ok(id)) => self.usb.pid = id,
+                (_, Err(setting)) => {
+                    self.error(format!("{setting}: USB IDs are numbers up to 0xFFFF"))
+                }
+            }
+        }
+        for (key, var) in [
+            ("manufacturer", USB_ENV[2]),
+            ("product", USB_ENV[3]),
+            ("serial", USB_ENV[4]),
+        ] {
+            let text = match env::var(var) {
+                Ok(text) => text,
+                Err(_) => match get(key) {
+                    None => continue,
+                    Some(value) => match value.as_str() {
+                        Some(text) => text.to_owned(),
+                        None => {
+                            self.error(format!("usb.{key} = {value} isn't a string"));
+                            continue;
+                        }
+                    },
+                },
+            };
+            if text.is_empty() || text.len() > STRING_LEN {
+                self.error(format!(
+                    "USB {key} \"{text}\" isn't 1 to {STRING_LEN} bytes long"
+                ));
+                continue;
+            }
+            match key {
+                "manufacturer" => self.usb.manufacturer = text,
+                "product" => self.usb.product = text,
+                _ => self.usb.serial = Some(text),
+            }
+        }
+    }
+
+    fn read_timing(&mut self, table: &Table) {
+        let timing = table.get("timing");
+        let get = |key: &str| timing.and_then(|timing| timing.get(key));
+        if let Some(value) = get("debounce_ms") {
+            // At most `MAX_DEBOUNCE_MS` in src/settings.rs
+            match value.as_integer().and_then(|ms| u32::try_from(ms).ok()) {
+                Some(ms) if ms <= 100 => self.debounce_ms = ms,
+                _ => self.error(format!(
+                    "timing.debounce_ms = {value} isn't a number of ms up to 100"
+                )),
+            }
+        }
+        if let Some(value) = get("keyboard_idle_ms") {
+            match value.as_integer().and_then(|ms| u32::try_from(ms).ok()) {
+                Some(ms) if ms <= 1020 && ms % 4 == 0 => self.keyboard_idle_ms = ms,
+                _ => self.error(format!(
+                    "timing.keyboard_idle_ms = {value}: HID idle rates are multiples of 4 ms up \
+                     to 1020 ms"
+                )),
+            }
+        }
+    }
+
+    fn read_midi(&mut self, table: &Table) {
+        let midi = section(table, "midi");
+        let get = |key: &str| midi.and_then(|midi| midi.get(key));
+        if let Some(value) = get("channel") {
+            match value.as_integer() {
+                Some(channel @ 1..=16) => self.midi.channel = channel as u8 - 1,
+                _ => self.error(format!(
+                    "midi.channel = {value} isn't a channel from 1 to 16"
+                )),
+            }
+        }
+        if let Some(value) = get("velocity") {
+            match value.as_integer() {
+                Some(velocity @ 1..=127) => self.midi.velocity = velocity as u8,
+                _ => self.error(format!("midi.velocity = {value} isn't 1 to 127")),
+            }
+        }
+        let columns = self.columns();
+        let Some(rows) = get("pads") else {
+            // A chromatic scale from middle C
+            self.midi.pads = (0..self.key_count())
+                .map(|key| format!("Note({})", 60 + key))
+                .collect();
+            return;
+        };
+        let rows = rows.as_array().map(Vec::as_slice).unwrap_or_default();
+        if rows.len() != self.labels.len()
+            || rows
+                .iter()
+                .any(|row| row.as_array().is_none_or(|row| row.len() != columns))
+        {
+            self.error(format!(
+                "midi.pads isn't laid out like the {}x{columns} matrix",
+                self.labels.len()
+            ));
+            return;
+        }
+        let pads = rows.iter().flat_map(|row| row.as_array().unwrap());
+        for (key, pad) in pads.enumerate() {
+            let label = self.labels[key / columns][key % columns];
+            match pad_action(pad) {
+                Some(action) => self.midi.pads.push(action),
+                None => self.error(format!(
+                    "midi.pads, key '{label}': {pad} isn't a note from 0 to 127, \"CC<n>\", \
+                     \"CC<n>=<value>\", \"OCT-\" or \"OCT+\""
+                )),
+            }
+        }
+    }
+
+    /// The list `key` of the `section` table, reporting it if it's missing.
+    fn array<'a>(
+        &mut self,
+        table: Option<&'a Table>,
+        section: &str,
+        key: &str,
+    ) -> Option<&'a [Value]> {
+        let array = table
+            .and_then(|table| table.get(key))
+            .and_then(Value::as_array);
+        if array.is_none() {
+            self.error(format!("{section}.{key} is missing or isn't a list"));
+        }
+        array.map(Vec::as_slice)
+    }
+
+    fn generate(&self) -> String {
+        let mut out = String::new();
+        let rows = self.labels.len();
+        let columns = self.columns();
+        let key_count = self.key_count();
+        writeln!(out, "/// Keypad rows, the inputs of the matrix.").unwrap();
+        writeln!(out, "pub const ROWS: usize = {rows};").unwrap();
+        writeln!(out, "/// Keypad columns, the outputs of the matrix.").unwrap();
+        writeln!(out, "pub const COLUMNS: usize = {columns};").unwrap();
+        writeln!(out, "pub const KEY_COUNT: usize = {key_count};").unwrap();
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .flatten()
+            .map(|label| format!("{label:?}"))
+            .collect();
+        writeln!(
+            out,
+            "/// Printed labels of the keys, in [`Keypad::scan`] order."
+        )
+        .unwrap();
+        writeln!(out, "///").unwrap();
+        writeln!(out, "/// [`Keypad::scan`]: crate::keypad::Keypad::scan").unwrap();
+        writeln!(
+            out,
+            "pub const KEY_LABELS: [char; KEY_COUNT] = [{}];",
+            labels.join(", ")
+        )
+        .unwrap();
+
+        writeln!(out, "pub const DEFAULT_KEYMAP: crate::keymap::Keymap = [").unwrap();
+        for layer in 0..LAYERS {
+            match self.keymap.get(layer) {
+                Some(keycodes) => {
+                    let keycodes: Vec<String> = keycodes
+                        .iter()
+                        .map(|code| format!("0x{code:04X}"))
+                        .collect();
+                    writeln!(out, "    [{}],", keycodes.join(", ")).unwrap();
+                }
+                None => writeln!(out, "    [crate::keymap::KC_TRANSPARENT; KEY_COUNT],").unwrap(),
+            }
+        }
+        writeln!(out, "];").unwrap();
+
+        let usb = &self.usb;
+        writeln!(out, "pub const USB_VID: u16 = 0x{:04X};", usb.vid).unwrap();
+        writeln!(out, "pub const USB_PID: u16 = 0x{:04X};", usb.pid).unwrap();
+        writeln!(
+            out,
+            "pub const USB_MANUFACTURER: &str = {:?};",
+            usb.manufacturer
+        )
+        .unwrap();
+        writeln!(out, "pub const USB_PRODUCT: &str = {:?};", usb.product).unwrap();
+        writeln!(
+            out,
+            "pub const USB_SERIAL: Option<&str> = {:?};",
+            usb.serial
+        )
+        .unwrap();
+
+        writeln!(out, "pub const DEBOUNCE_MS: u32 = {};", self.debounce_ms).unwrap();
+        writeln!(
+            out,
+            "pub const KEYBOARD_IDLE_MS: u32 = {};",
+            self.keyboard_idle_ms
+        )
+        .unwrap();
+
+        let midi = &self.midi;
+        writeln!(out, "/// MIDI channel, 0-15.").unwrap();
+        writeln!(out, "pub const MIDI_CHANNEL: u8 = {};", midi.channel).unwrap();
+        writeln!(out, "pub const MIDI_VELOCITY: u8 = {};", midi.velocity).unwrap();
+        let pads: Vec<String> = midi
+            .pads
+            .iter()
+            .map(|pad| format!("crate::midi::PadAction::{pad}"))
+            .collect();
+        writeln!(
+            out,
+            "pub const MIDI_PADS: [crate::midi::PadAction; KEY_COUNT] = [{}];",
+            pads.join(", ")
+        )
+        .unwrap();
+
+        let pins = |names: &[String], make: &str| {
+            names
+                .iter()
+                .map(|name| make.replace("PIN", name))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        writeln!(
+            out,
+            "/// The keypad rows and columns, taken from `Peripherals`.\n\
+             macro_rules! keypad_pins {{\n    ($peripherals:ident) => {{ (\n        \
+             [{}],\n        [{}],\n    ) }};\n}}\npub(crate) use keypad_pins;",
+            pins(
+                &self.row_pins,
+                "::embassy_stm32::gpio::Input::new($peripherals.PIN, ::embassy_stm32::gpio::Pull::Down)"
+            ),
+            pins(
+                &self.column_pins,
+                "::embassy_stm32::gpio::Output::new($peripherals.PIN, \
+                 ::embassy_stm32::gpio::Level::High, ::embassy_stm32::gpio::Speed::Low)"
+            ),
+        )
+        .unwrap();
+        out
+    }
+}
+
+fn section<'a>(table: &'a Table, name: &str) -> Option<&'a Table> {
+    table.get(name).and_then(Value::as_table)
+}
+
+/// The `PadAction` of a pad in `midi.pads`, as code.
+fn pad_action(pad: &Value) -> Option<String> {
+    let byte = |text: &str| text.parse::<u8>().ok().filter(|&byte| byte <= 127);
+    if let Some(note) = pad.as_integer() {
+        return u8::try_from(note)
+            .ok()
+            .filter(|&note| note <= 127)
+            .map(|note| format!("Note({note})"));
+    }
+    let text = pad.as_str()?.trim().to_ascii_uppercase();
+    match text.as_str() {
+        "OCT-" => return Some("OctaveDown".into()),
+        "OCT+" => return Some("OctaveUp".into()),
+        _ => {}
+    }
+    let change = text.strip_prefix("CC")?;
+    let (controller, value) = change.split_once('=').unwrap_or((change, "127"));
+    Some(format!(
+        "ControlChange {{ controller: {}, value: {} }}",
+        byte(controller)?,
+        byte(value)?
+    ))
+}
+
+/// The keycode of a QMK key name, or of a number.
+fn keycode(name: &str) -> Option<u16> {
+    let name = name.trim();
+    if let Some(hex) = name.strip_prefix("0x").or_else(|| name.strip_prefix("0X")) {
+        return u16::from_str_radix(hex, 16).ok();
+    }
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        return name.parse().ok();
+    }
+    if let Some((function, argument)) = name.strip_suffix(')').and_then(|name| name.split_once('('))
+    {
+        let layer = || {
+            argument
+                .trim()
+                .parse::<u16>()
+                .ok()
+                .filter(|&layer| usize::from(layer) < LAYERS)
+        };
+        let mods = match function {
+            "TO" => return layer().map(|layer| 0x5200 | layer),
+            "MO" => return layer().map(|layer| 0x5220 | layer),
+            "TG" => return layer().map(|layer| 0x5260 | layer),
+            "LCTL" | "C" => 0x0100,
+            "LSFT" | "S" => 0x0200,
+            "LALT" | "A" => 0x0400,
+            "LGUI" | "G" => 0x0800,
+            "RCTL" => 0x1100,
+            "RSFT" => 0x1200,
+            "RALT" => 0x1400,
+            "RGUI" => 0x1800,
+            _ => return None,
+        };
+        // Modifier wrappers nest, e.g. LCTL(LSFT(KC_T))
+        return keycode(argument)
+            .filter(|&code| code <= 0x1FFF)
+            .map(|code| code | mods);
+    }
+    match name {
+        "XXXXXXX" => return Some(0x00),
+        "_______" => return Some(0x01),
+        _ => {}
+    }
+    let numbered = |prefix: &str, base: u16| {
+        let index: u16 = name.strip_prefix(prefix)?.parse().ok()?;
+        (index < 16).then_some(base + index)
+    };
+    if let Some(code) = numbered("M", 0x7700).or_else(|| numbered("IR", 0x7E00)) {
+        return Some(code);
+    }
+    basic_keycode(name.strip_prefix("KC_")?)
+}
+
+/// HID keyboard usages by their QMK name, without the `KC_` prefix.
+fn basic_keycode(name: &str) -> Option<u16> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(letter @ 'A'..='Z'), None) => return Some(0x04 + letter as u16 - 'A' as u16),
+        (Some('0'), None) => return Some(0x27),
+        (Some(digit @ '1'..='9'), None) => return Some(0x1E + digit as u16 - '1' as u16),
+        _ => {}
+    }
+    if let Some(number) = name.strip_prefix('F').and_then(|n| n.parse::<u16>().ok()) {
+        return match number {
+            1..=12 => Some(0x3A + number - 1),
+            13..=24 => Some(0x68 + number - 13),
+            _ => None,
+        };
+    }
+    if let Some(digit) = name.strip_prefix('P').or_else(|| name.strip_prefix("KP_"))
+        && let [digit @ b'0'..=b'9'] = digit.as_bytes()
+    {
+        return Some(match digit {
+            b'0' => 0x62,
+            _ => 0x59 + u16::from(digit - b'1'),
+        });
+    }
+    let code = match name {
+        "NO" => 0x00,
+        "TRNS" | "TRANSPARENT" => 0x01,
+        "ENT" | "ENTER" => 0x28,
+        "ESC" | "ESCAPE" => 0x29,
+        "BSPC" | "BACKSPACE" => 0x2A,
+        "TAB" => 0x2B,
+        "SPC" | "SPACE" => 0x2C,
+        "MINS" | "MINUS" => 0x2D,
+        "EQL" | "EQUAL" => 0x2E,
+        "LBRC" | "LEFT_BRACKET" => 0x2F,
+        "RBRC" | "RIGHT_BRACKET" => 0x30,
+        "BSLS" | "BACKSLASH" => 0x31,
+        "SCLN" | "SEMICOLON" => 0x33,
+        "QUOT" | "QUOTE" => 0x34,
+        "GRV" | "GRAVE" => 0x35,
+        "COMM" | "COMMA" => 0x36,
+        "DOT" => 0x37,
+        "SLSH" | "SLASH" => 0x38,
+        "CAPS" | "CAPS_LOCK" => 0x39,
+        "PSCR" | "PRINT_SCREEN" => 0x46,
+        "SCRL" | "SCROLL_LOCK" => 0x47,
+        "PAUS" | "PAUSE" => 0x48,
+        "INS" | "INSERT" => 0x49,
+        "HOME" => 0x4A,
+        "PGUP" | "PAGE_UP" => 0x4B,
+        "DEL" | "DELETE" => 0x4C,
+        "END" => 0x4D,
+        "PGDN" | "PAGE_DOWN" => 0x4E,
+        "RGHT" | "RIGHT" => 0x4F,
+        "LEFT" => 0x50,
+        "DOWN" => 0x51,
+        "UP" => 0x52,
+        "NUM" | "NUM_LOCK" => 0x53,
+        "PSLS" | "KP_SLASH" => 0x54,
+        "PAST" | "KP_ASTERISK" => 0x55,
+        "PMNS" | "KP_MINUS" => 0x56,
+        "PPLS" | "KP_PLUS" => 0x57,
+        "PENT" | "KP_ENTER" => 0x58,
+        "PDOT" | "KP_DOT" => 0x63,
+        "APP" | "APPLICATION" => 0x65,
+        "PEQL" | "KP_EQUAL" => 0x67,
+        "MUTE" | "KB_MUTE" => 0x7F,
+        "VOLU" | "KB_VOLUME_UP" => 0x80,
+        "VOLD" | "KB_VOLUME_DOWN" => 0x81,
+        "LCTL" | "LEFT_CTRL" => 0xE0,
+        "LSFT" | "LEFT_SHIFT" => 0xE1,
+        "LALT" | "LEFT_ALT" => 0xE2,
+        "LGUI" | "LEFT_GUI" => 0xE3,
+        "RCTL" | "RIGHT_CTRL" => 0xE4,
+        "RSFT" | "RIGHT_SHIFT" => 0xE5,
+        "RALT" | "RIGHT_ALT" => 0xE6,
+        "RGUI" | "RIGHT_GUI" => 0xE7,
+        _ => return None,
+    };
+    Some(code)
 }
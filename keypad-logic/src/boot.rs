@@ -0,0 +1,316 @@
+//! A/B firmware updates with rollback, shared by the bootloader and the
+//! firmware.
+//!
+//! The firmware runs from the active partition. An update is downloaded to
+//! the DFU partition, and the next boot swaps the two page by page, so the
+//! active partition holds the new firmware and the DFU partition the old one.
+//! The new firmware runs on trial: unless it confirms itself with
+//! [`mark_booted`], the boot after swaps the old firmware back.
+//!
+//! The state partition holds a log of words, appended to and never rewritten:
+//! [`request_update`] starts an update, every page copied adds a step, and
+//! [`mark_booted`] ends it. Each copy starts with erasing its target, and its
+//! source isn't overwritten until the step is logged, so a power cut at any
+//! point leaves the log telling which copy to redo.
+//!
+//! The DFU partition is a page larger than the active one. Swapping goes from
+//! the last page down, moving the old page one page up in the DFU partition
+//! before the new one takes its place, and reverting undoes that from the
+//! first page up.
+
+use embedded_storage::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
+
+/// Where the flash is mapped.
+pub const FLASH_BASE: u32 = 0x0800_0000;
+
+/// A range of the flash, as offsets from [`FLASH_BASE`].
+#[derive(Clone, Copy)]
+pub struct Region {
+    pub offset: u32,
+    pub size: u32,
+}
+
+impl Region {
+    pub const fn contains(&self, address: u32) -> bool {
+        address >= FLASH_BASE + self.offset && address < FLASH_BASE + self.offset + self.size
+    }
+}
+
+/// Sector 4.
+pub const STATE: Region = Region {
+    offset: 0x1_0000,
+    size: 0x1_0000,
+};
+/// Sectors 5 and 6.
+pub const ACTIVE: Region = Region {
+    offset: 0x2_0000,
+    size: 2 * PAGE_SIZE,
+};
+/// Sectors 7 to 9.
+pub const DFU: Region = Region {
+    offset: 0x6_0000,
+    size: ACTIVE.size + PAGE_SIZE,
+};
+
+/// The unit of the swap, a sector of the active and DFU partitions.
+pub const PAGE_SIZE: u32 = 0x2_0000;
+const PAGES: u32 = ACTIVE.size / PAGE_SIZE;
+
+/// Two copies per page each way.
+const SWAP_STEPS: u32 = 2 * PAGES;
+/// What one update can add to the log: the request, swapping and reverting,
+/// and the confirmation.
+const MAX_UPDATE_WORDS: u32 = 1 + 2 * SWAP_STEPS + 1;
+
+/// The RAM the stack pointer of a firmware can start in.
+const RAM_START: u32 = 0x2000_0000;
+const RAM_END: u32 = 0x2002_0000;
+
+const UPDATE: u32 = 0x5550_4454;
+const STEP: u32 = 0x5354_4550;
+const BOOTED: u32 = 0x424F_4F54;
+/// What a word of erased flash reads as.
+const ERASED: u32 = 0xFFFF_FFFF;
+
+/// Copied a chunk at a time through RAM.
+const CHUNK_SIZE: usize = 1024;
+
+/// Where an update is at.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Phase {
+    /// Nothing to do, the active partition holds confirmed firmware.
+    Idle,
+    /// Swapping in the new firmware, with the steps done so far.
+    Swapping(u32),
+    /// The new firmware runs, but hasn't confirmed itself yet.
+    Trial,
+    /// Swapping the old firmware back, with the steps done so far, counting
+    /// those of the swap.
+    Reverting(u32),
+}
+
+/// The log in the state partition.
+struct Log {
+    /// Where the next word goes.
+    end: u32,
+    /// The steps since the last request, while an update is open.
+    steps: Option<u32>,
+}
+
+impl Log {
+    fn read<S: ReadNorFlash>(state: &mut S) -> Result<Self, S::Error> {
+        let mut log = Log {
+            end: 0,
+            steps: None,
+        };
+        let mut word = [0; 4];
+        while (log.end as usize) < state.capacity() {
+            state.read(log.end, &mut word)?;
+            match u32::from_le_bytes(word) {
+                ERASED => break,
+                UPDATE => log.steps = Some(0),
+                BOOTED => log.steps = None,
+                // Steps, and any word a power cut tore: the page of a step
+                // was copied before its word was written, and a torn
+                // confirmation makes a trial revert, which is safe
+                _ => {
+                    if let Some(steps) = &mut log.steps {
+                        *steps += 1;
+                    }
+                }
+            }
+            log.end += 4;
+        }
+        Ok(log)
+    }
+
+    fn phase(&self) -> Phase {
+        match self.steps {
+            None => Phase::Idle,
+            Some(steps) if steps < SWAP_STEPS => Phase::Swapping(steps),
+            Some(SWAP_STEPS) => Phase::Trial,
+            Some(steps) if steps < 2 * SWAP_STEPS => Phase::Reverting(steps),
+            // Reverted
+            Some(_) => Phase::Idle,
+        }
+    }
+
+    fn append<S: NorFlash>(&mut self, state: &mut S, word: u32) -> Result<(), S::Error> {
+        state.write(self.end, &word.to_le_bytes())?;
+        self.end += 4;
+        self.steps = match word {
+            UPDATE => Some(0),
+            BOOTED => None,
+            _ => self.steps.map(|steps| steps + 1),
+        };
+        Ok(())
+    }
+}
+
+/// Where an update is at, from the state partition.
+pub fn phase<S: ReadNorFlash>(state: &mut S) -> Result<Phase, S::Error> {
+    Ok(Log::read(state)?.phase())
+}
+
+/// Has the next boot swap in the firmware written to the DFU partition. Only
+/// for the firmware, while no swap is under way.
+pub fn request_update<S: NorFlash>(state: &mut S) -> Result<(), S::Error> {
+    let mut log = Log::read(state)?;
+    // Makes room for the whole update, so the bootloader never has to erase.
+    // A cut erase leaves an empty log, which is as good as a confirmed one.
+    if log.end + 4 * MAX_UPDATE_WORDS > state.capacity() as u32 {
+        state.erase(0, state.capacity() as u32)?;
+        log = Log {
+            end: 0,
+            steps: None,
+        };
+    }
+    log.append(state, UPDATE)
+}
+
+/// Confirms the firmware on trial, so it stays. Returns whether there was
+/// anything to confirm.
+pub fn mark_booted<S: NorFlash>(state: &mut S) -> Result<bool, S::Error> {
+    let mut log = Log::read(state)?;
+    if log.phase() != Phase::Trial {
+        return Ok(false);
+    }
+    log.append(state, BOOTED)?;
+    Ok(true)
+}
+
+/// Whether the first two words of an image, the initial stack pointer and
+/// the reset vector, look like firmware for the active partition.
+pub fn is_firmware(vectors: [u32; 2]) -> bool {
+    let [stack_pointer, reset] = vectors;
+    (RAM_START..=RAM_END).contains(&stack_pointer) && reset & 1 == 1 && ACTIVE.contains(reset & !1)
+}
+
+/// Carries out what the log asks for before the firmware starts: swapping in
+/// an update, or swapping the old firmware back after a trial that didn't
+/// confirm itself. Returns the phase the firmware starts in. Only for the
+/// bootloader, and picks up where a power cut stopped it.
+pub fn prepare<S, A, D, E>(state: &mut S, active: &mut A, dfu: &mut D) -> Result<Phase, E>
+where
+    S: NorFlash + ErrorType<Error = E>,
+    A: NorFlash + ErrorType<Error = E>,
+    D: NorFlash + ErrorType<Error = E>,
+{
+    let mut log = Log::read(state)?;
+    match log.phase() {
+        Phase::Idle => {}
+        Phase::Swapping(done) => {
+            if done == 0 {
+                let mut vectors = [0; 8];
+                dfu.read(0, &mut vectors)?;
+                let word = |i: usize| u32::from_le_bytes(vectors[i..i + 4].try_into().unwrap());
+                if !is_firmware([word(0), word(4)]) {
+                    // Keeps the firmware there is
+                    log.append(state, BOOTED)?;
+                    return Ok(Phase::Idle);
+                }
+            }
+            for step in done..SWAP_STEPS {
+                let page = PAGES - 1 - step / 2;
+                if step % 2 == 0 {
+                    copy(active, page, dfu, page + 1)?;
+                } else {
+                    copy(dfu, page, active, page)?;
+                }
+                log.append(state, STEP)?;
+            }
+        }
+        Phase::Trial | Phase::Reverting(_) => {
+            let done = match log.phase() {
+                Phase::Reverting(done) => done,
+                _ => SWAP_STEPS,
+            };
+            for step in done..2 * SWAP_STEPS {
+                let page = (step - SWAP_STEPS) / 2;
+                if step % 2 == 0 {
+                    copy(active, page, dfu, page)?;
+                } else {
+                    copy(dfu, page + 1, active, page)?;
+                }
+                log.append(state, STEP)?;
+            }
+        }
+    }
+    Ok(log.phase())
+}
+
+/// Erases page `to_page` of `to` and copies page `from_page` of `from` to it.
+fn copy<F, T, E>(from: &mut F, from_page: u32, to: &mut T, to_page: u32) -> Result<(), E>
+where
+    F: ReadNorFlash + ErrorType<Error = E>,
+    T: NorFlash + ErrorType<Error = E>,
+{
+    let (from_offset, to_offset) = (from_page * PAGE_SIZE, to_page * PAGE_SIZE);
+    to.erase(to_offset, to_offset + PAGE_SIZE)?;
+    let mut chunk = [0; CHUNK_SIZE];
+    for at in (0..PAGE_SIZE).step_by(CHUNK_SIZE) {
+        from.read(from_offset + at, &mut chunk)?;
+        // Erased already
+        if chunk.iter().any(|&byte| byte != 0xFF) {
+            to.write(to_offset + at, &chunk)?;
+        }
+    }
+    Ok(())
+}
+
+/// Part of a flash, as a flash of its own starting at `offset`.
+pub struct Partition<F> {
+    flash: F,
+    offset: u32,
+    size: u32,
+}
+
+impl<F: NorFlash> Partition<F> {
+    pub const fn new(flash: F, offset: u32, size: u32) -> Self {
+        Self {
+            flash,
+            offset,
+            size,
+        }
+    }
+
+    fn check(&self, offset: u32, len: usize) {
+        assert!(
+            offset as usize + len <= self.size as usize,
+            "outside the partition"
+        );
+    }
+}
+
+impl<F: NorFlash> ErrorType for Partition<F> {
+    type Error = F::Error;
+}
+
+impl<F: NorFlash> ReadNorFlash for Partition<F> {
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), F::Error> {
+        self.check(offset, bytes.len());
+        self.flash.read(self.offset + offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.size as usize
+    }
+}
+
+impl<F: NorFlash> NorFlash for Partition<F> {
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), F::Error> {
+        self.check(from, (to - from) as usize);
+        self.flash.erase(self.offset + from, self.offset + to)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), F::Error> {
+        self.check(offset, bytes.len());
+        self.flash.write(self.offset + offset, bytes)
+    }
+}
@@ -0,0 +1,153 @@
+//! Works out the PLL dividers for a board's HSE, so USB gets exactly 48 MHz.
+//!
+//! A board gives its crystal or clock input and the system clock it wants,
+//! and [`solve`] searches the dividers. It is a `const fn`, so a board whose
+//! clocks can't make 48 MHz fails to build instead of failing to enumerate,
+//! see the firmware's `ClockPlan`.
+
+/// The clock the USB peripheral needs.
+pub const USB_CLOCK_HZ: u32 = 48_000_000;
+
+/// Clock limits of a chip, from its reference manual.
+pub struct Chip {
+    pub max_sysclk_hz: u32,
+    pub max_apb1_hz: u32,
+    pub max_apb2_hz: u32,
+}
+
+pub const STM32F401: Chip = Chip {
+    max_sysclk_hz: 84_000_000,
+    max_apb1_hz: 42_000_000,
+    max_apb2_hz: 84_000_000,
+};
+
+pub const STM32F407: Chip = Chip {
+    max_sysclk_hz: 168_000_000,
+    max_apb1_hz: 42_000_000,
+    max_apb2_hz: 84_000_000,
+};
+
+pub const STM32F411: Chip = Chip {
+    max_sysclk_hz: 100_000_000,
+    max_apb1_hz: 50_000_000,
+    max_apb2_hz: 100_000_000,
+};
+
+/// How the HSE is fed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HseMode {
+    /// A crystal between OSC_IN and OSC_OUT.
+    Oscillator,
+    /// A clock on OSC_IN.
+    Bypass,
+}
+
+/// The PLL takes 1 to 2 MHz after the M divider.
+const PLL_IN_MIN: u32 = 1_000_000;
+const PLL_IN_MAX: u32 = 2_000_000;
+/// The VCO runs at 100 to 432 MHz, but the F401 needs at least 192 MHz.
+const VCO_MIN: u32 = 192_000_000;
+const VCO_MAX: u32 = 432_000_000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PlanError {
+    /// The HSE frequency is outside what the mode allows: 4 to 26 MHz for a
+    /// crystal, 1 to 50 MHz for a clock on OSC_IN.
+    HseFrequency,
+    /// The system clock is faster than the chip allows.
+    SysclkTooFast,
+    /// No dividers make both the system clock and 48 MHz from this HSE.
+    NoDividers,
+}
+
+impl PlanError {
+    pub const fn message(self) -> &'static str {
+        match self {
+            PlanError::HseFrequency => {
+                "HSE frequency out of range: 4-26 MHz for a crystal, 1-50 MHz for bypass"
+            }
+            PlanError::SysclkTooFast => "the system clock is faster than the chip allows",
+            PlanError::NoDividers => {
+                "no PLL dividers make both the system clock and exactly 48 MHz for USB"
+            }
+        }
+    }
+}
+
+/// PLL dividers and bus prescalers that run the system clock from the HSE,
+/// with exactly [`USB_CLOCK_HZ`] on the PLL's Q output.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Dividers {
+    /// PLLM, PLLN, PLLP and PLLQ.
+    pub m: u32,
+    pub n: u32,
+    pub p: u32,
+    pub q: u32,
+    pub apb1_div: u32,
+    pub apb2_div: u32,
+}
+
+/// Searches the dividers, preferring the highest PLL input frequency, which
+/// has the least jitter.
+pub const fn solve(
+    hse_hz: u32,
+    hse_mode: HseMode,
+    sysclk_hz: u32,
+    chip: &Chip,
+) -> Result<Dividers, PlanError> {
+    let (hse_min, hse_max) = match hse_mode {
+        HseMode::Oscillator => (4_000_000, 26_000_000),
+        HseMode::Bypass => (1_000_000, 50_000_000),
+    };
+    if hse_hz < hse_min || hse_hz > hse_max {
+        return Err(PlanError::HseFrequency);
+    }
+    if sysclk_hz > chip.max_sysclk_hz {
+        return Err(PlanError::SysclkTooFast);
+    }
+
+    let mut m = 2;
+    while m <= 63 {
+        let pll_in = hse_hz / m;
+        if pll_in >= PLL_IN_MIN && pll_in <= PLL_IN_MAX {
+            let mut p = 2;
+            while p <= 8 {
+                // VCO = HSE / M * N has to be sysclk * P exactly
+                let vco = sysclk_hz as u64 * p as u64;
+                let n = vco * m as u64 / hse_hz as u64;
+                let exact = n * hse_hz as u64 == vco * m as u64;
+                let q = vco / USB_CLOCK_HZ as u64;
+                if exact
+                    && n >= 50
+                    && n <= 432
+                    && vco >= VCO_MIN as u64
+                    && vco <= VCO_MAX as u64
+                    && q * USB_CLOCK_HZ as u64 == vco
+                    && q >= 2
+                    && q <= 15
+                {
+                    return Ok(Dividers {
+                        m,
+                        n: n as u32,
+                        p,
+                        q: q as u32,
+                        apb1_div: apb_div(sysclk_hz, chip.max_apb1_hz),
+                        apb2_div: apb_div(sysclk_hz, chip.max_apb2_hz),
+                    });
+                }
+                p += 2;
+            }
+        }
+        m += 1;
+    }
+    Err(PlanError::NoDividers)
+}
+
+/// The smallest APB divider that keeps the bus within `max_hz`.
+const fn apb_div(sysclk_hz: u32, max_hz: u32) -> u32 {
+    let mut div = 1;
+    while sysclk_hz / div > max_hz {
+        div *= 2;
+    }
+    div
+}
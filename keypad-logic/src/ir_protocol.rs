@@ -0,0 +1,641 @@
+//! Encoders turning remote control codes into the marks and spaces the
+//! firmware's `IrTransmitter` sends, and parsers of the codes as text.
+
+use core::fmt;
+use core::ops::RangeInclusive;
+use heapless::Vec;
+
+/// Most marks and spaces in one frame.
+pub const MAX_PULSES: usize = 128;
+
+/// A frame to send: mark and space lengths in µs, starting with a mark, on
+/// a carrier.
+pub struct Pulses {
+    pub carrier_hz: u32,
+    /// Part of the carrier period the LED is on.
+    pub duty_percent: u32,
+    pub durations_us: Vec<u16, MAX_PULSES>,
+    /// Silence the receiver needs after the frame, before the next one.
+    pub gap_us: u32,
+}
+
+impl Pulses {
+    pub fn new(carrier_hz: u32, duty_percent: u32) -> Self {
+        Self {
+            carrier_hz,
+            duty_percent,
+            durations_us: Vec::new(),
+            gap_us: 0,
+        }
+    }
+
+    /// Adds a mark, or lengthens the last one if nothing came in between.
+    fn mark(&mut self, us: u16) {
+        self.add(us, true);
+    }
+
+    /// Adds a space, or lengthens the last one. The idle time before the
+    /// first mark is left out.
+    fn space(&mut self, us: u16) {
+        if !self.durations_us.is_empty() {
+            self.add(us, false);
+        }
+    }
+
+    /// Pads with a space until the frame has lasted `us`.
+    fn space_until(&mut self, us: u32) {
+        let elapsed: u32 = self.durations_us.iter().map(|&us| u32::from(us)).sum();
+        let gap = us.saturating_sub(elapsed);
+        self.space(gap.try_into().unwrap_or(u16::MAX));
+    }
+
+    fn add(&mut self, us: u16, mark: bool) {
+        let len = self.durations_us.len();
+        // Marks are at even indices
+        if len.is_multiple_of(2) != mark {
+            let last = &mut self.durations_us[len - 1];
+            *last = last.saturating_add(us);
+        } else {
+            // Encoders stay well below the capacity
+            self.durations_us.push(us).unwrap();
+        }
+    }
+
+    /// A mark and a space, the space telling a pulse distance coded bit.
+    fn pulse_distance_bit(&mut self, mark_us: u16, zero_us: u16, one_us: u16, bit: bool) {
+        self.mark(mark_us);
+        self.space(if bit { one_us } else { zero_us });
+    }
+
+    /// `count` bits of `bits`, least significant first, in pulse distance
+    /// coding.
+    fn pulse_distance_bits(&mut self, timing: [u16; 3], bits: u64, count: u32) {
+        let [mark_us, zero_us, one_us] = timing;
+        for bit in 0..count {
+            self.pulse_distance_bit(mark_us, zero_us, one_us, bits & (1 << bit) != 0);
+        }
+    }
+
+    /// A Manchester coded bit of two halves of `half_us`. Ones are a mark
+    /// then a space if `one_marks_first`, otherwise the other way round.
+    fn manchester_bit(&mut self, half_us: u16, bit: bool, one_marks_first: bool) {
+        if bit == one_marks_first {
+            self.mark(half_us);
+            self.space(half_us);
+        } else {
+            self.space(half_us);
+            self.mark(half_us);
+        }
+    }
+
+    /// `count` bits of `bits`, most significant first, in Manchester coding.
+    fn manchester_bits(&mut self, half_us: u16, bits: u32, count: u32, one_marks_first: bool) {
+        for bit in (0..count).rev() {
+            self.manchester_bit(half_us, bits & (1 << bit) != 0, one_marks_first);
+        }
+    }
+}
+
+/// A button of a remote control.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum IrCode {
+    /// NEC, as used by most TVs and projectors from Asia. Addresses up to
+    /// 0xFF are sent followed by their inverse, larger ones as extended
+    /// 16-bit addresses.
+    Nec { address: u16, command: u8 },
+    /// Philips RC5, with a 5-bit address and a 7-bit command, commands from
+    /// 64 on in the extended RC5X form.
+    Rc5 { address: u8, command: u8 },
+    /// Philips RC6 mode 0, with an 8-bit address and command.
+    Rc6 { address: u8, command: u8 },
+    /// Sony SIRC, with a 7-bit command. Addresses up to 0x1F make 12-bit
+    /// frames, up to 0xFF 15-bit frames, and larger ones 20-bit frames with
+    /// the bits from 5 on as the extended address.
+    Sirc { address: u16, command: u8 },
+    /// Samsung32, the NEC variant with a shorter leader that repeats whole
+    /// frames.
+    Samsung { address: u8, command: u8 },
+    /// Kaseikyo, as used by Panasonic, Denon, JVC and others, with a 16-bit
+    /// vendor ID, a 12-bit address and an 8-bit command.
+    Kaseikyo {
+        vendor: u16,
+        address: u16,
+        command: u8,
+    },
+}
+
+/// Duty cycle of every protocol's carrier.
+pub const DUTY_PERCENT: u32 = 33;
+
+const NEC_CARRIER_HZ: u32 = 38_000;
+const NEC_UNIT_US: u16 = 562;
+const NEC_BIT: [u16; 3] = [NEC_UNIT_US, NEC_UNIT_US, 3 * NEC_UNIT_US];
+const NEC_LEADER_MARK_US: u16 = 16 * NEC_UNIT_US;
+const NEC_LEADER_SPACE_US: u16 = 8 * NEC_UNIT_US;
+const NEC_REPEAT_SPACE_US: u16 = 4 * NEC_UNIT_US;
+/// Frames start this far apart while a button is held.
+const NEC_FRAME_PERIOD_US: u32 = 108_000;
+
+const RC5_CARRIER_HZ: u32 = 36_000;
+const RC5_HALF_BIT_US: u16 = 889;
+const RC5_FRAME_PERIOD_US: u32 = 113_778;
+
+const RC6_CARRIER_HZ: u32 = 36_000;
+const RC6_UNIT_US: u16 = 444;
+const RC6_LEADER_MARK_US: u16 = 6 * RC6_UNIT_US;
+const RC6_LEADER_SPACE_US: u16 = 2 * RC6_UNIT_US;
+const RC6_MODE_0: u32 = 0b000;
+const RC6_FRAME_PERIOD_US: u32 = 106_667;
+
+const SIRC_CARRIER_HZ: u32 = 40_000;
+const SIRC_UNIT_US: u16 = 600;
+const SIRC_LEADER_MARK_US: u16 = 4 * SIRC_UNIT_US;
+const SIRC_PERIOD_US: u32 = 45_000;
+/// Sony devices only act on a code seen this many times.
+const SIRC_MIN_FRAMES: u32 = 3;
+
+const SAMSUNG_CARRIER_HZ: u32 = 38_000;
+const SAMSUNG_LEADER_US: u16 = 4500;
+const SAMSUNG_FRAME_PERIOD_US: u32 = 108_000;
+
+const KASEIKYO_CARRIER_HZ: u32 = 37_000;
+const KASEIKYO_UNIT_US: u16 = 432;
+const KASEIKYO_BIT: [u16; 3] = [KASEIKYO_UNIT_US, KASEIKYO_UNIT_US, 3 * KASEIKYO_UNIT_US];
+const KASEIKYO_LEADER_MARK_US: u16 = 8 * KASEIKYO_UNIT_US;
+const KASEIKYO_LEADER_SPACE_US: u16 = 4 * KASEIKYO_UNIT_US;
+const KASEIKYO_FRAME_PERIOD_US: u32 = 130_000;
+/// Vendor ID of Panasonic, the most common Kaseikyo vendor.
+pub const PANASONIC_VENDOR_ID: u16 = 0x2002;
+
+impl IrCode {
+    /// Parses the text [`Display`](fmt::Display) writes, a protocol and its
+    /// numbers, e.g. `nec 0x04 0x08`. Numbers are decimal or `0x` hex.
+    ///
+    /// The protocols are `nec`, `rc5`, `rc6`, `sirc`, `samsung`, each with
+    /// an address and a command, `panasonic` with the same, and `kaseikyo`
+    /// with a vendor ID before them.
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut words = text.split_whitespace();
+        let protocol = words.next()?;
+        let mut numbers = [0u32; 3];
+        let mut count = 0;
+        for word in words {
+            *numbers.get_mut(count)? = parse_number(word)?;
+            count += 1;
+        }
+
+        let code = match (protocol, &numbers[..count]) {
+            ("nec", &[address, command]) => IrCode::Nec {
+                address: address.try_into().ok()?,
+                command: command.try_into().ok()?,
+            },
+            ("rc5", &[address @ 0..=0x1F, command @ 0..=0x7F]) => IrCode::Rc5 {
+                address: address as u8,
+                command: command as u8,
+            },
+            ("rc6", &[address, command]) => IrCode::Rc6 {
+                address: address.try_into().ok()?,
+                command: command.try_into().ok()?,
+            },
+            ("sirc", &[address @ 0..=0x1FFF, command @ 0..=0x7F]) => IrCode::Sirc {
+                address: address as u16,
+                command: command as u8,
+            },
+            ("samsung", &[address, command]) => IrCode::Samsung {
+                address: address.try_into().ok()?,
+                command: command.try_into().ok()?,
+            },
+            ("panasonic", &[address @ 0..=0xFFF, command]) => IrCode::Kaseikyo {
+                vendor: PANASONIC_VENDOR_ID,
+                address: address as u16,
+                command: command.try_into().ok()?,
+            },
+            ("kaseikyo", &[vendor, address @ 0..=0xFFF, command]) => IrCode::Kaseikyo {
+                vendor: vendor.try_into().ok()?,
+                address: address as u16,
+                command: command.try_into().ok()?,
+            },
+            _ => return None,
+        };
+        Some(code)
+    }
+
+    /// The frame sent when the button is pressed. `toggle` changes with
+    /// every press, for the protocols that tell presses apart with it.
+    pub fn encode(&self, toggle: bool) -> Pulses {
+        match *self {
+            IrCode::Nec { address, command } => {
+                let address = match u8::try_from(address) {
+                    Ok(address) => u16::from_le_bytes([address, !address]),
+                    Err(_) => address,
+                };
+                let bits =
+                    u64::from(address) | u64::from(command) << 16 | u64::from(!command) << 24;
+
+                let mut pulses = Pulses::new(NEC_CARRIER_HZ, DUTY_PERCENT);
+                pulses.mark(NEC_LEADER_MARK_US);
+                pulses.space(NEC_LEADER_SPACE_US);
+                pulses.pulse_distance_bits(NEC_BIT, bits, 32);
+                pulses.mark(NEC_UNIT_US);
+                pulses
+            }
+            IrCode::Rc5 { address, command } => {
+                // The second start bit is the inverted seventh command bit
+                let field = command & 0x40 == 0;
+                let bits = 1 << 13
+                    | u32::from(field) << 12
+                    | u32::from(toggle) << 11
+                    | u32::from(address & 0x1F) << 6
+                    | u32::from(command & 0x3F);
+
+                let mut pulses = Pulses::new(RC5_CARRIER_HZ, DUTY_PERCENT);
+                pulses.manchester_bits(RC5_HALF_BIT_US, bits, 14, false);
+                pulses
+            }
+            IrCode::Rc6 { address, command } => {
+                let mut pulses = Pulses::new(RC6_CARRIER_HZ, DUTY_PERCENT);
+                pulses.mark(RC6_LEADER_MARK_US);
+                pulses.space(RC6_LEADER_SPACE_US);
+                pulses.manchester_bit(RC6_UNIT_US, true, true);
+                pulses.manchester_bits(RC6_UNIT_US, RC6_MODE_0, 3, true);
+                // The trailer bit, which carries the toggle, is twice as long
+                pulses.manchester_bit(2 * RC6_UNIT_US, toggle, true);
+                let bits = u32::from(address) << 8 | u32::from(command);
+                pulses.manchester_bits(RC6_UNIT_US, bits, 16, true);
+                pulses
+            }
+            IrCode::Sirc { address, command } => {
+                let length = match address {
+                    0..=0x1F => 12,
+                    0x20..=0xFF => 15,
+                    _ => 20,
+                };
+                let bits = u64::from(command & 0x7F) | u64::from(address) << 7;
+
+                let mut pulses = Pulses::new(SIRC_CARRIER_HZ, DUTY_PERCENT);
+                for frame in 1..=SIRC_MIN_FRAMES {
+                    pulses.mark(SIRC_LEADER_MARK_US);
+                    pulses.space(SIRC_UNIT_US);
+                    // Here the marks tell the bits apart
+                    for bit in 0..length {
+                        let mark = if bits & (1 << bit) != 0 { 2 } else { 1 };
+                        pulses.mark(mark * SIRC_UNIT_US);
+                        pulses.space(SIRC_UNIT_US);
+                    }
+                    pulses.space_until(frame * SIRC_PERIOD_US);
+                }
+                pulses
+            }
+            IrCode::Samsung { address, command } => {
+                let bits = u64::from(address)
+                    | u64::from(address) << 8
+                    | u64::from(command) << 16
+                    | u64::from(!command) << 24;
+
+                let mut pulses = Pulses::new(SAMSUNG_CARRIER_HZ, DUTY_PERCENT);
+                pulses.mark(SAMSUNG_LEADER_US);
+                pulses.space(SAMSUNG_LEADER_US);
+                pulses.pulse_distance_bits(NEC_BIT, bits, 32);
+                pulses.mark(NEC_UNIT_US);
+                pulses
+            }
+            IrCode::Kaseikyo {
+                vendor,
+                address,
+                command,
+            } => {
+                let [vendor_low, vendor_high] = vendor.to_le_bytes();
+                let vendor_parity = vendor_low ^ vendor_high;
+                let vendor_parity = (vendor_parity ^ vendor_parity >> 4) & 0x0F;
+                let [low, mid] = (address << 4 | u16::from(vendor_parity)).to_le_bytes();
+                let parity = low ^ mid ^ command;
+                let bits = u64::from(vendor)
+                    | u64::from(low) << 16
+                    | u64::from(mid) << 24
+                    | u64::from(command) << 32
+                    | u64::from(parity) << 40;
+
+                let mut pulses = Pulses::new(KASEIKYO_CARRIER_HZ, DUTY_PERCENT);
+                pulses.mark(KASEIKYO_LEADER_MARK_US);
+                pulses.space(KASEIKYO_LEADER_SPACE_US);
+                pulses.pulse_distance_bits(KASEIKYO_BIT, bits, 48);
+                pulses.mark(KASEIKYO_UNIT_US);
+                pulses
+            }
+        }
+    }
+
+    /// The frame sent over and over while the button is held.
+    pub fn encode_repeat(&self, toggle: bool) -> Pulses {
+        match self {
+            IrCode::Nec { .. } => {
+                let mut pulses = Pulses::new(NEC_CARRIER_HZ, DUTY_PERCENT);
+                pulses.mark(NEC_LEADER_MARK_US);
+                pulses.space(NEC_REPEAT_SPACE_US);
+                pulses.mark(NEC_UNIT_US);
+                pulses
+            }
+            IrCode::Sirc { .. } => {
+                let mut pulses = self.encode(toggle);
+                // Just one of the frames
+                let frame_len = pulses.durations_us.len() / SIRC_MIN_FRAMES as usize;
+                pulses.durations_us.truncate(frame_len);
+                pulses
+            }
+            // The others repeat the whole frame, with the same toggle
+            _ => self.encode(toggle),
+        }
+    }
+
+    /// Time from the start of one frame to the start of the next, in µs.
+    pub fn frame_period_us(&self) -> u32 {
+        match self {
+            IrCode::Nec { .. } => NEC_FRAME_PERIOD_US,
+            IrCode::Rc5 { .. } => RC5_FRAME_PERIOD_US,
+            IrCode::Rc6 { .. } => RC6_FRAME_PERIOD_US,
+            // The frames pad themselves to their period
+            IrCode::Sirc { .. } => 0,
+            IrCode::Samsung { .. } => SAMSUNG_FRAME_PERIOD_US,
+            IrCode::Kaseikyo { .. } => KASEIKYO_FRAME_PERIOD_US,
+        }
+    }
+}
+
+impl fmt::Display for IrCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IrCode::Nec { address, command } => {
+                write!(f, "nec 0x{:02X} 0x{:02X}", address, command)
+            }
+            IrCode::Rc5 { address, command } => {
+                write!(f, "rc5 0x{:02X} 0x{:02X}", address, command)
+            }
+            IrCode::Rc6 { address, command } => {
+                write!(f, "rc6 0x{:02X} 0x{:02X}", address, command)
+            }
+            IrCode::Sirc { address, command } => {
+                write!(f, "sirc 0x{:02X} 0x{:02X}", address, command)
+            }
+            IrCode::Samsung { address, command } => {
+                write!(f, "samsung 0x{:02X} 0x{:02X}", address, command)
+            }
+            IrCode::Kaseikyo {
+                vendor: PANASONIC_VENDOR_ID,
+                address,
+                command,
+            } => write!(f, "panasonic 0x{:03X} 0x{:02X}", address, command),
+            IrCode::Kaseikyo {
+                vendor,
+                address,
+                command,
+            } => write!(
+                f,
+                "kaseikyo 0x{:04X} 0x{:03X} 0x{:02X}",
+                vendor, address, command
+            ),
+        }
+    }
+}
+
+/// Why an IR code was rejected.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CodeError {
+    /// Not a protocol code, raw code or Pronto code, or numbers out of range.
+    Invalid,
+    /// A Pronto code other than a learned one (`0000`).
+    UnsupportedPronto,
+    /// The burst pair counts of a Pronto code don't match its length.
+    PairCount,
+    TooLong,
+    /// The carrier is outside 20-100 kHz.
+    Carrier,
+    /// A mark or space is zero, or longer than 65 ms without being the gap
+    /// at the end.
+    Duration,
+    /// The raw codes together take more room than there is.
+    NoRoom,
+}
+
+impl fmt::Display for CodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            CodeError::Invalid => "not a valid code",
+            CodeError::UnsupportedPronto => "only learned Pronto codes (0000) are supported",
+            CodeError::PairCount => "the Pronto burst pair counts don't match its length",
+            CodeError::TooLong => "too many marks and spaces",
+            CodeError::Carrier => "the carrier has to be 20-100 kHz",
+            CodeError::Duration => "marks and spaces have to be 1 µs to 65 ms",
+            CodeError::NoRoom => "no room left for raw codes",
+        })
+    }
+}
+
+/// Mark and space lengths taken as they are, for devices no encoder covers.
+///
+/// As text, it is `raw`, the carrier in Hz and the marks and spaces in µs,
+/// starting with a mark. A `repeat` part may follow, sent over and over
+/// while the key is held, and `max` with the most times it is repeated:
+///
+/// ```text
+/// raw 38000 9000 4500 562 562 ... 562 40000 repeat 9000 2250 562 96000 max 5
+/// ```
+///
+/// Pronto hex codes, `pronto 0000 006D ...`, are converted to this form. A
+/// part ending with a space keeps that space as the gap before the next
+/// frame, which may be longer than 65 ms.
+pub struct RawCode {
+    pub carrier_hz: u32,
+    /// The part sent on press, then the repeated part from `repeat_start`.
+    pub durations_us: Vec<u16, MAX_PULSES>,
+    pub repeat_start: usize,
+    pub once_gap_us: u32,
+    pub repeat_gap_us: u32,
+    /// Most times the repeated part is sent while the key is held, `None`
+    /// for as long as it is held.
+    pub max_repeats: Option<u8>,
+}
+
+/// Carriers raw and Pronto codes can have.
+pub const CARRIER_HZ: RangeInclusive<u32> = 20_000..=100_000;
+
+/// Length of a Pronto code time unit, in ns per unit of the frequency word.
+const PRONTO_CLOCK_NS: u32 = 241;
+const PRONTO_LEARNED: u32 = 0x0000;
+
+impl RawCode {
+    pub fn parse(text: &str) -> Result<Self, CodeError> {
+        let (text, max_repeats) = match text.split_once(" max ") {
+            Some((text, count)) => (
+                text,
+                Some(
+                    parse_number(count.trim())
+                        .and_then(|count| count.try_into().ok())
+                        .ok_or(CodeError::Invalid)?,
+                ),
+            ),
+            None => (text, None),
+        };
+        let mut words = text.split_whitespace();
+        let mut code = match words.next() {
+            Some("raw") => Self::parse_raw(words)?,
+            Some("pronto") => Self::parse_pronto(words)?,
+            _ => return Err(CodeError::Invalid),
+        };
+        if code.durations_us.is_empty() {
+            return Err(CodeError::Invalid);
+        }
+        code.max_repeats = max_repeats;
+        Ok(code)
+    }
+
+    fn new(carrier_hz: u32) -> Result<Self, CodeError> {
+        if !CARRIER_HZ.contains(&carrier_hz) {
+            return Err(CodeError::Carrier);
+        }
+        Ok(Self {
+            carrier_hz,
+            durations_us: Vec::new(),
+            repeat_start: 0,
+            once_gap_us: 0,
+            repeat_gap_us: 0,
+            max_repeats: None,
+        })
+    }
+
+    fn parse_raw<'a>(mut words: impl Iterator<Item = &'a str>) -> Result<Self, CodeError> {
+        let carrier_hz = words
+            .next()
+            .and_then(parse_number)
+            .ok_or(CodeError::Invalid)?;
+        let mut code = Self::new(carrier_hz)?;
+        let mut durations = Vec::<u32, MAX_PULSES>::new();
+        let mut repeat_part = false;
+        for word in words {
+            if word == "repeat" && !repeat_part {
+                code.once_gap_us = code.push_part(&durations)?;
+                code.repeat_start = code.durations_us.len();
+                durations.clear();
+                repeat_part = true;
+                continue;
+            }
+            let us = parse_number(word).ok_or(CodeError::Invalid)?;
+            durations.push(us).map_err(|_| CodeError::TooLong)?;
+        }
+        // Without a repeat part, the whole code is repeated
+        code.repeat_gap_us = code.push_part(&durations)?;
+        Ok(code)
+    }
+
+    fn parse_pronto<'a>(mut words: impl Iterator<Item = &'a str>) -> Result<Self, CodeError> {
+        let mut next = || {
+            words
+                .next()
+                .and_then(|word| u16::from_str_radix(word, 16).ok())
+                .map(u32::from)
+        };
+        let (Some(format), Some(frequency), Some(once_pairs), Some(repeat_pairs)) =
+            (next(), next(), next(), next())
+        else {
+            return Err(CodeError::Invalid);
+        };
+        if format != PRONTO_LEARNED {
+            return Err(CodeError::UnsupportedPronto);
+        }
+        if frequency == 0 {
+            return Err(CodeError::Carrier);
+        }
+        let unit_ns = frequency * PRONTO_CLOCK_NS;
+        let mut code = Self::new(1_000_000_000 / unit_ns)?;
+
+        for (part, pairs) in [once_pairs, repeat_pairs].into_iter().enumerate() {
+            let mut durations = Vec::<u32, MAX_PULSES>::new();
+            for _ in 0..pairs * 2 {
+                let units = next().ok_or(CodeError::PairCount)?;
+                let us = (units * unit_ns).div_ceil(1000);
+                durations.push(us).map_err(|_| CodeError::TooLong)?;
+            }
+            let gap = code.push_part(&durations)?;
+            if part == 0 {
+                code.once_gap_us = gap;
+                code.repeat_start = code.durations_us.len();
+            } else {
+                code.repeat_gap_us = gap;
+            }
+        }
+        if next().is_some() {
+            return Err(CodeError::PairCount);
+        }
+        Ok(code)
+    }
+
+    /// Adds the marks and spaces of a part, returning the gap it ends with.
+    fn push_part(&mut self, durations: &[u32]) -> Result<u32, CodeError> {
+        let (durations, gap) = match durations.split_last() {
+            // An even count ends with a space
+            Some((&gap, rest)) if durations.len().is_multiple_of(2) => (rest, gap),
+            _ => (durations, 0),
+        };
+        for &us in durations {
+            let us = u16::try_from(us)
+                .ok()
+                .filter(|&us| us > 0)
+                .ok_or(CodeError::Duration)?;
+            self.durations_us.push(us).map_err(|_| CodeError::TooLong)?;
+        }
+        Ok(gap)
+    }
+
+    /// The part sent on press, or the repeated one.
+    pub fn pulses(&self, repeat: bool) -> Pulses {
+        // A missing part is replaced by the other one
+        let repeated_part = if repeat {
+            self.repeat_start < self.durations_us.len()
+        } else {
+            self.repeat_start == 0
+        };
+        let (durations, gap_us) = if repeated_part {
+            (&self.durations_us[self.repeat_start..], self.repeat_gap_us)
+        } else {
+            (&self.durations_us[..self.repeat_start], self.once_gap_us)
+        };
+        let mut pulses = Pulses::new(self.carrier_hz, DUTY_PERCENT);
+        // It fits, it came from a vector of the same size
+        pulses.durations_us.extend_from_slice(durations).unwrap();
+        pulses.gap_us = gap_us;
+        pulses
+    }
+}
+
+impl fmt::Display for RawCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "raw {}", self.carrier_hz)?;
+        let parts = [
+            (&self.durations_us[..self.repeat_start], self.once_gap_us),
+            (&self.durations_us[self.repeat_start..], self.repeat_gap_us),
+        ];
+        for (part, (durations, gap)) in parts.into_iter().enumerate() {
+            if part == 1 && self.repeat_start > 0 {
+                f.write_str(" repeat")?;
+            }
+            for us in durations {
+                write!(f, " {}", us)?;
+            }
+            if gap > 0 {
+                write!(f, " {}", gap)?;
+            }
+        }
+        if let Some(count) = self.max_repeats {
+            write!(f, " max {}", count)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_number(text: &str) -> Option<u32> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
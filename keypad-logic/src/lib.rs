@@ -0,0 +1,12 @@
+//! The parts of the keypad firmware that don't touch the hardware, so they
+//! build for the host too and are tested there, see `cargo test-logic`.
+
+#![no_std]
+
+pub mod boot;
+pub mod clock_plan;
+pub mod ir_protocol;
+pub mod profile;
+pub mod remap;
+pub mod settings_store;
+pub mod watchdog;
@@ -0,0 +1,200 @@
+//! Profiles: named sets of a keymap and the key settings, picked with a chord
+//! and each saved as one record. The firmware side, which holds the active
+//! profile and loads and saves them, is `src/profile.rs` and
+//! `src/settings.rs`.
+
+use core::fmt::Write;
+use heapless::String;
+
+pub const PROFILE_COUNT: usize = 8;
+/// Longest profile name, in bytes.
+pub const NAME_LEN: usize = 16;
+
+pub type Name = String<NAME_LEN>;
+
+/// The label of a key pressed while `*` and `#` are held, which picks a
+/// profile or the remap mode instead of being typed. `labels` are those of
+/// the keys in scan order, `pressed` is the one key just pressed, and `keys`
+/// all the keys held.
+pub fn chord(labels: &[char], keys: u16, pressed: u16) -> Option<char> {
+    let is_held = |label| {
+        labels
+            .iter()
+            .position(|&key| key == label)
+            .is_some_and(|key| keys & (1 << key) != 0)
+    };
+    let label = *labels.get(pressed.trailing_zeros() as usize)?;
+    (is_held('*') && is_held('#') && label != '*' && label != '#').then_some(label)
+}
+
+/// The key that starts the remap mode in a [`chord`].
+pub const REMAP_CHORD: char = '0';
+
+/// Whether a key pressed in a [`chord`] picks a profile or the remap mode.
+fn picks_something(label: char) -> bool {
+    label == REMAP_CHORD || from_chord(label).is_some()
+}
+
+/// Holds back the presses of `*` and `#`, which may start a [`chord`], so
+/// that a chord types nothing. A held back key is typed as a tap when it is
+/// released without making a chord, and as an ordinary press when a key
+/// that makes no chord is pressed meanwhile.
+#[derive(Default)]
+pub struct ChordGate {
+    /// Chord keys pressed and not reported yet.
+    held_back: u16,
+    /// Keys that made a chord, not reported until they are released.
+    chorded: u16,
+}
+
+/// What [`ChordGate::update`] lets through of a scan.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Gated {
+    /// Keys to report as held.
+    pub keys: u16,
+    /// Held back keys released without a chord, to report as pressed and
+    /// released at once.
+    pub tapped: u16,
+    /// The label of the key that made a chord, if one was just made.
+    pub chord: Option<char>,
+}
+
+impl ChordGate {
+    pub const fn new() -> Self {
+        Self {
+            held_back: 0,
+            chorded: 0,
+        }
+    }
+
+    /// Takes a scan, `held` being the keys of the one before. `labels` are
+    /// those of the keys in scan order.
+    pub fn update(&mut self, labels: &[char], held: u16, keys: u16) -> Gated {
+        let mut chord = None;
+        let pressed = keys & !held;
+        for (key, &label) in labels.iter().enumerate() {
+            let bit = 1 << key;
+            if pressed & bit == 0 {
+                continue;
+            }
+            if label == '*' || label == '#' {
+                self.held_back |= bit;
+            } else if let Some(label) =
+                self::chord(labels, keys, bit).filter(|&l| picks_something(l))
+            {
+                chord = Some(label);
+                self.chorded |= bit | self.held_back;
+                self.held_back = 0;
+            } else {
+                // Not a chord after all
+                self.held_back = 0;
+            }
+        }
+
+        let tapped = self.held_back & !keys;
+        self.held_back &= keys;
+        self.chorded &= keys;
+        Gated {
+            keys: keys & !self.held_back & !self.chorded,
+            tapped,
+            chord,
+        }
+    }
+}
+
+/// The profile picked by a digit in a [`chord`], `1` for the first profile.
+pub fn from_chord(label: char) -> Option<usize> {
+    match label.to_digit(10) {
+        Some(number @ 1..) if number as usize <= PROFILE_COUNT => Some(number as usize - 1),
+        _ => None,
+    }
+}
+
+/// "Profile <n>", the name of a profile that wasn't given one.
+pub fn default_name(profile: usize) -> Name {
+    let mut name = Name::new();
+    let _ = write!(name, "Profile {}", profile + 1);
+    name
+}
+
+/// As much of `name` as fits in [`NAME_LEN`] bytes, without cutting a
+/// character in half.
+pub fn truncate_name(name: &str) -> &str {
+    let mut end = name.len().min(NAME_LEN);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+/// The settings a profile keeps besides its keymap.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct KeySettings {
+    pub debounce_ms: u32,
+    pub remote_wakeup: bool,
+    /// The firmware's `QueuePolicy`.
+    pub queue_policy: u8,
+}
+
+/// Size of a saved profile with `keycodes` keycodes in its keymap: the
+/// name's length and bytes, the debounce delay, wakeup, the queue policy and
+/// the keymap.
+pub const fn record_size(keycodes: usize) -> usize {
+    1 + NAME_LEN + 4 + 1 + 1 + keycodes * 2
+}
+
+/// A saved profile, read from the bytes of its record.
+pub struct Record<'a> {
+    pub name: &'a str,
+    pub settings: KeySettings,
+    keymap: &'a [u8],
+}
+
+impl<'a> Record<'a> {
+    /// Writes the record of a profile into `buf`, returning its length. The
+    /// name has to fit in [`NAME_LEN`] bytes.
+    pub fn encode(name: &str, settings: &KeySettings, keycodes: &[u16], buf: &mut [u8]) -> usize {
+        let size = record_size(keycodes.len());
+        let record = &mut buf[..size];
+        record.fill(0);
+        record[0] = name.len() as u8;
+        record[1..][..name.len()].copy_from_slice(name.as_bytes());
+        let fields = &mut record[1 + NAME_LEN..];
+        fields[..4].copy_from_slice(&settings.debounce_ms.to_le_bytes());
+        fields[4] = settings.remote_wakeup.into();
+        fields[5] = settings.queue_policy;
+        for (bytes, keycode) in fields[6..].chunks_exact_mut(2).zip(keycodes) {
+            bytes.copy_from_slice(&keycode.to_le_bytes());
+        }
+        size
+    }
+
+    /// Reads a record with `keycodes` keycodes in its keymap, or `None` if
+    /// it has another size or its name isn't valid.
+    pub fn decode(value: &'a [u8], keycodes: usize) -> Option<Self> {
+        if value.len() != record_size(keycodes) {
+            return None;
+        }
+        let name = value[1..]
+            .get(..usize::from(value[0]))
+            .filter(|name| name.len() <= NAME_LEN)
+            .and_then(|name| core::str::from_utf8(name).ok())?;
+        let fields = &value[1 + NAME_LEN..];
+        Some(Self {
+            name,
+            settings: KeySettings {
+                debounce_ms: u32::from_le_bytes([fields[0], fields[1], fields[2], fields[3]]),
+                remote_wakeup: fields[4] != 0,
+                queue_policy: fields[5],
+            },
+            keymap: &fields[6..],
+        })
+    }
+
+    /// The keycodes of the keymap, layer by layer.
+    pub fn keycodes(&self) -> impl Iterator<Item = u16> + 'a {
+        self.keymap
+            .chunks_exact(2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+}
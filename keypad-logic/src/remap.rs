@@ -0,0 +1,118 @@
+//! What the remap mode makes of the keys pressed: first the key to change,
+//! then a HID keyboard usage in decimal ended by `#`, or a [`MENU`] key. The
+//! firmware side, which scans the keypad and shows the progress on the LEDs,
+//! is `src/remap.rs`.
+
+use usbd_hid::descriptor::KeyboardUsage;
+
+/// Keys picked by pressing a letter key instead of typing their usage.
+pub const MENU: [(char, KeyboardUsage); 4] = [
+    ('A', KeyboardUsage::KeyboardEnter),
+    ('B', KeyboardUsage::KeyboardBackspace),
+    ('C', KeyboardUsage::KeyboardTab),
+    ('D', KeyboardUsage::KeyboardEscape),
+];
+
+/// Usages go up to 255, so three digits.
+const MAX_DIGITS: usize = 3;
+
+/// The keycode that clears a key.
+const KC_NO: u16 = 0;
+
+/// Where the remap mode is at.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+enum State {
+    /// Waiting for the key to change.
+    #[default]
+    Key,
+    /// Taking the new keycode for `key`.
+    Usage {
+        key: usize,
+        usage: u16,
+        digits: usize,
+    },
+    Done,
+}
+
+/// What a key press did.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Outcome {
+    /// The key to change, or a digit of the usage, was taken.
+    Taken,
+    /// The remap is complete: `key` gets `keycode` on the base layer.
+    Remapped { key: usize, keycode: u16 },
+    /// Cancelled with `*`, or a usage that isn't a key.
+    Rejected,
+}
+
+/// The remap mode, fed the keys pressed one at a time.
+#[derive(Default)]
+pub struct Remap {
+    state: State,
+}
+
+impl Remap {
+    pub const fn new() -> Self {
+        Self { state: State::Key }
+    }
+
+    /// Takes a press of the key numbered `key` in scan order, labelled
+    /// `label`. Presses after the remap is complete or rejected are
+    /// rejected too.
+    pub fn press(&mut self, key: usize, label: char) -> Outcome {
+        let outcome = match self.state {
+            State::Key => {
+                self.state = State::Usage {
+                    key,
+                    usage: 0,
+                    digits: 0,
+                };
+                return Outcome::Taken;
+            }
+            State::Usage { key, usage, digits } => {
+                if let Some(digit) = label.to_digit(10)
+                    && digits < MAX_DIGITS
+                {
+                    self.state = State::Usage {
+                        key,
+                        usage: usage * 10 + digit as u16,
+                        digits: digits + 1,
+                    };
+                    return Outcome::Taken;
+                }
+                let keycode = if label == '#' && digits > 0 {
+                    keycode_of(usage)
+                } else {
+                    MENU.iter()
+                        .find(|(menu, _)| *menu == label)
+                        .map(|&(_, usage)| usage as u16)
+                };
+                // `*`, a fourth digit or `#` without a usage give none
+                match keycode {
+                    Some(keycode) => Outcome::Remapped { key, keycode },
+                    None => Outcome::Rejected,
+                }
+            }
+            State::Done => Outcome::Rejected,
+        };
+        self.state = State::Done;
+        outcome
+    }
+}
+
+/// The keycode for a typed usage, if it is a key: 0 clears the key, while the
+/// error codes 1-3 and reserved usages are rejected.
+pub fn keycode_of(usage: u16) -> Option<u16> {
+    let key = u8::try_from(usage).ok()?;
+    if key == 0 {
+        return Some(KC_NO);
+    }
+    match KeyboardUsage::from(key) {
+        KeyboardUsage::KeyboardErrorRollOver
+        | KeyboardUsage::KeyboardPOSTFail
+        | KeyboardUsage::KeyboardErrorUndefined
+        | KeyboardUsage::Reserved => None,
+        _ => Some(key.into()),
+    }
+}
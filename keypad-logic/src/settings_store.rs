@@ -0,0 +1,367 @@
+//! Settings kept in flash across power cycles.
+//!
+//! Two erase sectors take turns holding a log of records. A changed setting is
+//! appended as a new record, and reading a setting finds its last record.
+//! When the active sector is full, the last record of every other setting and
+//! the new value are copied to the other sector, whose header is written
+//! last: until then the old sector stays the active one, so a power cut at any
+//! point leaves one complete log. Each record has a CRC, so a write cut short
+//! is skipped.
+//!
+//! The sectors are 1 and 2 of the flash, 16 KiB each on all supported chips,
+//! kept free by `_stext` in `memory-in-place.x`, or with A/B updates by the firmware
+//! starting further up, see `memory-ab.x`.
+
+use core::marker::PhantomData;
+use embedded_storage::nor_flash::NorFlash;
+
+/// Offset of the first of the two sectors from the start of the flash.
+pub const SECTORS_OFFSET: u32 = 0x4000;
+
+/// Largest setting, the macro buffer.
+pub const MAX_VALUE_SIZE: usize = 512;
+
+const PAGE_MAGIC: u32 = 0x4B50_5331;
+/// Magic, sequence number, and a CRC of both, so a sector whose erase was cut
+/// short isn't taken for a log.
+const PAGE_HEADER_SIZE: u32 = 12;
+const RECORD_HEADER_SIZE: u32 = 8;
+/// What a word of erased flash reads as.
+const ERASED: u32 = 0xFFFF_FFFF;
+
+/// A setting in the store.
+pub trait Key: Copy + PartialEq + 'static {
+    /// Every setting. Records of other keys are dropped by the next
+    /// compaction.
+    const ALL: &'static [Self];
+
+    /// The number the key is stored as, which can't change.
+    fn id(self) -> u8;
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    Flash(E),
+    /// The value is larger than [`MAX_VALUE_SIZE`].
+    TooLarge,
+    /// The settings don't fit in a sector even after compacting.
+    Full,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(error: E) -> Self {
+        Error::Flash(error)
+    }
+}
+
+/// A record as found in the log.
+#[derive(Clone, Copy)]
+struct Record {
+    key: u8,
+    version: u8,
+    len: u16,
+    crc: u32,
+}
+
+impl Record {
+    fn parse(header: [u8; RECORD_HEADER_SIZE as usize]) -> Self {
+        Self {
+            key: header[0],
+            version: header[1],
+            len: u16::from_le_bytes([header[2], header[3]]),
+            crc: u32::from_le_bytes([header[4], header[5], header[6], header[7]]),
+        }
+    }
+
+    /// Space the record takes in the log, padded to whole words.
+    fn size(&self) -> u32 {
+        RECORD_HEADER_SIZE + u32::from(self.len).next_multiple_of(4)
+    }
+}
+
+/// The settings store on two flash sectors of `F::ERASE_SIZE` bytes.
+pub struct Settings<F: NorFlash, K: Key> {
+    flash: F,
+    keys: PhantomData<K>,
+    /// The active sector, 0 or 1.
+    active: u32,
+    sequence: u32,
+    /// Where the next record goes, from the start of the active sector.
+    end: u32,
+    /// A record was cut short, so nothing can be appended after it until the
+    /// log is compacted.
+    torn: bool,
+}
+
+impl<F: NorFlash, K: Key> Settings<F, K> {
+    /// Opens the store, starting an empty one if neither sector holds a log.
+    pub fn new(flash: F) -> Result<Self, Error<F::Error>> {
+        let mut settings = Self {
+            flash,
+            keys: PhantomData,
+            active: 0,
+            sequence: 0,
+            end: PAGE_HEADER_SIZE,
+            torn: false,
+        };
+        let sequences = [settings.page_sequence(0)?, settings.page_sequence(1)?];
+        match sequences {
+            [None, None] => {
+                settings.erase_if_needed(0)?;
+                settings.write_page_header(0, 1)?;
+                settings.sequence = 1;
+            }
+            // Sequence numbers only go up, so the newer sector wins if a
+            // compaction was cut short before the old one was erased
+            [Some(first), Some(second)] if second.wrapping_sub(first) as i32 > 0 => {
+                (settings.active, settings.sequence) = (1, second)
+            }
+            [Some(sequence), _] => (settings.active, settings.sequence) = (0, sequence),
+            [None, Some(sequence)] => (settings.active, settings.sequence) = (1, sequence),
+        }
+        settings.find_end()?;
+        Ok(settings)
+    }
+
+    /// Copies the value last written for `key` into `buf`, returning its
+    /// length. Values written with another `version` are not returned.
+    pub fn read(
+        &mut self,
+        key: K,
+        version: u8,
+        buf: &mut [u8],
+    ) -> Result<Option<usize>, Error<F::Error>> {
+        let Some((offset, record)) = self.find(self.active, key.id())? else {
+            return Ok(None);
+        };
+        let len = usize::from(record.len);
+        if record.version != version || len > buf.len() {
+            return Ok(None);
+        }
+        let start = self.page_start(self.active) + offset + RECORD_HEADER_SIZE;
+        self.flash.read(start, &mut buf[..len])?;
+        Ok(Some(len))
+    }
+
+    /// Gives the flash back.
+    pub fn into_flash(self) -> F {
+        self.flash
+    }
+
+    /// Stores `value` as the new value of `key`.
+    pub fn write(&mut self, key: K, version: u8, value: &[u8]) -> Result<(), Error<F::Error>> {
+        if value.len() > MAX_VALUE_SIZE {
+            return Err(Error::TooLarge);
+        }
+        if self.torn || self.end + record_size(value) > F::ERASE_SIZE as u32 {
+            return self.compact(key, version, value);
+        }
+        let start = self.page_start(self.active) + self.end;
+        self.append(start, key.id(), version, value)?;
+        self.end += record_size(value);
+        Ok(())
+    }
+
+    /// Copies the last record of every key but `key` to the other sector,
+    /// followed by `value` as the new value of `key`, and makes it the active
+    /// one.
+    fn compact(&mut self, key: K, version: u8, value: &[u8]) -> Result<(), Error<F::Error>> {
+        let target = 1 - self.active;
+        self.erase_if_needed(target)?;
+        let mut end = PAGE_HEADER_SIZE;
+        let mut buf = [0; MAX_VALUE_SIZE];
+        for other in K::ALL.iter().filter(|&&other| other != key) {
+            let Some((offset, record)) = self.find(self.active, other.id())? else {
+                continue;
+            };
+            let copy = &mut buf[..usize::from(record.len)];
+            let start = self.page_start(self.active) + offset + RECORD_HEADER_SIZE;
+            self.flash.read(start, copy)?;
+            self.append(
+                self.page_start(target) + end,
+                record.key,
+                record.version,
+                copy,
+            )?;
+            end += record.size();
+        }
+        // Left without a header, the half written sector is erased by the
+        // next compaction
+        if end + record_size(value) > F::ERASE_SIZE as u32 {
+            return Err(Error::Full);
+        }
+        self.append(self.page_start(target) + end, key.id(), version, value)?;
+        end += record_size(value);
+        // The header makes the new sector the active one
+        self.write_page_header(target, self.sequence.wrapping_add(1))?;
+        let old = self.active;
+        (self.active, self.sequence, self.end, self.torn) =
+            (target, self.sequence.wrapping_add(1), end, false);
+        self.erase(old)?;
+        Ok(())
+    }
+
+    fn append(&mut self, start: u32, key: u8, version: u8, value: &[u8]) -> Result<(), F::Error> {
+        let len = value.len() as u16;
+        let crc = record_crc(key, version, len, value);
+        let mut header = [0; RECORD_HEADER_SIZE as usize];
+        header[0] = key;
+        header[1] = version;
+        header[2..4].copy_from_slice(&len.to_le_bytes());
+        header[4..8].copy_from_slice(&crc.to_le_bytes());
+        // The value first, so a record is only found once it's complete
+        let whole = value.len() / 4 * 4;
+        let value_start = start + RECORD_HEADER_SIZE;
+        if whole > 0 {
+            self.flash.write(value_start, &value[..whole])?;
+        }
+        if whole < value.len() {
+            let mut last = [0xFF; 4];
+            last[..value.len() - whole].copy_from_slice(&value[whole..]);
+            self.flash.write(value_start + whole as u32, &last)?;
+        }
+        self.flash.write(start, &header)
+    }
+
+    /// The offset and header of the last complete record of `key`.
+    fn find(&mut self, page: u32, key: u8) -> Result<Option<(u32, Record)>, F::Error> {
+        let mut found = None;
+        let mut offset = PAGE_HEADER_SIZE;
+        while let Some(record) = self.record_at(page, offset)? {
+            if record.key == key && self.is_complete(page, offset, record)? {
+                found = Some((offset, record));
+            }
+            offset += record.size();
+        }
+        Ok(found)
+    }
+
+    /// Walks the log of the active sector to where the next record goes.
+    fn find_end(&mut self) -> Result<(), F::Error> {
+        let mut offset = PAGE_HEADER_SIZE;
+        while let Some(record) = self.record_at(self.active, offset)? {
+            offset += record.size();
+        }
+        self.end = offset;
+        // Anything but erased flash after the last record is a record cut
+        // short, or a header written over a value that was
+        if offset < F::ERASE_SIZE as u32 {
+            let mut word = [0; 4];
+            self.flash
+                .read(self.page_start(self.active) + offset, &mut word)?;
+            let value_written = self.value_written_after(offset)?;
+            self.torn = u32::from_le_bytes(word) != ERASED || value_written;
+        }
+        Ok(())
+    }
+
+    /// Whether anything after `offset` in the active sector was written, as
+    /// the value of a record whose header never was.
+    fn value_written_after(&mut self, offset: u32) -> Result<bool, F::Error> {
+        let start = self.page_start(self.active);
+        let mut word = [0; 4];
+        let mut at = offset + RECORD_HEADER_SIZE;
+        // A value is at most MAX_VALUE_SIZE, so looking that far is enough
+        let last = (offset + RECORD_HEADER_SIZE + MAX_VALUE_SIZE as u32).min(F::ERASE_SIZE as u32);
+        while at < last {
+            self.flash.read(start + at, &mut word)?;
+            if u32::from_le_bytes(word) != ERASED {
+                return Ok(true);
+            }
+            at += 4;
+        }
+        Ok(false)
+    }
+
+    /// The record header at `offset`, or `None` at the end of the log.
+    fn record_at(&mut self, page: u32, offset: u32) -> Result<Option<Record>, F::Error> {
+        if offset + RECORD_HEADER_SIZE > F::ERASE_SIZE as u32 {
+            return Ok(None);
+        }
+        let mut header = [0; RECORD_HEADER_SIZE as usize];
+        self.flash
+            .read(self.page_start(page) + offset, &mut header)?;
+        let record = Record::parse(header);
+        let fits = offset + record.size() <= F::ERASE_SIZE as u32;
+        let erased = header == [0xFF; RECORD_HEADER_SIZE as usize];
+        Ok((!erased && fits && usize::from(record.len) <= MAX_VALUE_SIZE).then_some(record))
+    }
+
+    fn is_complete(&mut self, page: u32, offset: u32, record: Record) -> Result<bool, F::Error> {
+        let mut buf = [0; MAX_VALUE_SIZE];
+        let value = &mut buf[..usize::from(record.len)];
+        self.flash
+            .read(self.page_start(page) + offset + RECORD_HEADER_SIZE, value)?;
+        Ok(record_crc(record.key, record.version, record.len, value) == record.crc)
+    }
+
+    fn page_sequence(&mut self, page: u32) -> Result<Option<u32>, F::Error> {
+        let mut header = [0; PAGE_HEADER_SIZE as usize];
+        self.flash.read(self.page_start(page), &mut header)?;
+        let expected = page_header(u32::from_le_bytes([
+            header[4], header[5], header[6], header[7],
+        ]));
+        Ok((header == expected).then_some(u32::from_le_bytes(expected[4..8].try_into().unwrap())))
+    }
+
+    fn write_page_header(&mut self, page: u32, sequence: u32) -> Result<(), F::Error> {
+        self.flash
+            .write(self.page_start(page), &page_header(sequence))
+    }
+
+    fn erase(&mut self, page: u32) -> Result<(), F::Error> {
+        let start = self.page_start(page);
+        self.flash.erase(start, start + F::ERASE_SIZE as u32)
+    }
+
+    /// Erases the sector unless it already is, which saves the CPU a stall of
+    /// a few hundred milliseconds.
+    fn erase_if_needed(&mut self, page: u32) -> Result<(), F::Error> {
+        let start = self.page_start(page);
+        let mut words = [0; 64];
+        for offset in (0..F::ERASE_SIZE as u32).step_by(words.len()) {
+            self.flash.read(start + offset, &mut words)?;
+            if words.iter().any(|&byte| byte != 0xFF) {
+                return self.erase(page);
+            }
+        }
+        Ok(())
+    }
+
+    fn page_start(&self, page: u32) -> u32 {
+        SECTORS_OFFSET + page * F::ERASE_SIZE as u32
+    }
+}
+
+/// Space a record of `value` takes in the log, padded to whole words.
+fn record_size(value: &[u8]) -> u32 {
+    RECORD_HEADER_SIZE + (value.len() as u32).next_multiple_of(4)
+}
+
+fn page_header(sequence: u32) -> [u8; PAGE_HEADER_SIZE as usize] {
+    let mut header = [0; PAGE_HEADER_SIZE as usize];
+    header[..4].copy_from_slice(&PAGE_MAGIC.to_le_bytes());
+    header[4..8].copy_from_slice(&sequence.to_le_bytes());
+    let crc = crc32(&header[..8]);
+    header[8..].copy_from_slice(&crc.to_le_bytes());
+    header
+}
+
+fn record_crc(key: u8, version: u8, len: u16, value: &[u8]) -> u32 {
+    let [len_low, len_high] = len.to_le_bytes();
+    crc32_update(crc32_update(!0, &[key, version, len_low, len_high]), value) ^ !0
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    crc32_update(!0, bytes) ^ !0
+}
+
+/// CRC-32 (IEEE), bit by bit, as settings are small and rarely written.
+fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(crc, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+            (crc >> 1) ^ (0xEDB8_8320 & 0u32.wrapping_sub(crc & 1))
+        })
+    })
+}
@@ -0,0 +1,181 @@
+//! The bookkeeping of the watchdog supervisor: the supervised tasks, which of
+//! them stalled in a round, and what the chip was last reset by. The firmware
+//! side, which feeds the IWDG and reads the reset flags, is `src/watchdog.rs`.
+
+/// Marks the stalled tasks kept in RAM across a reset.
+const STALLED_MAGIC: u32 = 0x5741_5444;
+
+/// A supervised task, one bit each.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Task {
+    /// `usb_run`.
+    Usb = 0,
+    /// The task reporting the keypad in the current mode.
+    Keys = 1,
+    /// `save_settings`.
+    Settings = 2,
+    /// The task taking requests on the configuration interface: the console,
+    /// raw HID, the vendor interface or the keymap drive.
+    Config = 3,
+    /// `config_write`, which sends the raw HID replies.
+    ConfigReplies = 4,
+    /// `hid_read`, which takes the output reports, e.g. the keyboard LEDs.
+    HidRead = 5,
+    /// `send_ir`.
+    Ir = 6,
+    /// `show_keyboard_leds`.
+    Leds = 7,
+}
+
+impl Task {
+    pub const ALL: [Task; 8] = [
+        Task::Usb,
+        Task::Keys,
+        Task::Settings,
+        Task::Config,
+        Task::ConfigReplies,
+        Task::HidRead,
+        Task::Ir,
+        Task::Leds,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Task::Usb => "USB",
+            Task::Keys => "keys",
+            Task::Settings => "settings",
+            Task::Config => "configuration",
+            Task::ConfigReplies => "configuration replies",
+            Task::HidRead => "output reports",
+            Task::Ir => "IR",
+            Task::Leds => "LEDs",
+        }
+    }
+
+    pub const fn bit(self) -> u16 {
+        1 << self as u8
+    }
+}
+
+/// What the supervisor keeps from one round to the next.
+#[derive(Default)]
+pub struct Rounds {
+    /// The tasks that had something to do in the last round without checking
+    /// in.
+    owing: u16,
+}
+
+impl Rounds {
+    pub const fn new() -> Self {
+        Self { owing: 0 }
+    }
+
+    /// Ends a round, returning the tasks that stalled. `alive` are the tasks
+    /// that checked in during the round, and `idle` those that have nothing
+    /// to do, e.g. the USB task while the bus raised no event. A supervised
+    /// task stalls when it owes a check-in for two rounds in a row, so one
+    /// that only just got something to do, or lost a round to a flash erase,
+    /// gets another round.
+    pub fn end(&mut self, supervised: u16, alive: u16, idle: u16) -> u16 {
+        let owing = supervised & !alive & !idle;
+        let stalled = owing & self.owing;
+        self.owing = owing;
+        stalled
+    }
+}
+
+/// The reset flags of the RCC.
+#[derive(Clone, Copy, Default)]
+pub struct ResetFlags {
+    pub watchdog: bool,
+    pub window_watchdog: bool,
+    pub low_power: bool,
+    pub software: bool,
+    pub power_on: bool,
+    pub brownout: bool,
+}
+
+/// Why the chip last started.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ResetCause {
+    PowerOn,
+    Brownout,
+    /// The reset pin, e.g. the reset button or a debug probe.
+    Pin,
+    /// A reboot asked for, e.g. by the `reboot` command or a DFU detach.
+    Software,
+    /// The watchdog, with the [`Task`] bits that stopped checking in.
+    Watchdog(u16),
+    WindowWatchdog,
+    LowPower,
+}
+
+impl ResetCause {
+    /// The cause from the reset flags, and the words kept in RAM across the
+    /// reset by [`retained`].
+    pub fn new(flags: ResetFlags, retained: [u32; 2]) -> Self {
+        let stalled = match retained {
+            [STALLED_MAGIC, tasks] => tasks as u16,
+            _ => 0,
+        };
+        // Every reset also pulls the reset pin, and a power on also sets the
+        // brownout flag, so those are looked at last
+        if flags.watchdog {
+            ResetCause::Watchdog(stalled)
+        } else if flags.window_watchdog {
+            ResetCause::WindowWatchdog
+        } else if flags.low_power {
+            ResetCause::LowPower
+        } else if flags.software {
+            ResetCause::Software
+        } else if flags.power_on {
+            ResetCause::PowerOn
+        } else if flags.brownout {
+            ResetCause::Brownout
+        } else {
+            ResetCause::Pin
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ResetCause::PowerOn => "power on",
+            ResetCause::Brownout => "brownout",
+            ResetCause::Pin => "reset pin",
+            ResetCause::Software => "software",
+            ResetCause::Watchdog(_) => "watchdog",
+            ResetCause::WindowWatchdog => "window watchdog",
+            ResetCause::LowPower => "low power",
+        }
+    }
+
+    /// The tasks that stopped before a watchdog reset.
+    pub fn stalled(self) -> impl Iterator<Item = Task> {
+        let stalled = match self {
+            ResetCause::Watchdog(stalled) => stalled,
+            _ => 0,
+        };
+        Task::ALL
+            .into_iter()
+            .filter(move |task| stalled & task.bit() != 0)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for ResetCause {
+    fn format(&self, fmt: defmt::Formatter) {
+        match self {
+            ResetCause::Watchdog(stalled) => {
+                defmt::write!(fmt, "watchdog, stalled tasks {=u16:08b}", stalled)
+            }
+            _ => defmt::write!(fmt, "{}", self.name()),
+        }
+    }
+}
+
+/// The words to keep in RAM across a reset, so the next boot knows which
+/// tasks had stalled.
+pub fn retained(stalled: u16) -> [u32; 2] {
+    [STALLED_MAGIC, stalled.into()]
+}
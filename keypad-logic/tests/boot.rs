@@ -0,0 +1,191 @@
+mod common;
+
+use common::{FlashError, MockFlash, power};
+use keypad_logic::boot::{self, ACTIVE, DFU, FLASH_BASE, PAGE_SIZE, Phase, STATE};
+
+/// The state, active and DFU partitions of a chip.
+struct Chip {
+    state: MockFlash<0x1_0000>,
+    active: MockFlash<0x2_0000>,
+    dfu: MockFlash<0x2_0000>,
+}
+
+impl Chip {
+    fn new() -> Self {
+        let power = power(None);
+        Self {
+            state: MockFlash::new(STATE.size as usize, &power),
+            active: MockFlash::new(ACTIVE.size as usize, &power),
+            dfu: MockFlash::new(DFU.size as usize, &power),
+        }
+    }
+
+    /// The chip after a restart, with power for `operations` operations.
+    fn restarted(&self, operations: Option<usize>) -> Self {
+        let power = power(operations);
+        Self {
+            state: self.state.restarted(&power),
+            active: self.active.restarted(&power),
+            dfu: self.dfu.restarted(&power),
+        }
+    }
+
+    fn boot(&mut self) -> Result<Phase, FlashError> {
+        boot::prepare(&mut self.state, &mut self.active, &mut self.dfu)
+    }
+
+    fn running(&self) -> u8 {
+        version_of(&self.active.memory)
+    }
+
+    fn phase(&mut self) -> Phase {
+        boot::phase(&mut self.state).unwrap()
+    }
+}
+
+/// An image of firmware `version`, with a valid vector table and a little
+/// code on every page.
+fn image(version: u8) -> Vec<u8> {
+    let mut image = vec![0xFF; ACTIVE.size as usize];
+    let reset = FLASH_BASE + ACTIVE.offset + 0x1C1;
+    image[..8].copy_from_slice(&[0x2002_0000u32.to_le_bytes(), reset.to_le_bytes()].concat());
+    for page in 0..(ACTIVE.size / PAGE_SIZE) as usize {
+        let start = page * PAGE_SIZE as usize + 0x200;
+        for (i, byte) in image[start..start + 0x600].iter_mut().enumerate() {
+            *byte = version ^ (page as u8) ^ (i as u8);
+        }
+    }
+    image
+}
+
+/// Which [`image`] `memory` starts with, checking every page of it.
+fn version_of(memory: &[u8]) -> u8 {
+    let version = memory[0x200];
+    assert_eq!(
+        &memory[..ACTIVE.size as usize],
+        &image(version)[..],
+        "not a whole image"
+    );
+    version
+}
+
+/// A chip running confirmed firmware 1, with firmware 2 downloaded and the
+/// update requested.
+fn updating() -> Chip {
+    let mut chip = Chip::new();
+    chip.active.memory.copy_from_slice(&image(1));
+    chip.dfu.memory[..ACTIVE.size as usize].copy_from_slice(&image(2));
+    boot::request_update(&mut chip.state).unwrap();
+    chip
+}
+
+#[test]
+fn boots_the_firmware_there_is() {
+    let mut chip = Chip::new();
+    chip.active.memory.copy_from_slice(&image(1));
+    assert_eq!(chip.boot(), Ok(Phase::Idle));
+    assert_eq!(chip.running(), 1);
+}
+
+#[test]
+fn swaps_in_an_update_and_keeps_it_once_confirmed() {
+    let mut chip = updating();
+    assert_eq!(chip.phase(), Phase::Swapping(0));
+    assert_eq!(chip.boot(), Ok(Phase::Trial));
+    assert_eq!(chip.running(), 2);
+    assert_eq!(boot::mark_booted(&mut chip.state), Ok(true));
+    assert_eq!(boot::mark_booted(&mut chip.state), Ok(false));
+
+    assert_eq!(chip.boot(), Ok(Phase::Idle));
+    assert_eq!(chip.running(), 2);
+}
+
+#[test]
+fn reverts_an_update_that_isnt_confirmed() {
+    let mut chip = updating();
+    assert_eq!(chip.boot(), Ok(Phase::Trial));
+    assert_eq!(chip.running(), 2);
+
+    assert_eq!(chip.boot(), Ok(Phase::Idle));
+    assert_eq!(chip.running(), 1);
+    // The update is back where it was downloaded to
+    assert_eq!(version_of(&chip.dfu.memory), 2);
+    assert_eq!(chip.boot(), Ok(Phase::Idle));
+    assert_eq!(chip.running(), 1);
+}
+
+#[test]
+fn ignores_an_update_that_isnt_firmware() {
+    let mut chip = updating();
+    chip.dfu.memory[..8].fill(0xFF);
+    assert_eq!(chip.boot(), Ok(Phase::Idle));
+    assert_eq!(chip.running(), 1);
+}
+
+#[test]
+fn checks_the_vector_table() {
+    let reset = FLASH_BASE + ACTIVE.offset + 0x1C1;
+    assert!(boot::is_firmware([0x2002_0000, reset]));
+    // Not in RAM
+    assert!(!boot::is_firmware([0x0800_0000, reset]));
+    // Not Thumb code
+    assert!(!boot::is_firmware([0x2002_0000, reset - 1]));
+    // In the bootloader, or in the DFU partition
+    assert!(!boot::is_firmware([0x2002_0000, FLASH_BASE + 0x1C1]));
+    assert!(!boot::is_firmware([
+        0x2002_0000,
+        FLASH_BASE + DFU.offset + 0x1C1
+    ]));
+}
+
+#[test]
+fn makes_room_in_the_log() {
+    let mut chip = Chip::new();
+    chip.active.memory.copy_from_slice(&image(1));
+    // Many more updates than the state partition holds the log of
+    for version in 2..2000 {
+        let version = version as u8;
+        chip.dfu.memory[..ACTIVE.size as usize].copy_from_slice(&image(version));
+        boot::request_update(&mut chip.state).unwrap();
+        assert_eq!(chip.boot(), Ok(Phase::Trial));
+        assert_eq!(chip.running(), version);
+        assert_eq!(boot::mark_booted(&mut chip.state), Ok(true));
+    }
+}
+
+/// Cuts the power after every flash operation of an update, its trial that
+/// doesn't confirm itself and the revert, restarting until the boot goes
+/// through. The firmware that starts is always whole, and the right one.
+#[test]
+fn survives_a_power_cut_at_any_point() {
+    let mut cut_after = 0;
+    loop {
+        let mut chip = updating().restarted(Some(cut_after));
+        let first = chip.boot();
+        let mut chip = chip.restarted(None);
+        let phase = chip.boot().unwrap();
+        if first.is_ok() {
+            // The trial ran, now reverted
+            assert_eq!(phase, Phase::Idle);
+            assert_eq!(chip.running(), 1);
+            break;
+        }
+        // Cut during the swap, which the restart finished
+        assert_eq!(phase, Phase::Trial, "cut after {cut_after} operations");
+        assert_eq!(chip.running(), 2, "cut after {cut_after} operations");
+
+        // The trial doesn't confirm itself, and the revert is cut at the
+        // same point into it
+        let mut reverting = chip.restarted(Some(cut_after));
+        let cut_revert = reverting.boot();
+        let mut reverting = reverting.restarted(None);
+        assert_eq!(reverting.boot(), Ok(Phase::Idle));
+        assert_eq!(reverting.running(), 1, "revert cut after {cut_after}");
+        if cut_revert.is_ok() {
+            assert_eq!(reverting.phase(), Phase::Idle);
+        }
+        cut_after += 1;
+    }
+    // Four page copies of erasing and writing, all of which were cut
+    assert!(cut_after > 4 * 0x600 / 4);
+}
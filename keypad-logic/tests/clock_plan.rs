@@ -0,0 +1,110 @@
+use keypad_logic::clock_plan::{
+    Dividers, HseMode, PlanError, STM32F401, STM32F407, STM32F411, USB_CLOCK_HZ, solve,
+};
+
+const MHZ: u32 = 1_000_000;
+
+/// The system clock and the USB clock the dividers make from `hse_hz`.
+fn clocks(hse_hz: u32, dividers: Dividers) -> (u32, u32) {
+    let vco = u64::from(hse_hz) * u64::from(dividers.n) / u64::from(dividers.m);
+    let clock = |div: u32| (vco / u64::from(div)) as u32;
+    (clock(dividers.p), clock(dividers.q))
+}
+
+#[test]
+fn plans_the_discovery_board() {
+    // 8 MHz from the ST-LINK's MCO
+    let dividers = solve(8 * MHZ, HseMode::Bypass, 168 * MHZ, &STM32F407).unwrap();
+    assert_eq!(
+        dividers,
+        Dividers {
+            m: 4,
+            n: 168,
+            p: 2,
+            q: 7,
+            apb1_div: 4,
+            apb2_div: 2,
+        }
+    );
+    assert_eq!(clocks(8 * MHZ, dividers), (168 * MHZ, USB_CLOCK_HZ));
+}
+
+#[test]
+fn plans_the_black_pills() {
+    let dividers = solve(25 * MHZ, HseMode::Oscillator, 96 * MHZ, &STM32F411).unwrap();
+    assert_eq!(clocks(25 * MHZ, dividers), (96 * MHZ, USB_CLOCK_HZ));
+    assert_eq!((dividers.apb1_div, dividers.apb2_div), (2, 1));
+
+    let dividers = solve(25 * MHZ, HseMode::Oscillator, 84 * MHZ, &STM32F401).unwrap();
+    assert_eq!(clocks(25 * MHZ, dividers), (84 * MHZ, USB_CLOCK_HZ));
+    assert_eq!((dividers.apb1_div, dividers.apb2_div), (2, 1));
+}
+
+#[test]
+fn keeps_the_pll_input_in_range() {
+    for hse_mhz in 4..=26 {
+        for sysclk_mhz in 24..=168 {
+            let Ok(dividers) = solve(
+                hse_mhz * MHZ,
+                HseMode::Oscillator,
+                sysclk_mhz * MHZ,
+                &STM32F407,
+            ) else {
+                continue;
+            };
+            let pll_in = hse_mhz * MHZ / dividers.m;
+            assert!((MHZ..=2 * MHZ).contains(&pll_in), "{hse_mhz} MHz");
+            assert_eq!(
+                clocks(hse_mhz * MHZ, dividers),
+                (sysclk_mhz * MHZ, USB_CLOCK_HZ),
+                "{hse_mhz} MHz for {sysclk_mhz} MHz"
+            );
+        }
+    }
+}
+
+#[test]
+fn finds_96_mhz_the_fastest_the_f411_makes_from_25_mhz() {
+    for sysclk_mhz in 97..=100 {
+        assert_eq!(
+            solve(25 * MHZ, HseMode::Oscillator, sysclk_mhz * MHZ, &STM32F411),
+            Err(PlanError::NoDividers),
+            "{sysclk_mhz} MHz"
+        );
+    }
+}
+
+#[test]
+fn rejects_a_crystal_without_48_mhz() {
+    // A UART crystal, whose multiples never land on 48 MHz
+    for sysclk_mhz in 1..=168 {
+        assert_eq!(
+            solve(
+                14_745_600,
+                HseMode::Oscillator,
+                sysclk_mhz * MHZ,
+                &STM32F407
+            ),
+            Err(PlanError::NoDividers),
+            "{sysclk_mhz} MHz"
+        );
+    }
+}
+
+#[test]
+fn rejects_clocks_out_of_range() {
+    assert_eq!(
+        solve(30 * MHZ, HseMode::Oscillator, 84 * MHZ, &STM32F401),
+        Err(PlanError::HseFrequency)
+    );
+    assert_eq!(
+        solve(3 * MHZ, HseMode::Oscillator, 84 * MHZ, &STM32F401),
+        Err(PlanError::HseFrequency)
+    );
+    // Fine as a clock on OSC_IN
+    assert!(solve(30 * MHZ, HseMode::Bypass, 84 * MHZ, &STM32F401).is_ok());
+    assert_eq!(
+        solve(25 * MHZ, HseMode::Oscillator, 96 * MHZ, &STM32F401),
+        Err(PlanError::SysclkTooFast)
+    );
+}
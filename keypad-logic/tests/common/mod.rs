@@ -0,0 +1,115 @@
+//! Flash in RAM for the tests, which can lose power partway through.
+
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+use std::cell::Cell;
+use std::rc::Rc;
+
+#[derive(Debug, PartialEq)]
+pub enum FlashError {
+    PowerCut,
+}
+
+impl NorFlashError for FlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        NorFlashErrorKind::Other
+    }
+}
+
+/// Operations left before the power goes, shared by the flashes of a chip.
+pub type Power = Rc<Cell<Option<usize>>>;
+
+/// Power for `operations` operations, or for any number of them.
+pub fn power(operations: Option<usize>) -> Power {
+    Rc::new(Cell::new(operations))
+}
+
+/// Flash in RAM, which loses power after a number of operations: writes of a
+/// word, and erases, which are cut halfway.
+#[derive(Clone)]
+pub struct MockFlash<const ERASE_SIZE: usize> {
+    pub memory: Vec<u8>,
+    power: Power,
+}
+
+impl<const ERASE_SIZE: usize> MockFlash<ERASE_SIZE> {
+    /// Erased flash of `size` bytes.
+    pub fn new(size: usize, power: &Power) -> Self {
+        Self {
+            memory: vec![0xFF; size],
+            power: power.clone(),
+        }
+    }
+
+    /// The flash after a restart, with `power`.
+    pub fn restarted(&self, power: &Power) -> Self {
+        Self {
+            memory: self.memory.clone(),
+            power: power.clone(),
+        }
+    }
+
+    /// Counts an operation, returning whether there is still power for it.
+    fn operate(&mut self) -> bool {
+        match self.power.get() {
+            Some(0) => false,
+            Some(left) => {
+                self.power.set(Some(left - 1));
+                true
+            }
+            None => true,
+        }
+    }
+}
+
+impl<const ERASE_SIZE: usize> ErrorType for MockFlash<ERASE_SIZE> {
+    type Error = FlashError;
+}
+
+impl<const ERASE_SIZE: usize> ReadNorFlash for MockFlash<ERASE_SIZE> {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.memory[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.memory.len()
+    }
+}
+
+impl<const ERASE_SIZE: usize> NorFlash for MockFlash<ERASE_SIZE> {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), FlashError> {
+        assert_eq!(from as usize % ERASE_SIZE, 0);
+        assert_eq!(to as usize % ERASE_SIZE, 0);
+        let (from, to) = (from as usize, to as usize);
+        if !self.operate() {
+            self.memory[from..(from + to) / 2].fill(0xFF);
+            return Err(FlashError::PowerCut);
+        }
+        self.memory[from..to].fill(0xFF);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        assert_eq!(offset % 4, 0);
+        assert_eq!(bytes.len() % 4, 0);
+        for (i, word) in bytes.chunks(4).enumerate() {
+            if !self.operate() {
+                return Err(FlashError::PowerCut);
+            }
+            let at = offset as usize + i * 4;
+            for (cell, byte) in self.memory[at..at + 4].iter_mut().zip(word) {
+                // Programming only clears bits
+                *cell &= byte;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,287 @@
+use keypad_logic::ir_protocol::{CodeError, IrCode, PANASONIC_VENDOR_ID, RawCode};
+
+/// A pulse distance coded frame: a leader, the bytes least significant bit
+/// first, each bit a `unit` mark and a space of one unit for a zero or three
+/// for a one, and a stop mark.
+fn pulse_distance(leader: [u16; 2], unit: u16, bytes: &[u8]) -> Vec<u16> {
+    let mut durations = leader.to_vec();
+    for byte in bytes {
+        for bit in 0..8 {
+            let one = byte & (1 << bit) != 0;
+            durations.extend([unit, if one { 3 * unit } else { unit }]);
+        }
+    }
+    durations.push(unit);
+    durations
+}
+
+fn encode(code: IrCode, toggle: bool) -> Vec<u16> {
+    code.encode(toggle).durations_us.to_vec()
+}
+
+fn encode_repeat(code: IrCode) -> Vec<u16> {
+    code.encode_repeat(false).durations_us.to_vec()
+}
+
+#[test]
+fn encodes_nec() {
+    let code = IrCode::Nec {
+        address: 0x04,
+        command: 0x08,
+    };
+    let frame = code.encode(false);
+    assert_eq!(frame.carrier_hz, 38_000);
+    assert_eq!(
+        frame.durations_us.to_vec(),
+        pulse_distance([8992, 4496], 562, &[0x04, 0xFB, 0x08, 0xF7])
+    );
+    assert_eq!(encode_repeat(code), [8992, 2248, 562]);
+    assert_eq!(code.frame_period_us(), 108_000);
+
+    // Extended addresses are sent whole instead of with their inverse
+    let code = IrCode::Nec {
+        address: 0x1234,
+        command: 0x56,
+    };
+    assert_eq!(
+        encode(code, false),
+        pulse_distance([8992, 4496], 562, &[0x34, 0x12, 0x56, 0xA9])
+    );
+}
+
+#[test]
+fn encodes_rc5() {
+    // Start bits 1 1, toggle 0, address 00101, command 110101, in halves
+    // of 889 µs with the idle space before the first mark left out
+    let code = IrCode::Rc5 {
+        address: 0x05,
+        command: 0x35,
+    };
+    let frame = code.encode(false);
+    assert_eq!(frame.carrier_hz, 36_000);
+    assert_eq!(
+        frame.durations_us.to_vec(),
+        [
+            889, 889, 1778, 889, 889, 889, 889, 1778, 1778, 1778, 889, 889, 889, 889, 1778, 1778,
+            1778, 1778, 889
+        ]
+    );
+    assert_eq!(encode_repeat(code), encode(code, false));
+    assert_eq!(code.frame_period_us(), 113_778);
+
+    // RC5X: the second start bit is the inverted seventh command bit
+    let code = IrCode::Rc5 {
+        address: 0x00,
+        command: 0x40,
+    };
+    let mut expected = vec![1778, 1778, 1778];
+    expected.extend([889; 21]);
+    assert_eq!(encode(code, true), expected);
+}
+
+#[test]
+fn encodes_rc6() {
+    // The leader, start bit 1, mode 000, the double length toggle 0, then
+    // address 0x12 and command 0x34, in units of 444 µs
+    let code = IrCode::Rc6 {
+        address: 0x12,
+        command: 0x34,
+    };
+    let units = [
+        6, 2, 1, 2, 1, 1, 1, 1, 1, 2, 2, 1, 1, 1, 1, 1, 2, 2, 1, 1, 2, 2, 1, 1, 1, 1, 2, 1, 1, 2,
+        2, 2, 1, 1, 1,
+    ];
+    let frame = code.encode(false);
+    assert_eq!(frame.carrier_hz, 36_000);
+    assert_eq!(
+        frame.durations_us.to_vec(),
+        units.map(|units| units * 444).to_vec()
+    );
+    assert_eq!(code.frame_period_us(), 106_667);
+
+    // The toggle turns the trailer around, merging it with the last mode
+    // bit and the first address bit
+    let toggled = encode(code, true);
+    assert_eq!(
+        toggled[..10],
+        [2664, 888, 444, 888, 444, 444, 444, 444, 1332, 1332]
+    );
+    assert_eq!(toggled[10..], frame.durations_us[12..]);
+}
+
+#[test]
+fn encodes_sirc() {
+    // Command 0x15 and address 0x01, least significant bit first, the marks
+    // telling the bits apart, padded to 45 ms
+    let code = IrCode::Sirc {
+        address: 0x01,
+        command: 0x15,
+    };
+    let frame_durations = [
+        2400, 600, 1200, 600, 600, 600, 1200, 600, 600, 600, 1200, 600, 600, 600, 600, 600, 1200,
+        600, 600, 600, 600, 600, 600, 600, 600, 25800,
+    ];
+    let frame = code.encode(false);
+    assert_eq!(frame.carrier_hz, 40_000);
+    // Sent three times, as Sony devices want
+    assert_eq!(frame.durations_us.to_vec(), frame_durations.repeat(3));
+    assert_eq!(encode_repeat(code), frame_durations);
+    assert_eq!(code.frame_period_us(), 0);
+
+    // 15 and 20-bit frames
+    let sum = |durations: Vec<u16>| durations.iter().map(|&us| u32::from(us)).sum::<u32>();
+    for (address, bits) in [(0x80, 15), (0x1F00, 20)] {
+        let code = IrCode::Sirc {
+            address,
+            command: 0,
+        };
+        let durations = encode_repeat(code);
+        assert_eq!(durations.len(), 2 + 2 * bits);
+        assert_eq!(sum(durations), 45_000);
+    }
+}
+
+#[test]
+fn encodes_samsung() {
+    let code = IrCode::Samsung {
+        address: 0x07,
+        command: 0x02,
+    };
+    let frame = code.encode(false);
+    assert_eq!(frame.carrier_hz, 38_000);
+    assert_eq!(
+        frame.durations_us.to_vec(),
+        pulse_distance([4500, 4500], 562, &[0x07, 0x07, 0x02, 0xFD])
+    );
+    // Repeats whole frames
+    assert_eq!(encode_repeat(code), encode(code, false));
+    assert_eq!(code.frame_period_us(), 108_000);
+}
+
+#[test]
+fn encodes_kaseikyo() {
+    // Panasonic's vendor ID with its parity nibble, the address, the command
+    // and the parity byte
+    let code = IrCode::Kaseikyo {
+        vendor: PANASONIC_VENDOR_ID,
+        address: 0x0AB,
+        command: 0x3C,
+    };
+    let frame = code.encode(false);
+    assert_eq!(frame.carrier_hz, 37_000);
+    assert_eq!(
+        frame.durations_us.to_vec(),
+        pulse_distance([3456, 1728], 432, &[0x02, 0x20, 0xB0, 0x0A, 0x3C, 0x86])
+    );
+    assert_eq!(code.frame_period_us(), 130_000);
+
+    // A vendor ID whose nibbles don't cancel out
+    let code = IrCode::Kaseikyo {
+        vendor: 0x5431,
+        address: 0x001,
+        command: 0x00,
+    };
+    assert_eq!(
+        encode(code, false),
+        pulse_distance([3456, 1728], 432, &[0x31, 0x54, 0x13, 0x00, 0x00, 0x13])
+    );
+}
+
+#[test]
+fn parses_what_it_displays() {
+    for text in [
+        "nec 0x04 0x08",
+        "nec 0x1234 0x56",
+        "rc5 0x05 0x35",
+        "rc6 0x12 0x34",
+        "sirc 0x1F00 0x15",
+        "samsung 0x07 0x02",
+        "panasonic 0x0AB 0x3C",
+        "kaseikyo 0x5432 0x001 0x00",
+    ] {
+        let code = IrCode::parse(text).unwrap();
+        assert_eq!(code.to_string(), text);
+    }
+    assert_eq!(
+        IrCode::parse("nec 4 8"),
+        Some(IrCode::Nec {
+            address: 4,
+            command: 8
+        })
+    );
+}
+
+#[test]
+fn rejects_bad_codes() {
+    for text in [
+        "",
+        "nec 0x04",
+        "nec 0x04 0x08 0x01",
+        "nec 0x04 0x108",
+        "rc5 0x20 0x01",
+        "rc5 0x01 0x80",
+        "sirc 0x2000 0x01",
+        "panasonic 0x1000 0x01",
+        "sony 0x01 0x01",
+        "nec 0x04 eight",
+    ] {
+        assert_eq!(IrCode::parse(text), None, "{text:?}");
+    }
+}
+
+#[test]
+fn parses_raw_codes() {
+    let text = "raw 38000 9000 4500 562 40000 repeat 9000 2250 562 96000 max 5";
+    let code = RawCode::parse(text).unwrap();
+    assert_eq!(code.carrier_hz, 38_000);
+    assert_eq!(code.max_repeats, Some(5));
+    let once = code.pulses(false);
+    assert_eq!(once.durations_us, [9000, 4500, 562]);
+    assert_eq!(once.gap_us, 40_000);
+    let repeat = code.pulses(true);
+    assert_eq!(repeat.durations_us, [9000, 2250, 562]);
+    assert_eq!(repeat.gap_us, 96_000);
+    assert_eq!(code.to_string(), text);
+
+    // Without a repeat part, the whole code is repeated
+    let code = RawCode::parse("raw 36000 500 500 500").unwrap();
+    assert_eq!(code.pulses(true).durations_us, [500, 500, 500]);
+    assert_eq!(code.pulses(true).gap_us, 0);
+}
+
+#[test]
+fn converts_pronto_codes() {
+    // 109 units of 241 ns to a carrier period
+    let code = RawCode::parse("pronto 0000 006D 0002 0000 0156 00AB 0015 0040").unwrap();
+    assert_eq!(code.carrier_hz, 38_067);
+    let once = code.pulses(false);
+    assert_eq!(once.durations_us, [8984, 4492, 552]);
+    assert_eq!(once.gap_us, 1682);
+    // The missing repeat part is replaced by the other one
+    assert_eq!(code.pulses(true).durations_us, [8984, 4492, 552]);
+}
+
+#[test]
+fn rejects_bad_raw_codes() {
+    let error = |text| RawCode::parse(text).err();
+    assert_eq!(error("raw"), Some(CodeError::Invalid));
+    assert_eq!(error("raw 38000"), Some(CodeError::Invalid));
+    assert_eq!(error("raw 10000 500 500"), Some(CodeError::Carrier));
+    assert_eq!(error("raw 38000 0 500"), Some(CodeError::Duration));
+    assert_eq!(error("raw 38000 70000 500 500"), Some(CodeError::Duration));
+    assert_eq!(error("raw 38000 500 max 256"), Some(CodeError::Invalid));
+    assert_eq!(
+        error("pronto 0100 006D 0001 0000 0156 00AB"),
+        Some(CodeError::UnsupportedPronto)
+    );
+    assert_eq!(
+        error("pronto 0000 006D 0002 0000 0156 00AB"),
+        Some(CodeError::PairCount)
+    );
+    assert_eq!(
+        error("pronto 0000 006D 0001 0000 0156 00AB 0015"),
+        Some(CodeError::PairCount)
+    );
+    let long = format!("raw 38000{}", " 500".repeat(129));
+    assert_eq!(error(&long), Some(CodeError::TooLong));
+}
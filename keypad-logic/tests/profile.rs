@@ -0,0 +1,207 @@
+use keypad_logic::profile::{
+    self, ChordGate, Gated, KeySettings, NAME_LEN, PROFILE_COUNT, REMAP_CHORD, Record,
+    default_name, from_chord, record_size, truncate_name,
+};
+
+/// The labels of `keypad.toml`'s default keypad.
+const LABELS: [char; 16] = [
+    '1', '2', '3', 'A', '4', '5', '6', 'B', '7', '8', '9', 'C', '*', '0', '#', 'D',
+];
+
+fn key(label: char) -> u16 {
+    1 << LABELS.iter().position(|&key| key == label).unwrap()
+}
+
+fn keys(labels: &str) -> u16 {
+    labels.chars().map(key).fold(0, |keys, key| keys | key)
+}
+
+/// Presses `label` while the keys of `held` are down.
+fn chord(held: &str, label: char) -> Option<char> {
+    profile::chord(&LABELS, keys(held) | key(label), key(label))
+}
+
+#[test]
+fn switches_with_star_hash_and_a_digit() {
+    for digit in 1..=8 {
+        let label = char::from_digit(digit, 10).unwrap();
+        assert_eq!(chord("*#", label), Some(label));
+        assert_eq!(from_chord(label), Some(digit as usize - 1));
+    }
+    // The remap mode and keys that pick nothing
+    assert_eq!(chord("*#", '0'), Some('0'));
+    assert_eq!(from_chord('0'), None);
+    assert_eq!(from_chord('9'), None);
+    assert_eq!(from_chord('A'), None);
+}
+
+#[test]
+fn needs_both_chord_keys() {
+    assert_eq!(chord("*", '1'), None);
+    assert_eq!(chord("#", '1'), None);
+    assert_eq!(chord("", '1'), None);
+    // Other keys held too don't matter
+    assert_eq!(chord("*#5", '1'), Some('1'));
+    // Pressing the chord keys themselves isn't a chord
+    assert_eq!(profile::chord(&LABELS, keys("*#"), key('#')), None);
+    assert_eq!(profile::chord(&LABELS, keys("*#"), key('*')), None);
+}
+
+#[test]
+fn works_without_chord_keys() {
+    let labels = ['1', '2', '3', '4'];
+    assert_eq!(profile::chord(&labels, 0b11, 0b01), None);
+    // A key outside the labels
+    assert_eq!(profile::chord(&LABELS, keys("*#"), 0), None);
+}
+
+/// Feeds the scans with the keys of `scans` held in turn to a gate.
+fn gate(scans: &[&str]) -> Vec<Gated> {
+    let mut gate = ChordGate::new();
+    let mut held = 0;
+    scans
+        .iter()
+        .map(|scan| {
+            let gated = gate.update(&LABELS, held, keys(scan));
+            held = keys(scan);
+            gated
+        })
+        .collect()
+}
+
+fn gated(keys: u16, tapped: u16, chord: Option<char>) -> Gated {
+    Gated {
+        keys,
+        tapped,
+        chord,
+    }
+}
+
+#[test]
+fn types_nothing_of_a_chord() {
+    assert_eq!(
+        gate(&["*", "*#", "*#3", "*#", "#", ""]),
+        [
+            gated(0, 0, None),
+            gated(0, 0, None),
+            gated(0, 0, Some('3')),
+            gated(0, 0, None),
+            gated(0, 0, None),
+            gated(0, 0, None),
+        ]
+    );
+    // Two chords in a row
+    assert_eq!(
+        gate(&["*#", "*#1", "*#", "*#2"])
+            .iter()
+            .map(|gated| gated.chord)
+            .collect::<Vec<_>>(),
+        [None, Some('1'), None, Some('2')]
+    );
+}
+
+#[test]
+fn types_nothing_on_the_way_into_the_remap_mode() {
+    assert_eq!(
+        gate(&["#", "#*", "#*0"]),
+        [
+            gated(0, 0, None),
+            gated(0, 0, None),
+            gated(0, 0, Some(REMAP_CHORD)),
+        ]
+    );
+}
+
+#[test]
+fn taps_a_chord_key_released_without_a_chord() {
+    assert_eq!(
+        gate(&["*", ""]),
+        [gated(0, 0, None), gated(0, key('*'), None)]
+    );
+    assert_eq!(
+        gate(&["*#", "*", ""]),
+        [
+            gated(0, 0, None),
+            gated(0, key('#'), None),
+            gated(0, key('*'), None),
+        ]
+    );
+}
+
+#[test]
+fn types_chord_keys_held_with_other_keys() {
+    // `A`, and digits without both chord keys, make no chord
+    assert_eq!(
+        gate(&["*", "*A", "A", ""]),
+        [
+            gated(0, 0, None),
+            gated(keys("*A"), 0, None),
+            gated(key('A'), 0, None),
+            gated(0, 0, None),
+        ]
+    );
+    assert_eq!(gate(&["#", "#5"])[1], gated(keys("#5"), 0, None));
+    assert_eq!(gate(&["*#", "*#9"])[1], gated(keys("*#9"), 0, None));
+    // `*` is held back with other keys down too
+    assert_eq!(gate(&["A", "A*"])[1], gated(key('A'), 0, None));
+}
+
+#[test]
+fn names_profiles() {
+    assert_eq!(default_name(0), "Profile 1");
+    assert_eq!(default_name(PROFILE_COUNT - 1), "Profile 8");
+    assert_eq!(truncate_name("Numpad"), "Numpad");
+    assert_eq!(
+        truncate_name("A very long profile name"),
+        "A very long prof"
+    );
+    // 'é' is two bytes: 16 in all fit, but one ending at byte 17 doesn't
+    assert_eq!(truncate_name("Pavé numérique"), "Pavé numérique");
+    assert_eq!(truncate_name("Profile number é"), "Profile number ");
+}
+
+const SETTINGS: KeySettings = KeySettings {
+    debounce_ms: 25,
+    remote_wakeup: false,
+    queue_policy: 0,
+};
+
+#[test]
+fn saves_and_restores_a_profile() {
+    let keycodes: Vec<u16> = (0..64).map(|key| 0x5100 + key).collect();
+    let mut buf = [0xAA; 256];
+    let len = Record::encode("Hex", &SETTINGS, &keycodes, &mut buf);
+    assert_eq!(len, record_size(64));
+    assert_eq!(len, 1 + NAME_LEN + 6 + 128);
+
+    let record = Record::decode(&buf[..len], 64).unwrap();
+    assert_eq!(record.name, "Hex");
+    assert_eq!(record.settings, SETTINGS);
+    assert_eq!(record.keycodes().collect::<Vec<_>>(), keycodes);
+}
+
+#[test]
+fn keeps_the_record_format() {
+    let mut buf = [0; 64];
+    let len = Record::encode("Fn", &SETTINGS, &[0x0004, 0x5221], &mut buf);
+    let mut expected = vec![2, b'F', b'n'];
+    expected.resize(1 + NAME_LEN, 0);
+    expected.extend([25, 0, 0, 0, 0, 0, 0x04, 0x00, 0x21, 0x52]);
+    assert_eq!(buf[..len], expected);
+}
+
+#[test]
+fn rejects_records_of_another_keymap_or_with_bad_names() {
+    let mut buf = [0; 256];
+    let len = Record::encode("Macros", &SETTINGS, &[0; 64], &mut buf);
+    // Saved for a larger or smaller keypad
+    assert!(Record::decode(&buf[..len], 48).is_none());
+    assert!(Record::decode(&buf[..len], 80).is_none());
+
+    let mut bad = buf;
+    bad[0] = NAME_LEN as u8 + 1;
+    assert!(Record::decode(&bad[..len], 64).is_none());
+    let mut bad = buf;
+    bad[1] = 0xFF;
+    assert!(Record::decode(&bad[..len], 64).is_none());
+}
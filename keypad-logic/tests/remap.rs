@@ -0,0 +1,141 @@
+use keypad_logic::remap::{MENU, Outcome, Remap, keycode_of};
+
+/// The labels of `keypad.toml`'s default keypad.
+const LABELS: [char; 16] = [
+    '1', '2', '3', 'A', '4', '5', '6', 'B', '7', '8', '9', 'C', '*', '0', '#', 'D',
+];
+
+/// Presses the keys labelled `labels` in turn, returning what each did.
+fn press(remap: &mut Remap, labels: &str) -> Vec<Outcome> {
+    labels
+        .chars()
+        .map(|label| {
+            let key = LABELS.iter().position(|&key| key == label).unwrap();
+            remap.press(key, label)
+        })
+        .collect()
+}
+
+/// What the last of the presses did.
+fn remap(labels: &str) -> Outcome {
+    *press(&mut Remap::new(), labels).last().unwrap()
+}
+
+#[test]
+fn takes_a_usage_typed_in_decimal() {
+    let mut remap = Remap::new();
+    // Key 5, then 4 for A
+    assert_eq!(
+        press(&mut remap, "54#"),
+        [
+            Outcome::Taken,
+            Outcome::Taken,
+            Outcome::Remapped {
+                key: 5,
+                keycode: 0x04
+            }
+        ]
+    );
+    // 40 for Enter, and three digits
+    assert_eq!(
+        self::remap("A40#"),
+        Outcome::Remapped {
+            key: 3,
+            keycode: 0x28
+        }
+    );
+    assert_eq!(
+        self::remap("1101#"),
+        Outcome::Remapped {
+            key: 0,
+            keycode: 0x65
+        }
+    );
+    // Leading zeros count as digits
+    assert_eq!(
+        self::remap("1007#"),
+        Outcome::Remapped {
+            key: 0,
+            keycode: 0x07
+        }
+    );
+}
+
+#[test]
+fn takes_menu_keys() {
+    for (label, usage) in MENU {
+        assert_eq!(
+            remap(&format!("D{label}")),
+            Outcome::Remapped {
+                key: 15,
+                keycode: usage as u16
+            }
+        );
+    }
+    // Even after digits
+    assert_eq!(
+        remap("D12B"),
+        Outcome::Remapped {
+            key: 15,
+            keycode: 0x2A
+        }
+    );
+}
+
+#[test]
+fn remaps_the_chord_keys_too() {
+    assert_eq!(
+        remap("*5#"),
+        Outcome::Remapped {
+            key: 12,
+            keycode: 0x05
+        }
+    );
+    assert_eq!(
+        remap("#C"),
+        Outcome::Remapped {
+            key: 14,
+            keycode: 0x2B
+        }
+    );
+}
+
+#[test]
+fn clears_a_key_with_0() {
+    assert_eq!(remap("20#"), Outcome::Remapped { key: 1, keycode: 0 });
+}
+
+#[test]
+fn cancels() {
+    assert_eq!(remap("1*"), Outcome::Rejected);
+    assert_eq!(remap("14*"), Outcome::Rejected);
+    // `#` without a usage, and a fourth digit
+    assert_eq!(remap("1#"), Outcome::Rejected);
+    assert_eq!(remap("11234"), Outcome::Rejected);
+}
+
+#[test]
+fn rejects_usages_that_arent_keys() {
+    for usage in ["1", "2", "3", "256", "999"] {
+        assert_eq!(remap(&format!("1{usage}#")), Outcome::Rejected, "{usage}");
+    }
+}
+
+#[test]
+fn ends_after_the_outcome() {
+    let mut remap = Remap::new();
+    press(&mut remap, "14#");
+    assert_eq!(press(&mut remap, "15#"), [Outcome::Rejected; 3]);
+    let mut remap = Remap::new();
+    press(&mut remap, "1*");
+    assert_eq!(press(&mut remap, "A"), [Outcome::Rejected]);
+}
+
+#[test]
+fn checks_usages() {
+    assert_eq!(keycode_of(0), Some(0));
+    assert_eq!(keycode_of(1), None);
+    assert_eq!(keycode_of(0x04), Some(0x04));
+    assert_eq!(keycode_of(0xE0), Some(0xE0));
+    assert_eq!(keycode_of(0x100), None);
+}
@@ -0,0 +1,189 @@
+mod common;
+
+use common::{MockFlash, power};
+use keypad_logic::settings_store::{self, Error, MAX_VALUE_SIZE, SECTORS_OFFSET, Settings};
+
+/// Small sectors, so a few values fill one.
+const SECTOR_SIZE: usize = 2048;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Key {
+    Small,
+    Large,
+    Other,
+    Spare,
+}
+
+impl settings_store::Key for Key {
+    const ALL: &'static [Key] = &[Key::Small, Key::Large, Key::Other, Key::Spare];
+
+    fn id(self) -> u8 {
+        match self {
+            Key::Small => 1,
+            Key::Large => 2,
+            Key::Other => 3,
+            Key::Spare => 4,
+        }
+    }
+}
+
+/// Flash with the two sectors.
+type Flash = MockFlash<SECTOR_SIZE>;
+
+fn blank_flash() -> Flash {
+    MockFlash::new(SECTORS_OFFSET as usize + 2 * SECTOR_SIZE, &power(None))
+}
+
+fn open(flash: Flash) -> Settings<Flash, Key> {
+    Settings::new(flash).unwrap()
+}
+
+fn read(settings: &mut Settings<Flash, Key>, key: Key) -> Option<Vec<u8>> {
+    let mut buf = [0; MAX_VALUE_SIZE];
+    let len = settings.read(key, 1, &mut buf).unwrap()?;
+    Some(buf[..len].to_vec())
+}
+
+fn value(fill: u8, len: usize) -> Vec<u8> {
+    (0..len).map(|i| fill.wrapping_add(i as u8)).collect()
+}
+
+#[test]
+fn starts_empty() {
+    let mut settings = open(blank_flash());
+    assert_eq!(read(&mut settings, Key::Small), None);
+}
+
+#[test]
+fn reads_the_last_value_written() {
+    let mut settings = open(blank_flash());
+    settings.write(Key::Small, 1, &[1, 2, 3]).unwrap();
+    settings.write(Key::Other, 1, &[9]).unwrap();
+    settings.write(Key::Small, 1, &[4, 5, 6, 7, 8]).unwrap();
+    assert_eq!(read(&mut settings, Key::Small), Some(vec![4, 5, 6, 7, 8]));
+    assert_eq!(read(&mut settings, Key::Other), Some(vec![9]));
+    assert_eq!(read(&mut settings, Key::Large), None);
+}
+
+#[test]
+fn ignores_other_versions() {
+    let mut settings = open(blank_flash());
+    settings.write(Key::Small, 2, &[1]).unwrap();
+    assert_eq!(read(&mut settings, Key::Small), None);
+}
+
+#[test]
+fn rejects_values_too_large() {
+    let mut settings = open(blank_flash());
+    let result = settings.write(Key::Large, 1, &value(0, MAX_VALUE_SIZE + 1));
+    assert!(matches!(result, Err(Error::TooLarge)));
+}
+
+#[test]
+fn keeps_values_across_restarts_and_compactions() {
+    let mut flash = blank_flash();
+    for round in 0..40 {
+        let mut settings = open(flash);
+        settings.write(Key::Large, 1, &value(round, 300)).unwrap();
+        settings.write(Key::Small, 1, &[round]).unwrap();
+        let mut settings = open(settings.into_flash().restarted(&power(None)));
+        assert_eq!(read(&mut settings, Key::Large), Some(value(round, 300)));
+        assert_eq!(read(&mut settings, Key::Small), Some(vec![round]));
+        flash = settings.into_flash();
+    }
+}
+
+#[test]
+fn reports_full_when_the_settings_dont_fit_a_sector() {
+    let mut settings = open(blank_flash());
+    settings.write(Key::Large, 1, &value(1, 512)).unwrap();
+    settings.write(Key::Other, 1, &value(2, 512)).unwrap();
+    settings.write(Key::Spare, 1, &value(3, 512)).unwrap();
+    // Four values of 512 bytes don't fit a sector
+    let result = settings.write(Key::Small, 1, &value(4, 512));
+    assert!(matches!(result, Err(Error::Full)));
+    assert_eq!(read(&mut settings, Key::Small), None);
+    assert_eq!(read(&mut settings, Key::Large), Some(value(1, 512)));
+    assert_eq!(read(&mut settings, Key::Spare), Some(value(3, 512)));
+    // Still takes values that fit
+    settings.write(Key::Small, 1, &[5]).unwrap();
+    assert_eq!(read(&mut settings, Key::Small), Some(vec![5]));
+}
+
+/// Cuts the power after every flash operation of a write that compacts the
+/// log, and checks that after a restart every key has either its old or its
+/// new value, and the store still works.
+#[test]
+fn survives_a_power_cut_at_any_point() {
+    // Fills the active sector, so the next write compacts
+    let mut flash = blank_flash();
+    let mut settings = open(flash.restarted(&power(None)));
+    settings.write(Key::Other, 1, &[0xAA; 20]).unwrap();
+    flash = settings.into_flash();
+    let mut round = 0;
+    let needs_compaction = loop {
+        let mut settings = open(flash.restarted(&power(None)));
+        settings.write(Key::Large, 1, &value(round, 400)).unwrap();
+        let written = settings.into_flash();
+        if active_sector(&written) != active_sector(&flash) {
+            break flash;
+        }
+        flash = written;
+        round += 1;
+    };
+    let old_large = value(round.wrapping_sub(1), 400);
+    let new_large = value(round, 400);
+
+    let mut cut_after = 0;
+    loop {
+        let flash = needs_compaction.restarted(&power(Some(cut_after)));
+        let mut settings = open(flash);
+        let result = settings.write(Key::Large, 1, &new_large);
+        let flash = settings.into_flash();
+
+        let mut settings = open(flash.restarted(&power(None)));
+        let large = read(&mut settings, Key::Large);
+        assert!(
+            large == Some(old_large.clone()) || large == Some(new_large.clone()),
+            "lost the written value with the power cut after {cut_after} operations"
+        );
+        assert_eq!(
+            read(&mut settings, Key::Other),
+            Some(vec![0xAA; 20]),
+            "lost another value with the power cut after {cut_after} operations"
+        );
+        if result.is_ok() {
+            assert_eq!(large, Some(new_large.clone()));
+        }
+        // The next writes work, whatever was left behind
+        for round in 0..8 {
+            settings.write(Key::Small, 1, &value(round, 200)).unwrap();
+        }
+        assert_eq!(read(&mut settings, Key::Small), Some(value(7, 200)));
+        assert_eq!(read(&mut settings, Key::Other), Some(vec![0xAA; 20]));
+
+        if result.is_ok() {
+            break;
+        }
+        cut_after += 1;
+    }
+    // Copying, writing the value and the header, and erasing take many
+    // operations, all of which were cut
+    assert!(cut_after > 100);
+}
+
+/// The sector with the newest log.
+fn active_sector(flash: &Flash) -> Option<usize> {
+    let sequence = |sector: usize| {
+        let start = SECTORS_OFFSET as usize + sector * SECTOR_SIZE;
+        let header = &flash.memory[start..start + 8];
+        (header[..4] == 0x4B50_5331u32.to_le_bytes())
+            .then(|| u32::from_le_bytes(header[4..8].try_into().unwrap()))
+    };
+    match (sequence(0), sequence(1)) {
+        (Some(first), Some(second)) => Some(if second > first { 1 } else { 0 }),
+        (Some(_), None) => Some(0),
+        (None, Some(_)) => Some(1),
+        (None, None) => None,
+    }
+}
@@ -0,0 +1,122 @@
+use keypad_logic::watchdog::{ResetCause, ResetFlags, Rounds, Task, retained};
+
+const USB: u16 = Task::Usb.bit();
+const KEYS: u16 = Task::Keys.bit();
+const SETTINGS: u16 = Task::Settings.bit();
+
+#[test]
+fn tasks_checking_in_never_stall() {
+    let mut rounds = Rounds::new();
+    for _ in 0..10 {
+        assert_eq!(rounds.end(KEYS | SETTINGS, KEYS | SETTINGS, 0), 0);
+    }
+}
+
+#[test]
+fn a_task_stalls_after_two_rounds_without_checking_in() {
+    let mut rounds = Rounds::new();
+    assert_eq!(rounds.end(KEYS | SETTINGS, KEYS | SETTINGS, 0), 0);
+    assert_eq!(rounds.end(KEYS | SETTINGS, SETTINGS, 0), 0);
+    assert_eq!(rounds.end(KEYS | SETTINGS, SETTINGS, 0), KEYS);
+    assert_eq!(rounds.end(KEYS | SETTINGS, SETTINGS, 0), KEYS);
+}
+
+#[test]
+fn one_missed_round_is_forgiven() {
+    let mut rounds = Rounds::new();
+    for _ in 0..5 {
+        assert_eq!(rounds.end(KEYS, 0, 0), 0);
+        assert_eq!(rounds.end(KEYS, KEYS, 0), 0);
+    }
+}
+
+#[test]
+fn unsupervised_tasks_are_ignored() {
+    let mut rounds = Rounds::new();
+    for _ in 0..3 {
+        assert_eq!(rounds.end(KEYS, KEYS, 0), 0);
+    }
+}
+
+#[test]
+fn an_idle_task_needs_no_check_ins() {
+    let mut rounds = Rounds::new();
+    for _ in 0..10 {
+        assert_eq!(rounds.end(USB | KEYS, KEYS, USB), 0);
+    }
+}
+
+#[test]
+fn an_idle_task_stalls_when_work_waits_for_two_rounds() {
+    let mut rounds = Rounds::new();
+    assert_eq!(rounds.end(USB, 0, USB), 0);
+    // A bus event came up just before the round ended
+    assert_eq!(rounds.end(USB, 0, 0), 0);
+    // and the stack handled it
+    assert_eq!(rounds.end(USB, USB, 0), 0);
+    assert_eq!(rounds.end(USB, 0, USB), 0);
+    // Another one that is never handled
+    assert_eq!(rounds.end(USB, 0, 0), 0);
+    assert_eq!(rounds.end(USB, 0, 0), USB);
+}
+
+#[test]
+fn the_reset_flags_give_the_cause() {
+    let none = [0; 2];
+    let flags = ResetFlags::default();
+    assert_eq!(ResetCause::new(flags, none), ResetCause::Pin);
+    let power_on = ResetFlags {
+        power_on: true,
+        brownout: true,
+        ..flags
+    };
+    assert_eq!(ResetCause::new(power_on, none), ResetCause::PowerOn);
+    let brownout = ResetFlags {
+        brownout: true,
+        ..flags
+    };
+    assert_eq!(ResetCause::new(brownout, none), ResetCause::Brownout);
+    let software = ResetFlags {
+        software: true,
+        ..flags
+    };
+    assert_eq!(ResetCause::new(software, none), ResetCause::Software);
+    let low_power = ResetFlags {
+        low_power: true,
+        ..flags
+    };
+    assert_eq!(ResetCause::new(low_power, none), ResetCause::LowPower);
+}
+
+#[test]
+fn a_watchdog_reset_names_the_stalled_tasks() {
+    let flags = ResetFlags {
+        watchdog: true,
+        ..ResetFlags::default()
+    };
+    let cause = ResetCause::new(flags, retained(USB | SETTINGS));
+    assert_eq!(cause, ResetCause::Watchdog(USB | SETTINGS));
+    let stalled: Vec<_> = cause.stalled().collect();
+    assert_eq!(stalled, [Task::Usb, Task::Settings]);
+    assert_eq!(cause.name(), "watchdog");
+}
+
+#[test]
+fn random_ram_holds_no_stalled_tasks() {
+    let flags = ResetFlags {
+        watchdog: true,
+        ..ResetFlags::default()
+    };
+    let cause = ResetCause::new(flags, [0xDEAD_BEEF, 0xFFFF]);
+    assert_eq!(cause, ResetCause::Watchdog(0));
+    assert_eq!(cause.stalled().count(), 0);
+}
+
+#[test]
+fn other_resets_have_no_stalled_tasks() {
+    let flags = ResetFlags {
+        software: true,
+        ..ResetFlags::default()
+    };
+    assert_eq!(ResetCause::new(flags, retained(KEYS)).stalled().count(), 0);
+}
@@ -0,0 +1,15 @@
+MEMORY
+{
+  /* NOTE K = KiBi = 1024 bytes */
+  /* The active partition of the A/B updates, see `keypad-logic/src/boot.rs`.
+     Sector 0 holds the bootloader, sectors 1 and 2 the settings, sector 4
+     the state of the updates, and the DFU partition follows. */
+  FLASH : ORIGIN = 0x08020000, LENGTH = 256K
+  /* SRAM1 and SRAM2 of the STM32F407, one after the other. */
+  RAM : ORIGIN = 0x20000000, LENGTH = 128K
+}
+
+/* This is where the call stack will be allocated. */
+/* The stack is of the full descending type. */
+/* NOTE Do NOT modify `_stack_start` unless you know what you are doing */
+_stack_start = ORIGIN(RAM) + LENGTH(RAM);
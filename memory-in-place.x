@@ -0,0 +1,19 @@
+MEMORY
+{
+  /* NOTE K = KiBi = 1024 bytes */
+  /* For the boards without A/B updates, see `memory-ab.x`. 256K is the
+     smallest flash of their chips and 64K, the STM32F401CC's, the smallest
+     RAM. */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 256K
+  RAM : ORIGIN = 0x20000000, LENGTH = 64K
+}
+
+/* Sectors 1 and 2 (0x08004000-0x0800BFFF) hold the settings, see
+   `keypad-logic/src/settings_store.rs`, so the code starts after them.
+   Sector 0 keeps the vector table, where the chip boots from. */
+_stext = ORIGIN(FLASH) + 48K;
+
+/* This is where the call stack will be allocated. */
+/* The stack is of the full descending type. */
+/* NOTE Do NOT modify `_stack_start` unless you know what you are doing */
+_stack_start = ORIGIN(RAM) + LENGTH(RAM);
@@ -1,42 +1,82 @@
+//! What the firmware needs from a board, and the boards it supports.
+//!
+//! Everything above this module only sees a [`Board`]: the keypad pins, the
+//! USB peripheral and the LEDs. A board sets up the clocks and the LEDs, takes
+//! the keypad pins `keypad.toml` has for it, and is chosen with a `board-*`
+//! cargo feature.
+
+use crate::keypad::{COLUMNS, ROWS};
 use embassy_stm32::exti::ExtiInput;
-use embassy_stm32::gpio::{Input, Level, Output, Pull, Speed};
-use embassy_stm32::peripherals::{PA11, PA12, USB_OTG_FS};
-use embassy_stm32::{Peripherals, bind_interrupts, usb};
+use embassy_stm32::gpio::{Input, Output};
+use embassy_stm32::peripherals::{FLASH, IWDG, PA11, PA12, TIM3, USB_OTG_FS};
+use embassy_stm32::{Config, Peripherals, bind_interrupts, usb};
+
+#[cfg(feature = "board-stm32f401-blackpill")]
+pub use crate::board_stm32f401_blackpill::Stm32f401BlackPill as SelectedBoard;
+#[cfg(feature = "board-stm32f407-discovery")]
+pub use crate::board_stm32f407_discovery::Stm32f407Discovery as SelectedBoard;
+#[cfg(feature = "board-stm32f411-blackpill")]
+pub use crate::board_stm32f411_blackpill::Stm32f411BlackPill as SelectedBoard;
+
+#[cfg(not(any(
+    feature = "board-stm32f401-blackpill",
+    feature = "board-stm32f407-discovery",
+    feature = "board-stm32f411-blackpill"
+)))]
+compile_error!("Pick a board with one of the `board-*` features");
 
 bind_interrupts!(pub struct Irqs {
     OTG_FS => usb::InterruptHandler<USB_OTG_FS>;
 });
 
+/// A board the keypad firmware runs on.
+///
+/// The keypad interrupt line has to be PB1 on every board, as the IR LED on
+/// it is driven by TIM3 channel 4, see [`crate::ir`].
+pub trait BoardSupport {
+    const NAME: &'static str;
+
+    /// The clock setup, which has to give the USB peripheral 48 MHz, see
+    /// [`ClockPlan`](crate::clock_plan::ClockPlan).
+    fn config() -> Config;
+
+    fn board(peripherals: Peripherals) -> Board;
+}
+
 pub struct Board {
     pub usb_peripheral: USB_OTG_FS,
     pub usb_interrupt: Irqs,
     pub usb_d_plus: PA12,
     pub usb_d_minus: PA11,
-    pub keypad_rows: [Input<'static>; 4],
-    pub keypad_columns: [Output<'static>; 4],
+    /// Wired as set in `keypad.toml`.
+    pub keypad_rows: [Input<'static>; ROWS],
+    pub keypad_columns: [Output<'static>; COLUMNS],
     pub keypad_interrupt: ExtiInput<'static>,
+    /// Makes the IR carrier on the keypad interrupt line, see [`crate::ir`].
+    pub ir_timer: TIM3,
+    /// Holds the settings, see [`keypad_logic::settings_store`].
+    pub flash: FLASH,
+    /// Restarts the keypad if a task hangs, see [`crate::watchdog`].
+    pub watchdog: IWDG,
+    /// LEDs for num lock, caps lock, scroll lock and compose or kana, where
+    /// the board has them.
+    pub leds: [Option<Led>; 4],
+}
+
+/// A board LED, lit by whichever level the board needs.
+pub struct Led {
+    pin: Output<'static>,
+    active_low: bool,
 }
 
-impl Board {
-    pub fn new(peripherals: Peripherals) -> Self {
-        Self {
-            usb_peripheral: peripherals.USB_OTG_FS,
-            usb_interrupt: Irqs,
-            usb_d_plus: peripherals.PA12,
-            usb_d_minus: peripherals.PA11,
-            keypad_rows: [
-                Input::new(peripherals.PA0, Pull::Down),
-                Input::new(peripherals.PA1, Pull::Down),
-                Input::new(peripherals.PA2, Pull::Down),
-                Input::new(peripherals.PA3, Pull::Down),
-            ],
-            keypad_columns: [
-                Output::new(peripherals.PA4, Level::High, Speed::Low),
-                Output::new(peripherals.PA5, Level::High, Speed::Low),
-                Output::new(peripherals.PC4, Level::High, Speed::Low),
-                Output::new(peripherals.PA7, Level::High, Speed::Low),
-            ],
-            keypad_interrupt: ExtiInput::new(peripherals.PB1, peripherals.EXTI1, Pull::Down),
-        }
+impl Led {
+    pub fn new(pin: Output<'static>, active_low: bool) -> Self {
+        let mut led = Self { pin, active_low };
+        led.set(false);
+        led
+    }
+
+    pub fn set(&mut self, lit: bool) {
+        self.pin.set_level((lit != self.active_low).into());
     }
 }
@@ -0,0 +1,57 @@
+//! The WeAct STM32F401CC "Black Pill".
+
+use crate::board_pinout::{Board, BoardSupport, Irqs, Led};
+use crate::clock_plan::{ClockPlan, STM32F401};
+use crate::keypad_config::keypad_pins;
+use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::gpio::{Level, Output, Pull, Speed};
+use embassy_stm32::rcc::HseMode;
+use embassy_stm32::time::Hertz;
+use embassy_stm32::{Config, Peripherals};
+
+/// The 25 MHz crystal, for the full 84 MHz.
+const CLOCKS: ClockPlan = ClockPlan::new(
+    Hertz::mhz(25),
+    HseMode::Oscillator,
+    Hertz::mhz(84),
+    &STM32F401,
+);
+
+pub struct Stm32f401BlackPill;
+
+impl BoardSupport for Stm32f401BlackPill {
+    const NAME: &'static str = "STM32F401 Black Pill";
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        CLOCKS.apply(&mut config);
+
+        config
+    }
+
+    fn board(peripherals: Peripherals) -> Board {
+        let (keypad_rows, keypad_columns) = keypad_pins!(peripherals);
+        Board {
+            usb_peripheral: peripherals.USB_OTG_FS,
+            usb_interrupt: Irqs,
+            usb_d_plus: peripherals.PA12,
+            usb_d_minus: peripherals.PA11,
+            keypad_rows,
+            keypad_columns,
+            keypad_interrupt: ExtiInput::new(peripherals.PB1, peripherals.EXTI1, Pull::Down),
+            ir_timer: peripherals.TIM3,
+            flash: peripherals.FLASH,
+            watchdog: peripherals.IWDG,
+            // The only user LED, on PC13, shows caps lock
+            leds: [
+                None,
+                Some(Led::new(
+                    Output::new(peripherals.PC13, Level::High, Speed::Low),
+                    true,
+                )),
+                None,
+                None,
+            ],
+        }
+    }
+}
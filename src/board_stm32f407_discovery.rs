@@ -0,0 +1,63 @@
+//! The STM32F407G-DISC1 board the schematic in `hardware` is drawn for.
+
+use crate::board_pinout::{Board, BoardSupport, Irqs, Led};
+use crate::clock_plan::{ClockPlan, STM32F407};
+use crate::keypad_config::keypad_pins;
+use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::gpio::{Level, Output, Pull, Speed};
+use embassy_stm32::rcc::{HseMode, mux};
+use embassy_stm32::time::Hertz;
+use embassy_stm32::{Config, Peripherals};
+
+/// 8 MHz from the ST-LINK's MCO, for 168 MHz.
+const CLOCKS: ClockPlan =
+    ClockPlan::new(Hertz::mhz(8), HseMode::Bypass, Hertz::mhz(168), &STM32F407);
+
+pub struct Stm32f407Discovery;
+
+impl BoardSupport for Stm32f407Discovery {
+    const NAME: &'static str = "STM32F407G-DISC1";
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        CLOCKS.apply(&mut config);
+        config.rcc.mux.clk48sel = mux::Clk48sel::PLL1_Q;
+
+        config
+    }
+
+    fn board(peripherals: Peripherals) -> Board {
+        let (keypad_rows, keypad_columns) = keypad_pins!(peripherals);
+        Board {
+            usb_peripheral: peripherals.USB_OTG_FS,
+            usb_interrupt: Irqs,
+            usb_d_plus: peripherals.PA12,
+            usb_d_minus: peripherals.PA11,
+            keypad_rows,
+            keypad_columns,
+            keypad_interrupt: ExtiInput::new(peripherals.PB1, peripherals.EXTI1, Pull::Down),
+            ir_timer: peripherals.TIM3,
+            flash: peripherals.FLASH,
+            watchdog: peripherals.IWDG,
+            // Green, orange, red and blue
+            leds: [
+                Some(Led::new(
+                    Output::new(peripherals.PD12, Level::Low, Speed::Low),
+                    false,
+                )),
+                Some(Led::new(
+                    Output::new(peripherals.PD13, Level::Low, Speed::Low),
+                    false,
+                )),
+                Some(Led::new(
+                    Output::new(peripherals.PD14, Level::Low, Speed::Low),
+                    false,
+                )),
+                Some(Led::new(
+                    Output::new(peripherals.PD15, Level::Low, Speed::Low),
+                    false,
+                )),
+            ],
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! Applies the PLL dividers [`keypad_logic::clock_plan`] works out for a
+//! board's HSE, so USB gets exactly 48 MHz.
+//!
+//! [`ClockPlan::new`] runs in a `const`, so a board whose clocks can't make
+//! 48 MHz fails to build instead of failing to enumerate.
+
+use embassy_stm32::Config;
+use embassy_stm32::rcc::{
+    AHBPrescaler, APBPrescaler, Hse, HseMode, Pll, PllMul, PllPDiv, PllPreDiv, PllQDiv, PllSource,
+    Sysclk,
+};
+use embassy_stm32::time::Hertz;
+use keypad_logic::clock_plan::{self, Chip, Dividers};
+
+#[cfg(feature = "board-stm32f401-blackpill")]
+pub use keypad_logic::clock_plan::STM32F401;
+#[cfg(feature = "board-stm32f407-discovery")]
+pub use keypad_logic::clock_plan::STM32F407;
+#[cfg(feature = "board-stm32f411-blackpill")]
+pub use keypad_logic::clock_plan::STM32F411;
+
+/// The HSE and the dividers that run the system clock from it.
+#[derive(Clone, Copy)]
+pub struct ClockPlan {
+    hse: Hertz,
+    hse_mode: HseMode,
+    dividers: Dividers,
+}
+
+impl ClockPlan {
+    /// Plans the clocks, failing the build when used in a `const` and no
+    /// plan exists.
+    pub const fn new(hse: Hertz, hse_mode: HseMode, sysclk: Hertz, chip: &Chip) -> Self {
+        let mode = match hse_mode {
+            HseMode::Oscillator => clock_plan::HseMode::Oscillator,
+            HseMode::Bypass => clock_plan::HseMode::Bypass,
+        };
+        match clock_plan::solve(hse.0, mode, sysclk.0, chip) {
+            Ok(dividers) => Self {
+                hse,
+                hse_mode,
+                dividers,
+            },
+            Err(error) => panic!("{}", error.message()),
+        }
+    }
+
+    /// Sets up the HSE, the PLL and the bus prescalers in `config`. Chips
+    /// with a CLK48 mux still have to point it at the PLL's Q output.
+    pub fn apply(&self, config: &mut Config) {
+        let Dividers {
+            m,
+            n,
+            p,
+            q,
+            apb1_div,
+            apb2_div,
+        } = self.dividers;
+        config.rcc.hse = Some(Hse {
+            freq: self.hse,
+            mode: self.hse_mode,
+        });
+        config.rcc.pll_src = PllSource::HSE;
+        config.rcc.pll = Some(Pll {
+            prediv: PllPreDiv::from_bits(m as u8),
+            mul: PllMul::from_bits(n as u16),
+            divp: Some(PllPDiv::from_bits((p / 2 - 1) as u8)),
+            divq: Some(PllQDiv::from_bits(q as u8)),
+            divr: None,
+        });
+        config.rcc.ahb_pre = AHBPrescaler::DIV1;
+        config.rcc.apb1_pre = apb_prescaler(apb1_div);
+        config.rcc.apb2_pre = apb_prescaler(apb2_div);
+        config.rcc.sys = Sysclk::PLL1_P;
+    }
+}
+
+fn apb_prescaler(div: u32) -> APBPrescaler {
+    match div {
+        1 => APBPrescaler::DIV1,
+        2 => APBPrescaler::DIV2,
+        4 => APBPrescaler::DIV4,
+        8 => APBPrescaler::DIV8,
+        _ => APBPrescaler::DIV16,
+    }
+}
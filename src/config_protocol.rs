@@ -0,0 +1,153 @@
+//! Binary configuration protocol carried in the 32-byte reports of the vendor
+//! defined HID interface.
+//!
+//! Every output report is one request, `[command, arguments...]`, and is
+//! answered by one input report, `[command, status, results...]`. Multi-byte
+//! values are little endian. Hosts should check the protocol version with
+//! [`GET_INFO`] before using any other command.
+//!
+//! | Command            | Arguments          | Results                                            |
+//! |--------------------|--------------------|----------------------------------------------------|
+//! | [`GET_INFO`]       |                    | protocol version, firmware major, minor, patch, mode, key count |
+//! | [`GET_KEY`]        | layer, key         | layer, key, keycode (u16)                          |
+//! | [`SET_KEY`]        | layer, key, keycode (u16) | layer, key, keycode (u16)                   |
+//! | [`GET_SETTING`]    | setting            | setting, value (u32)                               |
+//! | [`SET_SETTING`]    | setting, value (u32) | setting, value (u32)                             |
+//! | [`ENTER_BOOTLOADER`] |                  |                                                    |
+//!
+//! Keys are numbered in [`Keypad::scan`] order, keycodes are those of the
+//! [`Keymap`] and settings are numbered as in [`Setting`]. Version 1 had no
+//! layers and 8-bit usages in [`GET_KEY`] and [`SET_KEY`].
+//!
+//! The VIA commands share the interface, see [`via`](crate::via).
+//!
+//! [`Keypad::scan`]: crate::keypad::Keypad::scan
+//! [`Keymap`]: crate::keymap::Keymap
+
+use crate::device_mode::DeviceMode;
+use crate::keymap::{self, LAYERS};
+use crate::keypad::KEY_LABELS;
+use crate::settings::Setting;
+use crate::via;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+
+pub const REPORT_SIZE: usize = 32;
+pub const PROTOCOL_VERSION: u8 = 2;
+
+// Command ids start at 0x40 to stay clear of the ids VIA uses on the same
+// usage page
+pub const GET_INFO: u8 = 0x40;
+pub const GET_KEY: u8 = 0x41;
+pub const SET_KEY: u8 = 0x42;
+pub const GET_SETTING: u8 = 0x43;
+pub const SET_SETTING: u8 = 0x44;
+/// Restarts into the bootloader after the reply has been sent.
+pub const ENTER_BOOTLOADER: u8 = 0x45;
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNKNOWN_COMMAND: u8 = 1;
+const STATUS_INVALID_ARGUMENT: u8 = 2;
+
+/// Replies waiting to be sent on the interrupt IN endpoint.
+pub static REPLIES: Channel<CriticalSectionRawMutex, Reply, 4> = Channel::new();
+
+pub struct Reply {
+    pub report: [u8; REPORT_SIZE],
+    pub enter_bootloader: bool,
+}
+
+/// Answers a request of this protocol or of [`via`], which share the
+/// configuration interfaces.
+pub fn dispatch(request: &[u8], mode: DeviceMode) -> Reply {
+    match request.first() {
+        Some(GET_INFO..=ENTER_BOOTLOADER) => handle(request, mode),
+        _ => via::handle(request),
+    }
+}
+
+pub fn handle(request: &[u8], mode: DeviceMode) -> Reply {
+    let (&command, arguments) = request.split_first().unwrap_or((&0, &[]));
+    let mut report = [0; REPORT_SIZE];
+    let results = &mut report[2..];
+
+    let status = match command {
+        GET_INFO => get_info(mode, results),
+        GET_KEY => get_key(arguments, results),
+        SET_KEY => set_key(arguments, results),
+        GET_SETTING => get_setting(arguments, results),
+        SET_SETTING => set_setting(arguments, results),
+        ENTER_BOOTLOADER => Ok(()),
+        _ => Err(STATUS_UNKNOWN_COMMAND),
+    };
+
+    report[0] = command;
+    report[1] = status.err().unwrap_or(STATUS_OK);
+    Reply {
+        report,
+        enter_bootloader: command == ENTER_BOOTLOADER,
+    }
+}
+
+fn get_info(mode: DeviceMode, results: &mut [u8]) -> Result<(), u8> {
+    let version = |part: &str| part.parse().unwrap_or(0);
+    results[..6].copy_from_slice(&[
+        PROTOCOL_VERSION,
+        version(env!("CARGO_PKG_VERSION_MAJOR")),
+        version(env!("CARGO_PKG_VERSION_MINOR")),
+        version(env!("CARGO_PKG_VERSION_PATCH")),
+        mode as u8,
+        KEY_LABELS.len() as u8,
+    ]);
+    Ok(())
+}
+
+fn get_key(arguments: &[u8], results: &mut [u8]) -> Result<(), u8> {
+    let &[layer, key, ..] = arguments else {
+        return Err(STATUS_INVALID_ARGUMENT);
+    };
+    check_key(layer, key)?;
+    let keycode = keymap::keycode(layer as usize, key as usize).to_le_bytes();
+    results[..4].copy_from_slice(&[layer, key, keycode[0], keycode[1]]);
+    Ok(())
+}
+
+fn set_key(arguments: &[u8], results: &mut [u8]) -> Result<(), u8> {
+    let &[layer, key, low, high, ..] = arguments else {
+        return Err(STATUS_INVALID_ARGUMENT);
+    };
+    check_key(layer, key)?;
+    keymap::set_keycode(
+        layer as usize,
+        key as usize,
+        u16::from_le_bytes([low, high]),
+    );
+    get_key(arguments, results)
+}
+
+fn check_key(layer: u8, key: u8) -> Result<(), u8> {
+    if (layer as usize) < LAYERS && (key as usize) < KEY_LABELS.len() {
+        Ok(())
+    } else {
+        Err(STATUS_INVALID_ARGUMENT)
+    }
+}
+
+fn get_setting(arguments: &[u8], results: &mut [u8]) -> Result<(), u8> {
+    let &[id, ..] = arguments else {
+        return Err(STATUS_INVALID_ARGUMENT);
+    };
+    let setting = Setting::from_id(id).ok_or(STATUS_INVALID_ARGUMENT)?;
+    results[0] = id;
+    results[1..5].copy_from_slice(&setting.get().to_le_bytes());
+    Ok(())
+}
+
+fn set_setting(arguments: &[u8], results: &mut [u8]) -> Result<(), u8> {
+    let &[id, a, b, c, d, ..] = arguments else {
+        return Err(STATUS_INVALID_ARGUMENT);
+    };
+    let setting = Setting::from_id(id).ok_or(STATUS_INVALID_ARGUMENT)?;
+    setting.set(u32::from_le_bytes([a, b, c, d]));
+    get_setting(arguments, results)
+}
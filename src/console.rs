@@ -0,0 +1,452 @@
+use crate::device_mode::DeviceMode;
+use crate::ir::{self, IR_CODE_COUNT};
+use crate::keymap::{self, LAYERS};
+use crate::keypad::{COLUMNS, KEY_LABELS, LAST_SCAN, ROWS, key_index};
+use crate::profile::{self, PROFILE_COUNT};
+use crate::reboot;
+use crate::settings::{DEBOUNCE_MS, MAX_DEBOUNCE_MS, QueuePolicy, REMOTE_WAKEUP};
+use crate::usb_identity::{self, Field, UsbIdentity};
+use crate::watchdog::{self, Task};
+use core::fmt::Write;
+use core::sync::atomic::Ordering;
+use defmt::{info, warn};
+use embassy_stm32::peripherals::USB_OTG_FS;
+use embassy_stm32::usb::Driver;
+use embassy_time::{TimeoutError, Timer, with_timeout};
+use embassy_usb::class::cdc_acm::CdcAcmClass;
+use embassy_usb::driver::EndpointError;
+use heapless::String;
+
+const PROMPT: &str = "> ";
+/// Longest command line, room for a Pronto code.
+const LINE_SIZE: usize = 512;
+/// Room for the reply to one command, the longest being the help text.
+const OUTPUT_SIZE: usize = 1152;
+const HELP: &str = "Commands:\r
+  keymap [layer]               show a keymap layer\r
+  set <key> <keycode> [layer]  map a key to a keycode (decimal or 0x hex)\r
+  matrix                       show the keys held in the last scan\r
+  debounce [ms]                show or change the debounce delay\r
+  wakeup [on|off]              show or change whether keys wake the host\r
+  queue [replay|drop]          replay or drop keys pressed before setup\r
+  mode [<mode>]                show or change the mode started without a\r
+                               mode key: keyboard, gamepad, midi or phone\r
+  profile [<n>|name <name>]    list, switch or rename the profiles\r
+  ir [<n> [<code>|none]]       show or change the IR codes, e.g.\r
+                               'ir 0 nec 0x04 0x08' or 'ir 1 pronto ...'\r
+  usb [<field> <value>]        show or change the USB identity: vid, pid,\r
+                               manufacturer, product, serial ('uid' for the\r
+                               chip's unique ID) or 'default'\r
+  version                      show the firmware version and last reset\r
+  reboot                       restart the device\r
+";
+
+/// Line based command shell on the CDC-ACM interface, for use with any
+/// terminal program, e.g. `screen /dev/ttyACM0`.
+pub struct Console<'a> {
+    class: CdcAcmClass<'a, Driver<'a, USB_OTG_FS>>,
+    mode: DeviceMode,
+    line: String<LINE_SIZE>,
+    last_byte: u8,
+}
+
+enum Outcome {
+    Done,
+    Reboot,
+}
+
+impl<'a> Console<'a> {
+    pub fn new(class: CdcAcmClass<'a, Driver<'a, USB_OTG_FS>>, mode: DeviceMode) -> Self {
+        Self {
+            class,
+            mode,
+            line: String::new(),
+            last_byte: 0,
+        }
+    }
+
+    pub async fn run(&mut self) -> ! {
+        loop {
+            watchdog::alive_while(Task::Config, self.class.wait_connection()).await;
+            info!("Serial console connected");
+            match self.serve().await {
+                Err(EndpointError::Disabled) => info!("Serial console disconnected"),
+                Err(e) => warn!("Serial console failed: {:?}", e),
+                Ok(()) => {}
+            }
+        }
+    }
+
+    async fn serve(&mut self) -> Result<(), EndpointError> {
+        self.line.clear();
+        self.write(PROMPT).await?;
+
+        let mut buf = [0; 64];
+        loop {
+            let len = watchdog::alive_while(Task::Config, self.class.read_packet(&mut buf)).await?;
+            for &byte in &buf[..len] {
+                self.input(byte).await?;
+                self.last_byte = byte;
+            }
+        }
+    }
+
+    async fn input(&mut self, byte: u8) -> Result<(), EndpointError> {
+        match byte {
+            // Terminals send CR, pipes send LF, some send both
+            b'\n' if self.last_byte == b'\r' => {}
+            b'\r' | b'\n' => {
+                self.write("\r\n").await?;
+                let mut output = String::<OUTPUT_SIZE>::new();
+                let outcome = self.execute(&mut output);
+                self.line.clear();
+                self.write(&output).await?;
+
+                if let Outcome::Reboot = outcome {
+                    // Give the host a moment to fetch the reply
+                    Timer::after_millis(50).await;
+                    reboot::reboot();
+                }
+                self.write(PROMPT).await?;
+            }
+            // Backspace and delete
+            0x08 | 0x7F if self.line.pop().is_some() => {
+                self.write("\x08 \x08").await?;
+            }
+            b' '..=b'~' if self.line.push(byte as char).is_ok() => {
+                self.send(&[byte]).await?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, text: &str) -> Result<(), EndpointError> {
+        let max_packet_size = self.class.max_packet_size() as usize;
+        for chunk in text.as_bytes().chunks(max_packet_size) {
+            if !self.send(chunk).await? {
+                return Ok(());
+            }
+        }
+        // A full last packet needs a short one after it to end the transfer
+        if !text.is_empty() && text.len().is_multiple_of(max_packet_size) {
+            self.send(&[]).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends a packet, returning whether the terminal took it. A terminal
+    /// that stopped reading doesn't hold up the console, it misses the output
+    /// instead.
+    async fn send(&mut self, packet: &[u8]) -> Result<bool, EndpointError> {
+        watchdog::check_in(Task::Config);
+        match with_timeout(watchdog::HOST_TIMEOUT, self.class.write_packet(packet)).await {
+            Ok(result) => result.map(|()| true),
+            Err(TimeoutError) => {
+                warn!("Serial console output dropped, the terminal isn't reading");
+                Ok(false)
+            }
+        }
+    }
+
+    fn execute(&self, output: &mut String<OUTPUT_SIZE>) -> Outcome {
+        let mut words = self.line.split_whitespace();
+        let result = match (words.next(), words.next(), words.next(), words.next()) {
+            (None, ..) => Ok(()),
+            (Some("help"), None, ..) => output.push_str(HELP).map_err(|_| core::fmt::Error),
+            (Some("keymap"), layer, None, _) => show_keymap(output, layer.unwrap_or("0")),
+            (Some("set"), Some(key), Some(keycode), layer) => {
+                set_key(output, key, keycode, layer.unwrap_or("0"))
+            }
+            (Some("matrix"), None, ..) => show_matrix(output),
+            (Some("debounce"), None, ..) => writeln!(
+                output,
+                "debounce: {} ms\r",
+                DEBOUNCE_MS.load(Ordering::Relaxed)
+            ),
+            (Some("debounce"), Some(ms), None, _) => set_debounce(output, ms),
+            (Some("wakeup"), None, ..) => show_wakeup(output),
+            (Some("wakeup"), Some("on"), None, _) => {
+                REMOTE_WAKEUP.store(true, Ordering::Relaxed);
+                show_wakeup(output)
+            }
+            (Some("wakeup"), Some("off"), None, _) => {
+                REMOTE_WAKEUP.store(false, Ordering::Relaxed);
+                show_wakeup(output)
+            }
+            (Some("queue"), None, ..) => show_queue_policy(output),
+            (Some("queue"), Some("replay"), None, _) => {
+                QueuePolicy::Replay.set();
+                show_queue_policy(output)
+            }
+            (Some("queue"), Some("drop"), None, _) => {
+                QueuePolicy::Drop.set();
+                show_queue_policy(output)
+            }
+            (Some("mode"), None, ..) => show_start_mode(output),
+            (Some("mode"), Some(name), None, _) => match DeviceMode::from_name(name) {
+                Some(mode) => {
+                    mode.set_start_mode();
+                    show_start_mode(output)
+                }
+                None => writeln!(output, "Unknown mode '{}'\r", name),
+            },
+            (Some("profile"), None, ..) => show_profiles(output),
+            (Some("profile"), Some("name"), Some(_), _) => {
+                profile::set_name(profile::active(), skip_words(&self.line, 2));
+                show_profiles(output)
+            }
+            (Some("profile"), Some(number), None, _) => match number.parse::<usize>() {
+                Ok(number @ 1..=PROFILE_COUNT) if profile::request_switch(number - 1) => {
+                    writeln!(output, "Switching to profile {}\r", number)
+                }
+                Ok(1..=PROFILE_COUNT) => {
+                    writeln!(output, "Can't switch profiles without the settings flash\r")
+                }
+                _ => writeln!(output, "Invalid profile '{}'\r", number),
+            },
+            (Some("ir"), None, ..) => show_ir_codes(output),
+            (Some("ir"), Some(index), ..) => set_ir_code(output, index, skip_words(&self.line, 2)),
+            (Some("usb"), None, ..) => show_usb_identity(output),
+            (Some("usb"), Some("default"), None, _) => {
+                usb_identity::set_next(&UsbIdentity::build_default());
+                show_usb_identity(output)
+            }
+            (Some("usb"), Some(field), Some(_), _) => {
+                set_usb_identity(output, field, skip_words(&self.line, 2))
+            }
+            (Some("version"), None, ..) => writeln!(
+                output,
+                "{} {} ({} mode)\r",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION"),
+                self.mode.name()
+            )
+            .and_then(|()| show_last_reset(output)),
+            (Some("reboot"), None, ..) => {
+                let _ = writeln!(output, "Rebooting\r");
+                return Outcome::Reboot;
+            }
+            _ => writeln!(output, "Unknown command, try 'help'\r"),
+        };
+        if result.is_err() {
+            warn!("Serial console output truncated");
+        }
+        Outcome::Done
+    }
+}
+
+fn show_keymap(output: &mut String<OUTPUT_SIZE>, layer: &str) -> core::fmt::Result {
+    let Some(layer) = parse_layer(layer) else {
+        return writeln!(output, "Invalid layer '{}'\r", layer);
+    };
+    let keymap = keymap::keymap();
+    for row in 0..ROWS {
+        for column in 0..COLUMNS {
+            let key = row * COLUMNS + column;
+            write!(output, "{}=0x{:04X} ", KEY_LABELS[key], keymap[layer][key])?;
+        }
+        writeln!(output, "\r")?;
+    }
+    Ok(())
+}
+
+fn set_key(
+    output: &mut String<OUTPUT_SIZE>,
+    key: &str,
+    keycode: &str,
+    layer: &str,
+) -> core::fmt::Result {
+    let mut chars = key.chars();
+    let key = match (chars.next().and_then(key_index), chars.next()) {
+        (Some(key), None) => key,
+        _ => return writeln!(output, "Unknown key '{}'\r", key),
+    };
+    let Some(layer) = parse_layer(layer) else {
+        return writeln!(output, "Invalid layer '{}'\r", layer);
+    };
+    match parse_number(keycode).and_then(|keycode| u16::try_from(keycode).ok()) {
+        Some(keycode) => {
+            keymap::set_keycode(layer, key, keycode);
+            writeln!(output, "{}=0x{:04X}\r", KEY_LABELS[key], keycode)
+        }
+        None => writeln!(output, "Invalid keycode '{}'\r", keycode),
+    }
+}
+
+fn show_matrix(output: &mut String<OUTPUT_SIZE>) -> core::fmt::Result {
+    let keys = LAST_SCAN.load(Ordering::Relaxed);
+    for row in 0..ROWS {
+        for column in 0..COLUMNS {
+            let key = row * COLUMNS + column;
+            let label = if keys & (1 << key) != 0 {
+                KEY_LABELS[key]
+            } else {
+                '.'
+            };
+            write!(output, "{} ", label)?;
+        }
+        writeln!(output, "\r")?;
+    }
+    Ok(())
+}
+
+fn set_debounce(output: &mut String<OUTPUT_SIZE>, ms: &str) -> core::fmt::Result {
+    match parse_number(ms) {
+        Some(ms) if ms <= MAX_DEBOUNCE_MS => {
+            DEBOUNCE_MS.store(ms, Ordering::Relaxed);
+            writeln!(output, "debounce: {} ms\r", ms)
+        }
+        Some(_) => writeln!(output, "The delay is at most {} ms\r", MAX_DEBOUNCE_MS),
+        None => writeln!(output, "Invalid delay '{}'\r", ms),
+    }
+}
+
+fn show_wakeup(output: &mut String<OUTPUT_SIZE>) -> core::fmt::Result {
+    let state = if REMOTE_WAKEUP.load(Ordering::Relaxed) {
+        "on"
+    } else {
+        "off"
+    };
+    writeln!(output, "wakeup: {}\r", state)
+}
+
+fn show_queue_policy(output: &mut String<OUTPUT_SIZE>) -> core::fmt::Result {
+    let policy = match QueuePolicy::get() {
+        QueuePolicy::Replay => "replay",
+        QueuePolicy::Drop => "drop",
+    };
+    writeln!(output, "queue: {}\r", policy)
+}
+
+fn show_start_mode(output: &mut String<OUTPUT_SIZE>) -> core::fmt::Result {
+    writeln!(output, "mode: {}\r", DeviceMode::start_mode().name())
+}
+
+fn show_last_reset(output: &mut String<OUTPUT_SIZE>) -> core::fmt::Result {
+    let cause = watchdog::last_reset();
+    write!(output, "last reset: {}", cause.name())?;
+    for (i, task) in cause.stalled().enumerate() {
+        let separator = if i == 0 { ", stalled: " } else { ", " };
+        write!(output, "{}{}", separator, task.name())?;
+    }
+    writeln!(output, "\r")
+}
+
+fn show_profiles(output: &mut String<OUTPUT_SIZE>) -> core::fmt::Result {
+    for index in 0..PROFILE_COUNT {
+        let marker = if index == profile::active() { '*' } else { ' ' };
+        writeln!(
+            output,
+            "{} {}: {}\r",
+            marker,
+            index + 1,
+            profile::name(index)
+        )?;
+    }
+    Ok(())
+}
+
+fn show_ir_codes(output: &mut String<OUTPUT_SIZE>) -> core::fmt::Result {
+    for index in (0..IR_CODE_COUNT).filter(|&index| ir::is_set(index)) {
+        show_ir_code(output, index)?;
+    }
+    Ok(())
+}
+
+fn show_ir_code(output: &mut String<OUTPUT_SIZE>, index: usize) -> core::fmt::Result {
+    write!(output, "{}: ", index)?;
+    ir::write_code(index, output)?;
+    writeln!(output, "\r")
+}
+
+fn set_ir_code(output: &mut String<OUTPUT_SIZE>, index: &str, code: &str) -> core::fmt::Result {
+    let Some(index) = parse_number(index)
+        .map(|index| index as usize)
+        .filter(|&index| index < IR_CODE_COUNT)
+    else {
+        return writeln!(output, "Invalid IR code number '{}'\r", index);
+    };
+    if code.is_empty() {
+        return show_ir_code(output, index);
+    }
+    match ir::set_code(index, code) {
+        Ok(()) => show_ir_code(output, index),
+        Err(e) => writeln!(output, "Invalid IR code: {}\r", e),
+    }
+}
+
+fn show_usb_identity(output: &mut String<OUTPUT_SIZE>) -> core::fmt::Result {
+    let identity = usb_identity::next();
+    writeln!(output, "vid: 0x{:04X}\r", identity.vid)?;
+    writeln!(output, "pid: 0x{:04X}\r", identity.pid)?;
+    writeln!(output, "manufacturer: {}\r", identity.manufacturer)?;
+    writeln!(output, "product: {}\r", identity.product)?;
+    match identity.serial_number {
+        Some(_) => writeln!(output, "serial: {}\r", identity.serial_number())?,
+        None => writeln!(output, "serial: {} (uid)\r", identity.serial_number())?,
+    }
+    if usb_identity::is_changed() {
+        writeln!(output, "Reboot to apply\r")?;
+    }
+    Ok(())
+}
+
+fn set_usb_identity(
+    output: &mut String<OUTPUT_SIZE>,
+    field: &str,
+    value: &str,
+) -> core::fmt::Result {
+    let mut identity = usb_identity::next();
+    let id = parse_number(value).and_then(|id| u16::try_from(id).ok());
+    let result = match (field, id) {
+        ("vid" | "pid", None) => return writeln!(output, "Invalid ID '{}'\r", value),
+        ("vid", Some(vid)) => {
+            identity.vid = vid;
+            Ok(())
+        }
+        ("pid", Some(pid)) => {
+            identity.pid = pid;
+            Ok(())
+        }
+        ("manufacturer", _) => identity.set_string(Field::Manufacturer, Some(value)),
+        ("product", _) => identity.set_string(Field::Product, Some(value)),
+        ("serial", _) if value == "uid" => identity.set_string(Field::SerialNumber, None),
+        ("serial", _) => identity.set_string(Field::SerialNumber, Some(value)),
+        _ => return writeln!(output, "Unknown field '{}', try 'help'\r", field),
+    };
+    match result {
+        Ok(()) => {
+            usb_identity::set_next(&identity);
+            show_usb_identity(output)
+        }
+        Err(()) => writeln!(
+            output,
+            "Too long, at most {} bytes\r",
+            usb_identity::STRING_LEN
+        ),
+    }
+}
+
+fn parse_layer(text: &str) -> Option<usize> {
+    parse_number(text)
+        .map(|layer| layer as usize)
+        .filter(|&layer| layer < LAYERS)
+}
+
+/// The rest of `line` after its first `count` words, spaces included.
+fn skip_words(line: &str, count: usize) -> &str {
+    let mut rest = line.trim_start();
+    for _ in 0..count {
+        let end = rest.find(' ').unwrap_or(rest.len());
+        rest = rest[end..].trim_start();
+    }
+    rest.trim_end()
+}
+
+fn parse_number(text: &str) -> Option<u32> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
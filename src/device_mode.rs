@@ -0,0 +1,111 @@
+use crate::keypad::is_held;
+use core::sync::atomic::{AtomicU8, Ordering};
+use defmt::Format;
+
+/// The mode started when no mode key is held, see [`DeviceMode::start_mode`].
+static START_MODE: AtomicU8 = AtomicU8::new(DeviceMode::Keyboard as u8);
+
+/// What the device presents itself as on the USB bus.
+///
+/// Changing the mode changes the USB descriptors, so it can only be
+/// chosen at boot, before the device enumerates.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum DeviceMode {
+    Keyboard = 0,
+    Gamepad = 1,
+    Midi = 2,
+    Telephony = 3,
+}
+
+impl DeviceMode {
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(DeviceMode::Keyboard),
+            1 => Some(DeviceMode::Gamepad),
+            2 => Some(DeviceMode::Midi),
+            3 => Some(DeviceMode::Telephony),
+            _ => None,
+        }
+    }
+
+    /// Parses a [`name`](Self::name), ignoring case.
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            DeviceMode::Keyboard,
+            DeviceMode::Gamepad,
+            DeviceMode::Midi,
+            DeviceMode::Telephony,
+        ]
+        .into_iter()
+        .find(|mode| mode.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceMode::Keyboard => "keyboard",
+            DeviceMode::Gamepad => "gamepad",
+            DeviceMode::Midi => "MIDI",
+            DeviceMode::Telephony => "phone",
+        }
+    }
+
+    /// The mode started when no mode key is held, a keyboard unless changed.
+    pub fn start_mode() -> Self {
+        Self::from_id(START_MODE.load(Ordering::Relaxed)).unwrap_or(DeviceMode::Keyboard)
+    }
+
+    pub fn set_start_mode(self) {
+        START_MODE.store(self as u8, Ordering::Relaxed);
+    }
+
+    /// Picks the mode from the keys held down while the board powers up:
+    /// `A` starts a gamepad, `B` a MIDI controller, `C` a phone keypad, and
+    /// anything else the [`start_mode`](Self::start_mode).
+    pub fn from_boot_keys(keys: u16) -> Self {
+        if is_held(keys, 'A') {
+            DeviceMode::Gamepad
+        } else if is_held(keys, 'B') {
+            DeviceMode::Midi
+        } else if is_held(keys, 'C') {
+            DeviceMode::Telephony
+        } else {
+            Self::start_mode()
+        }
+    }
+}
+
+/// The interface the device is configured through, next to the keys.
+///
+/// The USB peripheral doesn't have enough endpoints for all of them at once,
+/// so like the mode this is chosen at boot.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum ConfigChannel {
+    /// CDC-ACM command shell, see [`Console`](crate::console::Console).
+    Serial,
+    /// Vendor defined HID interface speaking the
+    /// [`config_protocol`](crate::config_protocol).
+    RawHid,
+    /// Mass storage drive holding the keymap as a text file, see
+    /// [`KeymapDrive`](crate::keymap_drive::KeymapDrive).
+    Storage,
+    /// Vendor specific bulk interface for WebUSB and libusb configurators,
+    /// see [`vendor`](crate::vendor).
+    Vendor,
+}
+
+impl ConfigChannel {
+    /// Holding `#` while the board powers up swaps the serial console for the
+    /// raw HID interface, `*` for the storage drive and `D` for the vendor
+    /// interface.
+    pub fn from_boot_keys(keys: u16) -> Self {
+        if is_held(keys, '#') {
+            ConfigChannel::RawHid
+        } else if is_held(keys, '*') {
+            ConfigChannel::Storage
+        } else if is_held(keys, 'D') {
+            ConfigChannel::Vendor
+        } else {
+            ConfigChannel::Serial
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! DFU runtime interface, through which `dfu-util` switches the running
+//! firmware to update mode.
+//!
+//! On `DFU_DETACH` the device restarts into the STM32 system bootloader, which
+//! enumerates as `0483:df11` and takes the new firmware over USB DFU. Boards
+//! with the A/B bootloader restart into update mode instead, see
+//! [`crate::update`].
+
+use core::mem::MaybeUninit;
+use defmt::info;
+use embassy_stm32::peripherals::USB_OTG_FS;
+use embassy_stm32::usb::Driver;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_usb::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use embassy_usb::types::InterfaceNumber;
+use embassy_usb::{Builder, Handler};
+
+pub const USB_CLASS_APPLICATION_SPECIFIC: u8 = 0xFE;
+pub const USB_SUBCLASS_DFU: u8 = 0x01;
+const USB_PROTOCOL_DFU_RUNTIME: u8 = 0x01;
+
+pub const DFU_DESC_DESCTYPE_FUNCTIONAL: u8 = 0x21;
+
+const DFU_REQ_DETACH: u8 = 0x00;
+pub const DFU_REQ_GETSTATUS: u8 = 0x03;
+pub const DFU_REQ_GETSTATE: u8 = 0x05;
+
+pub const DFU_ATTR_CAN_DOWNLOAD: u8 = 1 << 0;
+pub const DFU_ATTR_WILL_DETACH: u8 = 1 << 3;
+
+pub const DFU_STATUS_OK: u8 = 0x00;
+const DFU_STATE_APP_IDLE: u8 = 0x00;
+
+/// How long the host waits for the device to leave after `DFU_DETACH`.
+pub const DETACH_TIMEOUT_MS: u16 = 1000;
+
+/// The largest block the host downloads at once.
+pub const TRANSFER_SIZE: usize = 2048;
+
+/// Signalled when the host asks the device to switch to update mode. The
+/// device should restart into the bootloader once the request is answered.
+pub static DETACH_REQUESTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub struct State {
+    control: MaybeUninit<Control>,
+}
+
+impl State {
+    pub const fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+        }
+    }
+}
+
+/// Adds the DFU runtime interface. It only uses the control endpoint.
+pub fn new<'d>(builder: &mut Builder<'d, Driver<'d, USB_OTG_FS>>, state: &'d mut State) {
+    let mut func = builder.function(
+        USB_CLASS_APPLICATION_SPECIFIC,
+        USB_SUBCLASS_DFU,
+        USB_PROTOCOL_DFU_RUNTIME,
+    );
+    let mut iface = func.interface();
+    let if_num = iface.interface_number();
+    let mut alt = iface.alt_setting(
+        USB_CLASS_APPLICATION_SPECIFIC,
+        USB_SUBCLASS_DFU,
+        USB_PROTOCOL_DFU_RUNTIME,
+        None,
+    );
+    let [timeout_low, timeout_high] = DETACH_TIMEOUT_MS.to_le_bytes();
+    alt.descriptor(
+        DFU_DESC_DESCTYPE_FUNCTIONAL,
+        &[
+            DFU_ATTR_CAN_DOWNLOAD | DFU_ATTR_WILL_DETACH,
+            timeout_low,
+            timeout_high,
+            // Transfer size, only meaningful to the bootloader
+            TRANSFER_SIZE as u8,
+            (TRANSFER_SIZE >> 8) as u8,
+            // DFU 1.1
+            0x10,
+            0x01,
+        ],
+    );
+    drop(func);
+
+    builder.handler(state.control.write(Control { if_num }));
+}
+
+struct Control {
+    if_num: InterfaceNumber,
+}
+
+impl Control {
+    fn is_ours(&self, req: &Request) -> bool {
+        (req.request_type, req.recipient, req.index)
+            == (
+                RequestType::Class,
+                Recipient::Interface,
+                self.if_num.0 as u16,
+            )
+    }
+}
+
+impl Handler for Control {
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if !self.is_ours(&req) {
+            return None;
+        }
+
+        match req.request {
+            DFU_REQ_DETACH => {
+                info!("DFU detach requested");
+                DETACH_REQUESTED.signal(());
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if !self.is_ours(&req) {
+            return None;
+        }
+
+        match req.request {
+            DFU_REQ_GETSTATUS => {
+                // Status, poll timeout (3 bytes), state, status string
+                buf[..6].copy_from_slice(&[DFU_STATUS_OK, 0, 0, 0, DFU_STATE_APP_IDLE, 0]);
+                Some(InResponse::Accepted(&buf[..6]))
+            }
+            DFU_REQ_GETSTATE => {
+                buf[0] = DFU_STATE_APP_IDLE;
+                Some(InResponse::Accepted(&buf[..1]))
+            }
+            _ => Some(InResponse::Rejected),
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! What the user LEDs show besides the host's lock state, in keyboard mode.
+//!
+//! There is no display, so profile switches and the remap mode answer with
+//! blinks of all the LEDs the board has, see `show_keyboard_leds` in
+//! `main.rs`.
+
+use defmt::Format;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum Feedback {
+    /// Flashes the number of the profile switched to, from 0.
+    Profile(usize),
+    /// Keeps the LEDs lit while the remap mode waits for keys.
+    Remapping,
+    /// Flickers the LEDs to acknowledge a key in the remap mode.
+    KeyTaken,
+    /// One long flash: the key was remapped.
+    Accepted,
+    /// Quick flashes: the remap was cancelled or the keycode rejected, or
+    /// the profile can't be switched.
+    Rejected,
+}
+
+static FEEDBACK: Signal<CriticalSectionRawMutex, Feedback> = Signal::new();
+
+pub fn show(feedback: Feedback) {
+    FEEDBACK.signal(feedback);
+}
+
+pub async fn wait() -> Feedback {
+    FEEDBACK.wait().await
+}
@@ -0,0 +1,282 @@
+//! HID class for the key functions.
+//!
+//! Unlike the class in embassy-usb, it can declare a boot keyboard interface
+//! and lets the host switch between the boot and report protocols with
+//! SET_PROTOCOL. Everything else is answered through the embassy-usb
+//! [`RequestHandler`] trait, so handlers work with either class.
+
+use crate::watchdog::{self, Task};
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, Ordering};
+use defmt::{Format, info, warn};
+use embassy_stm32::peripherals::USB_OTG_FS;
+use embassy_stm32::usb::Driver;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_usb::class::hid::{ReportId, RequestHandler};
+use embassy_usb::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use embassy_usb::driver::{self, Endpoint as _, EndpointError, EndpointOut as _};
+use embassy_usb::types::InterfaceNumber;
+use embassy_usb::{Builder, Handler};
+
+pub type EndpointIn<'d> = <Driver<'d, USB_OTG_FS> as driver::Driver<'d>>::EndpointIn;
+type EndpointOut<'d> = <Driver<'d, USB_OTG_FS> as driver::Driver<'d>>::EndpointOut;
+
+const USB_CLASS_HID: u8 = 0x03;
+const USB_SUBCLASS_NONE: u8 = 0x00;
+const USB_SUBCLASS_BOOT: u8 = 0x01;
+const USB_PROTOCOL_NONE: u8 = 0x00;
+const USB_PROTOCOL_KEYBOARD: u8 = 0x01;
+
+const HID_DESC_DESCTYPE_HID: u8 = 0x21;
+const HID_DESC_DESCTYPE_HID_REPORT: u8 = 0x22;
+
+const HID_REQ_GET_REPORT: u8 = 0x01;
+const HID_REQ_GET_IDLE: u8 = 0x02;
+const HID_REQ_GET_PROTOCOL: u8 = 0x03;
+const HID_REQ_SET_REPORT: u8 = 0x09;
+const HID_REQ_SET_IDLE: u8 = 0x0a;
+const HID_REQ_SET_PROTOCOL: u8 = 0x0b;
+
+/// The report format the host asked for.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum Protocol {
+    /// The fixed 8-byte keyboard report BIOSes understand without parsing
+    /// the report descriptor.
+    Boot,
+    /// The format of the report descriptor.
+    Report,
+}
+
+static BOOT_PROTOCOL: AtomicBool = AtomicBool::new(false);
+/// Signalled whenever the host switches the protocol.
+pub static PROTOCOL_CHANGED: Signal<CriticalSectionRawMutex, Protocol> = Signal::new();
+
+pub fn protocol() -> Protocol {
+    if BOOT_PROTOCOL.load(Ordering::Relaxed) {
+        Protocol::Boot
+    } else {
+        Protocol::Report
+    }
+}
+
+fn set_protocol(protocol: Protocol) {
+    if BOOT_PROTOCOL.swap(protocol == Protocol::Boot, Ordering::Relaxed)
+        != (protocol == Protocol::Boot)
+    {
+        info!("HID protocol: {}", protocol);
+        PROTOCOL_CHANGED.signal(protocol);
+    }
+}
+
+pub struct Config<'d> {
+    pub report_descriptor: &'d [u8],
+    /// Declares the interface as a boot keyboard, which makes it accept the
+    /// boot protocol.
+    pub boot_keyboard: bool,
+    pub request_handler: &'d mut dyn RequestHandler,
+    pub poll_ms: u8,
+    /// Max packet size of both endpoints. Reports have to fit in one packet.
+    pub max_packet_size: u16,
+}
+
+pub struct State<'d> {
+    control: MaybeUninit<Control<'d>>,
+}
+
+impl State<'_> {
+    pub const fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+        }
+    }
+}
+
+/// Adds the HID interface, returning its output report reader and its
+/// interrupt IN endpoint.
+pub fn new<'d>(
+    builder: &mut Builder<'d, Driver<'d, USB_OTG_FS>>,
+    state: &'d mut State<'d>,
+    config: Config<'d>,
+) -> (ReportReader<'d>, EndpointIn<'d>) {
+    let (subclass, protocol) = if config.boot_keyboard {
+        (USB_SUBCLASS_BOOT, USB_PROTOCOL_KEYBOARD)
+    } else {
+        (USB_SUBCLASS_NONE, USB_PROTOCOL_NONE)
+    };
+    let hid_descriptor = hid_descriptor(config.report_descriptor.len());
+
+    let mut func = builder.function(USB_CLASS_HID, subclass, protocol);
+    let mut iface = func.interface();
+    let if_num = iface.interface_number();
+    let mut alt = iface.alt_setting(USB_CLASS_HID, subclass, protocol, None);
+    alt.descriptor(HID_DESC_DESCTYPE_HID, &hid_descriptor[2..]);
+    let ep_in = alt.endpoint_interrupt_in(config.max_packet_size, config.poll_ms);
+    let ep_out = alt.endpoint_interrupt_out(config.max_packet_size, config.poll_ms);
+    drop(func);
+
+    let control = state.control.write(Control {
+        if_num,
+        report_descriptor: config.report_descriptor,
+        hid_descriptor,
+        boot_keyboard: config.boot_keyboard,
+        request_handler: config.request_handler,
+    });
+    builder.handler(control);
+
+    (ReportReader { ep_out }, ep_in)
+}
+
+fn hid_descriptor(report_descriptor_len: usize) -> [u8; 9] {
+    let [len_low, len_high, ..] = report_descriptor_len.to_le_bytes();
+    [
+        9,
+        HID_DESC_DESCTYPE_HID,
+        // HID 1.11
+        0x11,
+        0x01,
+        // Not localized
+        0x00,
+        // One report descriptor
+        1,
+        HID_DESC_DESCTYPE_HID_REPORT,
+        len_low,
+        len_high,
+    ]
+}
+
+/// Receives the output reports sent on the interrupt OUT endpoint.
+pub struct ReportReader<'d> {
+    ep_out: EndpointOut<'d>,
+}
+
+impl ReportReader<'_> {
+    /// Delivers output reports to `handler`. Reports have no report ID.
+    pub async fn run<T: RequestHandler>(mut self, handler: &mut T) -> ! {
+        let mut buf = [0; 64];
+        loop {
+            match watchdog::alive_while(Task::HidRead, self.ep_out.read(&mut buf)).await {
+                // Some hosts send zero length packets
+                Ok(0) => {}
+                Ok(len) => {
+                    handler.set_report(ReportId::Out(0), &buf[..len]);
+                }
+                Err(EndpointError::Disabled) => {
+                    watchdog::alive_while(Task::HidRead, self.ep_out.wait_enabled()).await
+                }
+                Err(EndpointError::BufferOverflow) => warn!("Output report too long"),
+            }
+        }
+    }
+}
+
+struct Control<'d> {
+    if_num: InterfaceNumber,
+    report_descriptor: &'d [u8],
+    hid_descriptor: [u8; 9],
+    boot_keyboard: bool,
+    request_handler: &'d mut dyn RequestHandler,
+}
+
+impl Handler for Control<'_> {
+    fn reset(&mut self) {
+        // Devices start in the report protocol
+        set_protocol(Protocol::Report);
+    }
+
+    fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.index)
+            != (
+                RequestType::Class,
+                Recipient::Interface,
+                self.if_num.0 as u16,
+            )
+        {
+            return None;
+        }
+
+        match req.request {
+            HID_REQ_SET_IDLE => {
+                let id = req.value as u8;
+                let id = (id != 0).then_some(ReportId::In(id));
+                let duration = u32::from(req.value >> 8);
+                let duration = if duration == 0 {
+                    u32::MAX
+                } else {
+                    4 * duration
+                };
+                self.request_handler.set_idle_ms(id, duration);
+                Some(OutResponse::Accepted)
+            }
+            HID_REQ_SET_REPORT => match report_id(req.value) {
+                Some(id) => Some(self.request_handler.set_report(id, data)),
+                None => Some(OutResponse::Rejected),
+            },
+            HID_REQ_SET_PROTOCOL if self.boot_keyboard => {
+                set_protocol(if req.value == 0 {
+                    Protocol::Boot
+                } else {
+                    Protocol::Report
+                });
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if req.index != self.if_num.0 as u16 {
+            return None;
+        }
+
+        match (req.request_type, req.recipient, req.request) {
+            (RequestType::Standard, Recipient::Interface, Request::GET_DESCRIPTOR) => {
+                match (req.value >> 8) as u8 {
+                    HID_DESC_DESCTYPE_HID_REPORT => {
+                        Some(InResponse::Accepted(self.report_descriptor))
+                    }
+                    HID_DESC_DESCTYPE_HID => Some(InResponse::Accepted(&self.hid_descriptor)),
+                    _ => Some(InResponse::Rejected),
+                }
+            }
+            (RequestType::Class, Recipient::Interface, HID_REQ_GET_REPORT) => {
+                match report_id(req.value).and_then(|id| self.request_handler.get_report(id, buf)) {
+                    Some(len) => Some(InResponse::Accepted(&buf[..len])),
+                    None => Some(InResponse::Rejected),
+                }
+            }
+            (RequestType::Class, Recipient::Interface, HID_REQ_GET_IDLE) => {
+                let id = req.value as u8;
+                let id = (id != 0).then_some(ReportId::In(id));
+                match self.request_handler.get_idle_ms(id) {
+                    Some(duration) => {
+                        // Indefinite and anything beyond 1.02 s reads as 0
+                        buf[0] = u8::try_from(duration / 4).unwrap_or(0);
+                        Some(InResponse::Accepted(&buf[..1]))
+                    }
+                    None => Some(InResponse::Rejected),
+                }
+            }
+            (RequestType::Class, Recipient::Interface, HID_REQ_GET_PROTOCOL)
+                if self.boot_keyboard =>
+            {
+                buf[0] = match protocol() {
+                    Protocol::Boot => 0,
+                    Protocol::Report => 1,
+                };
+                Some(InResponse::Accepted(&buf[..1]))
+            }
+            (RequestType::Class, Recipient::Interface, _) => Some(InResponse::Rejected),
+            _ => None,
+        }
+    }
+}
+
+fn report_id(value: u16) -> Option<ReportId> {
+    match value >> 8 {
+        1 => Some(ReportId::In(value as u8)),
+        2 => Some(ReportId::Out(value as u8)),
+        3 => Some(ReportId::Feature(value as u8)),
+        _ => None,
+    }
+}
@@ -0,0 +1,174 @@
+use crate::hid_class::EndpointIn;
+use crate::suspend;
+use crate::usb_keyboard;
+use core::cell::{Cell, RefCell};
+use defmt::warn;
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use embassy_usb::class::hid::ReportId;
+use embassy_usb::driver::{EndpointError, EndpointIn as _};
+use heapless::FnvIndexMap;
+use ssmarshal::serialize;
+use usbd_hid::descriptor::AsInputReport;
+
+/// Longest input report of the key functions.
+pub const MAX_REPORT_SIZE: usize = 32;
+
+/// The idle rate meaning "only report changes".
+pub const IDLE_INDEFINITE: u32 = u32::MAX;
+
+/// The idle rate keyboards start with, 500 ms as HID 1.11 recommends unless
+/// `keypad.toml` says otherwise. Other devices start with
+/// [`IDLE_INDEFINITE`].
+pub const KEYBOARD_IDLE_MS: u32 = crate::keypad_config::KEYBOARD_IDLE_MS;
+
+struct IdleRates {
+    all: u32,
+    per_report: FnvIndexMap<u8, u32, 4>,
+}
+
+static LAST_REPORT: Mutex<CriticalSectionRawMutex, Cell<([u8; MAX_REPORT_SIZE], usize)>> =
+    Mutex::new(Cell::new(([0; MAX_REPORT_SIZE], 0)));
+
+static IDLE_RATES: Mutex<CriticalSectionRawMutex, RefCell<IdleRates>> =
+    Mutex::new(RefCell::new(IdleRates {
+        all: IDLE_INDEFINITE,
+        per_report: FnvIndexMap::new(),
+    }));
+
+static IDLE_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Copies the last input report into `buf`, for GET_REPORT.
+pub fn last_report(buf: &mut [u8]) -> usize {
+    let (report, len) = LAST_REPORT.lock(|last| last.get());
+    let len = len.min(buf.len());
+    buf[..len].copy_from_slice(&report[..len]);
+    len
+}
+
+/// The idle rate of report `id`, or of all reports if `id` is `None`.
+pub fn idle_ms(id: Option<ReportId>) -> u32 {
+    IDLE_RATES.lock(|rates| {
+        let rates = rates.borrow();
+        match id {
+            Some(ReportId::In(id)) => rates.per_report.get(&id).copied().unwrap_or(rates.all),
+            _ => rates.all,
+        }
+    })
+}
+
+/// Sets the idle rate of report `id`, or of all reports if `id` is `None`.
+pub fn set_idle_ms(id: Option<ReportId>, duration_ms: u32) {
+    IDLE_RATES.lock(|rates| {
+        let mut rates = rates.borrow_mut();
+        match id {
+            Some(ReportId::In(id)) => {
+                if rates.per_report.insert(id, duration_ms).is_err() {
+                    warn!("No room for the idle rate of report {}", id);
+                }
+            }
+            _ => {
+                rates.all = duration_ms;
+                rates.per_report.clear();
+            }
+        }
+    });
+    IDLE_CHANGED.signal(());
+}
+
+/// Sends the input reports of the key functions, remembering the last one for
+/// GET_REPORT and repeating it at the idle rate the host asked for.
+///
+/// Reports have no report ID, so only the rate of report 0 applies.
+pub struct ReportWriter<'d> {
+    ep_in: EndpointIn<'d>,
+    last_sent: Instant,
+}
+
+impl<'d> ReportWriter<'d> {
+    /// Creates the writer, with `initial` as the report the host gets until
+    /// the first one is sent.
+    pub fn new(ep_in: EndpointIn<'d>, initial: &[u8]) -> Self {
+        remember(initial);
+        Self {
+            ep_in,
+            last_sent: Instant::now(),
+        }
+    }
+
+    pub async fn write(&mut self, report: &[u8]) -> Result<(), EndpointError> {
+        remember(report);
+        self.last_sent = Instant::now();
+        self.ep_in.write(report).await
+    }
+
+    pub async fn write_serialize<IR: AsInputReport>(
+        &mut self,
+        r: &IR,
+    ) -> Result<(), EndpointError> {
+        let mut buf = [0; MAX_REPORT_SIZE];
+        let Ok(size) = serialize(&mut buf, r) else {
+            return Err(EndpointError::BufferOverflow);
+        };
+        self.write(&buf[..size]).await
+    }
+
+    /// Resends the last report each time the idle period runs out, until
+    /// dropped. Meant to be raced against whatever produces the next report.
+    pub async fn repeat_while_idle(&mut self) -> ! {
+        loop {
+            IDLE_CHANGED.reset();
+            match self.idle_deadline() {
+                Some(deadline) => {
+                    if let Either::First(()) =
+                        select(Timer::at(deadline), IDLE_CHANGED.wait()).await
+                    {
+                        self.repeat().await;
+                    }
+                }
+                None => IDLE_CHANGED.wait().await,
+            }
+        }
+    }
+
+    /// Resends the last report if the idle period ran out, for loops that poll
+    /// the keys anyway.
+    pub async fn repeat_if_idle(&mut self) {
+        if self
+            .idle_deadline()
+            .is_some_and(|deadline| deadline <= Instant::now())
+        {
+            self.repeat().await;
+        }
+    }
+
+    fn idle_deadline(&self) -> Option<Instant> {
+        match idle_ms(Some(ReportId::In(0))) {
+            IDLE_INDEFINITE => None,
+            idle_ms => Some(self.last_sent + Duration::from_millis(idle_ms.into())),
+        }
+    }
+
+    async fn repeat(&mut self) {
+        // Nothing goes out while the host sleeps or hasn't configured us
+        if suspend::is_suspended() || !usb_keyboard::is_configured() {
+            self.last_sent = Instant::now();
+            return;
+        }
+        let mut buf = [0; MAX_REPORT_SIZE];
+        let len = last_report(&mut buf);
+        if let Err(e) = self.write(&buf[..len]).await {
+            warn!("Failed to repeat report: {:?}", e);
+        }
+    }
+}
+
+fn remember(report: &[u8]) {
+    let mut copy = [0; MAX_REPORT_SIZE];
+    let len = report.len().min(MAX_REPORT_SIZE);
+    copy[..len].copy_from_slice(&report[..len]);
+    LAST_REPORT.lock(|last| last.set((copy, len)));
+}
@@ -0,0 +1,576 @@
+//! Infrared remote output through the IR26-21C LED on the keypad interrupt
+//! line.
+//!
+//! The LED and its 1 kΩ resistor sit between PB1 and ground, where the row
+//! followers pull the line up when a key is pressed. To send a frame the
+//! keypad columns are driven low, so the followers let go, and PB1 is handed
+//! to TIM3 channel 4, which makes the carrier. The frame is keyed by switching
+//! the channel between PWM and forced low, from a task on a high priority
+//! executor so the marks and spaces keep their length while the rest of the
+//! firmware is busy.
+
+use crate::keyboard::Keyboard;
+use crate::keypad::Keypad;
+use crate::watchdog::{self, Task};
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use defmt::{debug, warn};
+use embassy_stm32::pac;
+use embassy_stm32::pac::gpio::vals::Moder;
+use embassy_stm32::peripherals::TIM3;
+use embassy_stm32::time::Hertz;
+use embassy_stm32::timer::Channel;
+use embassy_stm32::timer::low_level::{OutputCompareMode, Timer};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant};
+use embedded_hal::digital::{InputPin, OutputPin};
+use heapless::Vec;
+use keypad_logic::ir_protocol::{CARRIER_HZ, CodeError, IrCode, MAX_PULSES, Pulses, RawCode};
+use keypad_logic::settings_store::MAX_VALUE_SIZE;
+
+/// Number of IR codes the keymap can send, see
+/// [`QK_IR`](crate::keymap::QK_IR).
+pub const IR_CODE_COUNT: usize = 16;
+
+/// PB1, alternate function 2 is TIM3_CH4.
+const PIN: usize = 1;
+const AF_TIM3: u8 = 2;
+const CHANNEL: Channel = Channel::Ch4;
+
+/// Room for the marks and spaces of all raw codes together.
+const RAW_POOL_SIZE: usize = 512;
+
+/// Longest text [`write_code`] writes for all the codes together: each raw
+/// code with the longest carrier, gaps and repeat count, and the pool full of
+/// the longest durations.
+pub const MAX_CODES_TEXT: usize = IR_CODE_COUNT
+    * "raw 4294967295 4294967295 repeat 4294967295 max 255".len()
+    + RAW_POOL_SIZE * " 65535".len();
+
+#[derive(Clone, Copy)]
+enum Slot {
+    Protocol(IrCode),
+    /// A [`RawCode`], with its marks and spaces at `start` in the pool.
+    Raw {
+        carrier_hz: u32,
+        start: usize,
+        len: usize,
+        repeat_start: usize,
+        once_gap_us: u32,
+        repeat_gap_us: u32,
+        max_repeats: Option<u8>,
+    },
+}
+
+#[derive(Clone)]
+struct Codes {
+    slots: [Option<Slot>; IR_CODE_COUNT],
+    pool: Vec<u16, RAW_POOL_SIZE>,
+}
+
+impl Codes {
+    fn raw(&self, slot: &Slot) -> Option<RawCode> {
+        let Slot::Raw {
+            carrier_hz,
+            start,
+            len,
+            repeat_start,
+            once_gap_us,
+            repeat_gap_us,
+            max_repeats,
+        } = *slot
+        else {
+            return None;
+        };
+        Some(RawCode {
+            carrier_hz,
+            // Raw codes are checked to fit when they are set
+            durations_us: Vec::from_slice(&self.pool[start..start + len]).unwrap(),
+            repeat_start,
+            once_gap_us,
+            repeat_gap_us,
+            max_repeats,
+        })
+    }
+
+    fn set(&mut self, index: usize, slot: Option<Slot>) {
+        // Close the hole the old raw code leaves in the pool
+        if let Some(Slot::Raw { start, len, .. }) = self.slots[index] {
+            self.pool.copy_within(start + len.., start);
+            self.pool.truncate(self.pool.len() - len);
+            for slot in self.slots.iter_mut().flatten() {
+                if let Slot::Raw { start: other, .. } = slot
+                    && *other > start
+                {
+                    *other -= len;
+                }
+            }
+        }
+        self.slots[index] = slot;
+    }
+
+    fn set_text(&mut self, index: usize, text: &str) -> Result<(), CodeError> {
+        let raw = match (text, IrCode::parse(text)) {
+            ("none", _) => {
+                self.set(index, None);
+                return Ok(());
+            }
+            (_, Some(code)) => {
+                self.set(index, Some(Slot::Protocol(code)));
+                return Ok(());
+            }
+            _ => RawCode::parse(text)?,
+        };
+        let replaced = match self.slots[index] {
+            Some(Slot::Raw { len, .. }) => len,
+            _ => 0,
+        };
+        if self.pool.len() - replaced + raw.durations_us.len() > RAW_POOL_SIZE {
+            return Err(CodeError::NoRoom);
+        }
+        self.set(index, None);
+        let start = self.pool.len();
+        self.pool.extend_from_slice(&raw.durations_us).unwrap();
+        let slot = Slot::Raw {
+            carrier_hz: raw.carrier_hz,
+            start,
+            len: raw.durations_us.len(),
+            repeat_start: raw.repeat_start,
+            once_gap_us: raw.once_gap_us,
+            repeat_gap_us: raw.repeat_gap_us,
+            max_repeats: raw.max_repeats,
+        };
+        self.set(index, Some(slot));
+        Ok(())
+    }
+}
+
+static IR_CODES: Mutex<CriticalSectionRawMutex, RefCell<Codes>> = Mutex::new(RefCell::new(Codes {
+    slots: [None; IR_CODE_COUNT],
+    pool: Vec::new(),
+}));
+
+static REQUEST: Signal<CriticalSectionRawMutex, Pulses> = Signal::new();
+static DONE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub fn is_set(index: usize) -> bool {
+    IR_CODES.lock(|codes| codes.borrow().slots[index].is_some())
+}
+
+/// Writes IR code `index` as the text [`set_code`] takes.
+pub fn write_code(index: usize, out: &mut impl Write) -> fmt::Result {
+    let (code, raw) = IR_CODES.lock(|codes| {
+        let codes = codes.borrow();
+        let slot = codes.slots[index];
+        (slot, slot.and_then(|slot| codes.raw(&slot)))
+    });
+    match (code, raw) {
+        (_, Some(raw)) => write!(out, "{}", raw),
+        (Some(Slot::Protocol(code)), _) => write!(out, "{}", code),
+        _ => out.write_str("none"),
+    }
+}
+
+/// Sets IR code `index` from text: `none`, a protocol code such as
+/// `nec 0x04 0x08`, or a raw or Pronto code, see [`RawCode`].
+pub fn set_code(index: usize, text: &str) -> Result<(), CodeError> {
+    IR_CODES.lock(|codes| codes.borrow_mut().set_text(index, text))
+}
+
+/// A copy of the IR codes, to change several of them at once or none.
+pub struct IrCodes(Codes);
+
+impl IrCodes {
+    /// The IR codes as they are now.
+    pub fn current() -> Self {
+        Self(IR_CODES.lock(|codes| codes.borrow().clone()))
+    }
+
+    /// Sets IR code `index` in the copy, see [`set_code`].
+    pub fn set(&mut self, index: usize, text: &str) -> Result<(), CodeError> {
+        self.0.set_text(index, text)
+    }
+
+    /// Makes the copy the IR codes.
+    pub fn save(self) {
+        IR_CODES.lock(|codes| *codes.borrow_mut() = self.0);
+    }
+}
+
+/// Marks and spaces of the pool in each part [`encode_pool`] writes.
+const POOL_PART_LEN: usize = MAX_VALUE_SIZE / 2;
+
+/// Number of parts the pool is saved in, each at most [`MAX_VALUE_SIZE`].
+pub const POOL_PARTS: usize = RAW_POOL_SIZE.div_ceil(POOL_PART_LEN);
+
+/// Size of a saved slot: a tag, then a protocol code's vendor, address and
+/// command, or a raw code's carrier, place in the pool, gaps and repeat count.
+const SAVED_SLOT_SIZE: usize = 21;
+const SAVED_RAW: u8 = 7;
+
+/// Writes the IR codes, without the marks and spaces of raw codes, into
+/// `buf`, returning the length.
+pub fn encode_slots(buf: &mut [u8]) -> usize {
+    let slots = IR_CODES.lock(|codes| codes.borrow().slots);
+    for (slot, saved) in slots.iter().zip(buf.chunks_exact_mut(SAVED_SLOT_SIZE)) {
+        saved.fill(0);
+        match *slot {
+            None => {}
+            Some(Slot::Protocol(code)) => {
+                let (tag, vendor, address, command) = match code {
+                    IrCode::Nec { address, command } => (1, 0, address, command),
+                    IrCode::Rc5 { address, command } => (2, 0, address.into(), command),
+                    IrCode::Rc6 { address, command } => (3, 0, address.into(), command),
+                    IrCode::Sirc { address, command } => (4, 0, address, command),
+                    IrCode::Samsung { address, command } => (5, 0, address.into(), command),
+                    IrCode::Kaseikyo {
+                        vendor,
+                        address,
+                        command,
+                    } => (6, vendor, address, command),
+                };
+                saved[0] = tag;
+                saved[1..3].copy_from_slice(&vendor.to_le_bytes());
+                saved[3..5].copy_from_slice(&address.to_le_bytes());
+                saved[5] = command;
+            }
+            Some(Slot::Raw {
+                carrier_hz,
+                start,
+                len,
+                repeat_start,
+                once_gap_us,
+                repeat_gap_us,
+                max_repeats,
+            }) => {
+                saved[0] = SAVED_RAW;
+                saved[1..5].copy_from_slice(&carrier_hz.to_le_bytes());
+                saved[5..7].copy_from_slice(&(start as u16).to_le_bytes());
+                saved[7..9].copy_from_slice(&(len as u16).to_le_bytes());
+                saved[9..11].copy_from_slice(&(repeat_start as u16).to_le_bytes());
+                saved[11..15].copy_from_slice(&once_gap_us.to_le_bytes());
+                saved[15..19].copy_from_slice(&repeat_gap_us.to_le_bytes());
+                saved[19] = max_repeats.is_some().into();
+                saved[20] = max_repeats.unwrap_or(0);
+            }
+        }
+    }
+    IR_CODE_COUNT * SAVED_SLOT_SIZE
+}
+
+/// Writes part `part` of the raw codes' marks and spaces into `buf`,
+/// returning the length, which is 0 past the end of the pool.
+pub fn encode_pool(part: usize, buf: &mut [u8]) -> usize {
+    IR_CODES.lock(|codes| {
+        let pool = &codes.borrow().pool;
+        let start = (part * POOL_PART_LEN).min(pool.len());
+        let end = (start + POOL_PART_LEN).min(pool.len());
+        for (us, saved) in pool[start..end].iter().zip(buf.chunks_exact_mut(2)) {
+            saved.copy_from_slice(&us.to_le_bytes());
+        }
+        (end - start) * 2
+    })
+}
+
+/// Appends a part [`encode_pool`] wrote to the pool, returning whether it was
+/// valid. The parts have to be restored in order, before the slots.
+pub fn restore_pool(part: usize, value: &[u8]) -> bool {
+    IR_CODES.lock(|codes| {
+        let pool = &mut codes.borrow_mut().pool;
+        let valid = pool.len() == part * POOL_PART_LEN
+            && value.len() <= POOL_PART_LEN * 2
+            && value.len().is_multiple_of(2);
+        if !valid {
+            return false;
+        }
+        for saved in value.chunks_exact(2) {
+            match u16::from_le_bytes([saved[0], saved[1]]) {
+                // Durations are checked to be 1 µs or longer when they are set
+                0 => return false,
+                us => pool.push(us).unwrap(),
+            }
+        }
+        true
+    })
+}
+
+/// Sets the IR codes to the ones [`encode_slots`] wrote, returning whether
+/// they were valid. Without valid codes the pool is emptied too.
+pub fn restore_slots(value: &[u8]) -> bool {
+    IR_CODES.lock(|codes| {
+        let mut codes = codes.borrow_mut();
+        let mut slots = [None; IR_CODE_COUNT];
+        let mut decoded = value.len() == IR_CODE_COUNT * SAVED_SLOT_SIZE;
+        for (slot, saved) in slots.iter_mut().zip(value.chunks_exact(SAVED_SLOT_SIZE)) {
+            match decode_slot(saved) {
+                Some(saved) => *slot = saved,
+                None => decoded = false,
+            }
+        }
+        let valid = decoded && raw_codes_fill(&slots, codes.pool.len());
+        if valid {
+            codes.slots = slots;
+        } else {
+            codes.pool.clear();
+        }
+        valid
+    })
+}
+
+fn decode_slot(saved: &[u8]) -> Option<Option<Slot>> {
+    let u16_at = |at: usize| u16::from_le_bytes([saved[at], saved[at + 1]]);
+    let u32_at = |at: usize| u32::from_le_bytes(saved[at..at + 4].try_into().unwrap());
+    let (vendor, address, command) = (u16_at(1), u16_at(3), saved[5]);
+    let code = match saved[0] {
+        0 => return Some(None),
+        1 => IrCode::Nec { address, command },
+        2 => IrCode::Rc5 {
+            address: address.try_into().ok()?,
+            command,
+        },
+        3 => IrCode::Rc6 {
+            address: address.try_into().ok()?,
+            command,
+        },
+        4 => IrCode::Sirc { address, command },
+        5 => IrCode::Samsung {
+            address: address.try_into().ok()?,
+            command,
+        },
+        6 => IrCode::Kaseikyo {
+            vendor,
+            address,
+            command,
+        },
+        SAVED_RAW => {
+            let slot = Slot::Raw {
+                carrier_hz: u32_at(1),
+                start: u16_at(5).into(),
+                len: u16_at(7).into(),
+                repeat_start: u16_at(9).into(),
+                once_gap_us: u32_at(11),
+                repeat_gap_us: u32_at(15),
+                max_repeats: (saved[19] != 0).then_some(saved[20]),
+            };
+            return Some(Some(slot));
+        }
+        _ => return None,
+    };
+    // Only codes that could have been set are restored, e.g. an RC5 address
+    // fits in 5 bits
+    let mut text = heapless::String::<32>::new();
+    write!(text, "{}", code).ok()?;
+    (IrCode::parse(&text) == Some(code)).then_some(Some(Slot::Protocol(code)))
+}
+
+/// Whether the raw codes are ones that could have been set, and their marks
+/// and spaces fill the pool without overlapping, as [`Codes::set`] keeps them.
+fn raw_codes_fill(slots: &[Option<Slot>], pool_len: usize) -> bool {
+    let ranges = || {
+        slots.iter().flatten().filter_map(|slot| match *slot {
+            Slot::Raw { start, len, .. } => Some(start..start + len),
+            _ => None,
+        })
+    };
+    let valid = slots.iter().flatten().all(|slot| match *slot {
+        Slot::Raw {
+            carrier_hz,
+            len,
+            repeat_start,
+            ..
+        } => {
+            CARRIER_HZ.contains(&carrier_hz)
+                && (1..=MAX_PULSES).contains(&len)
+                && repeat_start <= len
+        }
+        Slot::Protocol(_) => true,
+    });
+    let disjoint = ranges().enumerate().all(|(i, range)| {
+        ranges()
+            .skip(i + 1)
+            .all(|other| other.end <= range.start || range.end <= other.start)
+    });
+    valid
+        && disjoint
+        && ranges().all(|range| range.end <= pool_len)
+        && ranges().map(|range| range.len()).sum::<usize>() == pool_len
+}
+
+/// A frame of an IR code, ready to send.
+struct Frame {
+    pulses: Pulses,
+    /// Time from the start of this frame to the start of the next.
+    period: Duration,
+    /// Most repeat frames to send while the key is held.
+    max_repeats: Option<u8>,
+}
+
+fn frame(index: usize, repeat: bool, toggle: bool) -> Option<Frame> {
+    let (slot, raw) = IR_CODES.lock(|codes| {
+        let codes = codes.borrow();
+        let slot = codes.slots[index]?;
+        Some((slot, codes.raw(&slot)))
+    })?;
+    let frame = match (slot, raw) {
+        (_, Some(raw)) => Frame {
+            pulses: raw.pulses(repeat),
+            period: Duration::from_ticks(0),
+            max_repeats: raw.max_repeats,
+        },
+        (Slot::Protocol(code), _) => Frame {
+            pulses: if repeat {
+                code.encode_repeat(toggle)
+            } else {
+                code.encode(toggle)
+            },
+            period: Duration::from_micros(code.frame_period_us().into()),
+            max_repeats: None,
+        },
+        _ => return None,
+    };
+    Some(frame)
+}
+
+/// Sends the pulses and waits until they are out. The caller has to keep the
+/// keypad columns low meanwhile, see
+/// [`Keypad::release_interrupt`](crate::keypad::Keypad::release_interrupt).
+pub async fn transmit(pulses: Pulses) {
+    DONE.reset();
+    REQUEST.signal(pulses);
+    DONE.wait().await;
+}
+
+/// Drives the LED with the carrier from TIM3.
+pub struct IrTransmitter {
+    timer: Timer<'static, TIM3>,
+}
+
+impl IrTransmitter {
+    pub fn new(timer: TIM3) -> Self {
+        let timer = Timer::new(timer);
+        timer.set_output_compare_mode(CHANNEL, OutputCompareMode::ForceInactive);
+        timer.enable_channel(CHANNEL, true);
+        Self { timer }
+    }
+
+    /// Sends every [`transmit`] request.
+    pub async fn run(&mut self) -> ! {
+        loop {
+            let pulses = watchdog::alive_while(Task::Ir, REQUEST.wait()).await;
+            self.send(&pulses).await;
+            DONE.signal(());
+            watchdog::check_in(Task::Ir);
+        }
+    }
+
+    async fn send(&mut self, pulses: &Pulses) {
+        self.timer.set_frequency(Hertz(pulses.carrier_hz));
+        let duty = self.timer.get_max_compare_value() * pulses.duty_percent / 100;
+        self.timer.set_compare_value(CHANNEL, duty);
+        self.timer.start();
+        connect_pin(true);
+
+        // Deadlines are kept from the start of the frame, so late wakeups
+        // don't add up
+        let mut deadline = Instant::now();
+        for (i, &us) in pulses.durations_us.iter().enumerate() {
+            let mode = if i % 2 == 0 {
+                OutputCompareMode::PwmMode1
+            } else {
+                OutputCompareMode::ForceInactive
+            };
+            self.timer.set_output_compare_mode(CHANNEL, mode);
+            deadline += Duration::from_micros(us.into());
+            embassy_time::Timer::at(deadline).await;
+        }
+
+        self.timer
+            .set_output_compare_mode(CHANNEL, OutputCompareMode::ForceInactive);
+        self.timer.stop();
+        connect_pin(false);
+    }
+}
+
+/// Hands PB1 to the timer, or back to the keypad interrupt input.
+fn connect_pin(timer: bool) {
+    let gpio = pac::GPIOB;
+    if timer {
+        gpio.afr(PIN / 8).modify(|w| w.set_afr(PIN % 8, AF_TIM3));
+        gpio.moder().modify(|w| w.set_moder(PIN, Moder::ALTERNATE));
+    } else {
+        // The input kept its pull-down, so the LED goes dark
+        gpio.moder().modify(|w| w.set_moder(PIN, Moder::INPUT));
+    }
+}
+
+/// Sends the IR codes of the keymap's IR keys, repeating them while the key
+/// is held.
+pub struct IrRemote {
+    next_frame: Instant,
+    toggle: bool,
+    /// Repeat frames sent since the key was pressed.
+    repeats: u8,
+}
+
+impl IrRemote {
+    pub fn new() -> Self {
+        Self {
+            next_frame: Instant::MIN,
+            toggle: false,
+            repeats: 0,
+        }
+    }
+
+    /// Sends IR code `index`, or its repeat frame.
+    pub async fn send<T: InputPin, U: OutputPin>(
+        &mut self,
+        keypad: &mut Keypad<T, U>,
+        index: usize,
+        repeat: bool,
+    ) {
+        if repeat {
+            self.repeats = self.repeats.saturating_add(1);
+        } else {
+            self.toggle = !self.toggle;
+            self.repeats = 0;
+        }
+        let Some(frame) = frame(index, repeat, self.toggle) else {
+            warn!("IR code {} is not set", index);
+            return;
+        };
+        if !repeat {
+            debug!("Sending IR code {}", index);
+        }
+
+        // Receivers need the gap between frames
+        embassy_time::Timer::at(self.next_frame).await;
+        let start = Instant::now();
+        let gap = Duration::from_micros(frame.pulses.gap_us.into());
+        keypad.release_interrupt();
+        transmit(frame.pulses).await;
+        keypad.arm_interrupt();
+        self.next_frame = (start + frame.period).max(Instant::now() + gap);
+    }
+
+    /// Sends the repeat frame of a held IR key when it is due.
+    pub async fn repeat_held<T: InputPin, U: OutputPin>(
+        &mut self,
+        keypad: &mut Keypad<T, U>,
+        keyboard: &Keyboard,
+    ) {
+        let Some(index) = keyboard.held_ir() else {
+            return;
+        };
+        if Instant::now() < self.next_frame {
+            return;
+        }
+        match frame(index, true, self.toggle) {
+            Some(frame) if frame.max_repeats.is_none_or(|max| self.repeats < max) => {
+                self.send(keypad, index, true).await
+            }
+            _ => {}
+        }
+    }
+}
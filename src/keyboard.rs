@@ -0,0 +1,415 @@
+use crate::ir::IR_CODE_COUNT;
+use crate::keymap::{
+    self, KC_NO, KC_TRANSPARENT, Keymap, LAYERS, MACRO_COUNT, QK_IR, QK_MACRO, QK_MODS,
+    QK_MODS_MAX, QK_MOMENTARY, QK_TO, QK_TOGGLE_LAYER,
+};
+use crate::keypad::{KEY_COUNT, KEY_LABELS};
+use crate::profile;
+use heapless::Deque;
+use keypad_logic::profile::{ChordGate, REMAP_CHORD};
+
+const LEFT_SHIFT: u16 = QK_MODS | 0x0200;
+
+/// What a key press asks for besides the report.
+pub enum Action {
+    PlayMacro(usize),
+    SendIr(usize),
+    SwitchProfile(usize),
+    Remap,
+}
+
+/// Turns keypad scans into keyboard reports through the layered keymap.
+pub struct Keyboard {
+    held: u16,
+    chords: ChordGate,
+    /// Keys reported as held, which leaves out the keys of chords.
+    reported: u16,
+    /// Keys reported as pressed and released at once.
+    tapped: u16,
+    // Keycode each held key resolved to when it was pressed, so it is
+    // released the same way even if the active layers changed meanwhile
+    pressed: [u16; KEY_COUNT],
+    toggled_layers: u8,
+}
+
+impl Keyboard {
+    pub const fn new() -> Self {
+        Self {
+            held: 0,
+            chords: ChordGate::new(),
+            reported: 0,
+            tapped: 0,
+            pressed: [KC_NO; KEY_COUNT],
+            toggled_layers: 0,
+        }
+    }
+
+    /// Keys held in the last update.
+    pub fn held(&self) -> u16 {
+        self.held
+    }
+
+    /// Forgets the keys held, e.g. after the remap mode took the keypad over
+    /// until all keys were released.
+    pub fn release_all(&mut self) {
+        self.held = 0;
+        self.chords = ChordGate::new();
+        self.reported = 0;
+        self.tapped = 0;
+        self.pressed = [KC_NO; KEY_COUNT];
+    }
+
+    /// Applies a new [`Keypad::scan`], returning the macro to play, IR code
+    /// to send, profile to switch to or the remap mode if such a key was
+    /// pressed. The keys of a chord aren't typed, see [`ChordGate`]; if a
+    /// `*` or `#` is typed as a tap, [`end_tap`](Self::end_tap) releases it.
+    ///
+    /// [`Keypad::scan`]: crate::keypad::Keypad::scan
+    pub fn update(&mut self, keys: u16) -> Option<Action> {
+        let keymap = keymap::keymap();
+        let gated = self.chords.update(&KEY_LABELS, self.held, keys);
+        self.held = keys;
+        let released = self.reported & !gated.keys;
+        let pressed = (gated.keys | gated.tapped) & !self.reported;
+        self.reported = gated.keys;
+        self.tapped = gated.tapped;
+
+        for key in (0..KEY_COUNT).filter(|key| released & (1 << key) != 0) {
+            self.pressed[key] = KC_NO;
+        }
+
+        let mut action = match gated.chord {
+            Some(REMAP_CHORD) => Some(Action::Remap),
+            Some(label) => profile::from_chord(label).map(Action::SwitchProfile),
+            None => None,
+        };
+        for key in (0..KEY_COUNT).filter(|key| pressed & (1 << key) != 0) {
+            let keycode = self.resolve(&keymap, key);
+            self.pressed[key] = keycode;
+            if let Some(layer) = layer_of(keycode, QK_TO) {
+                self.toggled_layers = 1 << layer;
+            } else if let Some(layer) = layer_of(keycode, QK_TOGGLE_LAYER) {
+                self.toggled_layers ^= 1 << layer;
+            } else if let Some(index) = index_of(keycode, QK_MACRO, MACRO_COUNT) {
+                action = Some(Action::PlayMacro(index));
+            } else if let Some(index) = index_of(keycode, QK_IR, IR_CODE_COUNT) {
+                action = Some(Action::SendIr(index));
+            }
+        }
+        action
+    }
+
+    /// Releases the keys typed as a tap by the last [`update`](Self::update).
+    /// Returns whether there were any, so the release is reported.
+    pub fn end_tap(&mut self) -> bool {
+        for key in (0..KEY_COUNT).filter(|key| self.tapped & (1 << key) != 0) {
+            self.pressed[key] = KC_NO;
+        }
+        core::mem::take(&mut self.tapped) != 0
+    }
+
+    /// The IR code of the first held IR key.
+    pub fn held_ir(&self) -> Option<usize> {
+        self.pressed
+            .iter()
+            .find_map(|&keycode| index_of(keycode, QK_IR, IR_CODE_COUNT))
+    }
+
+    /// The keys currently held.
+    pub fn state(&self) -> KeyboardState {
+        KeyboardState::from_keycodes(self.pressed.iter().copied())
+    }
+
+    /// Bit mask of the layers in use: the base layer, toggled layers, and
+    /// layers held on by momentary keys.
+    pub fn active_layers(&self) -> u8 {
+        self.pressed
+            .iter()
+            .filter_map(|&keycode| layer_of(keycode, QK_MOMENTARY))
+            .fold(1 | self.toggled_layers, |layers, layer| layers | 1 << layer)
+    }
+
+    fn resolve(&self, keymap: &Keymap, key: usize) -> u16 {
+        let active = self.active_layers();
+        (0..LAYERS)
+            .rev()
+            .filter(|layer| active & (1 << layer) != 0)
+            .map(|layer| keymap[layer][key])
+            .find(|&keycode| keycode != KC_TRANSPARENT)
+            .unwrap_or(KC_NO)
+    }
+}
+
+fn layer_of(keycode: u16, base: u16) -> Option<usize> {
+    index_of(keycode, base, LAYERS)
+}
+
+fn index_of(keycode: u16, base: u16, count: usize) -> Option<usize> {
+    keycode
+        .checked_sub(base)
+        .map(usize::from)
+        .filter(|&index| index < count)
+}
+
+/// Number of key changes kept while the device isn't configured.
+pub const KEY_QUEUE_LEN: usize = 32;
+
+/// Keypad scans waiting for the host to configure the device.
+pub struct KeyQueue {
+    scans: Deque<u16, KEY_QUEUE_LEN>,
+    overflowed: bool,
+}
+
+impl KeyQueue {
+    pub const fn new() -> Self {
+        Self {
+            scans: Deque::new(),
+            overflowed: false,
+        }
+    }
+
+    /// Queues `keys` if they differ from the last queued scan, or from
+    /// `held` if nothing is queued. Returns whether they were queued.
+    ///
+    /// When the queue is full the last entry is replaced, so the keys end up
+    /// in the right state even though changes in between are lost.
+    pub fn push(&mut self, keys: u16, held: u16) -> bool {
+        if keys == self.scans.back().copied().unwrap_or(held) {
+            return false;
+        }
+        if let Err(keys) = self.scans.push_back(keys) {
+            self.overflowed = true;
+            if let Some(last) = self.scans.back_mut() {
+                *last = keys;
+            }
+        }
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<u16> {
+        let keys = self.scans.pop_front();
+        if self.scans.is_empty() {
+            self.overflowed = false;
+        }
+        keys
+    }
+
+    pub fn clear(&mut self) {
+        self.scans.clear();
+        self.overflowed = false;
+    }
+
+    pub fn len(&self) -> usize {
+        self.scans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scans.is_empty()
+    }
+
+    /// Whether changes were lost since the queue was last empty.
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+}
+
+/// Number of usages the report protocol keyboard report has a bit for.
+const NKRO_USAGES: usize = 0xA8;
+/// Size of the report protocol keyboard report: the modifiers, then a bit for
+/// each usage.
+pub const NKRO_REPORT_SIZE: usize = 1 + NKRO_USAGES / 8;
+pub const BOOT_REPORT_SIZE: usize = 8;
+/// Fills every key slot of a boot report when more than six keys are held.
+const ERROR_ROLL_OVER: u8 = 0x01;
+
+/// Modifiers and keyboard usages held at one moment.
+pub struct KeyboardState {
+    modifier: u8,
+    usages: [u8; NKRO_USAGES / 8],
+}
+
+impl KeyboardState {
+    pub fn from_keycodes(keycodes: impl IntoIterator<Item = u16>) -> Self {
+        let mut state = Self {
+            modifier: 0,
+            usages: [0; NKRO_USAGES / 8],
+        };
+        for keycode in keycodes {
+            let (modifier, usage) = match keycode {
+                0..=0xFF => (0, keycode as u8),
+                QK_MODS..=QK_MODS_MAX => {
+                    let mods = (keycode >> 8) as u8;
+                    // Bit 4 moves the modifiers to the right hand side
+                    let modifier = if mods & 0x10 != 0 {
+                        (mods & 0x0F) << 4
+                    } else {
+                        mods & 0x0F
+                    };
+                    (modifier, keycode as u8)
+                }
+                _ => continue,
+            };
+            state.modifier |= modifier;
+            match usage {
+                0xE0..=0xE7 => state.modifier |= 1 << (usage - 0xE0),
+                0x04..=0xA4 => state.usages[usage as usize / 8] |= 1 << (usage % 8),
+                _ => {}
+            }
+        }
+        state
+    }
+
+    /// The 8-byte boot protocol report, with up to six keys.
+    pub fn boot_report(&self) -> [u8; BOOT_REPORT_SIZE] {
+        let mut report = [self.modifier, 0, 0, 0, 0, 0, 0, 0];
+        let mut usages =
+            (0..NKRO_USAGES).filter(|&usage| self.usages[usage / 8] & (1 << (usage % 8)) != 0);
+        for slot in report[2..].iter_mut() {
+            *slot = usages.next().unwrap_or(0) as u8;
+        }
+        if usages.next().is_some() {
+            report[2..].fill(ERROR_ROLL_OVER);
+        }
+        report
+    }
+
+    /// The report protocol report, see
+    /// [`KEYBOARD_REPORT_DESCRIPTOR`](crate::usb_keyboard::KEYBOARD_REPORT_DESCRIPTOR).
+    pub fn nkro_report(&self) -> [u8; NKRO_REPORT_SIZE] {
+        let mut report = [0; NKRO_REPORT_SIZE];
+        report[0] = self.modifier;
+        report[1..].copy_from_slice(&self.usages);
+        report
+    }
+}
+
+/// A single step of a macro.
+pub enum MacroStep {
+    Press(u16),
+    Release(u16),
+    DelayMs(u32),
+}
+
+const SS_QMK_PREFIX: u8 = 1;
+const SS_TAP_CODE: u8 = 1;
+const SS_DOWN_CODE: u8 = 2;
+const SS_UP_CODE: u8 = 3;
+const SS_DELAY_CODE: u8 = 4;
+
+/// Walks a macro in the format VIA stores it: text is typed as is, and
+/// [`SS_QMK_PREFIX`] introduces taps, presses and releases of single
+/// keycodes, and delays written as decimal milliseconds ending with `|`.
+pub struct MacroSteps<'a> {
+    bytes: &'a [u8],
+    release: Option<u16>,
+}
+
+impl<'a> MacroSteps<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            release: None,
+        }
+    }
+}
+
+impl Iterator for MacroSteps<'_> {
+    type Item = MacroStep;
+
+    fn next(&mut self) -> Option<MacroStep> {
+        if let Some(keycode) = self.release.take() {
+            return Some(MacroStep::Release(keycode));
+        }
+        loop {
+            let (&first, rest) = self.bytes.split_first()?;
+            if first != SS_QMK_PREFIX {
+                self.bytes = rest;
+                if let Some(keycode) = ascii_keycode(first) {
+                    self.release = Some(keycode);
+                    return Some(MacroStep::Press(keycode));
+                }
+                continue;
+            }
+
+            return match rest {
+                [SS_TAP_CODE, keycode, rest @ ..] => {
+                    self.bytes = rest;
+                    self.release = Some((*keycode).into());
+                    Some(MacroStep::Press((*keycode).into()))
+                }
+                [SS_DOWN_CODE, keycode, rest @ ..] => {
+                    self.bytes = rest;
+                    Some(MacroStep::Press((*keycode).into()))
+                }
+                [SS_UP_CODE, keycode, rest @ ..] => {
+                    self.bytes = rest;
+                    Some(MacroStep::Release((*keycode).into()))
+                }
+                [SS_DELAY_CODE, rest @ ..] => {
+                    let end = rest
+                        .iter()
+                        .position(|&byte| byte == b'|')
+                        .unwrap_or(rest.len());
+                    let delay = rest[..end].iter().fold(0u32, |delay, digit| {
+                        delay
+                            .saturating_mul(10)
+                            .saturating_add(digit.wrapping_sub(b'0').into())
+                    });
+                    self.bytes = rest.get(end + 1..).unwrap_or(&[]);
+                    Some(MacroStep::DelayMs(delay))
+                }
+                _ => {
+                    self.bytes = &[];
+                    None
+                }
+            };
+        }
+    }
+}
+
+/// Keycode typing `c` on a US layout.
+fn ascii_keycode(c: u8) -> Option<u16> {
+    let keycode = match c {
+        b'a'..=b'z' => (c - b'a' + 0x04).into(),
+        b'A'..=b'Z' => LEFT_SHIFT | u16::from(c - b'A' + 0x04),
+        b'1'..=b'9' => (c - b'1' + 0x1E).into(),
+        b'0' => 0x27,
+        b'\n' => 0x28,
+        b'\t' => 0x2B,
+        b' ' => 0x2C,
+        b'-' => 0x2D,
+        b'=' => 0x2E,
+        b'[' => 0x2F,
+        b']' => 0x30,
+        b'\\' => 0x31,
+        b';' => 0x33,
+        b'\'' => 0x34,
+        b'`' => 0x35,
+        b',' => 0x36,
+        b'.' => 0x37,
+        b'/' => 0x38,
+        b'!' => LEFT_SHIFT | 0x1E,
+        b'@' => LEFT_SHIFT | 0x1F,
+        b'#' => LEFT_SHIFT | 0x20,
+        b'$' => LEFT_SHIFT | 0x21,
+        b'%' => LEFT_SHIFT | 0x22,
+        b'^' => LEFT_SHIFT | 0x23,
+        b'&' => LEFT_SHIFT | 0x24,
+        b'*' => LEFT_SHIFT | 0x25,
+        b'(' => LEFT_SHIFT | 0x26,
+        b')' => LEFT_SHIFT | 0x27,
+        b'_' => LEFT_SHIFT | 0x2D,
+        b'+' => LEFT_SHIFT | 0x2E,
+        b'{' => LEFT_SHIFT | 0x2F,
+        b'}' => LEFT_SHIFT | 0x30,
+        b'|' => LEFT_SHIFT | 0x31,
+        b':' => LEFT_SHIFT | 0x33,
+        b'"' => LEFT_SHIFT | 0x34,
+        b'~' => LEFT_SHIFT | 0x35,
+        b'<' => LEFT_SHIFT | 0x36,
+        b'>' => LEFT_SHIFT | 0x37,
+        b'?' => LEFT_SHIFT | 0x38,
+        _ => return None,
+    };
+    Some(keycode)
+}
@@ -0,0 +1,83 @@
+use crate::keypad::KEY_COUNT;
+use core::cell::{Cell, RefCell};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+/// Number of keymap layers.
+pub const LAYERS: usize = 4;
+
+/// Keycode for each key on each layer, in [`Keypad::scan`] order.
+///
+/// Keycodes follow the QMK numbering that VIA uses: HID keyboard usages up to
+/// 0xFF, modifiers in the high byte, and layer and macro keys above that, see
+/// the constants below.
+///
+/// [`Keypad::scan`]: crate::keypad::Keypad::scan
+pub type Keymap = [[u16; KEY_COUNT]; LAYERS];
+
+pub const KC_NO: u16 = 0x0000;
+/// Falls through to the next active layer below.
+pub const KC_TRANSPARENT: u16 = 0x0001;
+/// HID keyboard usages combined with modifiers in bits 8-12: control, shift,
+/// alt, GUI, and right hand instead of left.
+pub const QK_MODS: u16 = 0x0100;
+pub const QK_MODS_MAX: u16 = 0x1FFF;
+/// Makes the layer the only active one.
+pub const QK_TO: u16 = 0x5200;
+/// Activates the layer while held.
+pub const QK_MOMENTARY: u16 = 0x5220;
+/// Toggles the layer on press.
+pub const QK_TOGGLE_LAYER: u16 = 0x5260;
+/// Plays the macro on press.
+pub const QK_MACRO: u16 = 0x7700;
+/// Sends the IR code on press, and repeats it while held. These are the
+/// keyboard specific keycodes VIA offers as custom keycodes.
+pub const QK_IR: u16 = 0x7E00;
+
+pub const MACRO_COUNT: usize = 16;
+/// Macros are stored back to back, each terminated by a NUL, in the format
+/// VIA writes them.
+pub const MACRO_BUFFER_SIZE: usize = 512;
+
+pub use crate::keypad_config::DEFAULT_KEYMAP;
+
+static KEYMAP: Mutex<CriticalSectionRawMutex, Cell<Keymap>> = Mutex::new(Cell::new(DEFAULT_KEYMAP));
+
+static MACROS: Mutex<CriticalSectionRawMutex, RefCell<[u8; MACRO_BUFFER_SIZE]>> =
+    Mutex::new(RefCell::new([0; MACRO_BUFFER_SIZE]));
+
+pub fn keymap() -> Keymap {
+    KEYMAP.lock(|keymap| keymap.get())
+}
+
+pub fn set_keymap(keymap: Keymap) {
+    KEYMAP.lock(|current| current.set(keymap));
+}
+
+pub fn keycode(layer: usize, key: usize) -> u16 {
+    KEYMAP.lock(|keymap| keymap.get()[layer][key])
+}
+
+pub fn set_keycode(layer: usize, key: usize, keycode: u16) {
+    KEYMAP.lock(|keymap| {
+        let mut updated = keymap.get();
+        updated[layer][key] = keycode;
+        keymap.set(updated);
+    });
+}
+
+/// Runs `f` on the raw macro buffer.
+pub fn with_macros<R>(f: impl FnOnce(&mut [u8; MACRO_BUFFER_SIZE]) -> R) -> R {
+    MACROS.lock(|macros| f(&mut macros.borrow_mut()))
+}
+
+/// Copies macro `index` into `buf`, returning its length.
+pub fn copy_macro(index: usize, buf: &mut [u8; MACRO_BUFFER_SIZE]) -> usize {
+    with_macros(|macros| match macros.split(|&byte| byte == 0).nth(index) {
+        Some(content) => {
+            buf[..content.len()].copy_from_slice(content);
+            content.len()
+        }
+        None => 0,
+    })
+}
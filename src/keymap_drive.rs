@@ -0,0 +1,484 @@
+//! A small FAT12 volume holding `KEYMAP.TXT` and `ERRORS.TXT`, made up on the
+//! fly for the [mass storage interface](crate::msc).
+//!
+//! Until the host writes to it, every block is generated from the current
+//! keymap. Blocks the host writes are kept in RAM, and once it stops writing
+//! the drive looks up `KEYMAP.TXT` through the FAT and directory the host left
+//! behind and applies it. The volume then starts over from the new keymap, and
+//! the host is told the medium changed so it reads it again.
+
+use crate::keymap_file::{self, Parser};
+use crate::msc::{BLOCK_SIZE, BlockDevice};
+use core::fmt::{self, Write};
+use defmt::{info, warn};
+use heapless::{FnvIndexMap, String, Vec};
+
+/// 64 KiB, small enough that the FAT fits in one block.
+const BLOCK_COUNT: u32 = 128;
+const FAT_BLOCK: u32 = 1;
+const ROOT_DIR_BLOCK: u32 = 2;
+const ROOT_DIR_ENTRIES: usize = BLOCK_SIZE / DIR_ENTRY_SIZE;
+const FIRST_DATA_BLOCK: u32 = 3;
+/// Data clusters are numbered from 2 and are one block each.
+const FIRST_CLUSTER: u16 = 2;
+const CLUSTER_COUNT: u16 = (BLOCK_COUNT - FIRST_DATA_BLOCK) as u16;
+
+const DIR_ENTRY_SIZE: usize = 32;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const DELETED_ENTRY: u8 = 0xE5;
+const FAT_END_OF_CHAIN: u16 = 0xFFF;
+const MEDIA_FIXED: u8 = 0xF8;
+/// 2024-01-01, in FAT date format.
+const FILE_DATE: u16 = (2024 - 1980) << 9 | 1 << 5 | 1;
+
+const KEYMAP_NAME: &[u8; 11] = b"KEYMAP  TXT";
+const ERRORS_NAME: &[u8; 11] = b"ERRORS  TXT";
+/// Largest `KEYMAP.TXT` the drive takes, and room for the longest it
+/// serves, in whole blocks.
+const KEYMAP_SIZE: usize = keymap_file::MAX_SIZE.next_multiple_of(BLOCK_SIZE);
+const ERRORS_SIZE: usize = 1024;
+const KEYMAP_CLUSTER: u16 = FIRST_CLUSTER;
+const ERRORS_CLUSTER: u16 = KEYMAP_CLUSTER + (KEYMAP_SIZE / BLOCK_SIZE) as u16;
+
+/// Number of data blocks written by the host that can be kept, room for the
+/// longest `KEYMAP.TXT` and some of the files editors write next to it.
+const WRITE_CACHE_BLOCKS: usize = 16;
+
+#[rustfmt::skip]
+const BOOT_SECTOR_START: [u8; 62] = [
+    0xEB, 0x3C, 0x90,                                // Jump
+    b'M', b'S', b'D', b'O', b'S', b'5', b'.', b'0',  // OEM name
+    0x00, 0x02,                                      // Bytes per sector
+    0x01,                                            // Sectors per cluster
+    0x01, 0x00,                                      // Reserved sectors
+    0x01,                                            // FATs
+    ROOT_DIR_ENTRIES as u8, 0x00,                    // Root directory entries
+    BLOCK_COUNT as u8, 0x00,                         // Sectors
+    MEDIA_FIXED,                                     // Media
+    0x01, 0x00,                                      // Sectors per FAT
+    0x01, 0x00,                                      // Sectors per track
+    0x01, 0x00,                                      // Heads
+    0x00, 0x00, 0x00, 0x00,                          // Hidden sectors
+    0x00, 0x00, 0x00, 0x00,                          // Sectors (32-bit)
+    0x80,                                            // Drive number
+    0x00,                                            // Reserved
+    0x29,                                            // Extended boot signature
+    0x4B, 0x45, 0x59, 0x50,                          // Volume serial number
+    b'K', b'E', b'Y', b'P', b'A', b'D', b' ', b' ', b' ', b' ', b' ', // Label
+    b'F', b'A', b'T', b'1', b'2', b' ', b' ', b' ',  // File system
+];
+
+pub struct KeymapDrive {
+    /// The last rejected `KEYMAP.TXT`, served instead of the keymap so the
+    /// edits aren't lost.
+    rejected: Option<Vec<u8, KEYMAP_SIZE>>,
+    errors: String<ERRORS_SIZE>,
+    fat: Option<[u8; BLOCK_SIZE]>,
+    root_dir: Option<[u8; BLOCK_SIZE]>,
+    written: FnvIndexMap<u16, [u8; BLOCK_SIZE], WRITE_CACHE_BLOCKS>,
+}
+
+impl KeymapDrive {
+    pub const fn new() -> Self {
+        Self {
+            rejected: None,
+            errors: String::new(),
+            fat: None,
+            root_dir: None,
+            written: FnvIndexMap::new(),
+        }
+    }
+
+    /// Copies what fits in `part` of `KEYMAP.TXT` from byte `start` on,
+    /// returning the length of the file. The keymap is rendered again each
+    /// time rather than kept.
+    fn keymap_text(&self, start: usize, part: &mut [u8]) -> usize {
+        if let Some(rejected) = &self.rejected {
+            copy_from(rejected, start, part);
+            return rejected.len();
+        }
+        let mut window = Window {
+            start,
+            part,
+            len: 0,
+        };
+        // Writing to a window doesn't fail
+        keymap_file::render(&mut window).ok();
+        window.len
+    }
+
+    fn errors_text(&self) -> &str {
+        if self.errors.is_empty() {
+            "No errors\r\n"
+        } else {
+            &self.errors
+        }
+    }
+
+    /// Fills `block` with what the volume held before the host wrote to it.
+    fn generate(&self, lba: u32, block: &mut [u8; BLOCK_SIZE]) {
+        block.fill(0);
+        let errors = self.errors_text();
+        match lba {
+            0 => {
+                block[..BOOT_SECTOR_START.len()].copy_from_slice(&BOOT_SECTOR_START);
+                block[510..].copy_from_slice(&[0x55, 0xAA]);
+            }
+            FAT_BLOCK => {
+                set_fat_entry(block, 0, 0xF00 | u16::from(MEDIA_FIXED));
+                set_fat_entry(block, 1, FAT_END_OF_CHAIN);
+                chain(block, KEYMAP_CLUSTER, self.keymap_text(0, &mut []));
+                chain(block, ERRORS_CLUSTER, errors.len());
+            }
+            ROOT_DIR_BLOCK => {
+                dir_entry(&mut block[..32], b"KEYPAD     ", ATTR_VOLUME_ID, 0, 0);
+                dir_entry(
+                    &mut block[32..64],
+                    KEYMAP_NAME,
+                    ATTR_ARCHIVE,
+                    KEYMAP_CLUSTER,
+                    self.keymap_text(0, &mut []),
+                );
+                dir_entry(
+                    &mut block[64..96],
+                    ERRORS_NAME,
+                    ATTR_ARCHIVE,
+                    ERRORS_CLUSTER,
+                    errors.len(),
+                );
+            }
+            _ => {
+                let cluster = data_cluster(lba);
+                if cluster < ERRORS_CLUSTER {
+                    let start = usize::from(cluster - KEYMAP_CLUSTER) * BLOCK_SIZE;
+                    self.keymap_text(start, block);
+                } else {
+                    let start = usize::from(cluster - ERRORS_CLUSTER) * BLOCK_SIZE;
+                    copy_from(errors.as_bytes(), start, block);
+                }
+            }
+        }
+    }
+
+    /// Looks up `KEYMAP.TXT` as the host left it and applies it if it changed.
+    /// Returns whether anything was applied.
+    fn apply_keymap(&mut self) -> bool {
+        let mut root_dir = [0; BLOCK_SIZE];
+        let mut fat = [0; BLOCK_SIZE];
+        self.read(ROOT_DIR_BLOCK, &mut root_dir);
+        self.read(FAT_BLOCK, &mut fat);
+
+        let Some((cluster, size)) = root_dir
+            .chunks(DIR_ENTRY_SIZE)
+            .find(|entry| {
+                entry[..11] == KEYMAP_NAME[..]
+                    && entry[0] != DELETED_ENTRY
+                    && entry[11] & (ATTR_VOLUME_ID | ATTR_DIRECTORY) == 0
+            })
+            .map(|entry| {
+                let cluster = u16::from_le_bytes([entry[26], entry[27]]);
+                let size = u32::from_le_bytes([entry[28], entry[29], entry[30], entry[31]]);
+                (cluster, size as usize)
+            })
+        else {
+            // Deleted, or not written yet
+            return false;
+        };
+        let file = File { cluster, size };
+
+        if size > KEYMAP_SIZE {
+            return self.reject(None, "KEYMAP.TXT is too large\r\n");
+        }
+        let mut unchanged = size == self.keymap_text(0, &mut []);
+        let mut ours = [0; BLOCK_SIZE];
+        let read = self.read_file(&fat, file, |start, part| {
+            let ours = &mut ours[..part.len()];
+            self.keymap_text(start, ours);
+            unchanged &= part == ours;
+        });
+        if read.is_err() {
+            return self.reject(None, "KEYMAP.TXT is damaged\r\n");
+        }
+        if unchanged {
+            return false;
+        }
+
+        let mut errors = String::<ERRORS_SIZE>::new();
+        let mut parser = Parser::new();
+        let mut listed = Ok(());
+        self.read_file(&fat, file, |_, part| {
+            listed = listed.and_then(|()| parser.feed(part, &mut errors));
+        })
+        .ok();
+        match listed.and_then(|()| parser.finish(&mut errors)) {
+            Ok(true) => {
+                info!("KEYMAP.TXT applied");
+                self.rejected = None;
+                self.errors.clear();
+                true
+            }
+            Ok(false) => {
+                warn!("KEYMAP.TXT rejected");
+                self.reject(Some((&fat, file)), &errors)
+            }
+            Err(fmt::Error) => self.reject(Some((&fat, file)), "Too many errors to list\r\n"),
+        }
+    }
+
+    /// Passes the bytes of `file` to `part` a block at a time, with where
+    /// they start in it. Fails if its clusters leave the volume.
+    fn read_file(
+        &self,
+        fat: &[u8; BLOCK_SIZE],
+        file: File,
+        mut part: impl FnMut(usize, &[u8]),
+    ) -> Result<(), ()> {
+        let mut cluster = file.cluster;
+        let mut block = [0; BLOCK_SIZE];
+        for start in (0..file.size).step_by(BLOCK_SIZE) {
+            if !(FIRST_CLUSTER..FIRST_CLUSTER + CLUSTER_COUNT).contains(&cluster) {
+                return Err(());
+            }
+            self.read(
+                u32::from(cluster - FIRST_CLUSTER) + FIRST_DATA_BLOCK,
+                &mut block,
+            );
+            part(start, &block[..(file.size - start).min(BLOCK_SIZE)]);
+            cluster = fat_entry(fat, cluster);
+        }
+        Ok(())
+    }
+
+    /// Lists `errors` and keeps the rejected `file`, if it could be read, in
+    /// place of the keymap.
+    fn reject(&mut self, file: Option<(&[u8; BLOCK_SIZE], File)>, errors: &str) -> bool {
+        let mut rejected = Vec::new();
+        let kept = file.is_some_and(|(fat, file)| {
+            self.read_file(fat, file, |_, part| {
+                // The file was checked to fit
+                rejected.extend_from_slice(part).ok();
+            })
+            .is_ok()
+        });
+        self.rejected = kept.then_some(rejected);
+        self.errors.clear();
+        self.errors.push_str(errors).ok();
+        true
+    }
+
+    /// A cached block that isn't part of `KEYMAP.TXT` in the latest root
+    /// directory and FAT. Blocks of other files go first, as blocks of no
+    /// file may be a new `KEYMAP.TXT` the host hasn't linked up yet.
+    fn evictable(&self) -> Option<u16> {
+        let mut root_dir = [0; BLOCK_SIZE];
+        let mut fat = [0; BLOCK_SIZE];
+        self.read(ROOT_DIR_BLOCK, &mut root_dir);
+        self.read(FAT_BLOCK, &mut fat);
+
+        let mut keymap = 0;
+        let mut others = 0;
+        for entry in root_dir.chunks(DIR_ENTRY_SIZE) {
+            if entry[0] == 0 {
+                // The end of the directory
+                break;
+            }
+            // Long name entries have the volume ID bit set too
+            if entry[0] == DELETED_ENTRY || entry[11] & ATTR_VOLUME_ID != 0 {
+                continue;
+            }
+            let clusters = clusters_of(&fat, u16::from_le_bytes([entry[26], entry[27]]));
+            if entry[..11] == KEYMAP_NAME[..] {
+                keymap |= clusters;
+            } else {
+                others |= clusters;
+            }
+        }
+        let cached = || {
+            self.written
+                .keys()
+                .copied()
+                .filter(|&cluster| keymap & cluster_bit(cluster) == 0)
+        };
+        cached()
+            .find(|&cluster| others & cluster_bit(cluster) != 0)
+            .or_else(|| cached().next())
+    }
+
+    /// The block at `lba`, as the host wrote it or generated.
+    fn read(&self, lba: u32, block: &mut [u8; BLOCK_SIZE]) {
+        let written = match lba {
+            FAT_BLOCK => self.fat.as_ref(),
+            ROOT_DIR_BLOCK => self.root_dir.as_ref(),
+            FIRST_DATA_BLOCK.. => self.written.get(&data_cluster(lba)),
+            _ => None,
+        };
+        match written {
+            Some(written) => block.copy_from_slice(written),
+            None => self.generate(lba, block),
+        }
+    }
+}
+
+/// Where a file the host wrote starts, and its length.
+#[derive(Clone, Copy)]
+struct File {
+    cluster: u16,
+    size: usize,
+}
+
+/// A [`Write`] keeping what fits in `part` of the text from byte `start` on,
+/// and counting the whole length.
+struct Window<'a> {
+    start: usize,
+    part: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Window<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let skipped = self.start.saturating_sub(self.len);
+        if let Some(bytes) = s.as_bytes().get(skipped..) {
+            let at = self.len + skipped - self.start;
+            if let Some(room) = self.part.get_mut(at..) {
+                let len = bytes.len().min(room.len());
+                room[..len].copy_from_slice(&bytes[..len]);
+            }
+        }
+        self.len += s.len();
+        Ok(())
+    }
+}
+
+impl BlockDevice for KeymapDrive {
+    fn block_count(&self) -> u32 {
+        BLOCK_COUNT
+    }
+
+    fn read_block(&mut self, lba: u32, block: &mut [u8; BLOCK_SIZE]) -> Result<(), ()> {
+        self.read(lba, block);
+        Ok(())
+    }
+
+    fn write_block(&mut self, lba: u32, block: &[u8; BLOCK_SIZE]) -> Result<(), ()> {
+        match lba {
+            // The boot sector stays as it is
+            0 => Ok(()),
+            FAT_BLOCK => {
+                self.fat = Some(*block);
+                Ok(())
+            }
+            ROOT_DIR_BLOCK => {
+                self.root_dir = Some(*block);
+                Ok(())
+            }
+            _ => {
+                let cluster = data_cluster(lba);
+                if self.written.len() == WRITE_CACHE_BLOCKS
+                    && !self.written.contains_key(&cluster)
+                    && let Some(evicted) = self.evictable()
+                {
+                    // Only `KEYMAP.TXT` matters, other files can lose blocks
+                    self.written.remove(&evicted);
+                }
+                self.written
+                    .insert(cluster, *block)
+                    .map(|_| ())
+                    .map_err(|_| {
+                        warn!("No room for block {}", lba);
+                    })
+            }
+        }
+    }
+
+    fn writes_settled(&mut self) -> bool {
+        if self.fat.is_none() && self.root_dir.is_none() && self.written.is_empty() {
+            return false;
+        }
+        let changed = self.apply_keymap();
+        if changed {
+            // Start over with what the keymap is now
+            self.fat = None;
+            self.root_dir = None;
+            self.written.clear();
+        }
+        changed
+    }
+}
+
+/// Copies what fits in `part` of `text` from byte `start` on.
+fn copy_from(text: &[u8], start: usize, part: &mut [u8]) {
+    if let Some(text) = text.get(start..) {
+        let len = text.len().min(part.len());
+        part[..len].copy_from_slice(&text[..len]);
+    }
+}
+
+fn data_cluster(lba: u32) -> u16 {
+    (lba - FIRST_DATA_BLOCK) as u16 + FIRST_CLUSTER
+}
+
+fn fat_entry(fat: &[u8; BLOCK_SIZE], cluster: u16) -> u16 {
+    let offset = usize::from(cluster) * 3 / 2;
+    let pair = u16::from_le_bytes([fat[offset], fat[offset + 1]]);
+    if cluster.is_multiple_of(2) {
+        pair & 0xFFF
+    } else {
+        pair >> 4
+    }
+}
+
+fn set_fat_entry(fat: &mut [u8; BLOCK_SIZE], cluster: u16, value: u16) {
+    let offset = usize::from(cluster) * 3 / 2;
+    let pair = u16::from_le_bytes([fat[offset], fat[offset + 1]]);
+    let pair = if cluster.is_multiple_of(2) {
+        pair & 0xF000 | value
+    } else {
+        pair & 0x000F | value << 4
+    };
+    fat[offset..offset + 2].copy_from_slice(&pair.to_le_bytes());
+}
+
+/// The clusters of the chain starting at `first`, as [`cluster_bit`]s.
+fn clusters_of(fat: &[u8; BLOCK_SIZE], first: u16) -> u128 {
+    let mut clusters = 0;
+    let mut cluster = first;
+    while (FIRST_CLUSTER..FIRST_CLUSTER + CLUSTER_COUNT).contains(&cluster)
+        && clusters & cluster_bit(cluster) == 0
+    {
+        clusters |= cluster_bit(cluster);
+        cluster = fat_entry(fat, cluster);
+    }
+    clusters
+}
+
+/// A data cluster as a bit, the volume has fewer than 128.
+fn cluster_bit(cluster: u16) -> u128 {
+    1 << (cluster - FIRST_CLUSTER)
+}
+
+/// Links the clusters of a `len` bytes long file starting at `first`.
+fn chain(fat: &mut [u8; BLOCK_SIZE], first: u16, len: usize) {
+    let clusters = len.div_ceil(BLOCK_SIZE) as u16;
+    for cluster in first..first + clusters {
+        let next = if cluster + 1 == first + clusters {
+            FAT_END_OF_CHAIN
+        } else {
+            cluster + 1
+        };
+        set_fat_entry(fat, cluster, next);
+    }
+}
+
+fn dir_entry(entry: &mut [u8], name: &[u8; 11], attributes: u8, cluster: u16, len: usize) {
+    entry[..11].copy_from_slice(name);
+    entry[11] = attributes;
+    for date in [16, 18, 24] {
+        entry[date..date + 2].copy_from_slice(&FILE_DATE.to_le_bytes());
+    }
+    if len > 0 {
+        entry[26..28].copy_from_slice(&cluster.to_le_bytes());
+    }
+    entry[28..32].copy_from_slice(&(len as u32).to_le_bytes());
+}
@@ -0,0 +1,260 @@
+//! The keymap and settings as the text file the storage drive serves as
+//! `KEYMAP.TXT`:
+//!
+//! ```text
+//! debounce = 20
+//! wakeup = on
+//! queue = replay
+//!
+//! [layer 0]
+//! 1 = 0x001E
+//! ```
+//!
+//! Keys are named by their label and keycodes are QMK keycodes, decimal or
+//! `0x` hex. An `[ir]` section holds the IR codes by number, as
+//! [`ir::set_code`] takes them. Lines starting with `;` are comments. Keys,
+//! settings and IR codes missing from the file keep their value.
+
+use crate::ir::{self, IR_CODE_COUNT, IrCodes};
+use crate::keymap::{self, Keymap, LAYERS};
+use crate::keypad::{KEY_COUNT, KEY_LABELS, key_index};
+use crate::settings::{QueuePolicy, Setting};
+use core::fmt::{self, Write};
+use heapless::Vec;
+use keypad_logic::ir_protocol::CodeError;
+
+/// Most settings lines a file can have.
+const MAX_SETTINGS: usize = 16;
+/// Longest line a file can have, room for a raw IR code of the most pulses.
+const MAX_LINE: usize = 1024;
+
+/// Longest file [`render`] writes: the settings, the labels and keycodes of
+/// every layer, and a line for each IR code.
+pub const MAX_SIZE: usize = HEADER.len()
+    + "\r\ndebounce = 4294967295\r\nwakeup = off\r\nqueue = replay\r\n".len()
+    + LAYERS * ("\r\n[layer 0]\r\n".len() + KEY_COUNT * "\u{10FFFF} = 0xFFFF\r\n".len())
+    + "\r\n[ir]\r\n".len()
+    + IR_CODE_COUNT * "15 = \r\n".len()
+    + ir::MAX_CODES_TEXT;
+
+const HEADER: &str = "\
+; Keypad HID keymap and settings\r
+;\r
+; Edit and save this file to change them, problems are listed in ERRORS.TXT.\r
+; Keycodes are QMK keycodes, in decimal or 0x hex. Transparent is 0x0001.\r
+";
+
+/// Writes the current keymap and settings.
+pub fn render(out: &mut impl Write) -> fmt::Result {
+    out.write_str(HEADER)?;
+    write!(out, "\r\ndebounce = {}\r\n", Setting::DebounceMs.get())?;
+    let wakeup = if Setting::RemoteWakeup.get() != 0 {
+        "on"
+    } else {
+        "off"
+    };
+    write!(out, "wakeup = {}\r\n", wakeup)?;
+    let queue = match QueuePolicy::get() {
+        QueuePolicy::Replay => "replay",
+        QueuePolicy::Drop => "drop",
+    };
+    write!(out, "queue = {}\r\n", queue)?;
+
+    let keymap = keymap::keymap();
+    for (layer, keycodes) in keymap.iter().enumerate() {
+        write!(out, "\r\n[layer {}]\r\n", layer)?;
+        for (label, keycode) in KEY_LABELS.iter().zip(keycodes) {
+            write!(out, "{} = 0x{:04X}\r\n", label, keycode)?;
+        }
+    }
+
+    out.write_str("\r\n[ir]\r\n")?;
+    for index in (0..IR_CODE_COUNT).filter(|&index| ir::is_set(index)) {
+        write!(out, "{} = ", index)?;
+        ir::write_code(index, out)?;
+        out.write_str("\r\n")?;
+    }
+    Ok(())
+}
+
+/// Reads an edited file as it comes, in pieces of any size, so it is never
+/// held whole. It is applied if it has no errors, otherwise they are listed
+/// and nothing changes.
+pub struct Parser {
+    keymap: Keymap,
+    settings: Vec<(Setting, u32), MAX_SETTINGS>,
+    ir_codes: IrCodes,
+    /// The line so far.
+    line: Vec<u8, MAX_LINE>,
+    too_long: bool,
+    number: usize,
+    layer: usize,
+    in_ir: bool,
+    ok: bool,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self {
+            keymap: keymap::keymap(),
+            settings: Vec::new(),
+            ir_codes: IrCodes::current(),
+            line: Vec::new(),
+            too_long: false,
+            number: 0,
+            layer: 0,
+            in_ir: false,
+            ok: true,
+        }
+    }
+
+    /// Takes the next piece of the file, listing the errors of the lines it
+    /// ends in `errors`.
+    pub fn feed(&mut self, text: &[u8], errors: &mut impl Write) -> fmt::Result {
+        for &byte in text {
+            if byte == b'\n' {
+                self.end_line(errors)?;
+            } else if self.line.push(byte).is_err() {
+                self.too_long = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Ends the file and applies it if it has no errors. Returns whether it
+    /// was applied.
+    pub fn finish(mut self, errors: &mut impl Write) -> Result<bool, fmt::Error> {
+        if !self.line.is_empty() || self.too_long {
+            self.end_line(errors)?;
+        }
+        if self.ok {
+            keymap::set_keymap(self.keymap);
+            for (setting, value) in self.settings {
+                setting.set(value);
+            }
+            self.ir_codes.save();
+        }
+        Ok(self.ok)
+    }
+
+    fn end_line(&mut self, errors: &mut impl Write) -> fmt::Result {
+        self.number += 1;
+        let line = core::mem::take(&mut self.line);
+        if core::mem::take(&mut self.too_long) {
+            writeln!(errors, "Line {}: too long\r", self.number)?;
+            self.ok = false;
+            return Ok(());
+        }
+        let Ok(line) = core::str::from_utf8(&line) else {
+            writeln!(errors, "Line {}: not valid UTF-8 text\r", self.number)?;
+            self.ok = false;
+            return Ok(());
+        };
+        if let Err(error) = self.parse_line(line.trim()) {
+            writeln!(errors, "Line {}: {}\r", self.number, error)?;
+            self.ok = false;
+        }
+        Ok(())
+    }
+
+    fn parse_line<'a>(&mut self, line: &'a str) -> Result<(), LineError<'a>> {
+        if line.is_empty() || line.starts_with(';') {
+            return Ok(());
+        }
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            self.in_ir = section == "ir";
+            if !self.in_ir {
+                self.layer = section
+                    .strip_prefix("layer")
+                    .and_then(|n| n.trim().parse::<usize>().ok())
+                    .filter(|&n| n < LAYERS)
+                    .ok_or(LineError::Section(section))?;
+            }
+            return Ok(());
+        }
+        let (name, value) = line
+            .split_once('=')
+            .map(|(n, v)| (n.trim(), v.trim()))
+            .ok_or(LineError::NotNameValue)?;
+
+        if self.in_ir {
+            let index = name
+                .parse::<usize>()
+                .ok()
+                .filter(|&n| n < IR_CODE_COUNT)
+                .ok_or(LineError::IrIndex(name))?;
+            return self.ir_codes.set(index, value).map_err(LineError::IrCode);
+        }
+
+        let setting = match name {
+            "debounce" => Some((Setting::DebounceMs, value.parse::<u32>().ok())),
+            "wakeup" => Some((
+                Setting::RemoteWakeup,
+                match value {
+                    "on" => Some(1),
+                    "off" => Some(0),
+                    _ => None,
+                },
+            )),
+            "queue" => Some((
+                Setting::QueuePolicy,
+                match value {
+                    "replay" => Some(QueuePolicy::Replay as u32),
+                    "drop" => Some(QueuePolicy::Drop as u32),
+                    _ => None,
+                },
+            )),
+            _ => None,
+        };
+        if let Some((setting, parsed)) = setting {
+            let parsed = parsed.ok_or(LineError::Setting(name, value))?;
+            return self
+                .settings
+                .push((setting, parsed))
+                .map_err(|_| LineError::TooManySettings);
+        }
+
+        let mut chars = name.chars();
+        let key = chars
+            .next()
+            .filter(|_| chars.next().is_none())
+            .and_then(key_index)
+            .ok_or(LineError::Name(name))?;
+        self.keymap[self.layer][key] = parse_keycode(value).ok_or(LineError::Keycode(value))?;
+        Ok(())
+    }
+}
+
+/// What is wrong with a line.
+enum LineError<'a> {
+    Section(&'a str),
+    NotNameValue,
+    IrIndex(&'a str),
+    IrCode(CodeError),
+    Setting(&'a str, &'a str),
+    TooManySettings,
+    Name(&'a str),
+    Keycode(&'a str),
+}
+
+impl fmt::Display for LineError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Section(section) => write!(f, "unknown section [{}]", section),
+            Self::NotNameValue => f.write_str("expected 'name = value'"),
+            Self::IrIndex(name) => write!(f, "unknown IR code '{}'", name),
+            Self::IrCode(e) => write!(f, "invalid IR code: {}", e),
+            Self::Setting(name, value) => write!(f, "invalid {} '{}'", name, value),
+            Self::TooManySettings => f.write_str("too many settings"),
+            Self::Name(name) => write!(f, "unknown key or setting '{}'", name),
+            Self::Keycode(value) => write!(f, "invalid keycode '{}'", value),
+        }
+    }
+}
+
+fn parse_keycode(text: &str) -> Option<u16> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
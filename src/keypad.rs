@@ -1,106 +1,92 @@
+use core::sync::atomic::{AtomicU16, Ordering};
+use embassy_time::{Duration, block_for};
 use embedded_hal::digital::{InputPin, OutputPin, PinState};
 
-pub struct Keypad4x4<T, U>
+pub use crate::keypad_config::{COLUMNS, KEY_COUNT, KEY_LABELS, ROWS};
+
+/// Keys seen pressed by the last [`Keypad::scan`].
+pub static LAST_SCAN: AtomicU16 = AtomicU16::new(0);
+
+pub fn key_index(label: char) -> Option<usize> {
+    KEY_LABELS
+        .iter()
+        .position(|&key| key == label.to_ascii_uppercase())
+}
+
+/// The bit of the key with the given label in a [`Keypad::scan`], for labels
+/// `build.rs` makes `keypad.toml` have.
+pub const fn key(label: char) -> u16 {
+    let mut key = 0;
+    while key < KEY_COUNT {
+        if KEY_LABELS[key] == label {
+            return 1 << key;
+        }
+        key += 1;
+    }
+    panic!("keypad.toml has no key with this label");
+}
+
+/// Whether the key with the given label is set in a [`Keypad::scan`].
+pub fn is_held(keys: u16, label: char) -> bool {
+    key_index(label).is_some_and(|key| keys & (1 << key) != 0)
+}
+
+pub struct Keypad<T, U>
 where
     T: InputPin,
     U: OutputPin,
 {
-    rows: [T; 4],
-    columns: [U; 4],
+    rows: [T; ROWS],
+    columns: [U; COLUMNS],
 }
 
-impl<T, U> Keypad4x4<T, U>
+impl<T, U> Keypad<T, U>
 where
     T: InputPin,
     U: OutputPin,
 {
-    pub fn new(mut rows: [T; 4], columns: [U; 4]) -> Keypad4x4<T, U> {
-        if rows.iter_mut().any(|row| row.is_high().unwrap()) {
+    pub fn new(rows: [T; ROWS], columns: [U; COLUMNS]) -> Keypad<T, U> {
+        let mut keypad = Self { rows, columns };
+        // Release the columns first, so a key held during boot doesn't look
+        // like a missing pull-down
+        keypad.set_outputs(PinState::Low);
+        block_for(Duration::from_micros(10));
+        if keypad.rows.iter_mut().any(|row| row.is_high().unwrap()) {
             panic!("Input pins should be pulled low");
         }
-        Self { rows, columns }
-    }
-
-    pub fn key_0(&mut self) -> PinState {
-        self.check_key_state(Key::Num0)
-    }
-
-    pub fn key_1(&mut self) -> PinState {
-        self.check_key_state(Key::Num1)
-    }
-
-    pub fn key_2(&mut self) -> PinState {
-        self.check_key_state(Key::Num2)
-    }
-
-    pub fn key_3(&mut self) -> PinState {
-        self.check_key_state(Key::Num3)
-    }
-
-    pub fn key_4(&mut self) -> PinState {
-        self.check_key_state(Key::Num4)
-    }
-
-    pub fn key_5(&mut self) -> PinState {
-        self.check_key_state(Key::Num5)
-    }
-
-    pub fn key_6(&mut self) -> PinState {
-        self.check_key_state(Key::Num6)
-    }
-
-    pub fn key_7(&mut self) -> PinState {
-        self.check_key_state(Key::Num7)
-    }
-
-    pub fn key_8(&mut self) -> PinState {
-        self.check_key_state(Key::Num8)
-    }
-
-    pub fn key_9(&mut self) -> PinState {
-        self.check_key_state(Key::Num9)
-    }
-
-    pub fn key_a(&mut self) -> PinState {
-        self.check_key_state(Key::A)
-    }
-
-    pub fn key_b(&mut self) -> PinState {
-        self.check_key_state(Key::B)
-    }
-
-    pub fn key_c(&mut self) -> PinState {
-        self.check_key_state(Key::C)
-    }
-
-    pub fn key_d(&mut self) -> PinState {
-        self.check_key_state(Key::D)
-    }
-
-    pub fn key_star(&mut self) -> PinState {
-        self.check_key_state(Key::Star)
+        keypad.set_outputs(PinState::High);
+        keypad
+    }
+
+    /// Scans the whole matrix and returns one bit per key, numbered row by row
+    /// from the top left as the labels in `keypad.toml`, by default
+    /// `1 2 3 A / 4 5 6 B / 7 8 9 C / * 0 # D`.
+    pub fn scan(&mut self) -> u16 {
+        let mut keys = 0;
+        for column_index in 0..self.columns.len() {
+            self.set_outputs(PinState::Low);
+            self.columns[column_index].set_high().unwrap();
+            for (row_index, row) in self.rows.iter_mut().enumerate() {
+                if row.is_high().unwrap() {
+                    keys |= 1 << (row_index * COLUMNS + column_index);
+                }
+            }
+        }
+        self.set_outputs(PinState::High);
+        LAST_SCAN.store(keys, Ordering::Relaxed);
+        keys
     }
 
-    pub fn key_pound(&mut self) -> PinState {
-        self.check_key_state(Key::Pound)
+    /// Drives every column low, so held keys no longer pull up the interrupt
+    /// line, e.g. while it is used as an output.
+    pub fn release_interrupt(&mut self) {
+        self.set_outputs(PinState::Low);
     }
 
-    fn check_key_state(&mut self, key: Key) -> PinState {
-        let key_position = key.get_indexes();
-        self.set_outputs(PinState::Low);
-        self.columns
-            .get_mut(key_position.column_index)
-            .expect("Invalid column index")
-            .set_high()
-            .unwrap();
-        let is_pressed = self
-            .rows
-            .get_mut(key_position.row_index)
-            .expect("Invalid row index")
-            .is_high()
-            .unwrap();
+    /// Drives every column high again, so any key press pulls up the
+    /// interrupt line.
+    pub fn arm_interrupt(&mut self) {
         self.set_outputs(PinState::High);
-        is_pressed.into()
     }
 
     fn set_outputs(&mut self, state: PinState) {
@@ -110,98 +96,3 @@ where
         });
     }
 }
-
-struct KeyPosition {
-    row_index: usize,
-    column_index: usize,
-}
-
-enum Key {
-    Num0,
-    Num1,
-    Num2,
-    Num3,
-    Num4,
-    Num5,
-    Num6,
-    Num7,
-    Num8,
-    Num9,
-    A,
-    B,
-    C,
-    D,
-    Star,
-    Pound,
-}
-
-impl Key {
-    fn get_indexes(self) -> KeyPosition {
-        match self {
-            Key::Num1 => KeyPosition {
-                row_index: 0,
-                column_index: 0,
-            },
-            Key::Num2 => KeyPosition {
-                row_index: 0,
-                column_index: 1,
-            },
-            Key::Num3 => KeyPosition {
-                row_index: 0,
-                column_index: 2,
-            },
-            Key::A => KeyPosition {
-                row_index: 0,
-                column_index: 3,
-            },
-            Key::Num4 => KeyPosition {
-                row_index: 1,
-                column_index: 0,
-            },
-            Key::Num5 => KeyPosition {
-                row_index: 1,
-                column_index: 1,
-            },
-            Key::Num6 => KeyPosition {
-                row_index: 1,
-                column_index: 2,
-            },
-            Key::B => KeyPosition {
-                row_index: 1,
-                column_index: 3,
-            },
-            Key::Num7 => KeyPosition {
-                row_index: 2,
-                column_index: 0,
-            },
-            Key::Num8 => KeyPosition {
-                row_index: 2,
-                column_index: 1,
-            },
-            Key::Num9 => KeyPosition {
-                row_index: 2,
-                column_index: 2,
-            },
-            Key::C => KeyPosition {
-                row_index: 2,
-                column_index: 3,
-            },
-            Key::Star => KeyPosition {
-                row_index: 3,
-                column_index: 0,
-            },
-            Key::Num0 => KeyPosition {
-                row_index: 3,
-                column_index: 1,
-            },
-            Key::Pound => KeyPosition {
-                row_index: 3,
-                column_index: 2,
-            },
-            Key::D => KeyPosition {
-                row_index: 3,
-                column_index: 3,
-            },
-        }
-    }
-}
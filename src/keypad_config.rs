@@ -0,0 +1,4 @@
+//! The matrix, keypad pins, default keymap, USB identity, timing and MIDI map,
+//! generated by `build.rs` from `keypad.toml`.
+
+include!(concat!(env!("OUT_DIR"), "/keypad_config.rs"));
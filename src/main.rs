@@ -2,37 +2,130 @@
 #![no_main]
 
 mod board_pinout;
+#[cfg(feature = "board-stm32f401-blackpill")]
+mod board_stm32f401_blackpill;
+#[cfg(feature = "board-stm32f407-discovery")]
+mod board_stm32f407_discovery;
+#[cfg(feature = "board-stm32f411-blackpill")]
+mod board_stm32f411_blackpill;
+mod clock_plan;
+mod config_protocol;
+mod console;
+mod device_mode;
+mod dfu;
+mod feedback;
+mod hid_class;
+mod hid_report;
+mod ir;
+mod keyboard;
+mod keymap;
+mod keymap_drive;
+mod keymap_file;
 mod keypad;
+mod keypad_config;
+mod midi;
+mod msc;
+mod profile;
+mod reboot;
+mod remap;
+mod settings;
 mod stm32_configuration;
+mod suspend;
+#[cfg(feature = "ab-updates")]
+mod update;
+mod usb_identity;
 mod usb_keyboard;
+mod vendor;
+mod via;
+mod watchdog;
 
-use crate::board_pinout::Board;
-use crate::keypad::Keypad4x4;
+use crate::board_pinout::{BoardSupport, Led, SelectedBoard};
+use crate::config_protocol::REPORT_SIZE;
+use crate::console::Console;
+use crate::device_mode::{ConfigChannel, DeviceMode};
+use crate::feedback::Feedback;
+use crate::hid_class::{PROTOCOL_CHANGED, Protocol, ReportReader};
+use crate::hid_report::ReportWriter;
+use crate::ir::{IrRemote, IrTransmitter};
+use crate::keyboard::{Action, KeyQueue, Keyboard, KeyboardState, MacroStep, MacroSteps};
+use crate::keymap::MACRO_BUFFER_SIZE;
+use crate::keymap_drive::KeymapDrive;
+use crate::keypad::{KEY_COUNT, KEY_LABELS, Keypad, key};
+use crate::midi::{MidiMap, MidiPlayer};
+use crate::msc::MassStorage;
+use crate::settings::{QueuePolicy, Store, debounce};
 use crate::stm32_configuration::UsbDriverConfig;
-use crate::usb_keyboard::{UsbKeyboard, UsbKeyboardRequestHandler};
+use crate::usb_keyboard::{
+    CONFIGURED_CHANGED, ConfigInterface, GamepadReport, KEYBOARD_LED_CAPS_LOCK,
+    KEYBOARD_LED_COMPOSE, KEYBOARD_LED_KANA, KEYBOARD_LED_NUM_LOCK, KEYBOARD_LED_SCROLL_LOCK,
+    KEYBOARD_LEDS_CHANGED, RawHidRequestHandler, TELEPHONY_FLASH, TELEPHONY_HOOK_SWITCH,
+    TELEPHONY_LED_OFF_HOOK, TELEPHONY_LEDS, TELEPHONY_PHONE_MUTE, TELEPHONY_REDIAL, UsbFunction,
+    UsbKeyboard, UsbKeyboardRequestHandler,
+};
+use crate::vendor::VendorInterface;
+use crate::watchdog::Task;
+use core::sync::atomic::Ordering;
 use defmt::{debug, info, warn};
-use embassy_executor::Spawner;
+use embassy_executor::{InterruptExecutor, Spawner};
+use embassy_futures::select::{Either, select, select4};
 use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::flash::Flash;
 use embassy_stm32::gpio::{Input, Output};
-use embassy_stm32::peripherals::USB_OTG_FS;
+use embassy_stm32::init;
+use embassy_stm32::interrupt;
+use embassy_stm32::interrupt::{InterruptExt, Priority};
+use embassy_stm32::peripherals::{IWDG, USB_OTG_FS};
 use embassy_stm32::usb::Driver;
-use embassy_stm32::{Config, init};
-use embassy_time::Timer;
+use embassy_time::{TimeoutError, Timer, with_timeout};
 use embassy_usb::UsbDevice;
-use embassy_usb::class::hid::{HidReader, HidWriter};
+use embassy_usb::class::cdc_acm::CdcAcmClass;
+use embassy_usb::class::hid::{HidReader, HidWriter, ReadError, ReportId, RequestHandler};
+use embassy_usb::class::midi::MidiClass;
 use static_cell::StaticCell;
-use stm32_configuration::UsbConfiguration;
-use usbd_hid::descriptor::{KeyboardReport, KeyboardUsage};
 use {defmt_rtt as _, panic_probe as _};
 
 static USB_DRIVER_CONFIG: StaticCell<UsbDriverConfig> = StaticCell::new();
 static USB_KEYBOARD_CONFIG: StaticCell<usb_keyboard::Config> = StaticCell::new();
+static KEYMAP_DRIVE: StaticCell<KeymapDrive> = StaticCell::new();
+#[cfg(feature = "ab-updates")]
+static UPDATE_USB_CONFIG: StaticCell<update::UsbConfig> = StaticCell::new();
+
+/// Runs the IR transmitter above every other task, so its marks and spaces
+/// keep their length. It runs on the interrupt of SPI3, which every board
+/// has and none uses.
+static IR_EXECUTOR: InterruptExecutor = InterruptExecutor::new();
+
+#[interrupt]
+unsafe fn SPI3() {
+    unsafe { IR_EXECUTOR.on_interrupt() }
+}
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
-    info!("Start main");
-    let peripherals = init(Config::usb_configuration());
-    let board = Board::new(peripherals);
+    reboot::enter_bootloader_if_requested();
+    info!("Start main on {}", SelectedBoard::NAME);
+    let reset_cause = watchdog::check_reset_cause();
+    if matches!(reset_cause, watchdog::ResetCause::Watchdog(_)) {
+        warn!("Last reset: {}", reset_cause);
+    } else {
+        info!("Last reset: {}", reset_cause);
+    }
+    let peripherals = init(SelectedBoard::config());
+    let board = SelectedBoard::board(peripherals);
+
+    let flash = Flash::new_blocking(board.flash).into_blocking_regions();
+    info!("Restore settings");
+    let store = settings::restore(flash.bank1_region1);
+
+    info!("Create keypad I/O");
+    let mut keypad = Keypad::new(board.keypad_rows, board.keypad_columns);
+    let boot_keys = keypad.scan();
+    let mode = DeviceMode::from_boot_keys(boot_keys);
+    let config_channel = ConfigChannel::from_boot_keys(boot_keys);
+    info!(
+        "Device mode: {}, configured through: {}",
+        mode, config_channel
+    );
 
     info!("Create USB Driver");
     let usb_driver_config = USB_DRIVER_CONFIG.init(UsbDriverConfig::new());
@@ -46,154 +139,791 @@ async fn main(spawner: Spawner) {
     );
 
     info!("Create USB keyboard device");
-    let usb_keyboard_config = USB_KEYBOARD_CONFIG.init(usb_keyboard::Config::new());
-    let usb_keyboard = UsbKeyboard::new(usb_keyboard_config, usb_driver);
+    let usb_identity = usb_identity::load();
+    info!(
+        "USB identity {=u16:04x}:{=u16:04x}, serial number {}",
+        usb_identity.vid,
+        usb_identity.pid,
+        usb_identity.serial_number()
+    );
+    #[cfg(feature = "ab-updates")]
+    if reboot::update_mode_requested() {
+        info!("Start update mode");
+        let usb_config = UPDATE_USB_CONFIG.init(update::UsbConfig::new(usb_identity));
+        spawner
+            .spawn(usb_run(update::usb_device(usb_config, usb_driver)))
+            .unwrap();
+        spawner
+            .spawn(write_download(
+                update::state_partition(flash.bank1_region2),
+                update::dfu_partition(flash.bank1_region3),
+            ))
+            .unwrap();
+        return;
+    }
 
-    info!("Create keypad I/O");
-    let keypad = Keypad4x4::new(board.keypad_rows, board.keypad_columns);
+    let usb_keyboard_config = USB_KEYBOARD_CONFIG.init(usb_keyboard::Config::new(usb_identity));
+    let usb_keyboard = UsbKeyboard::new(usb_keyboard_config, usb_driver, mode, config_channel);
 
     spawner.spawn(usb_run(usb_keyboard.usb)).unwrap();
+    spawner.spawn(dfu_detach()).unwrap();
+    if let Some(store) = store {
+        profile::enable_switching();
+        spawner.spawn(save_settings(store)).unwrap();
+    }
+    match usb_keyboard.config_interface {
+        ConfigInterface::Serial(serial) => spawner.spawn(serial_console(serial, mode)).unwrap(),
+        ConfigInterface::RawHid(hid_reader, hid_writer) => {
+            spawner
+                .spawn(config_read(
+                    hid_reader,
+                    usb_keyboard.raw_hid_request_handler,
+                ))
+                .unwrap();
+            spawner.spawn(config_write(hid_writer)).unwrap();
+        }
+        ConfigInterface::Vendor(vendor) => spawner.spawn(vendor_config(vendor, mode)).unwrap(),
+        ConfigInterface::Storage(msc) => {
+            let drive = KEYMAP_DRIVE.init(KeymapDrive::new());
+            spawner.spawn(serve_keymap_drive(msc, drive)).unwrap()
+        }
+    }
+    match usb_keyboard.function {
+        UsbFunction::Hid(hid_reader, hid_writer) => {
+            spawner
+                .spawn(hid_read(hid_reader, usb_keyboard.request_handler))
+                .unwrap();
+            let keypad_interrupt = board.keypad_interrupt;
+            match mode {
+                DeviceMode::Gamepad => spawner
+                    .spawn(report_buttons(hid_writer, keypad, keypad_interrupt))
+                    .unwrap(),
+                DeviceMode::Telephony => spawner
+                    .spawn(report_phone_keys(hid_writer, keypad, keypad_interrupt))
+                    .unwrap(),
+                _ => {
+                    interrupt::SPI3.set_priority(Priority::P6);
+                    let ir_spawner = IR_EXECUTOR.start(interrupt::SPI3);
+                    ir_spawner
+                        .spawn(send_ir(IrTransmitter::new(board.ir_timer)))
+                        .unwrap();
+                    spawner
+                        .spawn(report_keystrokes(hid_writer, keypad, keypad_interrupt))
+                        .unwrap();
+                    spawner.spawn(show_keyboard_leds(board.leds)).unwrap();
+                }
+            }
+        }
+        UsbFunction::Midi(midi) => spawner
+            .spawn(play_midi(midi, keypad, board.keypad_interrupt))
+            .unwrap(),
+    }
+    spawner.spawn(supervise_tasks(board.watchdog)).unwrap();
+    #[cfg(feature = "ab-updates")]
     spawner
-        .spawn(hid_read(
-            usb_keyboard.hid_reader,
-            usb_keyboard.request_handler,
-        ))
-        .unwrap();
-    spawner
-        .spawn(report_keystrokes(
-            usb_keyboard.hid_writer,
-            keypad,
-            board.keypad_interrupt,
-        ))
+        .spawn(confirm_update(update::state_partition(flash.bank1_region2)))
         .unwrap();
 }
 
 #[embassy_executor::task]
 async fn usb_run(mut usb: UsbDevice<'static, Driver<'static, USB_OTG_FS>>) {
     info!("Start 'USB Run' task");
-    usb.run().await;
+    // Checks in from the bus events, see `UsbKeyboardDeviceHandler`
+    watchdog::supervise(Task::Usb);
+    loop {
+        usb.run_until_suspend().await;
+        match select(usb.wait_resume(), suspend::WAKEUP_REQUESTED.wait()).await {
+            Either::First(()) => {}
+            Either::Second(()) => match usb.remote_wakeup().await {
+                Ok(()) => {}
+                Err(e) => warn!("Remote wakeup failed: {:?}", e),
+            },
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn supervise_tasks(iwdg: IWDG) {
+    info!("Start 'Supervise Tasks' task");
+    watchdog::run(iwdg).await;
+}
+
+#[embassy_executor::task]
+async fn dfu_detach() {
+    dfu::DETACH_REQUESTED.wait().await;
+    info!("Restarting for a firmware update");
+    // Let the host see the request answered before the device goes away
+    Timer::after_millis(50).await;
+    #[cfg(feature = "ab-updates")]
+    reboot::reboot_to_update_mode();
+    #[cfg(not(feature = "ab-updates"))]
+    reboot::reboot_to_bootloader();
+}
+
+#[cfg(feature = "ab-updates")]
+#[embassy_executor::task]
+async fn write_download(state: update::StatePartition, dfu: update::DfuPartition) {
+    info!("Start 'Write Download' task");
+    update::write_download(state, dfu).await;
+}
+
+#[cfg(feature = "ab-updates")]
+#[embassy_executor::task]
+async fn confirm_update(state: update::StatePartition) {
+    update::confirm(state).await;
+}
+
+#[embassy_executor::task]
+async fn serial_console(
+    serial: CdcAcmClass<'static, Driver<'static, USB_OTG_FS>>,
+    mode: DeviceMode,
+) {
+    info!("Start 'Serial Console' task");
+    watchdog::supervise(Task::Config);
+    Console::new(serial, mode).run().await;
+}
+
+#[embassy_executor::task]
+async fn config_read(
+    mut hid_reader: HidReader<'static, Driver<'static, USB_OTG_FS>, REPORT_SIZE>,
+    request_handler: &'static mut RawHidRequestHandler,
+) {
+    info!("Start 'Config Read' task");
+    watchdog::supervise(Task::Config);
+    let mut request = [0; REPORT_SIZE];
+    loop {
+        match watchdog::alive_while(Task::Config, hid_reader.read(&mut request)).await {
+            Ok(len) => {
+                request_handler.set_report(ReportId::Out(0), &request[..len]);
+            }
+            Err(ReadError::Disabled) => {
+                watchdog::alive_while(Task::Config, hid_reader.ready()).await
+            }
+            Err(e) => warn!("Failed to read configuration request: {:?}", e),
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn config_write(
+    mut hid_writer: HidWriter<'static, Driver<'static, USB_OTG_FS>, REPORT_SIZE>,
+) {
+    info!("Start 'Config Write' task");
+    watchdog::supervise(Task::ConfigReplies);
+    loop {
+        let reply =
+            watchdog::alive_while(Task::ConfigReplies, config_protocol::REPLIES.receive()).await;
+        match with_timeout(watchdog::HOST_TIMEOUT, hid_writer.write(&reply.report)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Failed to send configuration reply: {:?}", e),
+            Err(TimeoutError) => warn!("Configuration reply dropped, the host isn't reading them"),
+        };
+        watchdog::check_in(Task::ConfigReplies);
+
+        if reply.enter_bootloader {
+            info!("Restarting into the bootloader");
+            // Give the host a moment to fetch the reply
+            Timer::after_millis(50).await;
+            reboot::reboot_to_bootloader();
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn vendor_config(mut vendor: VendorInterface<'static>, mode: DeviceMode) {
+    info!("Start 'Vendor Config' task");
+    watchdog::supervise(Task::Config);
+    let mut request = [0; REPORT_SIZE];
+    loop {
+        let len = match watchdog::alive_while(Task::Config, vendor.read_request(&mut request)).await
+        {
+            Ok(len) => len,
+            Err(e) => {
+                warn!("Failed to read configuration request: {:?}", e);
+                continue;
+            }
+        };
+        let reply = config_protocol::dispatch(&request[..len], mode);
+        match with_timeout(watchdog::HOST_TIMEOUT, vendor.write_reply(&reply.report)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Failed to send configuration reply: {:?}", e),
+            Err(TimeoutError) => warn!("Configuration reply dropped, the host isn't reading them"),
+        }
+        watchdog::check_in(Task::Config);
+
+        if reply.enter_bootloader {
+            info!("Restarting into the bootloader");
+            // Give the host a moment to fetch the reply
+            Timer::after_millis(50).await;
+            reboot::reboot_to_bootloader();
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn serve_keymap_drive(mut msc: MassStorage<'static>, drive: &'static mut KeymapDrive) {
+    info!("Start 'Keymap Drive' task");
+    watchdog::supervise(Task::Config);
+    msc.run(drive).await;
 }
 
 #[embassy_executor::task]
 async fn hid_read(
-    hid_reader: HidReader<'static, Driver<'static, USB_OTG_FS>, 1>,
+    hid_reader: ReportReader<'static>,
     request_handler: &'static mut UsbKeyboardRequestHandler,
 ) {
     info!("Start 'HID Read' task");
-    hid_reader.run(false, request_handler).await;
+    watchdog::supervise(Task::HidRead);
+    hid_reader.run(request_handler).await;
+}
+
+#[embassy_executor::task]
+async fn save_settings(mut store: Store) {
+    info!("Start 'Save Settings' task");
+    settings::save_changes(&mut store).await;
+}
+
+#[embassy_executor::task]
+async fn send_ir(mut transmitter: IrTransmitter) {
+    info!("Start 'Send IR' task");
+    watchdog::supervise(Task::Ir);
+    transmitter.run().await;
 }
 
 #[embassy_executor::task]
 async fn report_keystrokes(
-    mut hid_writer: HidWriter<'static, Driver<'static, USB_OTG_FS>, 8>,
-    mut keypad: Keypad4x4<Input<'static>, Output<'static>>,
+    mut hid_writer: ReportWriter<'static>,
+    mut keypad: Keypad<Input<'static>, Output<'static>>,
     mut keypad_interrupt: ExtiInput<'static>,
 ) {
     info!("Start 'Report Key Strokes' task");
+    watchdog::supervise(Task::Keys);
+    let mut keyboard = Keyboard::new();
+    let mut queue = KeyQueue::new();
+    let mut remote = IrRemote::new();
+    let mut protocol = hid_class::protocol();
+    loop {
+        wait_for_keys(&mut keypad_interrupt, &mut hid_writer).await;
+
+        // Keep scanning while anything is held, so keys stay down for as long
+        // as they are pressed and chords work
+        loop {
+            watchdog::check_in(Task::Keys);
+            let keys = keypad.scan();
+            if !usb_keyboard::is_configured() {
+                if queue_scan(&mut queue, keys, keyboard.held()).await {
+                    continue;
+                }
+                break;
+            }
+
+            flush_queue(&mut queue, async |keys| {
+                report_keys(
+                    &mut keyboard,
+                    &mut hid_writer,
+                    keys,
+                    &mut keypad,
+                    &mut remote,
+                )
+                .await
+            })
+            .await;
+            if keys != keyboard.held() {
+                protocol = hid_class::protocol();
+                report_keys(
+                    &mut keyboard,
+                    &mut hid_writer,
+                    keys,
+                    &mut keypad,
+                    &mut remote,
+                )
+                .await;
+
+                // Ignore the contacts bouncing after a change
+                debounce().await;
+            } else if hid_class::protocol() != protocol {
+                // The host expects the new report format from now on
+                protocol = hid_class::protocol();
+                send_keyboard_state(&mut hid_writer, &keyboard.state()).await;
+            } else if keys == 0 {
+                break;
+            } else {
+                remote.repeat_held(&mut keypad, &keyboard).await;
+                hid_writer.repeat_if_idle().await;
+                Timer::after_millis(10).await;
+            }
+        }
+    }
+}
+
+async fn report_keys(
+    keyboard: &mut Keyboard,
+    hid_writer: &mut ReportWriter<'static>,
+    keys: u16,
+    keypad: &mut Keypad<Input<'static>, Output<'static>>,
+    remote: &mut IrRemote,
+) {
+    let action = keyboard.update(keys);
+    debug!("keys: {=u16:016b}", keys);
+    send_keyboard_state(hid_writer, &keyboard.state()).await;
+    if keyboard.end_tap() {
+        send_keyboard_state(hid_writer, &keyboard.state()).await;
+    }
+
+    match action {
+        Some(Action::PlayMacro(index)) => {
+            play_macro(hid_writer, index).await;
+            send_keyboard_state(hid_writer, &keyboard.state()).await;
+        }
+        Some(Action::SendIr(index)) => remote.send(keypad, index, false).await,
+        Some(Action::SwitchProfile(index)) => {
+            if !profile::request_switch(index) {
+                feedback::show(Feedback::Rejected);
+            }
+        }
+        Some(Action::Remap) => {
+            // The chord wasn't typed, and nothing is while the keypad is
+            // being remapped
+            keyboard.release_all();
+            send_keyboard_state(hid_writer, &keyboard.state()).await;
+            remap::run(keypad).await;
+        }
+        None => {}
+    }
+}
+
+/// Holds on to a scan made while the device isn't configured, until the host
+/// can take it. Returns whether keys are still held, waiting out the bounce if
+/// the scan changed anything.
+async fn queue_scan(queue: &mut KeyQueue, keys: u16, held: u16) -> bool {
+    if queue.push(keys, held) {
+        debug!("queued keys: {=u16:016b}", keys);
+        debounce().await;
+        true
+    } else if keys == 0 {
+        false
+    } else {
+        Timer::after_millis(10).await;
+        true
+    }
+}
+
+/// Deals with the key presses made before the device was configured,
+/// according to the [`QueuePolicy`]: `report` gets every queued scan in
+/// order, or none of them.
+async fn flush_queue(queue: &mut KeyQueue, mut report: impl AsyncFnMut(u16)) {
+    if queue.is_empty() {
+        return;
+    }
+    if queue.overflowed() {
+        warn!("Too many key presses while not configured, some were lost");
+    }
+    match QueuePolicy::get() {
+        QueuePolicy::Replay => {
+            info!("Replaying {} queued key changes", queue.len());
+            while let Some(keys) = queue.pop() {
+                report(keys).await;
+            }
+        }
+        QueuePolicy::Drop => {
+            info!("Dropping {} queued key changes", queue.len());
+            queue.clear();
+        }
+    }
+}
+
+/// Waits for a key press, a protocol change or a change of the configured
+/// state, repeating the last report at the idle rate meanwhile.
+async fn wait_for_keys(
+    keypad_interrupt: &mut ExtiInput<'static>,
+    hid_writer: &mut ReportWriter<'static>,
+) {
+    let wait = select4(
+        wait_for_press(keypad_interrupt),
+        hid_writer.repeat_while_idle(),
+        PROTOCOL_CHANGED.wait(),
+        CONFIGURED_CHANGED.wait(),
+    );
+    watchdog::alive_while(Task::Keys, wait).await;
+}
+
+/// Waits for a key press, waking the host first if the bus is suspended.
+/// Presses the host can't be woken for are dropped.
+async fn wait_for_press(keypad_interrupt: &mut ExtiInput<'static>) {
     loop {
         keypad_interrupt.wait_for_high().await;
-        let keycodes = check_keypad_buttons(&mut keypad);
+        if suspend::wake_host().await {
+            return;
+        }
+        keypad_interrupt.wait_for_low().await;
+    }
+}
 
-        // Send the report
-        let report = KeyboardReport {
-            keycodes,
-            leds: 0,
-            modifier: 0,
-            reserved: 0,
-        };
-        match hid_writer.write_serialize(&report).await {
-            Ok(()) => {}
-            Err(e) => warn!("Failed to send report: {:?}", e),
-        };
+/// Mirrors the host's keyboard LEDs on whichever of them the board has, on
+/// the Discovery board num lock on green, caps lock on orange, scroll lock on
+/// red, and compose or kana on blue. All of them together show the
+/// [`Feedback`] of profile switches and the remap mode, which takes precedence.
+#[embassy_executor::task]
+async fn show_keyboard_leds(mut leds: [Option<Led>; 4]) {
+    info!("Start 'Show Keyboard LEDs' task");
+    watchdog::supervise(Task::Leds);
+    let mut state = 0;
+    let mut remapping = false;
+    loop {
+        let wait = select(KEYBOARD_LEDS_CHANGED.wait(), feedback::wait());
+        match watchdog::alive_while(Task::Leds, wait).await {
+            Either::First(changed) => state = changed,
+            Either::Second(feedback) => {
+                remapping = matches!(feedback, Feedback::Remapping | Feedback::KeyTaken);
+                show_feedback(&mut leds, feedback).await;
+            }
+        }
+        if remapping {
+            continue;
+        }
+        let lit = [
+            state & KEYBOARD_LED_NUM_LOCK != 0,
+            state & KEYBOARD_LED_CAPS_LOCK != 0,
+            state & KEYBOARD_LED_SCROLL_LOCK != 0,
+            state & (KEYBOARD_LED_COMPOSE | KEYBOARD_LED_KANA) != 0,
+        ];
+        set_leds(&mut leds, lit);
+    }
+}
+
+async fn show_feedback(leds: &mut [Option<Led>; 4], feedback: Feedback) {
+    let (flashes, on_ms, off_ms) = match feedback {
+        Feedback::Profile(profile) => (profile + 1, 200, 300),
+        Feedback::Remapping => (0, 0, 0),
+        Feedback::KeyTaken => {
+            set_leds(leds, [false; 4]);
+            Timer::after_millis(100).await;
+            (0, 0, 0)
+        }
+        Feedback::Accepted => (1, 1000, 300),
+        Feedback::Rejected => (5, 80, 80),
+    };
+    // Flashing a profile number takes a few rounds of the watchdog
+    for _ in 0..flashes {
+        set_leds(leds, [true; 4]);
+        watchdog::alive_while(Task::Leds, Timer::after_millis(on_ms)).await;
+        set_leds(leds, [false; 4]);
+        watchdog::alive_while(Task::Leds, Timer::after_millis(off_ms)).await;
+    }
+    if matches!(feedback, Feedback::Remapping | Feedback::KeyTaken) {
+        set_leds(leds, [true; 4]);
+    }
+}
+
+fn set_leds(leds: &mut [Option<Led>; 4], lit: [bool; 4]) {
+    for (led, lit) in leds.iter_mut().zip(lit) {
+        if let Some(led) = led {
+            led.set(lit);
+        }
+    }
+}
+
+async fn play_macro(hid_writer: &mut ReportWriter<'static>, index: usize) {
+    let mut buf = [0; MACRO_BUFFER_SIZE];
+    let len = keymap::copy_macro(index, &mut buf);
+    debug!("Playing macro {}: {=[u8]}", index, buf[..len]);
+
+    let mut held = heapless::Vec::<u16, 6>::new();
+    for step in MacroSteps::new(&buf[..len]) {
+        match step {
+            MacroStep::Press(keycode) => {
+                if held.push(keycode).is_err() {
+                    warn!("Macro {} holds too many keys", index);
+                }
+            }
+            MacroStep::Release(keycode) => held.retain(|&held| held != keycode),
+            MacroStep::DelayMs(ms) => {
+                watchdog::alive_while(Task::Keys, Timer::after_millis(ms.into())).await;
+                continue;
+            }
+        }
+        let state = KeyboardState::from_keycodes(held.iter().copied());
+        send_keyboard_state(hid_writer, &state).await;
+    }
+    if !held.is_empty() {
+        send_keyboard_state(hid_writer, &KeyboardState::from_keycodes([])).await;
+    }
+}
+
+/// Sends the keys in the layout of the protocol the host selected.
+async fn send_keyboard_state(hid_writer: &mut ReportWriter<'static>, state: &KeyboardState) {
+    let result = match hid_class::protocol() {
+        Protocol::Boot => hid_writer.write(&state.boot_report()).await,
+        Protocol::Report => hid_writer.write(&state.nkro_report()).await,
+    };
+    match result {
+        Ok(()) => {}
+        Err(e) => warn!("Failed to send report: {:?}", e),
+    };
+}
+
+#[embassy_executor::task]
+async fn report_buttons(
+    mut hid_writer: ReportWriter<'static>,
+    mut keypad: Keypad<Input<'static>, Output<'static>>,
+    mut keypad_interrupt: ExtiInput<'static>,
+) {
+    info!("Start 'Report Buttons' task");
+    watchdog::supervise(Task::Keys);
+    let mut reported = 0;
+    let mut queue = KeyQueue::new();
+    loop {
+        wait_for_keys(&mut keypad_interrupt, &mut hid_writer).await;
+
+        // Keep scanning while anything is held, so chords and releases of
+        // single buttons are reported too
+        loop {
+            watchdog::check_in(Task::Keys);
+            let buttons = keypad.scan();
+            if !usb_keyboard::is_configured() {
+                if queue_scan(&mut queue, buttons, reported).await {
+                    continue;
+                }
+                break;
+            }
+
+            flush_queue(&mut queue, async |buttons| {
+                send_buttons(&mut hid_writer, buttons, &mut reported).await
+            })
+            .await;
+            if buttons != reported {
+                send_buttons(&mut hid_writer, buttons, &mut reported).await;
+            }
+            if buttons == 0 {
+                break;
+            }
+            hid_writer.repeat_if_idle().await;
+            Timer::after_millis(10).await;
+        }
+    }
+}
+
+/// Sends a gamepad report of `buttons`, keeping them in `reported` once the
+/// host has them.
+async fn send_buttons(hid_writer: &mut ReportWriter<'static>, buttons: u16, reported: &mut u16) {
+    debug!("buttons: {=u16:016b}", buttons);
+    match hid_writer
+        .write_serialize(&GamepadReport {
+            buttons: buttons.to_le_bytes(),
+        })
+        .await
+    {
+        Ok(()) => *reported = buttons,
+        Err(e) => warn!("Failed to send report: {:?}", e),
+    };
+}
+
+#[embassy_executor::task]
+async fn report_phone_keys(
+    mut hid_writer: ReportWriter<'static>,
+    mut keypad: Keypad<Input<'static>, Output<'static>>,
+    mut keypad_interrupt: ExtiInput<'static>,
+) {
+    info!("Start 'Report Phone Keys' task");
+    watchdog::supervise(Task::Keys);
+    let mut phone = Phone::new();
+    let mut queue = KeyQueue::new();
+    loop {
+        wait_for_keys(&mut keypad_interrupt, &mut hid_writer).await;
 
-        // Send an empty report to avoid "sticky" keys
-        let report = KeyboardReport {
-            keycodes: [0, 0, 0, 0, 0, 0],
+        loop {
+            watchdog::check_in(Task::Keys);
+            let keys = keypad.scan();
+            if !usb_keyboard::is_configured() {
+                if queue_scan(&mut queue, keys, phone.held).await {
+                    continue;
+                }
+                break;
+            }
+
+            flush_queue(&mut queue, async |keys| {
+                phone.report(&mut hid_writer, keys).await
+            })
+            .await;
+            phone.report(&mut hid_writer, keys).await;
+            if keys == 0 {
+                break;
+            }
+            hid_writer.repeat_if_idle().await;
+            Timer::after_millis(10).await;
+        }
+    }
+}
+
+/// The hook state and the phone keys last reported.
+struct Phone {
+    off_hook: bool,
+    leds: u8,
+    held: u16,
+    reported: [u8; 2],
+}
+
+impl Phone {
+    const fn new() -> Self {
+        Self {
+            off_hook: false,
             leds: 0,
-            modifier: 0,
-            reserved: 0,
+            held: 0,
+            reported: [0; 2],
+        }
+    }
+
+    /// Reports a scan if it changed the phone keys or the hook state.
+    async fn report(&mut self, hid_writer: &mut ReportWriter<'static>, keys: u16) {
+        // Follow the host if it picked up or hung up on its own
+        let host_leds = TELEPHONY_LEDS.load(Ordering::Relaxed);
+        if host_leds != self.leds {
+            self.leds = host_leds;
+            self.off_hook = self.leds & TELEPHONY_LED_OFF_HOOK != 0;
+        }
+
+        // `A` picks up or hangs up
+        if keys & !self.held & HOOK_KEY != 0 {
+            self.off_hook = !self.off_hook;
+        }
+        self.held = keys;
+
+        let report = check_phone_keys(keys, self.off_hook);
+        if report != self.reported {
+            match hid_writer.write(&report).await {
+                Ok(()) => self.reported = report,
+                Err(e) => warn!("Failed to send report: {:?}", e),
+            };
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn play_midi(
+    mut midi: MidiClass<'static, Driver<'static, USB_OTG_FS>>,
+    mut keypad: Keypad<Input<'static>, Output<'static>>,
+    mut keypad_interrupt: ExtiInput<'static>,
+) {
+    info!("Start 'Play MIDI' task");
+    watchdog::supervise(Task::Keys);
+    let mut player = MidiPlayer::new(MidiMap::new());
+    let mut held = 0u16;
+    let mut queue = KeyQueue::new();
+    loop {
+        let wait = select(
+            wait_for_press(&mut keypad_interrupt),
+            CONFIGURED_CHANGED.wait(),
+        );
+        watchdog::alive_while(Task::Keys, wait).await;
+
+        loop {
+            watchdog::check_in(Task::Keys);
+            let pads = keypad.scan();
+            if !usb_keyboard::is_configured() {
+                if queue_scan(&mut queue, pads, held).await {
+                    continue;
+                }
+                break;
+            }
+
+            flush_queue(&mut queue, async |pads| {
+                play_pads(&mut midi, &mut player, &mut held, pads).await
+            })
+            .await;
+            if pads != held {
+                play_pads(&mut midi, &mut player, &mut held, pads).await;
+            }
+            if pads == 0 {
+                break;
+            }
+            Timer::after_millis(10).await;
+        }
+    }
+}
+
+/// Sends the events of the pads that changed since `held`, all in one bulk
+/// packet.
+async fn play_pads(
+    midi: &mut MidiClass<'static, Driver<'static, USB_OTG_FS>>,
+    player: &mut MidiPlayer,
+    held: &mut u16,
+    pads: u16,
+) {
+    let changed = pads ^ *held;
+    let mut packet = [0u8; 64];
+    let mut len = 0;
+    for pad in (0..KEY_COUNT).filter(|pad| changed & (1 << pad) != 0) {
+        let event = if pads & (1 << pad) != 0 {
+            player.press(pad)
+        } else {
+            player.release(pad)
         };
-        match hid_writer.write_serialize(&report).await {
-            Ok(()) => {}
-            Err(e) => warn!("Failed to send report: {:?}", e),
+        if let Some(event) = event {
+            packet[len..len + 4].copy_from_slice(&event);
+            len += 4;
+        }
+    }
+    debug!("pads: {=u16:016b}, octave: {}", pads, player.octave());
+    *held = pads;
+
+    // Without an application reading the port the host doesn't take the
+    // packet, so the events are dropped
+    if len > 0 {
+        match with_timeout(watchdog::HOST_TIMEOUT, midi.write_packet(&packet[..len])).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Failed to send MIDI events: {:?}", e),
+            Err(TimeoutError) => debug!("MIDI events dropped, the host isn't reading"),
         };
+    }
+}
+
+/// The keys of the phone buttons, see [`check_phone_keys`].
+const HOOK_KEY: u16 = key('A');
+const FLASH_KEY: u16 = key('B');
+const REDIAL_KEY: u16 = key('C');
+const MUTE_KEY: u16 = key('D');
 
-        Timer::after_millis(150).await;
-    }
-}
-
-fn check_keypad_buttons(keypad: &mut Keypad4x4<Input<'static>, Output<'static>>) -> [u8; 6] {
-    let keys = [
-        (
-            keypad.key_1().into(),
-            KeyboardUsage::Keyboard1Exclamation as u8,
-        ),
-        (keypad.key_2().into(), KeyboardUsage::Keyboard2At as u8),
-        (keypad.key_3().into(), KeyboardUsage::Keyboard3Hash as u8),
-        (keypad.key_a().into(), KeyboardUsage::KeyboardAa as u8),
-        (keypad.key_4().into(), KeyboardUsage::Keyboard4Dollar as u8),
-        (keypad.key_5().into(), KeyboardUsage::Keyboard5Percent as u8),
-        (keypad.key_6().into(), KeyboardUsage::Keyboard6Caret as u8),
-        (keypad.key_b().into(), KeyboardUsage::KeyboardBb as u8),
-        (
-            keypad.key_7().into(),
-            KeyboardUsage::Keyboard7Ampersand as u8,
-        ),
-        (
-            keypad.key_8().into(),
-            KeyboardUsage::Keyboard8Asterisk as u8,
-        ),
-        (
-            keypad.key_9().into(),
-            KeyboardUsage::Keyboard9OpenParens as u8,
-        ),
-        (keypad.key_c().into(), KeyboardUsage::KeyboardCc as u8),
-        (
-            keypad.key_star().into(),
-            KeyboardUsage::KeypadMultiply as u8,
-        ),
-        (
-            keypad.key_0().into(),
-            KeyboardUsage::Keyboard0CloseParens as u8,
-        ),
-        (
-            keypad.key_pound().into(),
-            KeyboardUsage::KeyboardDashUnderscore as u8,
-        ),
-        (keypad.key_d().into(), KeyboardUsage::KeyboardDd as u8),
+/// Builds a [`TELEPHONY_REPORT_DESCRIPTOR`] input report from a keypad scan.
+/// `A` is the hook switch, `B` flash, `C` redial and `D` phone mute.
+fn check_phone_keys(keys: u16, off_hook: bool) -> [u8; 2] {
+    // Phone key usages by label, offset by one so that 0 means no key
+    const PHONE_KEYS: [(char, u8); 12] = [
+        ('0', 1),
+        ('1', 2),
+        ('2', 3),
+        ('3', 4),
+        ('4', 5),
+        ('5', 6),
+        ('6', 7),
+        ('7', 8),
+        ('8', 9),
+        ('9', 10),
+        ('*', 11),
+        ('#', 12),
     ];
 
-    // Fill keycodes with up to 6 pressed keys
-    let mut keycodes: [u8; 6] = [0; 6];
-    for (i, (_, code)) in keys
-        .iter()
-        .filter(|(pressed, _)| *pressed)
-        .take(6)
-        .enumerate()
-    {
-        keycodes[i] = *code;
-    }
-
-    debug!(
-        "\nkey 1: {}\nkey 2: {}\nkey 3: {}\nkey A: {}\nkey 4: {}\nkey 5: {}\nkey 6: {}\nkey B: {}\nkey 7: {}\nkey 8: {}\nkey 9: {}\nkey C: {}\nkey *: {}\nkey 0: {}\nkey #: {}\nkey D: {}",
-        keys[0].0,  // key 1
-        keys[1].0,  // key 2
-        keys[2].0,  // key 3
-        keys[3].0,  // key A
-        keys[4].0,  // key 4
-        keys[5].0,  // key 5
-        keys[6].0,  // key 6
-        keys[7].0,  // key B
-        keys[8].0,  // key 7
-        keys[9].0,  // key 8
-        keys[10].0, // key 9
-        keys[11].0, // key C
-        keys[12].0, // key *
-        keys[13].0, // key 0
-        keys[14].0, // key #
-        keys[15].0, // key D
-    );
-    debug!("keycodes: {}", keycodes);
+    let mut buttons = 0;
+    if off_hook {
+        buttons |= TELEPHONY_HOOK_SWITCH;
+    }
+    if keys & FLASH_KEY != 0 {
+        buttons |= TELEPHONY_FLASH;
+    }
+    if keys & REDIAL_KEY != 0 {
+        buttons |= TELEPHONY_REDIAL;
+    }
+    if keys & MUTE_KEY != 0 {
+        buttons |= TELEPHONY_PHONE_MUTE;
+    }
+
+    let phone_key = (0..KEY_COUNT)
+        .filter(|key| keys & (1 << key) != 0)
+        .find_map(|key| {
+            PHONE_KEYS
+                .iter()
+                .find(|&&(label, _)| label == KEY_LABELS[key])
+        })
+        .map_or(0, |&(_, usage)| usage);
 
-    keycodes
+    [buttons, phone_key]
 }
@@ -0,0 +1,115 @@
+use crate::keypad::KEY_COUNT;
+use crate::keypad_config::{MIDI_CHANNEL, MIDI_PADS, MIDI_VELOCITY};
+use defmt::Format;
+
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const CONTROL_CHANGE: u8 = 0xB0;
+const RELEASE_VELOCITY: u8 = 0x40;
+const MAX_OCTAVE_SHIFT: i8 = 4;
+
+/// What a single pad does in MIDI mode.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum PadAction {
+    /// Plays the note, transposed by the current octave shift.
+    Note(u8),
+    /// Sends `value` on press and 0 on release, like a sustain pedal.
+    ControlChange {
+        controller: u8,
+        value: u8,
+    },
+    OctaveDown,
+    OctaveUp,
+}
+
+/// Maps the pads, in [`Keypad::scan`] order, to MIDI messages.
+///
+/// [`Keypad::scan`]: crate::keypad::Keypad::scan
+pub struct MidiMap {
+    /// MIDI channel, 0-15.
+    pub channel: u8,
+    pub velocity: u8,
+    pub pads: [PadAction; KEY_COUNT],
+}
+
+impl MidiMap {
+    /// The map in the `[midi]` section of `keypad.toml`.
+    pub const fn new() -> Self {
+        Self {
+            channel: MIDI_CHANNEL,
+            velocity: MIDI_VELOCITY,
+            pads: MIDI_PADS,
+        }
+    }
+}
+
+/// A USB MIDI event packet: cable number and code index, then the MIDI
+/// message itself.
+pub type EventPacket = [u8; 4];
+
+/// Turns pad presses and releases into MIDI event packets.
+pub struct MidiPlayer {
+    map: MidiMap,
+    octave: i8,
+    // Notes have to be released with the pitch they were started with, even
+    // if the octave changed while they were held
+    sounding: [Option<u8>; KEY_COUNT],
+}
+
+impl MidiPlayer {
+    pub const fn new(map: MidiMap) -> Self {
+        Self {
+            map,
+            octave: 0,
+            sounding: [None; KEY_COUNT],
+        }
+    }
+
+    pub fn press(&mut self, pad: usize) -> Option<EventPacket> {
+        match self.map.pads[pad] {
+            PadAction::Note(note) => {
+                let note = (note as i16 + self.octave as i16 * 12).clamp(0, 127) as u8;
+                self.sounding[pad] = Some(note);
+                Some(self.event(NOTE_ON, note, self.map.velocity))
+            }
+            PadAction::ControlChange { controller, value } => {
+                Some(self.event(CONTROL_CHANGE, controller, value))
+            }
+            PadAction::OctaveDown => {
+                self.octave = (self.octave - 1).max(-MAX_OCTAVE_SHIFT);
+                None
+            }
+            PadAction::OctaveUp => {
+                self.octave = (self.octave + 1).min(MAX_OCTAVE_SHIFT);
+                None
+            }
+        }
+    }
+
+    pub fn release(&mut self, pad: usize) -> Option<EventPacket> {
+        match self.map.pads[pad] {
+            PadAction::Note(_) => self.sounding[pad]
+                .take()
+                .map(|note| self.event(NOTE_OFF, note, RELEASE_VELOCITY)),
+            PadAction::ControlChange { controller, .. } => {
+                Some(self.event(CONTROL_CHANGE, controller, 0))
+            }
+            PadAction::OctaveDown | PadAction::OctaveUp => None,
+        }
+    }
+
+    pub fn octave(&self) -> i8 {
+        self.octave
+    }
+
+    fn event(&self, status: u8, data1: u8, data2: u8) -> EventPacket {
+        // Cable 0, and the code index number is the status nibble for
+        // channel voice messages
+        [
+            status >> 4,
+            status | (self.map.channel & 0x0F),
+            data1 & 0x7F,
+            data2 & 0x7F,
+        ]
+    }
+}
@@ -0,0 +1,434 @@
+//! USB mass storage class, bulk-only transport with the SCSI command set,
+//! serving a single [`BlockDevice`] of 512-byte blocks.
+
+use crate::watchdog::{self, Task};
+use core::mem::MaybeUninit;
+use defmt::{debug, warn};
+use embassy_futures::select::{Either, select};
+use embassy_stm32::peripherals::USB_OTG_FS;
+use embassy_stm32::usb::Driver;
+use embassy_time::Timer;
+use embassy_usb::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use embassy_usb::driver::{self, Endpoint as _, EndpointError, EndpointIn as _, EndpointOut as _};
+use embassy_usb::types::InterfaceNumber;
+use embassy_usb::{Builder, Handler};
+
+type EndpointIn<'d> = <Driver<'d, USB_OTG_FS> as driver::Driver<'d>>::EndpointIn;
+type EndpointOut<'d> = <Driver<'d, USB_OTG_FS> as driver::Driver<'d>>::EndpointOut;
+
+pub const BLOCK_SIZE: usize = 512;
+const MAX_PACKET_SIZE: u16 = 64;
+
+const USB_CLASS_MSC: u8 = 0x08;
+const USB_SUBCLASS_SCSI: u8 = 0x06;
+const USB_PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+const MSC_REQ_RESET: u8 = 0xFF;
+const MSC_REQ_GET_MAX_LUN: u8 = 0xFE;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CBW_SIZE: usize = 31;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CSW_PASSED: u8 = 0x00;
+const CSW_FAILED: u8 = 0x01;
+
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_REQUEST_SENSE: u8 = 0x03;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_MODE_SENSE_6: u8 = 0x1A;
+const SCSI_START_STOP_UNIT: u8 = 0x1B;
+const SCSI_PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1E;
+const SCSI_READ_FORMAT_CAPACITIES: u8 = 0x23;
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_WRITE_10: u8 = 0x2A;
+const SCSI_VERIFY_10: u8 = 0x2F;
+const SCSI_SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const SCSI_MODE_SENSE_10: u8 = 0x5A;
+
+/// Sense key, additional sense code and qualifier.
+type Sense = (u8, u8, u8);
+const SENSE_NONE: Sense = (0x00, 0x00, 0x00);
+const SENSE_MEDIUM_ERROR: Sense = (0x03, 0x00, 0x00);
+const SENSE_INVALID_COMMAND: Sense = (0x05, 0x20, 0x00);
+const SENSE_INVALID_FIELD: Sense = (0x05, 0x24, 0x00);
+const SENSE_LBA_OUT_OF_RANGE: Sense = (0x05, 0x21, 0x00);
+const SENSE_MEDIUM_CHANGED: Sense = (0x06, 0x28, 0x00);
+
+/// How long the host has to stop writing before the device is told with
+/// [`BlockDevice::writes_settled`].
+const WRITE_SETTLE_MS: u64 = 500;
+
+pub trait BlockDevice {
+    fn block_count(&self) -> u32;
+    fn read_block(&mut self, lba: u32, block: &mut [u8; BLOCK_SIZE]) -> Result<(), ()>;
+    fn write_block(&mut self, lba: u32, block: &[u8; BLOCK_SIZE]) -> Result<(), ()>;
+    /// Called once the host stopped writing for a while. Returns whether the
+    /// contents changed underneath the host, which then has to read them
+    /// again.
+    fn writes_settled(&mut self) -> bool;
+}
+
+pub struct State {
+    control: MaybeUninit<Control>,
+}
+
+impl State {
+    pub const fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+        }
+    }
+}
+
+/// Adds the mass storage interface.
+pub fn new<'d>(
+    builder: &mut Builder<'d, Driver<'d, USB_OTG_FS>>,
+    state: &'d mut State,
+) -> MassStorage<'d> {
+    let mut func = builder.function(USB_CLASS_MSC, USB_SUBCLASS_SCSI, USB_PROTOCOL_BULK_ONLY);
+    let mut iface = func.interface();
+    let if_num = iface.interface_number();
+    let mut alt = iface.alt_setting(
+        USB_CLASS_MSC,
+        USB_SUBCLASS_SCSI,
+        USB_PROTOCOL_BULK_ONLY,
+        None,
+    );
+    let ep_out = alt.endpoint_bulk_out(MAX_PACKET_SIZE);
+    let ep_in = alt.endpoint_bulk_in(MAX_PACKET_SIZE);
+    drop(func);
+
+    builder.handler(state.control.write(Control { if_num }));
+
+    MassStorage {
+        ep_in,
+        ep_out,
+        sense: SENSE_NONE,
+        medium_changed: false,
+    }
+}
+
+struct Control {
+    if_num: InterfaceNumber,
+}
+
+impl Handler for Control {
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.index)
+            != (
+                RequestType::Class,
+                Recipient::Interface,
+                self.if_num.0 as u16,
+            )
+        {
+            return None;
+        }
+
+        match req.request {
+            // Nothing is ever left half done between packets
+            MSC_REQ_RESET => Some(OutResponse::Accepted),
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient, req.index)
+            != (
+                RequestType::Class,
+                Recipient::Interface,
+                self.if_num.0 as u16,
+            )
+        {
+            return None;
+        }
+
+        match req.request {
+            MSC_REQ_GET_MAX_LUN => {
+                buf[0] = 0;
+                Some(InResponse::Accepted(&buf[..1]))
+            }
+            _ => Some(InResponse::Rejected),
+        }
+    }
+}
+
+/// A command block wrapper.
+struct Command {
+    tag: u32,
+    data_len: u32,
+    flags: u8,
+    block: [u8; 16],
+}
+
+impl Command {
+    /// Whether the data stage goes to the host.
+    fn is_in(&self) -> bool {
+        self.flags & 0x80 != 0
+    }
+}
+
+/// How a command ended.
+enum Outcome {
+    /// Passed, after moving this many bytes.
+    Passed(u32),
+    /// Failed with the given sense, after moving this many bytes.
+    Failed(Sense, u32),
+}
+
+pub struct MassStorage<'d> {
+    ep_in: EndpointIn<'d>,
+    ep_out: EndpointOut<'d>,
+    sense: Sense,
+    // Reported once with the next TEST UNIT READY
+    medium_changed: bool,
+}
+
+impl MassStorage<'_> {
+    /// Serves `device` to the host.
+    pub async fn run(&mut self, device: &mut impl BlockDevice) -> ! {
+        let mut written = false;
+        loop {
+            watchdog::check_in(Task::Config);
+            let command = if written {
+                let settled = Timer::after_millis(WRITE_SETTLE_MS);
+                let wait = select(self.read_command(), settled);
+                match watchdog::alive_while(Task::Config, wait).await {
+                    Either::First(command) => command,
+                    Either::Second(()) => {
+                        written = false;
+                        if device.writes_settled() {
+                            self.medium_changed = true;
+                        }
+                        continue;
+                    }
+                }
+            } else {
+                watchdog::alive_while(Task::Config, self.read_command()).await
+            };
+            let Some(command) = command else {
+                continue;
+            };
+
+            written |= command.block[0] == SCSI_WRITE_10;
+            let outcome = match self.execute(&command, device).await {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    warn!("Mass storage transfer failed: {:?}", e);
+                    continue;
+                }
+            };
+            let (status, moved) = match outcome {
+                Outcome::Passed(moved) => (CSW_PASSED, moved),
+                Outcome::Failed(sense, moved) => {
+                    self.sense = sense;
+                    (CSW_FAILED, moved)
+                }
+            };
+            let residue = command.data_len.saturating_sub(moved);
+            if let Err(e) = self.send_status(command.tag, residue, status).await {
+                warn!("Mass storage status failed: {:?}", e);
+            }
+        }
+    }
+
+    async fn read_command(&mut self) -> Option<Command> {
+        let mut buf = [0; MAX_PACKET_SIZE as usize];
+        let len = match self.ep_out.read(&mut buf).await {
+            Ok(len) => len,
+            Err(EndpointError::Disabled) => {
+                self.ep_out.wait_enabled().await;
+                return None;
+            }
+            Err(EndpointError::BufferOverflow) => return None,
+        };
+        let cbw = &buf[..len];
+        if len != CBW_SIZE || cbw[0..4] != CBW_SIGNATURE.to_le_bytes() {
+            warn!("Invalid command block wrapper");
+            return None;
+        }
+        let mut block = [0; 16];
+        block.copy_from_slice(&cbw[15..31]);
+        Some(Command {
+            tag: u32::from_le_bytes([cbw[4], cbw[5], cbw[6], cbw[7]]),
+            data_len: u32::from_le_bytes([cbw[8], cbw[9], cbw[10], cbw[11]]),
+            flags: cbw[12],
+            block,
+        })
+    }
+
+    async fn execute(
+        &mut self,
+        command: &Command,
+        device: &mut impl BlockDevice,
+    ) -> Result<Outcome, EndpointError> {
+        let cb = &command.block;
+        debug!("SCSI command {=u8:02x}", cb[0]);
+        let mut buf = [0; BLOCK_SIZE];
+        match cb[0] {
+            SCSI_TEST_UNIT_READY if self.medium_changed => {
+                self.medium_changed = false;
+                Ok(Outcome::Failed(SENSE_MEDIUM_CHANGED, 0))
+            }
+            SCSI_TEST_UNIT_READY
+            | SCSI_START_STOP_UNIT
+            | SCSI_PREVENT_ALLOW_MEDIUM_REMOVAL
+            | SCSI_VERIFY_10
+            | SCSI_SYNCHRONIZE_CACHE_10 => Ok(Outcome::Passed(0)),
+            SCSI_REQUEST_SENSE => {
+                let (key, asc, ascq) = core::mem::replace(&mut self.sense, SENSE_NONE);
+                let sense = [
+                    0x70, 0, key, 0, 0, 0, 0, 10, 0, 0, 0, 0, asc, ascq, 0, 0, 0, 0,
+                ];
+                self.send_data(command, &sense).await.map(Outcome::Passed)
+            }
+            // Vital product data pages aren't supported
+            SCSI_INQUIRY if cb[1] & 0x01 != 0 => self.fail_in(command, SENSE_INVALID_FIELD).await,
+            SCSI_INQUIRY => {
+                let mut inquiry = [0; 36];
+                // Direct access block device, removable, SPC-2
+                inquiry[..5].copy_from_slice(&[0x00, 0x80, 0x04, 0x02, 31]);
+                inquiry[8..16].copy_from_slice(b"Keypad  ");
+                inquiry[16..32].copy_from_slice(b"Keymap drive    ");
+                inquiry[32..36].copy_from_slice(b"1.0 ");
+                self.send_data(command, &inquiry).await.map(Outcome::Passed)
+            }
+            // No mode pages, not write protected
+            SCSI_MODE_SENSE_6 => self
+                .send_data(command, &[3, 0, 0, 0])
+                .await
+                .map(Outcome::Passed),
+            SCSI_MODE_SENSE_10 => self
+                .send_data(command, &[0, 6, 0, 0, 0, 0, 0, 0])
+                .await
+                .map(Outcome::Passed),
+            SCSI_READ_CAPACITY_10 => {
+                let last = device.block_count() - 1;
+                buf[..4].copy_from_slice(&last.to_be_bytes());
+                buf[4..8].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+                self.send_data(command, &buf[..8])
+                    .await
+                    .map(Outcome::Passed)
+            }
+            SCSI_READ_FORMAT_CAPACITIES => {
+                // Capacity list header, then the formatted capacity
+                buf[3] = 8;
+                buf[4..8].copy_from_slice(&device.block_count().to_be_bytes());
+                buf[8] = 0x02;
+                buf[9..12].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes()[1..]);
+                self.send_data(command, &buf[..12])
+                    .await
+                    .map(Outcome::Passed)
+            }
+            SCSI_READ_10 => {
+                let Some((lba, count)) = blocks(cb, device) else {
+                    return self.fail_in(command, SENSE_LBA_OUT_OF_RANGE).await;
+                };
+                let mut moved = 0;
+                for lba in lba..lba + count {
+                    if device.read_block(lba, &mut buf).is_err() {
+                        self.finish_in(command, moved).await?;
+                        return Ok(Outcome::Failed(SENSE_MEDIUM_ERROR, moved));
+                    }
+                    self.write_packets(&buf).await?;
+                    moved += BLOCK_SIZE as u32;
+                }
+                Ok(Outcome::Passed(moved))
+            }
+            SCSI_WRITE_10 => {
+                let Some((lba, count)) = blocks(cb, device) else {
+                    self.skip_out(command.data_len).await?;
+                    return Ok(Outcome::Failed(SENSE_LBA_OUT_OF_RANGE, 0));
+                };
+                let mut failed = false;
+                for lba in lba..lba + count {
+                    self.read_packets(&mut buf).await?;
+                    failed |= device.write_block(lba, &buf).is_err();
+                }
+                let moved = count * BLOCK_SIZE as u32;
+                if failed {
+                    Ok(Outcome::Failed(SENSE_MEDIUM_ERROR, moved))
+                } else {
+                    Ok(Outcome::Passed(moved))
+                }
+            }
+            _ if command.data_len > 0 && command.is_in() => {
+                self.fail_in(command, SENSE_INVALID_COMMAND).await
+            }
+            _ => {
+                self.skip_out(command.data_len).await?;
+                Ok(Outcome::Failed(SENSE_INVALID_COMMAND, 0))
+            }
+        }
+    }
+
+    /// Sends the reply to a command, cut to the length the host asked for.
+    async fn send_data(&mut self, command: &Command, data: &[u8]) -> Result<u32, EndpointError> {
+        let len = data.len().min(command.data_len as usize);
+        self.write_packets(&data[..len]).await?;
+        self.finish_in(command, len as u32).await?;
+        Ok(len as u32)
+    }
+
+    async fn fail_in(&mut self, command: &Command, sense: Sense) -> Result<Outcome, EndpointError> {
+        self.finish_in(command, 0).await?;
+        Ok(Outcome::Failed(sense, 0))
+    }
+
+    /// Ends an IN data stage that was shorter than the host expected.
+    ///
+    /// The endpoints can't stall, so a short packet tells the host no more
+    /// data is coming.
+    async fn finish_in(&mut self, command: &Command, sent: u32) -> Result<(), EndpointError> {
+        if sent < command.data_len && sent.is_multiple_of(u32::from(MAX_PACKET_SIZE)) {
+            self.ep_in.write(&[]).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_packets(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        for packet in data.chunks(MAX_PACKET_SIZE as usize) {
+            self.ep_in.write(packet).await?;
+        }
+        Ok(())
+    }
+
+    async fn read_packets(&mut self, data: &mut [u8]) -> Result<(), EndpointError> {
+        for packet in data.chunks_mut(MAX_PACKET_SIZE as usize) {
+            self.ep_out.read(packet).await?;
+        }
+        Ok(())
+    }
+
+    /// Reads and drops the OUT data of a command that isn't carried out.
+    async fn skip_out(&mut self, len: u32) -> Result<(), EndpointError> {
+        let mut buf = [0; MAX_PACKET_SIZE as usize];
+        let mut left = len as usize;
+        while left > 0 {
+            left = left.saturating_sub(self.ep_out.read(&mut buf).await?);
+        }
+        Ok(())
+    }
+
+    async fn send_status(
+        &mut self,
+        tag: u32,
+        residue: u32,
+        status: u8,
+    ) -> Result<(), EndpointError> {
+        let mut csw = [0; 13];
+        csw[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+        csw[4..8].copy_from_slice(&tag.to_le_bytes());
+        csw[8..12].copy_from_slice(&residue.to_le_bytes());
+        csw[12] = status;
+        self.ep_in.write(&csw).await
+    }
+}
+
+/// The first block and block count of a READ(10) or WRITE(10), if they are
+/// on the device.
+fn blocks(cb: &[u8; 16], device: &impl BlockDevice) -> Option<(u32, u32)> {
+    let lba = u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]);
+    let count = u32::from(u16::from_be_bytes([cb[7], cb[8]]));
+    (lba.checked_add(count)? <= device.block_count()).then_some((lba, count))
+}
@@ -0,0 +1,73 @@
+//! Profiles: named sets of a keymap and the key settings, one active at a time.
+//!
+//! The active profile's keymap and settings are the ones in [`crate::keymap`]
+//! and [`crate::settings`]; the others only live in flash. Holding `*` and `#`
+//! and pressing a digit switches profiles, which the settings task carries out
+//! as it owns the flash, see [`crate::settings::save_changes`]. The chord and
+//! the saved records are [`keypad_logic::profile`].
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use keypad_logic::profile::{Name, default_name, truncate_name};
+
+pub use keypad_logic::profile::{PROFILE_COUNT, from_chord};
+
+static ACTIVE: AtomicU8 = AtomicU8::new(0);
+
+static NAMES: Mutex<CriticalSectionRawMutex, RefCell<[Name; PROFILE_COUNT]>> =
+    Mutex::new(RefCell::new([const { Name::new() }; PROFILE_COUNT]));
+
+/// Whether the settings task runs, which carries out the switches.
+static SWITCHING: AtomicBool = AtomicBool::new(false);
+
+/// Signalled with the profile to switch to.
+pub static SWITCH_REQUESTED: Signal<CriticalSectionRawMutex, usize> = Signal::new();
+
+/// The active profile, from 0.
+pub fn active() -> usize {
+    ACTIVE.load(Ordering::Relaxed).into()
+}
+
+pub fn set_active(profile: usize) {
+    ACTIVE.store(profile as u8, Ordering::Relaxed);
+}
+
+/// The name of a profile, "Profile <n>" unless it was given one.
+pub fn name(profile: usize) -> Name {
+    let name = NAMES.lock(|names| names.borrow()[profile].clone());
+    if name.is_empty() {
+        default_name(profile)
+    } else {
+        name
+    }
+}
+
+/// Names a profile, back to its default name if `name` is empty. Longer
+/// names are cut short.
+pub fn set_name(profile: usize, name: &str) {
+    let mut truncated = Name::new();
+    let _ = truncated.push_str(truncate_name(name));
+    if truncated == default_name(profile) {
+        truncated.clear();
+    }
+    NAMES.lock(|names| names.borrow_mut()[profile] = truncated);
+}
+
+/// Lets [`request_switch`] ask the settings task to switch, once it runs.
+pub fn enable_switching() {
+    SWITCHING.store(true, Ordering::Relaxed);
+}
+
+/// Asks for a switch to `profile`, returning whether it will happen: not if
+/// `profile` doesn't exist, or without the settings in flash, which hold the
+/// other profiles.
+pub fn request_switch(profile: usize) -> bool {
+    let possible = profile < PROFILE_COUNT && SWITCHING.load(Ordering::Relaxed);
+    if possible {
+        SWITCH_REQUESTED.signal(profile);
+    }
+    possible
+}
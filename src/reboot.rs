@@ -0,0 +1,65 @@
+use core::mem::MaybeUninit;
+use cortex_m::peripheral::SCB;
+use embassy_stm32::pac;
+
+const BOOTLOADER_REQUEST: u32 = 0xB007_10AD;
+#[cfg(feature = "ab-updates")]
+const UPDATE_MODE_REQUEST: u32 = 0x0DF0_0D0E;
+const SYSTEM_MEMORY: u32 = 0x1FFF_0000;
+
+// Survives the reset, so the next boot can tell why it happened
+#[unsafe(link_section = ".uninit.BOOT_REQUEST")]
+static mut BOOT_REQUEST: MaybeUninit<u32> = MaybeUninit::uninit();
+
+pub fn reboot() -> ! {
+    SCB::sys_reset()
+}
+
+/// Restarts into the STM32 system bootloader, which offers USB DFU.
+pub fn reboot_to_bootloader() -> ! {
+    unsafe {
+        (&raw mut BOOT_REQUEST)
+            .cast::<u32>()
+            .write_volatile(BOOTLOADER_REQUEST)
+    };
+    SCB::sys_reset()
+}
+
+/// Jumps to the system bootloader if the last reset asked for it.
+///
+/// This has to run before anything else is set up, as the bootloader expects
+/// the clocks and peripherals in their reset state.
+pub fn enter_bootloader_if_requested() {
+    let request = (&raw mut BOOT_REQUEST).cast::<u32>();
+    if unsafe { request.read_volatile() } != BOOTLOADER_REQUEST {
+        return;
+    }
+    unsafe { request.write_volatile(0) };
+
+    // Map the system memory at address 0, as booting with BOOT0 high would
+    pac::RCC.apb2enr().modify(|w| w.set_syscfgen(true));
+    pac::SYSCFG.memrm().modify(|w| w.set_mem_mode(1));
+    unsafe { cortex_m::asm::bootload(SYSTEM_MEMORY as *const u32) }
+}
+
+/// Restarts the firmware in update mode, see [`crate::update`].
+#[cfg(feature = "ab-updates")]
+pub fn reboot_to_update_mode() -> ! {
+    unsafe {
+        (&raw mut BOOT_REQUEST)
+            .cast::<u32>()
+            .write_volatile(UPDATE_MODE_REQUEST)
+    };
+    SCB::sys_reset()
+}
+
+/// Whether the last reset asked for update mode.
+#[cfg(feature = "ab-updates")]
+pub fn update_mode_requested() -> bool {
+    let request = (&raw mut BOOT_REQUEST).cast::<u32>();
+    if unsafe { request.read_volatile() } != UPDATE_MODE_REQUEST {
+        return false;
+    }
+    unsafe { request.write_volatile(0) };
+    true
+}
@@ -0,0 +1,91 @@
+//! Remapping keys on the keypad itself, for computers where no configuration
+//! tool can be installed.
+//!
+//! Holding `*` and `#` and pressing `0` starts the remap mode, which keeps the
+//! LEDs lit. The next key pressed is the one to change. It then takes either
+//! a HID keyboard usage in decimal, e.g. `4` for A or `40` for Enter, ended by
+//! `#`, or one of the [`MENU`] keys on the letter keys. `*` cancels. The new
+//! keycode goes on the base layer of the active profile, and is saved like any
+//! other keymap change. What the keys pressed mean is worked out by
+//! [`keypad_logic::remap`].
+//!
+//! [`MENU`]: keypad_logic::remap::MENU
+
+use crate::feedback::{self, Feedback};
+use crate::keymap;
+use crate::keypad::{KEY_LABELS, Keypad};
+use crate::settings::debounce;
+use crate::watchdog::{self, Task};
+use defmt::{info, warn};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::digital::{InputPin, OutputPin};
+use keypad_logic::remap::{Outcome, Remap};
+
+/// How long the remap mode waits for a key before it gives up.
+const TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Runs the remap mode until a key is remapped or it is cancelled, and all
+/// keys are released again.
+pub async fn run<T: InputPin, U: OutputPin>(keypad: &mut Keypad<T, U>) {
+    info!("Remap mode started");
+    feedback::show(Feedback::Remapping);
+    wait_for_release(keypad).await;
+    let mut held = 0;
+    let mut remap = Remap::new();
+
+    let outcome = loop {
+        let Some(key) = next_press(keypad, &mut held).await else {
+            break Outcome::Rejected;
+        };
+        match remap.press(key, KEY_LABELS[key]) {
+            Outcome::Taken => feedback::show(Feedback::KeyTaken),
+            outcome => break outcome,
+        }
+    };
+
+    match outcome {
+        Outcome::Remapped { key, keycode } => {
+            info!("Remapped key {} to {=u16:#06x}", KEY_LABELS[key], keycode);
+            keymap::set_keycode(0, key, keycode);
+            finish(keypad, Feedback::Accepted).await
+        }
+        _ => {
+            warn!("Remap cancelled");
+            finish(keypad, Feedback::Rejected).await
+        }
+    }
+}
+
+async fn finish<T: InputPin, U: OutputPin>(keypad: &mut Keypad<T, U>, feedback: Feedback) {
+    feedback::show(feedback);
+    wait_for_release(keypad).await;
+}
+
+/// The next key pressed, or `None` after [`TIMEOUT`] without one. `held` is
+/// the keys of the last scan.
+async fn next_press<T: InputPin, U: OutputPin>(
+    keypad: &mut Keypad<T, U>,
+    held: &mut u16,
+) -> Option<usize> {
+    let deadline = Instant::now() + TIMEOUT;
+    while Instant::now() < deadline {
+        watchdog::check_in(Task::Keys);
+        let keys = keypad.scan();
+        let pressed = keys & !*held;
+        *held = keys;
+        if pressed != 0 {
+            debounce().await;
+            return Some(pressed.trailing_zeros() as usize);
+        }
+        Timer::after_millis(10).await;
+    }
+    None
+}
+
+async fn wait_for_release<T: InputPin, U: OutputPin>(keypad: &mut Keypad<T, U>) {
+    while keypad.scan() != 0 {
+        watchdog::check_in(Task::Keys);
+        Timer::after_millis(10).await;
+    }
+    debounce().await;
+}
@@ -0,0 +1,398 @@
+use crate::device_mode::DeviceMode;
+use crate::feedback::{self, Feedback};
+use crate::ir::{self, POOL_PARTS};
+use crate::keymap::{self, LAYERS, MACRO_BUFFER_SIZE};
+use crate::keypad::KEY_COUNT;
+use crate::profile::{self, PROFILE_COUNT};
+use crate::watchdog::{self, Task};
+use crate::{keypad_config, usb_identity};
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering};
+use defmt::{Format, info, warn};
+use embassy_futures::select::{Either, select};
+use embassy_stm32::flash::{Bank1Region1, Blocking};
+use embassy_time::Timer;
+use keypad_logic::profile::{KeySettings, Record};
+use keypad_logic::settings_store::{self, MAX_VALUE_SIZE, Settings};
+
+/// How long the keyboard waits after reporting a key press or release before
+/// it looks at the keypad again.
+pub static DEBOUNCE_MS: AtomicU32 = AtomicU32::new(keypad_config::DEBOUNCE_MS);
+
+/// Waits out the contacts bouncing after a change, for [`DEBOUNCE_MS`].
+pub async fn debounce() {
+    let delay = Timer::after_millis(DEBOUNCE_MS.load(Ordering::Relaxed).into());
+    watchdog::alive_while(Task::Keys, delay).await;
+}
+
+/// Longest debounce delay. Switches settle within a few milliseconds, and
+/// a longer delay would only make the keypad miss presses.
+pub const MAX_DEBOUNCE_MS: u32 = 100;
+
+/// Whether a key press wakes a sleeping host.
+pub static REMOTE_WAKEUP: AtomicBool = AtomicBool::new(true);
+
+/// What happens to the key presses made while the host hasn't configured the
+/// device, e.g. while it boots or after a bus reset.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum QueuePolicy {
+    /// Forget them, only the keys still held are reported.
+    Drop = 0,
+    /// Report them in order once the device is configured.
+    Replay = 1,
+}
+
+impl QueuePolicy {
+    pub fn get() -> Self {
+        match QUEUE_POLICY.load(Ordering::Relaxed) {
+            0 => QueuePolicy::Drop,
+            _ => QueuePolicy::Replay,
+        }
+    }
+
+    pub fn set(self) {
+        QUEUE_POLICY.store(self as u8, Ordering::Relaxed);
+    }
+}
+
+static QUEUE_POLICY: AtomicU8 = AtomicU8::new(QueuePolicy::Replay as u8);
+
+/// Settings that the configuration protocols read and write by number.
+#[derive(Clone, Copy)]
+pub enum Setting {
+    /// Up to [`MAX_DEBOUNCE_MS`], longer delays are cut short.
+    DebounceMs,
+    /// 1 if on, 0 if off.
+    RemoteWakeup,
+    /// A [`QueuePolicy`].
+    QueuePolicy,
+    /// The USB vendor ID from the next boot on.
+    UsbVendorId,
+    /// The USB product ID from the next boot on.
+    UsbProductId,
+    /// The [`DeviceMode`] started without a mode key, from the next boot on.
+    StartMode,
+    /// The active profile, from 0. Setting it switches profiles.
+    Profile,
+}
+
+impl Setting {
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Setting::DebounceMs),
+            1 => Some(Setting::RemoteWakeup),
+            2 => Some(Setting::QueuePolicy),
+            3 => Some(Setting::UsbVendorId),
+            4 => Some(Setting::UsbProductId),
+            5 => Some(Setting::StartMode),
+            6 => Some(Setting::Profile),
+            _ => None,
+        }
+    }
+
+    pub fn get(self) -> u32 {
+        match self {
+            Setting::DebounceMs => DEBOUNCE_MS.load(Ordering::Relaxed),
+            Setting::RemoteWakeup => REMOTE_WAKEUP.load(Ordering::Relaxed).into(),
+            Setting::QueuePolicy => QueuePolicy::get() as u32,
+            Setting::UsbVendorId => usb_identity::next().vid.into(),
+            Setting::UsbProductId => usb_identity::next().pid.into(),
+            Setting::StartMode => DeviceMode::start_mode() as u32,
+            Setting::Profile => profile::active() as u32,
+        }
+    }
+
+    pub fn set(self, value: u32) {
+        match self {
+            Setting::DebounceMs => DEBOUNCE_MS.store(value.min(MAX_DEBOUNCE_MS), Ordering::Relaxed),
+            Setting::RemoteWakeup => REMOTE_WAKEUP.store(value != 0, Ordering::Relaxed),
+            Setting::QueuePolicy => match value {
+                0 => QueuePolicy::Drop.set(),
+                _ => QueuePolicy::Replay.set(),
+            },
+            Setting::UsbVendorId => {
+                let mut identity = usb_identity::next();
+                identity.vid = value as u16;
+                usb_identity::set_next(&identity);
+            }
+            Setting::UsbProductId => {
+                let mut identity = usb_identity::next();
+                identity.pid = value as u16;
+                usb_identity::set_next(&identity);
+            }
+            Setting::StartMode => {
+                if let Some(mode) = u8::try_from(value).ok().and_then(DeviceMode::from_id) {
+                    mode.set_start_mode();
+                }
+            }
+            Setting::Profile => {
+                profile::request_switch(value as usize);
+            }
+        }
+    }
+}
+
+/// The settings in flash.
+pub type Store = Settings<Bank1Region1<'static, Blocking>, Key>;
+
+/// The values in the store.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum Key {
+    Macros,
+    StartMode,
+    UsbIdentity,
+    ActiveProfile,
+    /// The keymap and key settings of a profile.
+    Profile(u8),
+    /// A part of the marks and spaces of the raw IR codes.
+    IrPool(u8),
+    /// The IR codes, which point into the raw IR codes' marks and spaces.
+    IrCodes,
+}
+
+impl settings_store::Key for Key {
+    const ALL: &'static [Key] = &{
+        let mut all = [Key::Macros; 5 + PROFILE_COUNT + POOL_PARTS];
+        all[1] = Key::StartMode;
+        all[2] = Key::UsbIdentity;
+        all[3] = Key::ActiveProfile;
+        let mut profile = 0;
+        while profile < PROFILE_COUNT {
+            all[4 + profile] = Key::Profile(profile as u8);
+            profile += 1;
+        }
+        let mut part = 0;
+        while part < POOL_PARTS {
+            all[4 + PROFILE_COUNT + part] = Key::IrPool(part as u8);
+            part += 1;
+        }
+        all[4 + PROFILE_COUNT + POOL_PARTS] = Key::IrCodes;
+        all
+    };
+
+    /// 1, 3, 4 and 5 held the keymap and key settings before there were
+    /// profiles, and are dropped by the next compaction.
+    fn id(self) -> u8 {
+        match self {
+            Key::Macros => 2,
+            Key::StartMode => 6,
+            Key::UsbIdentity => 7,
+            Key::ActiveProfile => 8,
+            Key::Profile(profile) => 0x10 + profile,
+            Key::IrCodes => 9,
+            Key::IrPool(part) => 0x20 + part,
+        }
+    }
+}
+
+/// Format of the saved values. A value saved in another format, or with
+/// another size, such as a keymap for another matrix, is left at its default.
+const VERSION: u8 = 1;
+
+/// How often [`save_changes`] looks for changed settings. A setting is saved
+/// once it has stayed the same for this long, so a burst of changes, e.g.
+/// from VIA, is written once.
+const SAVE_INTERVAL_MS: u64 = 1000;
+
+/// Keycodes in a saved profile's keymap.
+const PROFILE_KEYCODES: usize = LAYERS * KEY_COUNT;
+
+/// Opens the settings store and loads the saved settings. Has to run before
+/// anything reads them, e.g. the device mode.
+pub fn restore(flash: Bank1Region1<'static, Blocking>) -> Option<Store> {
+    let mut store = match Settings::new(flash) {
+        Ok(store) => store,
+        Err(error) => {
+            warn!("Can't open the settings in flash: {}", error);
+            return None;
+        }
+    };
+    // The active profile comes before the profiles, so only its keymap and
+    // settings are applied, and the raw IR codes' marks and spaces before the
+    // IR codes, which are checked against them
+    let mut buf = [0; MAX_VALUE_SIZE];
+    for &key in <Key as settings_store::Key>::ALL {
+        match store.read(key, VERSION, &mut buf) {
+            Ok(Some(len)) if apply(key, &buf[..len]) => info!("Restored {}", key),
+            Ok(Some(_)) => warn!("Ignoring saved {}", key),
+            Ok(None) => {}
+            Err(error) => warn!("Can't read {}: {}", key, error),
+        }
+    }
+    Some(store)
+}
+
+/// Saves the settings that changed to flash, and switches profiles, forever.
+pub async fn save_changes(store: &mut Store) -> ! {
+    watchdog::supervise(Task::Settings);
+    let mut buf = [0; MAX_VALUE_SIZE];
+    let mut saved = live_keys().map(|key| {
+        let len = encode(key, &mut buf);
+        hash(&buf[..len])
+    });
+    let mut seen = saved;
+    loop {
+        let wait = select(
+            Timer::after_millis(SAVE_INTERVAL_MS),
+            profile::SWITCH_REQUESTED.wait(),
+        );
+        let switch = match watchdog::alive_while(Task::Settings, wait).await {
+            Either::First(()) => None,
+            Either::Second(profile) => Some(profile),
+        };
+        for (i, key) in live_keys().into_iter().enumerate() {
+            let len = encode(key, &mut buf);
+            let value = &buf[..len];
+            let current = hash(value);
+            // Before a switch, changes are saved whether they settled or not
+            let settled = current == seen[i] || switch.is_some();
+            if current != saved[i] && settled {
+                match store.write(key, VERSION, value) {
+                    Ok(()) => {
+                        info!("Saved {}", key);
+                        saved[i] = current;
+                    }
+                    Err(error) => warn!("Can't save {}: {}", key, error),
+                }
+            }
+            seen[i] = current;
+        }
+        if let Some(profile) = switch {
+            switch_profile(store, profile, &mut buf);
+            saved = live_keys().map(|key| {
+                let len = encode(key, &mut buf);
+                hash(&buf[..len])
+            });
+            seen = saved;
+        }
+    }
+}
+
+/// The settings in RAM, which are the ones that can change.
+fn live_keys() -> [Key; 6 + POOL_PARTS] {
+    let mut keys = [Key::IrCodes; 6 + POOL_PARTS];
+    keys[..5].copy_from_slice(&[
+        Key::Macros,
+        Key::StartMode,
+        Key::UsbIdentity,
+        Key::ActiveProfile,
+        Key::Profile(profile::active() as u8),
+    ]);
+    // The marks and spaces are saved before the IR codes pointing into them
+    for part in 0..POOL_PARTS {
+        keys[5 + part] = Key::IrPool(part as u8);
+    }
+    keys
+}
+
+/// Loads `profile` from flash, or the defaults if it was never saved, and
+/// makes it the active one.
+fn switch_profile(store: &mut Store, profile: usize, buf: &mut [u8; MAX_VALUE_SIZE]) {
+    profile::set_active(profile);
+    let key = Key::Profile(profile as u8);
+    match store.read(key, VERSION, buf) {
+        Ok(Some(len)) if apply(key, &buf[..len]) => {}
+        Ok(_) => reset_profile(),
+        Err(error) => {
+            warn!("Can't read {}: {}", key, error);
+            reset_profile();
+        }
+    }
+    let len = encode(Key::ActiveProfile, buf);
+    if let Err(error) = store.write(Key::ActiveProfile, VERSION, &buf[..len]) {
+        warn!("Can't save {}: {}", Key::ActiveProfile, error);
+    }
+    info!("Switched to profile {}", profile + 1);
+    feedback::show(Feedback::Profile(profile));
+}
+
+/// Sets the keymap and key settings of the active profile to the defaults.
+fn reset_profile() {
+    keymap::set_keymap(keymap::DEFAULT_KEYMAP);
+    DEBOUNCE_MS.store(keypad_config::DEBOUNCE_MS, Ordering::Relaxed);
+    REMOTE_WAKEUP.store(true, Ordering::Relaxed);
+    QueuePolicy::Replay.set();
+}
+
+/// Writes the current value of `key` into `buf`, returning its length. Only
+/// the active profile can be encoded.
+fn encode(key: Key, buf: &mut [u8; MAX_VALUE_SIZE]) -> usize {
+    match key {
+        Key::Macros => keymap::with_macros(|macros| {
+            buf[..MACRO_BUFFER_SIZE].copy_from_slice(macros);
+            MACRO_BUFFER_SIZE
+        }),
+        Key::StartMode => {
+            buf[0] = DeviceMode::start_mode() as u8;
+            1
+        }
+        Key::UsbIdentity => {
+            buf[..usb_identity::ENCODED_SIZE]
+                .copy_from_slice(&usb_identity::encode(&usb_identity::next()));
+            usb_identity::ENCODED_SIZE
+        }
+        Key::ActiveProfile => {
+            buf[0] = profile::active() as u8;
+            1
+        }
+        Key::Profile(profile) => {
+            let settings = KeySettings {
+                debounce_ms: DEBOUNCE_MS.load(Ordering::Relaxed),
+                remote_wakeup: REMOTE_WAKEUP.load(Ordering::Relaxed),
+                queue_policy: QueuePolicy::get() as u8,
+            };
+            let keymap = keymap::keymap();
+            let name = profile::name(profile.into());
+            Record::encode(&name, &settings, keymap.as_flattened(), buf)
+        }
+        Key::IrPool(part) => ir::encode_pool(part.into(), buf),
+        Key::IrCodes => ir::encode_slots(buf),
+    }
+}
+
+/// Applies a saved value, returning whether it was valid. Of a profile other
+/// than the active one only the name is applied.
+fn apply(key: Key, value: &[u8]) -> bool {
+    match (key, value) {
+        (Key::Macros, value) if value.len() == MACRO_BUFFER_SIZE => {
+            keymap::with_macros(|macros| macros.copy_from_slice(value))
+        }
+        (Key::StartMode, &[mode]) => match DeviceMode::from_id(mode) {
+            Some(mode) => mode.set_start_mode(),
+            None => return false,
+        },
+        (Key::UsbIdentity, value) => match usb_identity::decode(value) {
+            Some(identity) => usb_identity::restore(&identity),
+            None => return false,
+        },
+        (Key::ActiveProfile, &[profile]) if usize::from(profile) < PROFILE_COUNT => {
+            profile::set_active(profile.into())
+        }
+        (Key::Profile(profile), value) => {
+            let Some(record) = Record::decode(value, PROFILE_KEYCODES) else {
+                return false;
+            };
+            profile::set_name(profile.into(), record.name);
+            if usize::from(profile) != profile::active() {
+                return true;
+            }
+            Setting::DebounceMs.set(record.settings.debounce_ms);
+            REMOTE_WAKEUP.store(record.settings.remote_wakeup, Ordering::Relaxed);
+            Setting::QueuePolicy.set(record.settings.queue_policy.into());
+            let mut keycodes = record.keycodes();
+            keymap::set_keymap(core::array::from_fn(|_| {
+                core::array::from_fn(|_| keycodes.next().unwrap())
+            }));
+        }
+        (Key::IrPool(part), value) => return ir::restore_pool(part.into(), value),
+        (Key::IrCodes, value) => return ir::restore_slots(value),
+        _ => return false,
+    }
+    true
+}
+
+/// FNV-1a, to notice changed values without keeping a copy of them.
+fn hash(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811C_9DC5, |hash, &byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
@@ -0,0 +1,187 @@
+//! USB suspend and remote wakeup.
+//!
+//! While the bus is suspended the key tasks send nothing and sleep on the
+//! keypad interrupt, with the executor idling in WFE. To stay within the
+//! suspend current the chip then runs from the 16 MHz HSI with the HSE and
+//! the PLL off, and while the core sleeps only the GPIO ports, USB and the
+//! time driver's TIM9 keep their clocks. A key press then asks the USB task
+//! to signal remote wakeup, if the host enabled it and [`REMOTE_WAKEUP`] is
+//! on, with the PLL back for USB.
+
+use crate::settings::REMOTE_WAKEUP;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use defmt::info;
+use embassy_stm32::pac;
+use embassy_stm32::pac::rcc::{regs, vals};
+use embassy_stm32::pac::timer::vals::Urs;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, TICK_HZ, with_timeout};
+
+/// How long a key press waits for the host to resume the bus.
+const RESUME_TIMEOUT: Duration = Duration::from_secs(1);
+
+const HSI_HZ: u32 = 16_000_000;
+
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+static HOST_ALLOWS_WAKEUP: AtomicBool = AtomicBool::new(false);
+static RESUMED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Signalled when a key press should wake the host.
+pub static WAKEUP_REQUESTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// The clocks to go back to, while they are slowed down.
+static FAST_CLOCKS: Mutex<CriticalSectionRawMutex, Cell<Option<FastClocks>>> =
+    Mutex::new(Cell::new(None));
+
+/// What [`slow_clocks`] changed.
+#[derive(Clone, Copy)]
+struct FastClocks {
+    cfgr: regs::Cfgr,
+    time_prescaler: u16,
+    ahb1lpenr: regs::Ahb1lpenr,
+    ahb2lpenr: regs::Ahb2lpenr,
+    apb1lpenr: regs::Apb1lpenr,
+    apb2lpenr: regs::Apb2lpenr,
+}
+
+pub fn is_suspended() -> bool {
+    SUSPENDED.load(Ordering::Relaxed)
+}
+
+pub fn set_suspended(suspended: bool) {
+    if suspended {
+        slow_clocks();
+    } else {
+        fast_clocks();
+    }
+    SUSPENDED.store(suspended, Ordering::Relaxed);
+    if !suspended {
+        RESUMED.signal(());
+    }
+}
+
+/// Records whether the host enabled remote wakeup with SET_FEATURE.
+pub fn set_host_allows_wakeup(enabled: bool) {
+    HOST_ALLOWS_WAKEUP.store(enabled, Ordering::Relaxed);
+}
+
+/// Makes sure the bus is awake before a key press is reported, waking the
+/// host if needed. Returns `false` if the host stays asleep and the press
+/// should be dropped.
+pub async fn wake_host() -> bool {
+    RESUMED.reset();
+    if !is_suspended() {
+        return true;
+    }
+    if !(HOST_ALLOWS_WAKEUP.load(Ordering::Relaxed) && REMOTE_WAKEUP.load(Ordering::Relaxed)) {
+        return false;
+    }
+
+    info!("Waking the host");
+    // Signalling resume takes the USB clock
+    fast_clocks();
+    WAKEUP_REQUESTED.signal(());
+    let resumed = with_timeout(RESUME_TIMEOUT, RESUMED.wait()).await.is_ok();
+    if !resumed && is_suspended() {
+        slow_clocks();
+    }
+    resumed
+}
+
+/// Switches the system clock to the HSI and turns the PLL and the HSE off,
+/// and gates the clocks of the peripherals idle while suspended whenever the
+/// core sleeps.
+fn slow_clocks() {
+    FAST_CLOCKS.lock(|fast| {
+        if fast.get().is_some() {
+            return;
+        }
+        let rcc = pac::RCC;
+        rcc.cr().modify(|w| w.set_hsion(true));
+        while !rcc.cr().read().hsirdy() {}
+
+        let cfgr = rcc.cfgr().read();
+        let time_prescaler = retime((HSI_HZ / TICK_HZ as u32 - 1) as u16, || {
+            rcc.cfgr().modify(|w| {
+                w.set_sw(vals::Sw::HSI);
+                w.set_hpre(vals::Hpre::DIV1);
+                w.set_ppre1(vals::Ppre::DIV1);
+                w.set_ppre2(vals::Ppre::DIV1);
+            });
+            while rcc.cfgr().read().sws() != vals::Sw::HSI {}
+        });
+        rcc.cr().modify(|w| {
+            w.set_pllon(false);
+            w.set_hseon(false);
+        });
+
+        fast.set(Some(FastClocks {
+            cfgr,
+            time_prescaler,
+            ahb1lpenr: rcc.ahb1lpenr().read(),
+            ahb2lpenr: rcc.ahb2lpenr().read(),
+            apb1lpenr: rcc.apb1lpenr().read(),
+            apb2lpenr: rcc.apb2lpenr().read(),
+        }));
+        // The GPIO ports are bits 0 to 8, the keypad interrupt and the LEDs
+        // are on them
+        rcc.ahb1lpenr()
+            .write_value(regs::Ahb1lpenr(rcc.ahb1lpenr().read().0 & 0x1FF));
+        rcc.ahb2lpenr().write(|w| w.set_usb_otg_fslpen(true));
+        rcc.apb1lpenr().write_value(regs::Apb1lpenr(0));
+        rcc.apb2lpenr().write(|w| w.set_tim9lpen(true));
+    });
+}
+
+/// Brings back the clocks [`slow_clocks`] changed, if it did.
+fn fast_clocks() {
+    FAST_CLOCKS.lock(|fast| {
+        let Some(clocks) = fast.take() else {
+            return;
+        };
+        let rcc = pac::RCC;
+        rcc.ahb1lpenr().write_value(clocks.ahb1lpenr);
+        rcc.ahb2lpenr().write_value(clocks.ahb2lpenr);
+        rcc.apb1lpenr().write_value(clocks.apb1lpenr);
+        rcc.apb2lpenr().write_value(clocks.apb2lpenr);
+
+        rcc.cr().modify(|w| w.set_hseon(true));
+        while !rcc.cr().read().hserdy() {}
+        rcc.cr().modify(|w| w.set_pllon(true));
+        while !rcc.cr().read().pllrdy() {}
+
+        let sw = clocks.cfgr.sw();
+        retime(clocks.time_prescaler, || {
+            // The bus prescalers first, so the buses never run too fast
+            rcc.cfgr().modify(|w| {
+                w.set_hpre(clocks.cfgr.hpre());
+                w.set_ppre1(clocks.cfgr.ppre1());
+                w.set_ppre2(clocks.cfgr.ppre2());
+            });
+            rcc.cfgr().modify(|w| w.set_sw(sw));
+            while rcc.cfgr().read().sws() != sw {}
+        });
+    });
+}
+
+/// Changes the clocks in `switch` with TIM9, which counts the ticks of the
+/// time driver, stopped, and gives it `prescaler` right away rather than
+/// from its next overflow, so the ticks keep their length. Returns the
+/// prescaler it had.
+fn retime(prescaler: u16, switch: impl FnOnce()) -> u16 {
+    let timer = pac::TIM9;
+    timer.cr1().modify(|w| w.set_cen(false));
+    let count = timer.cnt().read().cnt();
+    let old_prescaler = timer.psc().read();
+    switch();
+    timer.psc().write_value(prescaler);
+    // Load the prescaler with an update event that doesn't count as an
+    // overflow, as the time driver does
+    timer.cr1().modify(|w| w.set_urs(Urs::COUNTER_ONLY));
+    timer.egr().write(|w| w.set_ug(true));
+    timer.cr1().modify(|w| w.set_urs(Urs::ANY_EVENT));
+    timer.cnt().write(|w| w.set_cnt(count));
+    timer.cr1().modify(|w| w.set_cen(true));
+    old_prescaler
+}
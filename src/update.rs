@@ -0,0 +1,426 @@
+//! Firmware updates on boards with the A/B bootloader, see
+//! [`keypad_logic::boot`] for the partitions and the swap.
+//!
+//! A `DFU_DETACH` restarts the firmware in update mode, where it enumerates
+//! with only a DFU mode interface and writes what `dfu-util` downloads to the
+//! DFU partition. Once the download is complete and looks like firmware, it
+//! asks the bootloader to swap it in and restarts. The new firmware confirms
+//! itself once a host configured it and it kept running for [`TRIAL`], or the
+//! next boot goes back to the old one.
+
+use crate::dfu::{
+    DETACH_TIMEOUT_MS, DFU_ATTR_CAN_DOWNLOAD, DFU_ATTR_WILL_DETACH, DFU_DESC_DESCTYPE_FUNCTIONAL,
+    DFU_REQ_GETSTATE, DFU_REQ_GETSTATUS, DFU_STATUS_OK, TRANSFER_SIZE,
+    USB_CLASS_APPLICATION_SPECIFIC, USB_SUBCLASS_DFU,
+};
+use crate::reboot;
+use crate::usb_identity::UsbIdentity;
+use crate::usb_keyboard;
+use core::cell::RefCell;
+use core::mem::MaybeUninit;
+use defmt::{Format, info, warn};
+use embassy_stm32::flash::{Bank1Region2, Bank1Region3, Blocking};
+use embassy_stm32::peripherals::USB_OTG_FS;
+use embassy_stm32::usb::Driver;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use embassy_usb::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use embassy_usb::types::InterfaceNumber;
+use embassy_usb::{Builder, Handler, UsbDevice};
+use embedded_storage::nor_flash::NorFlash;
+use keypad_logic::boot::{self, DFU, PAGE_SIZE, Partition, Phase, STATE};
+
+const USB_PROTOCOL_DFU_MODE: u8 = 0x02;
+
+const DFU_REQ_DNLOAD: u8 = 0x01;
+const DFU_REQ_CLRSTATUS: u8 = 0x04;
+const DFU_REQ_ABORT: u8 = 0x06;
+
+const DFU_STATUS_ERR_WRITE: u8 = 0x03;
+const DFU_STATUS_ERR_ERASE: u8 = 0x04;
+const DFU_STATUS_ERR_FILE: u8 = 0x02;
+const DFU_STATUS_ERR_ADDRESS: u8 = 0x08;
+const DFU_STATUS_ERR_STALLED_PACKET: u8 = 0x0F;
+
+const DFU_STATE_IDLE: u8 = 0x02;
+const DFU_STATE_DNLOAD_SYNC: u8 = 0x03;
+const DFU_STATE_DNBUSY: u8 = 0x04;
+const DFU_STATE_DNLOAD_IDLE: u8 = 0x05;
+const DFU_STATE_MANIFEST_SYNC: u8 = 0x06;
+const DFU_STATE_MANIFEST: u8 = 0x07;
+const DFU_STATE_ERROR: u8 = 0x0A;
+
+/// How long the host waits before asking whether a block was written. A
+/// block starting a sector also waits for its erase, which stalls the CPU
+/// and with it the answer.
+const POLL_TIMEOUT_MS: u32 = 20;
+
+/// Bank 1 region 3 starts at sector 5, the active partition.
+const REGION_3_OFFSET: u32 = 0x2_0000;
+
+/// How long new firmware has to keep running once configured before it
+/// confirms itself.
+pub const TRIAL: Duration = Duration::from_secs(10);
+
+/// Sector 4, bank 1 region 2.
+pub type StatePartition = Partition<Bank1Region2<'static, Blocking>>;
+pub type DfuPartition = Partition<Bank1Region3<'static, Blocking>>;
+
+pub fn state_partition(region: Bank1Region2<'static, Blocking>) -> StatePartition {
+    Partition::new(region, 0, STATE.size)
+}
+
+pub fn dfu_partition(region: Bank1Region3<'static, Blocking>) -> DfuPartition {
+    Partition::new(region, DFU.offset - REGION_3_OFFSET, DFU.size)
+}
+
+/// Confirms firmware on trial once a host configured it and it kept running
+/// for [`TRIAL`], as it would have been restarted by the watchdog if a task
+/// hung meanwhile.
+pub async fn confirm(mut state: StatePartition) {
+    match boot::phase(&mut state) {
+        Ok(Phase::Trial) => {}
+        Ok(_) => return,
+        Err(error) => return warn!("Can't read the update state: {}", error),
+    }
+    info!("New firmware on trial");
+    while !usb_keyboard::is_configured() {
+        Timer::after_secs(1).await;
+    }
+    Timer::after(TRIAL).await;
+    match boot::mark_booted(&mut state) {
+        Ok(_) => info!("New firmware confirmed"),
+        Err(error) => warn!("Can't confirm the new firmware: {}", error),
+    }
+}
+
+/// What update mode has to do in flash, while the host waits.
+enum Job {
+    /// Writes the [`BLOCK`] to the DFU partition at `offset`.
+    Write { offset: u32, len: usize },
+    /// The download is complete, have the bootloader swap it in.
+    Manifest,
+}
+
+/// How a [`Job`] failed, as the DFU status the host is told.
+#[derive(Clone, Copy, Format)]
+#[repr(u8)]
+enum JobError {
+    Write = DFU_STATUS_ERR_WRITE,
+    Erase = DFU_STATUS_ERR_ERASE,
+}
+
+static JOBS: Signal<CriticalSectionRawMutex, Job> = Signal::new();
+/// The last block downloaded, padded with `0xFF` to whole words.
+static BLOCK: Mutex<CriticalSectionRawMutex, RefCell<[u8; TRANSFER_SIZE]>> =
+    Mutex::new(RefCell::new([0xFF; TRANSFER_SIZE]));
+static JOB_DONE: Signal<CriticalSectionRawMutex, Result<(), JobError>> = Signal::new();
+
+/// Writes the download to the DFU partition as the host sends it, and
+/// restarts into the bootloader once it is complete, forever.
+pub async fn write_download(mut state: StatePartition, mut dfu: DfuPartition) -> ! {
+    // The download overwrites the old firmware that firmware on trial would
+    // go back to, so firmware good enough to update itself stays
+    if let Err(error) = boot::mark_booted(&mut state) {
+        warn!("Can't confirm the running firmware: {}", error);
+    }
+    // The end of the sectors erased for the download
+    let mut erased = 0;
+    loop {
+        let result = match JOBS.wait().await {
+            Job::Write { offset, len } => write_block(&mut dfu, &mut erased, offset, len),
+            Job::Manifest => match boot::request_update(&mut state) {
+                Ok(()) => {
+                    info!("Restarting to swap in the new firmware");
+                    // Let the host see the status before the device goes
+                    Timer::after_millis(50).await;
+                    reboot::reboot();
+                }
+                Err(error) => {
+                    warn!("Can't request the update: {}", error);
+                    Err(JobError::Write)
+                }
+            },
+        };
+        JOB_DONE.signal(result);
+    }
+}
+
+fn write_block(
+    dfu: &mut DfuPartition,
+    erased: &mut u32,
+    offset: u32,
+    len: usize,
+) -> Result<(), JobError> {
+    if offset == 0 {
+        *erased = 0;
+    }
+    let end = offset + len as u32;
+    if end > *erased {
+        let to = end.next_multiple_of(PAGE_SIZE);
+        dfu.erase(*erased, to).map_err(|error| {
+            warn!("Can't erase the DFU partition: {}", error);
+            JobError::Erase
+        })?;
+        *erased = to;
+    }
+    let block = BLOCK.lock(|block| *block.borrow());
+    dfu.write(offset, &block[..len]).map_err(|error| {
+        warn!("Can't write the DFU partition: {}", error);
+        JobError::Write
+    })
+}
+
+/// The USB device of update mode.
+pub struct UsbConfig<'a> {
+    embassy_config: embassy_usb::Config<'a>,
+    config_descriptor: [u8; 64],
+    bos_descriptor: [u8; 32],
+    /// Takes a whole block of the download.
+    control_buf: [u8; TRANSFER_SIZE],
+    download: MaybeUninit<Download>,
+}
+
+impl<'a> UsbConfig<'a> {
+    pub fn new(identity: &'a UsbIdentity) -> Self {
+        let mut config = embassy_usb::Config::new(identity.vid, identity.pid);
+        config.manufacturer = Some(&identity.manufacturer);
+        config.product = Some(&identity.product);
+        config.serial_number = Some(identity.serial_number());
+
+        Self {
+            embassy_config: config,
+            config_descriptor: [0; 64],
+            bos_descriptor: [0; 32],
+            control_buf: [0; TRANSFER_SIZE],
+            download: MaybeUninit::uninit(),
+        }
+    }
+}
+
+/// Builds the USB device of update mode, with the DFU mode interface only.
+pub fn usb_device<'d>(
+    config: &'d mut UsbConfig<'d>,
+    driver: Driver<'d, USB_OTG_FS>,
+) -> UsbDevice<'d, Driver<'d, USB_OTG_FS>> {
+    let mut builder = Builder::new(
+        driver,
+        config.embassy_config,
+        &mut config.config_descriptor,
+        &mut config.bos_descriptor,
+        &mut [],
+        &mut config.control_buf,
+    );
+
+    let mut func = builder.function(
+        USB_CLASS_APPLICATION_SPECIFIC,
+        USB_SUBCLASS_DFU,
+        USB_PROTOCOL_DFU_MODE,
+    );
+    let mut iface = func.interface();
+    let if_num = iface.interface_number();
+    let mut alt = iface.alt_setting(
+        USB_CLASS_APPLICATION_SPECIFIC,
+        USB_SUBCLASS_DFU,
+        USB_PROTOCOL_DFU_MODE,
+        None,
+    );
+    let [timeout_low, timeout_high] = DETACH_TIMEOUT_MS.to_le_bytes();
+    alt.descriptor(
+        DFU_DESC_DESCTYPE_FUNCTIONAL,
+        &[
+            // Restarts by itself once the download is manifested
+            DFU_ATTR_CAN_DOWNLOAD | DFU_ATTR_WILL_DETACH,
+            timeout_low,
+            timeout_high,
+            TRANSFER_SIZE as u8,
+            (TRANSFER_SIZE >> 8) as u8,
+            // DFU 1.1
+            0x10,
+            0x01,
+        ],
+    );
+    drop(func);
+
+    builder.handler(config.download.write(Download {
+        if_num,
+        state: DFU_STATE_IDLE,
+        status: DFU_STATUS_OK,
+        offset: 0,
+        vectors: [0; 2],
+        block: None,
+    }));
+    builder.build()
+}
+
+/// The DFU state machine of update mode. The blocks are written by
+/// [`write_download`] while the host polls `DFU_GETSTATUS`.
+struct Download {
+    if_num: InterfaceNumber,
+    state: u8,
+    status: u8,
+    /// Where the next block goes.
+    offset: u32,
+    /// The initial stack pointer and reset vector of the download.
+    vectors: [u32; 2],
+    /// Where the [`BLOCK`] downloaded goes and its length, until the host
+    /// asks for the status.
+    block: Option<(u32, usize)>,
+}
+
+impl Download {
+    fn is_ours(&self, req: &Request) -> bool {
+        (req.request_type, req.recipient, req.index)
+            == (
+                RequestType::Class,
+                Recipient::Interface,
+                self.if_num.0 as u16,
+            )
+    }
+
+    fn fail(&mut self, status: u8) {
+        warn!("DFU download failed with status {=u8:#04x}", status);
+        self.state = DFU_STATE_ERROR;
+        self.status = status;
+        self.block = None;
+    }
+
+    fn download(&mut self, data: &[u8]) -> OutResponse {
+        if !matches!(self.state, DFU_STATE_IDLE | DFU_STATE_DNLOAD_IDLE) {
+            self.fail(DFU_STATUS_ERR_STALLED_PACKET);
+            return OutResponse::Rejected;
+        }
+        if data.is_empty() {
+            // The end of the download
+            if self.state == DFU_STATE_IDLE {
+                self.fail(DFU_STATUS_ERR_STALLED_PACKET);
+                return OutResponse::Rejected;
+            }
+            if boot::is_firmware(self.vectors) {
+                self.state = DFU_STATE_MANIFEST_SYNC;
+            } else {
+                self.fail(DFU_STATUS_ERR_FILE);
+            }
+            return OutResponse::Accepted;
+        }
+
+        if self.state == DFU_STATE_IDLE {
+            info!("DFU download started");
+            self.offset = 0;
+            self.vectors = [0; 2];
+        }
+        if self.offset as usize + data.len() > boot::ACTIVE.size as usize {
+            self.fail(DFU_STATUS_ERR_ADDRESS);
+            return OutResponse::Rejected;
+        }
+        let len = data.len().next_multiple_of(4);
+        BLOCK.lock(|block| {
+            let mut block = block.borrow_mut();
+            block[..len].fill(0xFF);
+            block[..data.len()].copy_from_slice(data);
+        });
+        if self.offset == 0 && data.len() >= 8 {
+            let word = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+            self.vectors = [word(0), word(4)];
+        }
+        self.block = Some((self.offset, len));
+        self.offset += data.len() as u32;
+        self.state = DFU_STATE_DNLOAD_SYNC;
+        OutResponse::Accepted
+    }
+
+    /// Moves on as the host asks for the status.
+    fn poll(&mut self) {
+        match self.state {
+            DFU_STATE_DNLOAD_SYNC => {
+                if let Some((offset, len)) = self.block.take() {
+                    JOB_DONE.reset();
+                    JOBS.signal(Job::Write { offset, len });
+                }
+                self.state = DFU_STATE_DNBUSY;
+            }
+            DFU_STATE_DNBUSY => match JOB_DONE.try_take() {
+                Some(Ok(())) => self.state = DFU_STATE_DNLOAD_IDLE,
+                Some(Err(error)) => self.fail(error as u8),
+                None => {}
+            },
+            DFU_STATE_MANIFEST_SYNC => {
+                info!("DFU download of {=u32} bytes complete", self.offset);
+                JOB_DONE.reset();
+                JOBS.signal(Job::Manifest);
+                self.state = DFU_STATE_MANIFEST;
+            }
+            DFU_STATE_MANIFEST => {
+                if let Some(Err(error)) = JOB_DONE.try_take() {
+                    self.fail(error as u8);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Handler for Download {
+    fn reset(&mut self) {
+        if self.state != DFU_STATE_MANIFEST {
+            self.state = DFU_STATE_IDLE;
+            self.status = DFU_STATUS_OK;
+            self.block = None;
+        }
+    }
+
+    fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+        if !self.is_ours(&req) {
+            return None;
+        }
+
+        match req.request {
+            DFU_REQ_DNLOAD => Some(self.download(data)),
+            DFU_REQ_CLRSTATUS | DFU_REQ_ABORT
+                if matches!(
+                    self.state,
+                    DFU_STATE_IDLE | DFU_STATE_DNLOAD_IDLE | DFU_STATE_ERROR
+                ) =>
+            {
+                self.state = DFU_STATE_IDLE;
+                self.status = DFU_STATUS_OK;
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if !self.is_ours(&req) {
+            return None;
+        }
+
+        match req.request {
+            DFU_REQ_GETSTATUS => {
+                self.poll();
+                let poll_timeout = match self.state {
+                    DFU_STATE_DNBUSY | DFU_STATE_MANIFEST => POLL_TIMEOUT_MS,
+                    _ => 0,
+                };
+                let [timeout_0, timeout_1, timeout_2, _] = poll_timeout.to_le_bytes();
+                // Status, poll timeout (3 bytes), state, status string
+                buf[..6].copy_from_slice(&[
+                    self.status,
+                    timeout_0,
+                    timeout_1,
+                    timeout_2,
+                    self.state,
+                    0,
+                ]);
+                Some(InResponse::Accepted(&buf[..6]))
+            }
+            DFU_REQ_GETSTATE => {
+                buf[0] = self.state;
+                Some(InResponse::Accepted(&buf[..1]))
+            }
+            _ => Some(InResponse::Rejected),
+        }
+    }
+}
@@ -0,0 +1,234 @@
+//! The vendor and product IDs and the strings the device enumerates with.
+//!
+//! The defaults are set in the `[usb]` section of `keypad.toml`, and can be
+//! changed when building through the environment too:
+//!
+//! ```text
+//! KEYPAD_USB_VID=0x1209 KEYPAD_USB_PID=0x0001 KEYPAD_USB_PRODUCT="Lab keypad 3" cargo build
+//! ```
+//!
+//! `KEYPAD_USB_MANUFACTURER` and `KEYPAD_USB_SERIAL` work the same way. Without
+//! `KEYPAD_USB_SERIAL` the serial number is the hex of the 96-bit unique ID
+//! of the chip, so every unit has its own.
+//!
+//! Changes made at runtime apply from the next boot. They are kept in RAM that
+//! survives a reset, and saved to flash with the other settings, see
+//! [`settings`](crate::settings).
+
+use crate::keypad_config;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, Ordering};
+use defmt::Format;
+use embassy_stm32::uid;
+use heapless::String;
+use static_cell::StaticCell;
+
+/// Longest manufacturer, product or serial number string.
+pub const STRING_LEN: usize = 32;
+
+/// Which of the [`UsbIdentity`] fields a change is for.
+#[derive(Clone, Copy, Format)]
+pub enum Field {
+    Manufacturer,
+    Product,
+    SerialNumber,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct UsbIdentity {
+    pub vid: u16,
+    pub pid: u16,
+    pub manufacturer: String<STRING_LEN>,
+    pub product: String<STRING_LEN>,
+    /// `None` for the unique ID of the chip.
+    pub serial_number: Option<String<STRING_LEN>>,
+}
+
+impl UsbIdentity {
+    /// The identity chosen when building.
+    pub fn build_default() -> Self {
+        Self {
+            vid: keypad_config::USB_VID,
+            pid: keypad_config::USB_PID,
+            manufacturer: String::try_from(keypad_config::USB_MANUFACTURER).unwrap_or_default(),
+            product: String::try_from(keypad_config::USB_PRODUCT).unwrap_or_default(),
+            serial_number: keypad_config::USB_SERIAL
+                .and_then(|serial| String::try_from(serial).ok()),
+        }
+    }
+
+    /// The serial number reported to the host.
+    pub fn serial_number(&self) -> &str {
+        match &self.serial_number {
+            Some(serial_number) => serial_number,
+            None => uid::uid_hex(),
+        }
+    }
+
+    /// Changes one of the strings, `None` meaning the default. Fails if the
+    /// text is too long.
+    pub fn set_string(&mut self, field: Field, text: Option<&str>) -> Result<(), ()> {
+        let default = Self::build_default();
+        match (field, text) {
+            (Field::Manufacturer, Some(text)) => self.manufacturer = String::try_from(text)?,
+            (Field::Manufacturer, None) => self.manufacturer = default.manufacturer,
+            (Field::Product, Some(text)) => self.product = String::try_from(text)?,
+            (Field::Product, None) => self.product = default.product,
+            (Field::SerialNumber, Some(text)) => self.serial_number = Some(String::try_from(text)?),
+            (Field::SerialNumber, None) => self.serial_number = default.serial_number,
+        }
+        Ok(())
+    }
+}
+
+static ACTIVE: StaticCell<UsbIdentity> = StaticCell::new();
+static CHANGED: AtomicBool = AtomicBool::new(false);
+
+/// Picks the identity for this boot: the one set before the last reset if
+/// there is one, otherwise the build default. Can only be called once.
+pub fn load() -> &'static UsbIdentity {
+    ACTIVE.init(next())
+}
+
+/// The identity the device will have after the next reset.
+pub fn next() -> UsbIdentity {
+    let record = unsafe { (&raw const NEXT).cast::<Record>().read_volatile() };
+    record.identity().unwrap_or_else(UsbIdentity::build_default)
+}
+
+/// Makes the device enumerate as `identity` from the next reset on.
+pub fn set_next(identity: &UsbIdentity) {
+    let record = Record::new(identity);
+    unsafe { (&raw mut NEXT).cast::<Record>().write_volatile(record) };
+    CHANGED.store(true, Ordering::Relaxed);
+}
+
+/// Takes the identity saved in flash for the next reset, unless one was set
+/// before the last reset that may not have been saved yet.
+pub fn restore(identity: &UsbIdentity) {
+    let record = unsafe { (&raw const NEXT).cast::<Record>().read_volatile() };
+    if record.identity().is_none() {
+        let record = Record::new(identity);
+        unsafe { (&raw mut NEXT).cast::<Record>().write_volatile(record) };
+    }
+}
+
+/// Size of an [`encode`]d identity.
+pub const ENCODED_SIZE: usize = 4 + 3 * (STRING_LEN + 1);
+
+/// The identity as saved in flash: the IDs, then the strings as in [`Record`].
+pub fn encode(identity: &UsbIdentity) -> [u8; ENCODED_SIZE] {
+    let record = Record::new(identity);
+    let mut bytes = [0; ENCODED_SIZE];
+    bytes[..2].copy_from_slice(&record.vid.to_le_bytes());
+    bytes[2..4].copy_from_slice(&record.pid.to_le_bytes());
+    for (chunk, packed) in bytes[4..].chunks_mut(STRING_LEN + 1).zip([
+        record.manufacturer,
+        record.product,
+        record.serial_number,
+    ]) {
+        chunk.copy_from_slice(&packed);
+    }
+    bytes
+}
+
+pub fn decode(bytes: &[u8]) -> Option<UsbIdentity> {
+    let bytes: &[u8; ENCODED_SIZE] = bytes.try_into().ok()?;
+    let packed = |index: usize| {
+        let start = 4 + index * (STRING_LEN + 1);
+        bytes[start..start + STRING_LEN + 1].try_into().unwrap()
+    };
+    let mut record = Record {
+        magic: RECORD_MAGIC,
+        vid: u16::from_le_bytes([bytes[0], bytes[1]]),
+        pid: u16::from_le_bytes([bytes[2], bytes[3]]),
+        manufacturer: packed(0),
+        product: packed(1),
+        serial_number: packed(2),
+        checksum: 0,
+    };
+    record.checksum = record.compute_checksum();
+    record.identity()
+}
+
+/// Whether the identity was changed since boot, so the device needs a reset
+/// to enumerate as [`next`].
+pub fn is_changed() -> bool {
+    CHANGED.load(Ordering::Relaxed)
+}
+
+const RECORD_MAGIC: u32 = 0x05B1_D000;
+
+/// [`UsbIdentity`] as stored across resets, in a layout where every bit
+/// pattern is valid.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Record {
+    magic: u32,
+    vid: u16,
+    pid: u16,
+    // Length, then the text
+    manufacturer: [u8; STRING_LEN + 1],
+    product: [u8; STRING_LEN + 1],
+    // Length 0 for the unique ID
+    serial_number: [u8; STRING_LEN + 1],
+    checksum: u32,
+}
+
+// Survives the reset, like the bootloader request
+#[unsafe(link_section = ".uninit.USB_IDENTITY")]
+static mut NEXT: MaybeUninit<Record> = MaybeUninit::uninit();
+
+impl Record {
+    fn new(identity: &UsbIdentity) -> Self {
+        let mut record = Self {
+            magic: RECORD_MAGIC,
+            vid: identity.vid,
+            pid: identity.pid,
+            manufacturer: pack(&identity.manufacturer),
+            product: pack(&identity.product),
+            serial_number: pack(identity.serial_number.as_deref().unwrap_or("")),
+            checksum: 0,
+        };
+        record.checksum = record.compute_checksum();
+        record
+    }
+
+    fn identity(&self) -> Option<UsbIdentity> {
+        if self.magic != RECORD_MAGIC || self.checksum != self.compute_checksum() {
+            return None;
+        }
+        let serial_number = unpack(&self.serial_number)?;
+        Some(UsbIdentity {
+            vid: self.vid,
+            pid: self.pid,
+            manufacturer: unpack(&self.manufacturer)?,
+            product: unpack(&self.product)?,
+            serial_number: (!serial_number.is_empty()).then_some(serial_number),
+        })
+    }
+
+    /// FNV-1a over everything but the checksum.
+    fn compute_checksum(&self) -> u32 {
+        [&self.vid.to_le_bytes()[..], &self.pid.to_le_bytes()]
+            .into_iter()
+            .chain([&self.manufacturer[..], &self.product, &self.serial_number])
+            .flatten()
+            .fold(0x811C_9DC5, |hash, &byte| {
+                (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+            })
+    }
+}
+
+fn pack(text: &str) -> [u8; STRING_LEN + 1] {
+    let mut packed = [0; STRING_LEN + 1];
+    let len = text.len().min(STRING_LEN);
+    packed[0] = len as u8;
+    packed[1..=len].copy_from_slice(&text.as_bytes()[..len]);
+    packed
+}
+
+fn unpack(packed: &[u8; STRING_LEN + 1]) -> Option<String<STRING_LEN>> {
+    let text = packed[1..].get(..usize::from(packed[0]))?;
+    String::try_from(core::str::from_utf8(text).ok()?).ok()
+}
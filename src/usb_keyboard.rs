@@ -1,23 +1,197 @@
-use core::sync::atomic::{AtomicBool, Ordering};
-use defmt::info;
+use crate::config_protocol::{self, REPLIES, REPORT_SIZE};
+use crate::device_mode::{ConfigChannel, DeviceMode};
+use crate::dfu;
+use crate::hid_class::{self, ReportReader};
+use crate::hid_report::{self, IDLE_INDEFINITE, KEYBOARD_IDLE_MS, MAX_REPORT_SIZE, ReportWriter};
+use crate::keyboard::NKRO_REPORT_SIZE;
+use crate::msc::{self, MassStorage};
+use crate::suspend;
+use crate::usb_identity::UsbIdentity;
+use crate::vendor::{self, VendorInterface};
+use crate::watchdog::{self, Task};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use defmt::{info, warn};
+use embassy_stm32::pac;
 use embassy_stm32::peripherals::USB_OTG_FS;
 use embassy_stm32::usb::Driver;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_usb::class::cdc_acm::{self, CdcAcmClass};
 use embassy_usb::class::hid::{
     HidReader, HidReaderWriter, HidWriter, ReportId, RequestHandler, State,
 };
+use embassy_usb::class::midi::MidiClass;
 use embassy_usb::control::OutResponse;
 use embassy_usb::{Builder, Handler, UsbDevice};
-use usbd_hid::descriptor::{KeyboardReport, SerializedDescriptor};
+use usbd_hid::descriptor::generator_prelude::*;
+
+pub const KEYBOARD_LED_NUM_LOCK: u8 = 1 << 0;
+pub const KEYBOARD_LED_CAPS_LOCK: u8 = 1 << 1;
+pub const KEYBOARD_LED_SCROLL_LOCK: u8 = 1 << 2;
+pub const KEYBOARD_LED_COMPOSE: u8 = 1 << 3;
+pub const KEYBOARD_LED_KANA: u8 = 1 << 4;
+
+/// The last keyboard LED output report sent by the host.
+pub static KEYBOARD_LEDS: AtomicU8 = AtomicU8::new(0);
+/// Signalled with the new [`KEYBOARD_LEDS`] whenever the host sends them.
+pub static KEYBOARD_LEDS_CHANGED: Signal<CriticalSectionRawMutex, u8> = Signal::new();
+
+/// Keyboard in the report protocol: the modifiers, then one bit for each of the
+/// usages 0x00-0xA7, so any number of keys can be held at once. In the boot
+/// protocol the standard 8-byte report is sent instead.
+#[rustfmt::skip]
+pub const KEYBOARD_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xA1, 0x01, // Collection (Application)
+    0x05, 0x07, //   Usage Page (Keyboard/Keypad)
+    0x19, 0xE0, //   Usage Minimum (Left Control)
+    0x29, 0xE7, //   Usage Maximum (Right GUI)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x29, 0xA7, //   Usage Maximum (0xA7)
+    0x95, 0xA8, //   Report Count (168)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x19, 0x01, //   Usage Minimum (Num Lock)
+    0x29, 0x05, //   Usage Maximum (Kana)
+    0x95, 0x05, //   Report Count (5)
+    0x91, 0x02, //   Output (Data, Variable, Absolute)
+    0x95, 0x03, //   Report Count (3)
+    0x91, 0x01, //   Output (Constant)
+    0xC0,       // End Collection
+];
+
+/// Reports the 16 keys as gamepad buttons 1-16, in [`Keypad::scan`] order.
+///
+/// [`Keypad::scan`]: crate::keypad::Keypad::scan
+#[gen_hid_descriptor(
+    (collection = APPLICATION, usage_page = GENERIC_DESKTOP, usage = GAMEPAD) = {
+        (usage_page = BUTTON, usage_min = BUTTON_1, usage_max = 0x10) = {
+            #[packed_bits 16] #[item_settings data,variable,absolute] buttons=input;
+        };
+    }
+)]
+pub struct GamepadReport {
+    pub buttons: [u8; 2],
+}
+
+/// Phone keypad on the Telephony page, for softphones.
+///
+/// Input report: hook switch, flash, redial and phone mute bits, then one
+/// phone key (1-12 for keys 0-9, `*` and `#`, 0 for none). Output report:
+/// off-hook, ring and mute LED bits.
+#[rustfmt::skip]
+pub const TELEPHONY_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x0B, // Usage Page (Telephony)
+    0x09, 0x01, // Usage (Phone)
+    0xA1, 0x01, // Collection (Application)
+    0x09, 0x20, //   Usage (Hook Switch)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x01, //   Report Count (1)
+    0x81, 0x22, //   Input (Data, Variable, Absolute, No Preferred State)
+    0x09, 0x21, //   Usage (Flash)
+    0x09, 0x24, //   Usage (Redial)
+    0x09, 0x2F, //   Usage (Phone Mute)
+    0x95, 0x03, //   Report Count (3)
+    0x81, 0x06, //   Input (Data, Variable, Relative)
+    0x95, 0x04, //   Report Count (4)
+    0x81, 0x01, //   Input (Constant)
+    0x19, 0xB0, //   Usage Minimum (Phone Key 0)
+    0x29, 0xBB, //   Usage Maximum (Phone Key Pound)
+    0x15, 0x01, //   Logical Minimum (1)
+    0x25, 0x0C, //   Logical Maximum (12)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x01, //   Report Count (1)
+    0x81, 0x40, //   Input (Data, Array, Absolute, Null State)
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x09, 0x17, //   Usage (Off-Hook)
+    0x09, 0x18, //   Usage (Ring)
+    0x09, 0x09, //   Usage (Mute)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x03, //   Report Count (3)
+    0x91, 0x22, //   Output (Data, Variable, Absolute, No Preferred State)
+    0x95, 0x05, //   Report Count (5)
+    0x91, 0x01, //   Output (Constant)
+    0xC0,       // End Collection
+];
+
+pub const TELEPHONY_HOOK_SWITCH: u8 = 1 << 0;
+pub const TELEPHONY_FLASH: u8 = 1 << 1;
+pub const TELEPHONY_REDIAL: u8 = 1 << 2;
+pub const TELEPHONY_PHONE_MUTE: u8 = 1 << 3;
+
+pub const TELEPHONY_LED_OFF_HOOK: u8 = 1 << 0;
+pub const TELEPHONY_LED_RING: u8 = 1 << 1;
+pub const TELEPHONY_LED_MUTE: u8 = 1 << 2;
+
+/// The last telephony LED output report sent by the host.
+pub static TELEPHONY_LEDS: AtomicU8 = AtomicU8::new(0);
+
+/// Vendor defined page 0xFF60 with 32-byte input and output reports, the
+/// layout raw HID host tools look for.
+#[rustfmt::skip]
+pub const RAW_HID_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x60, 0xFF, // Usage Page (Vendor Defined 0xFF60)
+    0x09, 0x61,       // Usage (0x61)
+    0xA1, 0x01,       // Collection (Application)
+    0x09, 0x62,       //   Usage (0x62)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x20,       //   Report Count (32)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute)
+    0x09, 0x63,       //   Usage (0x63)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x20,       //   Report Count (32)
+    0x91, 0x02,       //   Output (Data, Variable, Absolute)
+    0xC0,             // End Collection
+];
 
 pub struct UsbKeyboard<'a> {
     pub usb: UsbDevice<'a, Driver<'a, USB_OTG_FS>>,
-    pub hid_reader: HidReader<'a, Driver<'a, USB_OTG_FS>, 1>,
-    pub hid_writer: HidWriter<'a, Driver<'a, USB_OTG_FS>, 8>,
+    pub function: UsbFunction<'a>,
+    pub config_interface: ConfigInterface<'a>,
     pub request_handler: &'a mut UsbKeyboardRequestHandler,
+    pub raw_hid_request_handler: &'a mut RawHidRequestHandler,
+}
+
+/// The class interface the device exposes for the keys, depending on the
+/// [`DeviceMode`].
+pub enum UsbFunction<'a> {
+    Hid(ReportReader<'a>, ReportWriter<'a>),
+    Midi(MidiClass<'a, Driver<'a, USB_OTG_FS>>),
+}
+
+/// The class interface the device is configured through, depending on the
+/// [`ConfigChannel`].
+pub enum ConfigInterface<'a> {
+    Serial(CdcAcmClass<'a, Driver<'a, USB_OTG_FS>>),
+    RawHid(
+        HidReader<'a, Driver<'a, USB_OTG_FS>, REPORT_SIZE>,
+        HidWriter<'a, Driver<'a, USB_OTG_FS>, REPORT_SIZE>,
+    ),
+    Storage(MassStorage<'a>),
+    Vendor(VendorInterface<'a>),
 }
 
 impl<'a> UsbKeyboard<'a> {
-    pub fn new(config: &'a mut Config<'a>, driver: Driver<'a, USB_OTG_FS>) -> UsbKeyboard<'a> {
+    pub fn new(
+        config: &'a mut Config<'a>,
+        driver: Driver<'a, USB_OTG_FS>,
+        mode: DeviceMode,
+        config_channel: ConfigChannel,
+    ) -> UsbKeyboard<'a> {
         let mut builder = Builder::new(
             driver,
             config.embassy_config,
@@ -29,26 +203,100 @@ impl<'a> UsbKeyboard<'a> {
 
         builder.handler(&mut config.device_handler);
 
-        let class_config = embassy_usb::class::hid::Config {
-            report_descriptor: KeyboardReport::desc(),
-            request_handler: None,
-            poll_ms: 60,
-            max_packet_size: 8,
+        config.request_handler.mode = mode;
+        config.control_request_handler.mode = mode;
+        let idle_ms = match mode {
+            DeviceMode::Keyboard => KEYBOARD_IDLE_MS,
+            _ => IDLE_INDEFINITE,
+        };
+        hid_report::set_idle_ms(None, idle_ms);
+        let function = match mode {
+            DeviceMode::Keyboard => hid_function(
+                &mut builder,
+                &mut config.hid_state,
+                KEYBOARD_REPORT_DESCRIPTOR,
+                NKRO_REPORT_SIZE,
+                true,
+                &mut config.control_request_handler,
+            ),
+            DeviceMode::Gamepad => hid_function(
+                &mut builder,
+                &mut config.hid_state,
+                GamepadReport::desc(),
+                2,
+                false,
+                &mut config.control_request_handler,
+            ),
+            DeviceMode::Telephony => hid_function(
+                &mut builder,
+                &mut config.hid_state,
+                TELEPHONY_REPORT_DESCRIPTOR,
+                2,
+                false,
+                &mut config.control_request_handler,
+            ),
+            DeviceMode::Midi => UsbFunction::Midi(MidiClass::new(&mut builder, 1, 1, 64)),
         };
 
-        let (hid_reader, hid_writer) =
-            HidReaderWriter::<_, 1, 8>::new(&mut builder, &mut config.hid_state, class_config)
-                .split();
+        let config_interface = match config_channel {
+            ConfigChannel::Serial => {
+                ConfigInterface::Serial(CdcAcmClass::new(&mut builder, &mut config.cdc_state, 64))
+            }
+            ConfigChannel::RawHid => {
+                let class_config = embassy_usb::class::hid::Config {
+                    report_descriptor: RAW_HID_REPORT_DESCRIPTOR,
+                    request_handler: None,
+                    poll_ms: 10,
+                    max_packet_size: REPORT_SIZE as u16,
+                };
+                let (reader, writer) =
+                    HidReaderWriter::new(&mut builder, &mut config.raw_hid_state, class_config)
+                        .split();
+                ConfigInterface::RawHid(reader, writer)
+            }
+            ConfigChannel::Storage => {
+                ConfigInterface::Storage(msc::new(&mut builder, &mut config.msc_state))
+            }
+            ConfigChannel::Vendor => {
+                ConfigInterface::Vendor(vendor::new(&mut builder, &mut config.vendor_state))
+            }
+        };
+        config.raw_hid_request_handler.mode = mode;
+        dfu::new(&mut builder, &mut config.dfu_state);
 
         Self {
             usb: builder.build(),
-            hid_reader,
-            hid_writer,
+            function,
+            config_interface,
             request_handler: &mut config.request_handler,
+            raw_hid_request_handler: &mut config.raw_hid_request_handler,
         }
     }
 }
 
+fn hid_function<'a>(
+    builder: &mut Builder<'a, Driver<'a, USB_OTG_FS>>,
+    state: &'a mut hid_class::State<'a>,
+    report_descriptor: &'a [u8],
+    report_size: usize,
+    boot_keyboard: bool,
+    request_handler: &'a mut UsbKeyboardRequestHandler,
+) -> UsbFunction<'a> {
+    let class_config = hid_class::Config {
+        report_descriptor,
+        boot_keyboard,
+        request_handler,
+        poll_ms: 60,
+        max_packet_size: MAX_REPORT_SIZE as u16,
+    };
+
+    let (hid_reader, ep_in) = hid_class::new(builder, state, class_config);
+    UsbFunction::Hid(
+        hid_reader,
+        ReportWriter::new(ep_in, &[0; MAX_REPORT_SIZE][..report_size]),
+    )
+}
+
 pub struct Config<'a> {
     embassy_config: embassy_usb::Config<'a>,
     config_descriptor: [u8; 256],
@@ -56,17 +304,27 @@ pub struct Config<'a> {
     msos_descriptor: [u8; 256],
     control_buf: [u8; 64],
     request_handler: UsbKeyboardRequestHandler,
+    // Handles the requests on the control pipe, `request_handler` those on
+    // the interrupt OUT endpoint
+    control_request_handler: UsbKeyboardRequestHandler,
+    raw_hid_request_handler: RawHidRequestHandler,
     device_handler: UsbKeyboardDeviceHandler,
-    hid_state: State<'a>,
+    hid_state: hid_class::State<'a>,
+    cdc_state: cdc_acm::State<'a>,
+    raw_hid_state: State<'a>,
+    msc_state: msc::State,
+    vendor_state: vendor::State,
+    dfu_state: dfu::State,
 }
 
-impl Config<'_> {
-    pub const fn new() -> Self {
+impl<'a> Config<'a> {
+    pub fn new(identity: &'a UsbIdentity) -> Self {
         // Create embassy-usb config
-        let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
-        config.manufacturer = Some("Embassy");
-        config.product = Some("HID keyboard example");
-        config.serial_number = Some("12345678");
+        let mut config = embassy_usb::Config::new(identity.vid, identity.pid);
+        config.manufacturer = Some(&identity.manufacturer);
+        config.product = Some(&identity.product);
+        config.serial_number = Some(identity.serial_number());
+        config.supports_remote_wakeup = true;
 
         Self {
             embassy_config: config,
@@ -75,56 +333,150 @@ impl Config<'_> {
             msos_descriptor: [0; 256],
             control_buf: [0; 64],
             request_handler: UsbKeyboardRequestHandler::new(),
+            control_request_handler: UsbKeyboardRequestHandler::new(),
+            raw_hid_request_handler: RawHidRequestHandler::new(),
             device_handler: UsbKeyboardDeviceHandler::new(),
-            hid_state: State::new(),
+            hid_state: hid_class::State::new(),
+            cdc_state: cdc_acm::State::new(),
+            raw_hid_state: State::new(),
+            msc_state: msc::State::new(),
+            vendor_state: vendor::State::new(),
+            dfu_state: dfu::State::new(),
         }
     }
 }
 
-pub struct UsbKeyboardRequestHandler {}
+pub struct UsbKeyboardRequestHandler {
+    mode: DeviceMode,
+}
 
 impl UsbKeyboardRequestHandler {
     const fn new() -> Self {
-        Self {}
+        Self {
+            mode: DeviceMode::Keyboard,
+        }
     }
 }
 
 impl RequestHandler for UsbKeyboardRequestHandler {
-    fn get_report(&mut self, id: ReportId, _buf: &mut [u8]) -> Option<usize> {
+    fn get_report(&mut self, id: ReportId, buf: &mut [u8]) -> Option<usize> {
         info!("Get report for {:?}", id);
-        None
+        let leds = match (self.mode, id) {
+            (_, ReportId::In(_)) => return Some(hid_report::last_report(buf)),
+            (DeviceMode::Keyboard, ReportId::Out(_)) => &KEYBOARD_LEDS,
+            (DeviceMode::Telephony, ReportId::Out(_)) => &TELEPHONY_LEDS,
+            _ => return None,
+        };
+        buf[0] = leds.load(Ordering::Relaxed);
+        Some(1)
     }
 
     fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
-        info!("Set report for {:?}: {=[u8]}", id, data);
+        match (self.mode, id, data) {
+            (DeviceMode::Keyboard, ReportId::Out(_), [leds, ..]) => {
+                KEYBOARD_LEDS.store(*leds, Ordering::Relaxed);
+                KEYBOARD_LEDS_CHANGED.signal(*leds);
+                info!(
+                    "Keyboard LEDs: num lock {}, caps lock {}, scroll lock {}, compose {}, kana {}",
+                    leds & KEYBOARD_LED_NUM_LOCK != 0,
+                    leds & KEYBOARD_LED_CAPS_LOCK != 0,
+                    leds & KEYBOARD_LED_SCROLL_LOCK != 0,
+                    leds & KEYBOARD_LED_COMPOSE != 0,
+                    leds & KEYBOARD_LED_KANA != 0,
+                );
+            }
+            (DeviceMode::Telephony, ReportId::Out(_), [leds, ..]) => {
+                TELEPHONY_LEDS.store(*leds, Ordering::Relaxed);
+                info!(
+                    "Telephony LEDs: off-hook {}, ring {}, mute {}",
+                    leds & TELEPHONY_LED_OFF_HOOK != 0,
+                    leds & TELEPHONY_LED_RING != 0,
+                    leds & TELEPHONY_LED_MUTE != 0,
+                );
+            }
+            _ => info!("Set report for {:?}: {=[u8]}", id, data),
+        }
         OutResponse::Accepted
     }
 
     fn get_idle_ms(&mut self, id: Option<ReportId>) -> Option<u32> {
         info!("Get idle rate for {:?}", id);
-        None
+        Some(hid_report::idle_ms(id))
     }
 
     fn set_idle_ms(&mut self, id: Option<ReportId>, duration_ms: u32) {
         info!("Set idle rate for {:?} to {:?}", id, duration_ms);
+        hid_report::set_idle_ms(id, duration_ms);
     }
 }
 
-struct UsbKeyboardDeviceHandler {
-    configured: AtomicBool,
+/// Answers the [`config_protocol`] and [`via`] requests arriving on the raw
+/// HID interface.
+///
+/// [`config_protocol`]: crate::config_protocol
+pub struct RawHidRequestHandler {
+    mode: DeviceMode,
 }
 
-impl UsbKeyboardDeviceHandler {
+impl RawHidRequestHandler {
     const fn new() -> Self {
         Self {
-            configured: AtomicBool::new(false),
+            mode: DeviceMode::Keyboard,
         }
     }
 }
 
+impl RequestHandler for RawHidRequestHandler {
+    fn set_report(&mut self, _id: ReportId, data: &[u8]) -> OutResponse {
+        let reply = config_protocol::dispatch(data, self.mode);
+        if REPLIES.try_send(reply).is_err() {
+            warn!("Configuration reply dropped, the host isn't reading them");
+        }
+        OutResponse::Accepted
+    }
+}
+
+static CONFIGURED: AtomicBool = AtomicBool::new(false);
+/// Signalled whenever the device gets configured or loses its configuration.
+pub static CONFIGURED_CHANGED: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+/// Whether the host has configured the device, so the endpoints carry
+/// reports.
+pub fn is_configured() -> bool {
+    CONFIGURED.load(Ordering::Relaxed)
+}
+
+fn set_configured(configured: bool) {
+    if CONFIGURED.swap(configured, Ordering::Relaxed) != configured {
+        CONFIGURED_CHANGED.signal(configured);
+    }
+}
+
+/// Whether the USB peripheral raised a bus event, e.g. a reset or a suspend,
+/// that the stack hasn't handled yet. The stack clears the flags as it
+/// handles the events.
+pub fn bus_event_pending() -> bool {
+    let events = pac::USB_OTG_FS.gintsts().read();
+    events.usbrst()
+        || events.enumdne()
+        || events.usbsusp()
+        || events.wkupint()
+        || events.srqint()
+        || events.otgint()
+}
+
+struct UsbKeyboardDeviceHandler;
+
+impl UsbKeyboardDeviceHandler {
+    const fn new() -> Self {
+        Self
+    }
+}
+
 impl Handler for UsbKeyboardDeviceHandler {
     fn enabled(&mut self, _enabled: bool) {
-        self.configured.store(false, Ordering::Relaxed);
+        watchdog::check_in(Task::Usb);
+        set_configured(false);
         if _enabled {
             info!("Device enabled");
         } else {
@@ -133,17 +485,22 @@ impl Handler for UsbKeyboardDeviceHandler {
     }
 
     fn reset(&mut self) {
-        self.configured.store(false, Ordering::Relaxed);
+        watchdog::check_in(Task::Usb);
+        set_configured(false);
+        // A reset ends a suspend too, without a resume
+        suspend::set_suspended(false);
         info!("Bus reset, the Vbus current limit is 100mA");
     }
 
     fn addressed(&mut self, _addr: u8) {
-        self.configured.store(false, Ordering::Relaxed);
+        watchdog::check_in(Task::Usb);
+        set_configured(false);
         info!("USB address set to: {}", _addr);
     }
 
     fn configured(&mut self, _configured: bool) {
-        self.configured.store(_configured, Ordering::Relaxed);
+        watchdog::check_in(Task::Usb);
+        set_configured(_configured);
         if _configured {
             info!(
                 "Device configured, it may now draw up to the configured current limit from Vbus."
@@ -152,4 +509,19 @@ impl Handler for UsbKeyboardDeviceHandler {
             info!("Device is no longer configured, the Vbus current limit is 100mA.");
         }
     }
+
+    fn suspended(&mut self, suspended: bool) {
+        watchdog::check_in(Task::Usb);
+        suspend::set_suspended(suspended);
+        if suspended {
+            info!("Bus suspended, the Vbus current limit is 2.5mA");
+        } else {
+            info!("Bus resumed");
+        }
+    }
+
+    fn remote_wakeup_enabled(&mut self, enabled: bool) {
+        suspend::set_host_allows_wakeup(enabled);
+        info!("Remote wakeup enabled by the host: {}", enabled);
+    }
 }
@@ -0,0 +1,165 @@
+//! Vendor specific bulk interface for configurators that talk to the device
+//! directly, through WebUSB in a browser or libusb.
+//!
+//! MS OS 2.0 descriptors bind it to WinUSB, so Windows needs no driver, and a
+//! WebUSB platform capability lets browsers open it. Every bulk OUT transfer is
+//! one request of the [`config_protocol`](crate::config_protocol), or of VIA,
+//! and is answered by one 32-byte bulk IN transfer, just like the reports of
+//! the raw HID interface.
+//!
+//! A landing page is announced if `KEYPAD_WEBUSB_URL` is set when building.
+
+use crate::config_protocol::REPORT_SIZE;
+use core::mem::MaybeUninit;
+use embassy_stm32::peripherals::USB_OTG_FS;
+use embassy_stm32::usb::Driver;
+use embassy_usb::control::{InResponse, Recipient, Request, RequestType};
+use embassy_usb::descriptor::capability_type;
+use embassy_usb::driver::{self, Endpoint as _, EndpointError, EndpointIn as _, EndpointOut as _};
+use embassy_usb::msos::{self, windows_version};
+use embassy_usb::{Builder, Handler};
+
+type EndpointIn<'d> = <Driver<'d, USB_OTG_FS> as driver::Driver<'d>>::EndpointIn;
+type EndpointOut<'d> = <Driver<'d, USB_OTG_FS> as driver::Driver<'d>>::EndpointOut;
+
+const USB_CLASS_VENDOR: u8 = 0xFF;
+const USB_SUBCLASS_NONE: u8 = 0x00;
+const USB_PROTOCOL_NONE: u8 = 0x00;
+const MAX_PACKET_SIZE: u16 = 64;
+
+/// `bRequest` of the MS OS 2.0 descriptor set request.
+const MSOS_VENDOR_CODE: u8 = 0x01;
+/// `bRequest` of the WebUSB requests.
+const WEBUSB_VENDOR_CODE: u8 = 0x02;
+const WEBUSB_REQ_GET_URL: u16 = 0x02;
+const WEBUSB_DESC_DESCTYPE_URL: u8 = 0x03;
+
+/// The interface class GUID host software finds the interface by.
+pub const DEVICE_INTERFACE_GUID: &str = "{619096B5-591C-4C05-A7E6-B29B848897F4}";
+
+const LANDING_PAGE: Option<&str> = option_env!("KEYPAD_WEBUSB_URL");
+
+pub struct State {
+    control: MaybeUninit<Control>,
+}
+
+impl State {
+    pub const fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+        }
+    }
+}
+
+/// Adds the vendor interface along with the descriptors that make Windows
+/// and browsers pick it up.
+pub fn new<'d>(
+    builder: &mut Builder<'d, Driver<'d, USB_OTG_FS>>,
+    state: &'d mut State,
+) -> VendorInterface<'d> {
+    builder.msos_descriptor(windows_version::WIN8_1, MSOS_VENDOR_CODE);
+
+    let mut func = builder.function(USB_CLASS_VENDOR, USB_SUBCLASS_NONE, USB_PROTOCOL_NONE);
+    func.msos_feature(msos::CompatibleIdFeatureDescriptor::new("WINUSB", ""));
+    func.msos_feature(msos::RegistryPropertyFeatureDescriptor::new(
+        "DeviceInterfaceGUIDs",
+        msos::PropertyData::RegMultiSz(&[DEVICE_INTERFACE_GUID]),
+    ));
+    let mut iface = func.interface();
+    let mut alt = iface.alt_setting(USB_CLASS_VENDOR, USB_SUBCLASS_NONE, USB_PROTOCOL_NONE, None);
+    alt.bos_capability(
+        capability_type::PLATFORM,
+        &[
+            // Reserved
+            0x00,
+            // WebUSB platform capability UUID 3408b638-09a9-47a0-8bfd-a0768815b665
+            0x38,
+            0xB6,
+            0x08,
+            0x34,
+            0xA9,
+            0x09,
+            0xA0,
+            0x47,
+            0x8B,
+            0xFD,
+            0xA0,
+            0x76,
+            0x88,
+            0x15,
+            0xB6,
+            0x65,
+            // WebUSB 1.0
+            0x00,
+            0x01,
+            WEBUSB_VENDOR_CODE,
+            // Landing page URL index
+            LANDING_PAGE.is_some().into(),
+        ],
+    );
+    let ep_out = alt.endpoint_bulk_out(MAX_PACKET_SIZE);
+    let ep_in = alt.endpoint_bulk_in(MAX_PACKET_SIZE);
+    drop(func);
+
+    builder.handler(state.control.write(Control));
+
+    VendorInterface { ep_in, ep_out }
+}
+
+struct Control;
+
+impl Handler for Control {
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient, req.request, req.index)
+            != (
+                RequestType::Vendor,
+                Recipient::Device,
+                WEBUSB_VENDOR_CODE,
+                WEBUSB_REQ_GET_URL,
+            )
+        {
+            return None;
+        }
+
+        let url = LANDING_PAGE.filter(|_| req.value == 1)?;
+        let (scheme, url) = match (url.strip_prefix("https://"), url.strip_prefix("http://")) {
+            (Some(url), _) => (1, url),
+            (_, Some(url)) => (0, url),
+            _ => (0xFF, url),
+        };
+        let len = 3 + url.len();
+        if len > buf.len().min(u8::MAX.into()) {
+            return Some(InResponse::Rejected);
+        }
+        buf[..3].copy_from_slice(&[len as u8, WEBUSB_DESC_DESCTYPE_URL, scheme]);
+        buf[3..len].copy_from_slice(url.as_bytes());
+        Some(InResponse::Accepted(&buf[..len]))
+    }
+}
+
+pub struct VendorInterface<'d> {
+    ep_in: EndpointIn<'d>,
+    ep_out: EndpointOut<'d>,
+}
+
+impl VendorInterface<'_> {
+    /// Waits for the next request, returning its length.
+    pub async fn read_request(
+        &mut self,
+        request: &mut [u8; REPORT_SIZE],
+    ) -> Result<usize, EndpointError> {
+        loop {
+            match self.ep_out.read(request).await {
+                // Some hosts send zero length packets
+                Ok(0) => {}
+                Ok(len) => return Ok(len),
+                Err(EndpointError::Disabled) => self.ep_out.wait_enabled().await,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub async fn write_reply(&mut self, reply: &[u8; REPORT_SIZE]) -> Result<(), EndpointError> {
+        self.ep_in.write(reply).await
+    }
+}
@@ -0,0 +1,193 @@
+//! The raw HID protocol of the [VIA](https://www.caniusevia.com/) keymap
+//! editor, so the keypad can be remapped from the VIA app with the
+//! definition in `via/keypad-hid.json`.
+//!
+//! Requests are answered with the same report, with the results written over
+//! the arguments. Multi-byte values are big endian, unlike in the
+//! [`config_protocol`](crate::config_protocol). Commands this firmware
+//! doesn't implement, such as lighting and the Vial extensions, are answered
+//! with [`ID_UNHANDLED`] in place of the command id.
+
+use crate::config_protocol::{REPORT_SIZE, Reply};
+use crate::keymap::{self, DEFAULT_KEYMAP, LAYERS, MACRO_BUFFER_SIZE, MACRO_COUNT};
+use crate::keypad::{COLUMNS, LAST_SCAN, ROWS};
+use core::sync::atomic::Ordering;
+use embassy_time::Instant;
+
+pub const PROTOCOL_VERSION: u16 = 0x000C;
+
+const ID_GET_PROTOCOL_VERSION: u8 = 0x01;
+const ID_GET_KEYBOARD_VALUE: u8 = 0x02;
+const ID_SET_KEYBOARD_VALUE: u8 = 0x03;
+const ID_DYNAMIC_KEYMAP_GET_KEYCODE: u8 = 0x04;
+const ID_DYNAMIC_KEYMAP_SET_KEYCODE: u8 = 0x05;
+const ID_DYNAMIC_KEYMAP_RESET: u8 = 0x06;
+const ID_EEPROM_RESET: u8 = 0x0A;
+const ID_BOOTLOADER_JUMP: u8 = 0x0B;
+const ID_DYNAMIC_KEYMAP_MACRO_GET_COUNT: u8 = 0x0C;
+const ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE: u8 = 0x0D;
+const ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER: u8 = 0x0E;
+const ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER: u8 = 0x0F;
+const ID_DYNAMIC_KEYMAP_MACRO_RESET: u8 = 0x10;
+const ID_DYNAMIC_KEYMAP_GET_LAYER_COUNT: u8 = 0x11;
+const ID_DYNAMIC_KEYMAP_GET_BUFFER: u8 = 0x12;
+const ID_DYNAMIC_KEYMAP_SET_BUFFER: u8 = 0x13;
+pub const ID_UNHANDLED: u8 = 0xFF;
+
+const ID_UPTIME: u8 = 0x01;
+const ID_LAYOUT_OPTIONS: u8 = 0x02;
+const ID_SWITCH_MATRIX_STATE: u8 = 0x03;
+const ID_FIRMWARE_VERSION: u8 = 0x04;
+
+/// Largest chunk the buffer commands move, after the id, offset and size.
+const MAX_CHUNK: usize = REPORT_SIZE - 4;
+
+pub fn handle(request: &[u8]) -> Reply {
+    let mut report = [0; REPORT_SIZE];
+    let len = request.len().min(REPORT_SIZE);
+    report[..len].copy_from_slice(&request[..len]);
+
+    let handled = match report[0] {
+        ID_GET_PROTOCOL_VERSION => {
+            report[1..3].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+            true
+        }
+        ID_GET_KEYBOARD_VALUE => get_keyboard_value(&mut report),
+        // There is only one layout, so the options are accepted and ignored
+        ID_SET_KEYBOARD_VALUE => report[1] == ID_LAYOUT_OPTIONS,
+        ID_DYNAMIC_KEYMAP_GET_KEYCODE => match key(&report) {
+            Some((layer, key)) => {
+                report[4..6].copy_from_slice(&keymap::keycode(layer, key).to_be_bytes());
+                true
+            }
+            None => false,
+        },
+        ID_DYNAMIC_KEYMAP_SET_KEYCODE => match key(&report) {
+            Some((layer, key)) => {
+                keymap::set_keycode(layer, key, u16::from_be_bytes([report[4], report[5]]));
+                true
+            }
+            None => false,
+        },
+        ID_DYNAMIC_KEYMAP_RESET => {
+            keymap::set_keymap(DEFAULT_KEYMAP);
+            true
+        }
+        ID_EEPROM_RESET => {
+            keymap::set_keymap(DEFAULT_KEYMAP);
+            keymap::with_macros(|macros| macros.fill(0));
+            true
+        }
+        ID_BOOTLOADER_JUMP => true,
+        ID_DYNAMIC_KEYMAP_MACRO_GET_COUNT => {
+            report[1] = MACRO_COUNT as u8;
+            true
+        }
+        ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE => {
+            report[1..3].copy_from_slice(&(MACRO_BUFFER_SIZE as u16).to_be_bytes());
+            true
+        }
+        ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER => {
+            let (offset, size) = chunk(&report, MACRO_BUFFER_SIZE);
+            keymap::with_macros(|macros| {
+                report[4..4 + size].copy_from_slice(&macros[offset..offset + size])
+            });
+            true
+        }
+        ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER => {
+            let (offset, size) = chunk(&report, MACRO_BUFFER_SIZE);
+            keymap::with_macros(|macros| {
+                macros[offset..offset + size].copy_from_slice(&report[4..4 + size])
+            });
+            true
+        }
+        ID_DYNAMIC_KEYMAP_MACRO_RESET => {
+            keymap::with_macros(|macros| macros.fill(0));
+            true
+        }
+        ID_DYNAMIC_KEYMAP_GET_LAYER_COUNT => {
+            report[1] = LAYERS as u8;
+            true
+        }
+        ID_DYNAMIC_KEYMAP_GET_BUFFER => {
+            let (offset, size) = chunk(&report, KEYMAP_BUFFER_SIZE);
+            let keymap = keymap::keymap();
+            for (i, byte) in report[4..4 + size].iter_mut().enumerate() {
+                *byte = keymap_byte(&keymap, offset + i);
+            }
+            true
+        }
+        ID_DYNAMIC_KEYMAP_SET_BUFFER => {
+            let (offset, size) = chunk(&report, KEYMAP_BUFFER_SIZE);
+            let mut keymap = keymap::keymap();
+            for (i, &byte) in report[4..4 + size].iter().enumerate() {
+                set_keymap_byte(&mut keymap, offset + i, byte);
+            }
+            keymap::set_keymap(keymap);
+            true
+        }
+        _ => false,
+    };
+
+    if !handled {
+        report[0] = ID_UNHANDLED;
+    }
+    Reply {
+        report,
+        enter_bootloader: handled && request.first() == Some(&ID_BOOTLOADER_JUMP),
+    }
+}
+
+fn get_keyboard_value(report: &mut [u8; REPORT_SIZE]) -> bool {
+    let value = match report[1] {
+        ID_UPTIME => Instant::now().as_millis() as u32,
+        ID_LAYOUT_OPTIONS => 0,
+        ID_SWITCH_MATRIX_STATE => {
+            // One byte per row, a bit per column
+            let keys = LAST_SCAN.load(Ordering::Relaxed);
+            for row in 0..ROWS {
+                report[2 + row] = (keys >> (row * COLUMNS)) as u8 & ((1 << COLUMNS) - 1) as u8;
+            }
+            return true;
+        }
+        ID_FIRMWARE_VERSION => {
+            let version = |part: &str| part.parse::<u32>().unwrap_or(0);
+            version(env!("CARGO_PKG_VERSION_MAJOR")) << 16
+                | version(env!("CARGO_PKG_VERSION_MINOR")) << 8
+                | version(env!("CARGO_PKG_VERSION_PATCH"))
+        }
+        _ => return false,
+    };
+    report[2..6].copy_from_slice(&value.to_be_bytes());
+    true
+}
+
+/// Layer and key index of a `[id, layer, row, column]` request.
+fn key(report: &[u8; REPORT_SIZE]) -> Option<(usize, usize)> {
+    let (layer, row, column) = (report[1] as usize, report[2] as usize, report[3] as usize);
+    (layer < LAYERS && row < ROWS && column < COLUMNS).then_some((layer, row * COLUMNS + column))
+}
+
+/// Offset and size of a `[id, offset, size]` buffer request, clamped to a
+/// buffer of `len` bytes.
+fn chunk(report: &[u8; REPORT_SIZE], len: usize) -> (usize, usize) {
+    let offset = (u16::from_be_bytes([report[1], report[2]]) as usize).min(len);
+    let size = (report[3] as usize).min(MAX_CHUNK).min(len - offset);
+    (offset, size)
+}
+
+/// The keymap as VIA sees it: big endian keycodes, layer by layer, row by
+/// row.
+const KEYMAP_BUFFER_SIZE: usize = LAYERS * LAYER_BUFFER_SIZE;
+const LAYER_BUFFER_SIZE: usize = ROWS * COLUMNS * 2;
+
+fn keymap_byte(keymap: &keymap::Keymap, offset: usize) -> u8 {
+    keymap[offset / LAYER_BUFFER_SIZE][offset % LAYER_BUFFER_SIZE / 2].to_be_bytes()[offset % 2]
+}
+
+fn set_keymap_byte(keymap: &mut keymap::Keymap, offset: usize, byte: u8) {
+    let keycode = &mut keymap[offset / LAYER_BUFFER_SIZE][offset % LAYER_BUFFER_SIZE / 2];
+    let mut bytes = keycode.to_be_bytes();
+    bytes[offset % 2] = byte;
+    *keycode = u16::from_be_bytes(bytes);
+}
@@ -0,0 +1,154 @@
+//! Watchdog supervision of the long running tasks.
+//!
+//! The IWDG restarts the chip unless it is fed, and [`run`] only feeds it
+//! while no supervised task stalled. A task checks in on every pass of its
+//! loop, and while it waits for something that may never come, e.g. a key
+//! press or a request from the host, through [`alive_while`]. Whatever a task
+//! does in between has to finish within a round, so what it sends the host is
+//! dropped after [`HOST_TIMEOUT`] if the host doesn't take it. A task stuck
+//! anywhere else, e.g. on a report that never goes out although the host
+//! polls for it, stops checking in, and the keypad restarts a few seconds
+//! later instead of going silent.
+//!
+//! The USB task checks in whenever the stack handles a bus event, and owes
+//! nothing while no bus event waits for it. A stack that stopped answering,
+//! e.g. in the middle of a control transfer, is found out once the host
+//! resets the bus to get it back.
+//!
+//! The tasks that stopped are kept in RAM across the reset, and together with
+//! the reset flags of the RCC make up the [`ResetCause`] of the next boot.
+
+use crate::usb_keyboard;
+use core::cell::Cell;
+use core::future::Future;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU16, Ordering};
+use defmt::warn;
+use embassy_futures::select::{Either, select};
+use embassy_stm32::pac;
+use embassy_stm32::peripherals::IWDG;
+use embassy_stm32::wdg::IndependentWatchdog;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Duration, Timer};
+use keypad_logic::watchdog::{self, ResetFlags, Rounds};
+
+pub use keypad_logic::watchdog::{ResetCause, Task};
+
+/// How often the supervisor looks at the tasks.
+const ROUND: Duration = Duration::from_secs(1);
+
+/// The IWDG runs from the LSI, which may be a third faster than its nominal
+/// 32 kHz, so this leaves room for the two rounds a task gets before it
+/// counts as stalled, and a flash erase stalling the CPU on top.
+const TIMEOUT_US: u32 = 5_000_000;
+
+/// How often a waiting task checks in, a few times per round.
+const CHECK_IN_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long a task waits for the host to take a packet before dropping it,
+/// well within a round.
+pub const HOST_TIMEOUT: Duration = Duration::from_millis(200);
+
+static SUPERVISED: AtomicU16 = AtomicU16::new(0);
+static ALIVE: AtomicU16 = AtomicU16::new(0);
+static LAST_RESET: Mutex<CriticalSectionRawMutex, Cell<ResetCause>> =
+    Mutex::new(Cell::new(ResetCause::PowerOn));
+
+// Survives the reset, so the next boot can tell which tasks stopped
+#[unsafe(link_section = ".uninit.STALLED")]
+static mut STALLED: MaybeUninit<[u32; 2]> = MaybeUninit::uninit();
+
+/// Reads and clears the reset flags, and remembers the cause for
+/// [`last_reset`]. Has to run once, early at boot.
+pub fn check_reset_cause() -> ResetCause {
+    let csr = pac::RCC.csr().read();
+    let retained = unsafe { (&raw mut STALLED).cast::<[u32; 2]>().read_volatile() };
+    record_stalled(0);
+    pac::RCC.csr().modify(|w| w.set_rmvf(true));
+
+    let flags = ResetFlags {
+        watchdog: csr.wdgrstf(),
+        window_watchdog: csr.wwdgrstf(),
+        low_power: csr.lpwrrstf(),
+        software: csr.sftrstf(),
+        power_on: csr.porrstf(),
+        brownout: csr.borrstf(),
+    };
+    let cause = ResetCause::new(flags, retained);
+    LAST_RESET.lock(|last| last.set(cause));
+    cause
+}
+
+/// The cause found by [`check_reset_cause`].
+pub fn last_reset() -> ResetCause {
+    LAST_RESET.lock(|cause| cause.get())
+}
+
+/// Makes the supervisor wait for `task` from now on. Called by the task
+/// itself when it starts.
+pub fn supervise(task: Task) {
+    SUPERVISED.fetch_or(task.bit(), Ordering::Relaxed);
+    check_in(task);
+}
+
+/// Tells the supervisor `task` is still making progress.
+pub fn check_in(task: Task) {
+    ALIVE.fetch_or(task.bit(), Ordering::Relaxed);
+}
+
+/// Runs `future`, checking in for `task` meanwhile. Only for waits that may
+/// legitimately take forever, or for a while longer than a round, as a task
+/// stuck in `future` isn't noticed.
+pub async fn alive_while<F: Future>(task: Task, future: F) -> F::Output {
+    let heartbeat = async {
+        loop {
+            check_in(task);
+            Timer::after(CHECK_IN_INTERVAL).await;
+        }
+    };
+    match select(future, heartbeat).await {
+        Either::First(output) => output,
+        Either::Second(_) => unreachable!(),
+    }
+}
+
+/// Starts the watchdog and feeds it while all supervised tasks check in,
+/// forever.
+pub async fn run(iwdg: IWDG) -> ! {
+    // Don't restart the chip while a debug probe has it halted
+    pac::DBGMCU.apb1fzr().modify(|w| w.set_iwdg(true));
+    let mut watchdog = IndependentWatchdog::new(iwdg, TIMEOUT_US);
+    watchdog.unleash();
+    let mut rounds = Rounds::new();
+    loop {
+        Timer::after(ROUND).await;
+        let idle = if usb_keyboard::bus_event_pending() {
+            0
+        } else {
+            Task::Usb.bit()
+        };
+        let stalled = rounds.end(
+            SUPERVISED.load(Ordering::Relaxed),
+            ALIVE.swap(0, Ordering::Relaxed),
+            idle,
+        );
+        if stalled == 0 {
+            watchdog.pet();
+        }
+        for task in ResetCause::Watchdog(stalled).stalled() {
+            warn!("Task {} didn't check in", task);
+        }
+        // Kept up to date, so the next boot knows which tasks stopped if the
+        // watchdog does reset the chip
+        record_stalled(stalled);
+    }
+}
+
+fn record_stalled(stalled: u16) {
+    unsafe {
+        (&raw mut STALLED)
+            .cast::<[u32; 2]>()
+            .write_volatile(watchdog::retained(stalled))
+    };
+}